@@ -1,7 +1,20 @@
 use std::io::Cursor;
 
 use anode_kv::codec::decode;
-use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use anode_kv::config::Config;
+use anode_kv::server::Server;
+use anode_kv::storage::StorageCommand;
+use anode_kv::transaction::{
+    Durability, FsyncPolicy, LogRequest, TransactionSendQueue, TransactionWorker,
+};
+use anode_kv::types::{Blob, Value};
+use anode_kv::worker::{StorageBatch, Worker, WorkerHandle, WorkerSendQueue};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Duration;
 
 fn kv_benchmark(c: &mut Criterion) {
     c.bench_function("parse_string", |b| {
@@ -16,5 +29,311 @@ fn kv_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, kv_benchmark);
+const CLIENTS: u64 = 32;
+const COMMANDS_PER_CLIENT: usize = 100;
+
+/// Starts a worker in front of a storage which answers every command at once,
+/// so what's measured is the cost of getting commands to storage and back.
+fn start_worker(runtime: &Runtime, max_batch_size: usize) -> WorkerSendQueue {
+    let config = Config {
+        max_batch_size,
+        ..Default::default()
+    };
+    let (tx, rx) = mpsc::channel(config.worker_queue_size);
+    let (storage_tx, mut storage_rx) = mpsc::channel::<StorageBatch>(config.storage_queue_size);
+
+    runtime.spawn(async move {
+        while let Some(batch) = storage_rx.recv().await {
+            for (_, reply) in batch {
+                reply.send(Ok(Some(Value::Int(1))));
+            }
+        }
+    });
+    runtime.spawn(async move {
+        Worker::new(rx, vec![storage_tx], &config)
+            .run(std::future::pending())
+            .await
+    });
+    tx
+}
+
+/// Many clients each running commands one after another, as connections do.
+async fn run_clients(queue: WorkerSendQueue) {
+    let mut clients = Vec::with_capacity(CLIENTS as usize);
+    for connection in 0..CLIENTS {
+        let mut handle = WorkerHandle::new(connection, queue.clone());
+        clients.push(tokio::spawn(async move {
+            for _ in 0..COMMANDS_PER_CLIENT {
+                let cmd = StorageCommand::Get(Blob(b"key".to_vec()));
+                handle.execute(cmd).await.unwrap().unwrap();
+            }
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+}
+
+type OneshotQueue = mpsc::Sender<(StorageCommand, oneshot::Sender<Option<Value>>)>;
+
+/// Starts a storage which clients send commands to directly, each with a
+/// oneshot channel for its reply, as they did before there was a worker.
+fn start_oneshot_storage(runtime: &Runtime) -> OneshotQueue {
+    let config = Config::default();
+    let (tx, mut rx) = mpsc::channel::<(StorageCommand, oneshot::Sender<Option<Value>>)>(
+        config.storage_queue_size,
+    );
+    runtime.spawn(async move {
+        while let Some((_, reply)) = rx.recv().await {
+            let _ = reply.send(Some(Value::Int(1)));
+        }
+    });
+    tx
+}
+
+/// The same clients as run_clients, sending to a storage from
+/// start_oneshot_storage.
+async fn run_oneshot_clients(queue: OneshotQueue) {
+    let mut clients = Vec::with_capacity(CLIENTS as usize);
+    for _ in 0..CLIENTS {
+        let queue = queue.clone();
+        clients.push(tokio::spawn(async move {
+            for _ in 0..COMMANDS_PER_CLIENT {
+                let cmd = StorageCommand::Get(Blob(b"key".to_vec()));
+                let (tx, rx) = oneshot::channel();
+                queue
+                    .send_timeout((cmd, tx), Duration::from_millis(1_000))
+                    .await
+                    .unwrap();
+                rx.await.unwrap().unwrap();
+            }
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+}
+
+fn dispatch_benchmark(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("dispatch");
+    // straight to storage with a oneshot per command, as before the worker, to
+    // show what the extra hop through the worker costs
+    let queue = start_oneshot_storage(&runtime);
+    group.bench_with_input(BenchmarkId::new("oneshot", ""), &queue, |b, queue| {
+        b.iter(|| runtime.block_on(run_oneshot_clients(queue.clone())))
+    });
+    // a batch size of 1 sends storage a message per command, as before batching
+    for max_batch_size in [1, 64] {
+        let queue = start_worker(&runtime, max_batch_size);
+        group.bench_with_input(
+            BenchmarkId::new("max_batch_size", max_batch_size),
+            &queue,
+            |b, queue| b.iter(|| runtime.block_on(run_clients(queue.clone()))),
+        );
+    }
+    group.finish();
+}
+
+const READ_CLIENTS: usize = 16;
+const READS_PER_CLIENT: usize = 200;
+
+/// Starts a server with nothing in its log, so the benchmark starts the same
+/// every time.
+fn start_server(runtime: &Runtime, name: &str, config: Config) -> String {
+    let dir = std::env::temp_dir().join(format!("anode-bench-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let config = Config {
+        address: "127.0.0.1:0".to_string(),
+        storage_basepath: dir.join("log").to_string_lossy().to_string(),
+        read_log: false,
+        ..config
+    };
+    let mut server = runtime.block_on(Server::create(config)).unwrap();
+    let addr = server.addr();
+    runtime.spawn(async move { server.run().await });
+    addr
+}
+
+/// Many clients reading one after another, with every tenth command a write
+/// so the shards have writes to get on with as well.
+async fn run_readers(addr: &str) {
+    let mut clients = Vec::with_capacity(READ_CLIENTS);
+    for client in 0..READ_CLIENTS {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        clients.push(tokio::spawn(async move {
+            let mut reply = [0u8; 5];
+            for i in 0..READS_PER_CLIENT {
+                if i % 10 == 0 {
+                    let set = format!("*3\r\n+SET\r\n+k{}\r\n+v\r\n", client);
+                    stream.write_all(set.as_bytes()).await.unwrap();
+                    stream.read_exact(&mut reply).await.unwrap(); // +OK
+                } else {
+                    let get = format!("*2\r\n+GET\r\n+k{}\r\n", client);
+                    stream.write_all(get.as_bytes()).await.unwrap();
+                    stream.read_exact(&mut reply[..4]).await.unwrap(); // $-1 or $1
+                    if &reply[..2] == b"$1" {
+                        stream.read_exact(&mut reply[..3]).await.unwrap();
+                    } else {
+                        stream.read_exact(&mut reply[..1]).await.unwrap();
+                    }
+                }
+            }
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+}
+
+fn read_benchmark(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("reads");
+    group.throughput(criterion::Throughput::Elements(
+        (READ_CLIENTS * READS_PER_CLIENT) as u64,
+    ));
+    for concurrent_reads in [false, true] {
+        let config = Config {
+            concurrent_reads,
+            ..Default::default()
+        };
+        let addr = start_server(&runtime, &format!("reads-{}", concurrent_reads), config);
+        group.bench_with_input(
+            BenchmarkId::new("concurrent_reads", concurrent_reads),
+            &addr,
+            |b, addr| b.iter(|| runtime.block_on(run_readers(addr))),
+        );
+    }
+    group.finish();
+}
+
+const WRITE_CLIENTS: usize = 32;
+const WRITES_PER_CLIENT: usize = 50;
+
+/// Many clients each sending SETs one after another and waiting for each to
+/// be acknowledged, so how fast they go depends on how many writes a shard
+/// gets logged at once.
+async fn run_writers(addr: &str) {
+    let mut clients = Vec::with_capacity(WRITE_CLIENTS);
+    for client in 0..WRITE_CLIENTS {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        clients.push(tokio::spawn(async move {
+            let mut reply = [0u8; 5];
+            let set = format!("*3\r\n+SET\r\n+k{}\r\n+v\r\n", client);
+            for _ in 0..WRITES_PER_CLIENT {
+                stream.write_all(set.as_bytes()).await.unwrap();
+                stream.read_exact(&mut reply).await.unwrap(); // +OK
+            }
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+}
+
+fn write_benchmark(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("writes");
+    group.throughput(criterion::Throughput::Elements(
+        (WRITE_CLIENTS * WRITES_PER_CLIENT) as u64,
+    ));
+    for durability in [Durability::Logged, Durability::Fsynced] {
+        let name = format!("{:?}", durability).to_lowercase();
+        let config = Config {
+            durability,
+            ..Default::default()
+        };
+        let addr = start_server(&runtime, &format!("writes-{}", name), config);
+        group.bench_with_input(BenchmarkId::new("durability", name), &addr, |b, addr| {
+            b.iter(|| runtime.block_on(run_writers(addr)))
+        });
+    }
+    group.finish();
+}
+
+const LOG_WRITERS: usize = 16;
+const WRITES_PER_WRITER: usize = 50;
+
+/// Writers each waiting for their write to be acknowledged before sending
+/// the next, like shards with logged durability.
+async fn run_log_writers(queue: &TransactionSendQueue) {
+    let mut writers = Vec::with_capacity(LOG_WRITERS);
+    for writer in 0..LOG_WRITERS {
+        let queue = queue.clone();
+        writers.push(tokio::spawn(async move {
+            let key = Blob(format!("k{}", writer).into_bytes());
+            for _ in 0..WRITES_PER_WRITER {
+                let (tx, rx) = oneshot::channel();
+                let cmd = StorageCommand::Incr(key.clone());
+                queue
+                    .send((LogRequest::Record(vec![cmd]), tx))
+                    .await
+                    .unwrap();
+                rx.await.unwrap().unwrap();
+            }
+        }));
+    }
+    for writer in writers {
+        writer.await.unwrap();
+    }
+}
+
+fn appendfsync_benchmark(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let dir = std::env::temp_dir().join(format!("anode-bench-fsync-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut group = c.benchmark_group("appendfsync");
+    group.throughput(criterion::Throughput::Elements(
+        (LOG_WRITERS * WRITES_PER_WRITER) as u64,
+    ));
+    for policy in [FsyncPolicy::Always, FsyncPolicy::Everysec, FsyncPolicy::No] {
+        let config = Config {
+            storage_basepath: dir
+                .join(format!("{:?}", policy))
+                .to_string_lossy()
+                .to_string(),
+            appendfsync: policy,
+            ..Default::default()
+        };
+        let (tx, rx) = mpsc::channel(config.transaction_queue_size);
+        let mut worker = TransactionWorker::new(rx, config);
+        runtime.spawn(async move { worker.run(std::future::pending()).await });
+
+        group.bench_with_input(
+            BenchmarkId::new("policy", format!("{:?}", policy).to_lowercase()),
+            &tx,
+            |b, tx| b.iter(|| runtime.block_on(run_log_writers(tx))),
+        );
+    }
+    group.finish();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+criterion_group!(
+    benches,
+    kv_benchmark,
+    dispatch_benchmark,
+    read_benchmark,
+    write_benchmark,
+    appendfsync_benchmark
+);
 criterion_main!(benches);
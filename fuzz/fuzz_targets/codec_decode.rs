@@ -0,0 +1,30 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use anode_kv::codec::{decode_with_limits, DecodeLimits, Token};
+use anode_kv::command::Command;
+use libfuzzer_sys::fuzz_target;
+
+// Decode a stream of tokens the same way a connection does, then try to turn
+// them into commands. Any panic here is a crash a client could trigger.
+fuzz_target!(|data: &[u8]| {
+    let limits = DecodeLimits {
+        max_bulk_len: 4096,
+        max_array_len: 64,
+        max_inline_size: 1024,
+    };
+
+    let mut cursor = Cursor::new(data);
+    let mut tokens: Vec<Token> = vec![];
+    while let Ok(token) = decode_with_limits(&mut cursor, &limits) {
+        tokens.push(token);
+    }
+
+    while let Ok((_, consumed)) = Command::from_tokens(&tokens) {
+        if consumed >= tokens.len() {
+            break;
+        }
+        tokens = tokens.split_off(consumed);
+    }
+});
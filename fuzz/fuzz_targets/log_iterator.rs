@@ -0,0 +1,11 @@
+#![no_main]
+
+use anode_kv::transaction::LogIterator;
+use libfuzzer_sys::fuzz_target;
+
+// Read records from an arbitrary log file until the parser gives up. Every
+// input has to end in a returned error, never a panic or abort.
+fuzz_target!(|data: &[u8]| {
+    let mut iter = LogIterator::new(data);
+    while let Ok(Some(_)) = iter.next_result() {}
+});
@@ -0,0 +1,103 @@
+/// Matches bytes against a glob pattern, the same way Redis does for key
+/// patterns: `*` matches any run of bytes, `?` matches any single byte,
+/// `[abc]`, `[a-z]` and `[^a]` match classes, and `\` escapes the next byte.
+pub fn glob_match(pattern: &[u8], input: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // where to resume if the current attempt fails: the pattern position just
+    // after the last `*`, and the input position it's matching up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while i < input.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p + 1, i));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match_class(&pattern[p..], input[i]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == input[i]).then_some(2),
+            Some(c) => (*c == input[i]).then_some(1),
+            None => None,
+        };
+
+        match (step, backtrack) {
+            (Some(len), _) => {
+                p += len;
+                i += 1;
+            }
+            (None, Some((star_p, star_i))) => {
+                backtrack = Some((star_p, star_i + 1));
+                p = star_p;
+                i = star_i + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Matches a byte against the class at the start of the pattern, and returns
+/// the length of the class if it matches. An unterminated class is treated as
+/// running to the end of the pattern.
+fn match_class(pattern: &[u8], c: u8) -> Option<usize> {
+    let mut p = 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (lo, hi) = (
+                pattern[p].min(pattern[p + 2]),
+                pattern[p].max(pattern[p + 2]),
+            );
+            matched |= lo <= c && c <= hi;
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+
+    (matched != negate).then_some((p + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, input: &str) -> bool {
+        glob_match(pattern.as_bytes(), input.as_bytes())
+    }
+
+    #[test]
+    fn it_matches_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("cache:*", "cache:users:1"));
+        assert!(!matches("cache:*", "session:1"));
+        assert!(matches("*:1", "cache:users:1"));
+        assert!(matches("a*b*c", "aXXbYYbc"));
+        assert!(!matches("a*b*c", "aXXbYYb"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+    }
+
+    #[test]
+    fn it_matches_classes_and_escapes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("key[0-9]", "key7"));
+        assert!(!matches("key[0-9]", "keyx"));
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "aXb"));
+    }
+}
@@ -0,0 +1,586 @@
+//! Users and the rules for what they're allowed to do.
+//!
+//! Each user has a set of passwords (stored as SHA-256 hashes), a list of
+//! command rules and a list of key rules. Rules are applied in order and the
+//! last one which matches wins, so `+@all -@admin` allows everything except
+//! admin commands, and `~* !~secret:*` allows every key except secret ones.
+//! Anything which no rule matches is denied.
+//!
+//! The rules use the same syntax as `ACL SETUSER` and the ACL file, which has
+//! one `user <name> <rules...>` line per user.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::types::Key;
+
+mod glob;
+pub use glob::glob_match;
+
+pub const DEFAULT_USER: &str = "default";
+
+/// AclError displays as the error reply sent back to a RESP client.
+#[derive(Error, Debug)]
+pub enum AclError {
+    #[error("NOAUTH Authentication required.")]
+    NoAuth,
+
+    #[error("WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+
+    #[error("NOPERM User {0} has no permissions to run the '{1}' command")]
+    CommandDenied(String, String),
+
+    #[error("NOPERM No permissions to access a key")]
+    KeyDenied,
+
+    #[error("ERR Error in ACL SETUSER modifier '{0}': Syntax error")]
+    InvalidRule(String),
+
+    #[error("ERR Invalid username '{0}'")]
+    InvalidUsername(String),
+
+    #[error("ERR The 'default' user cannot be removed")]
+    DeleteDefaultUser,
+
+    #[error("ERR ACL file line {0}: {1}")]
+    Parse(usize, String),
+
+    #[error("ERR This instance is not configured to use an ACL file")]
+    NoFile,
+
+    #[error("ERR failed to access ACL file: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Groups of commands which rules can allow or deny together with `+@name`
+/// and `-@name`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Category {
+    All,
+    Read,
+    Write,
+    Keyspace,
+    String,
+    Set,
+    Admin,
+    Connection,
+    Dangerous,
+}
+
+impl Category {
+    const NAMES: [(Category, &'static str); 9] = [
+        (Category::All, "all"),
+        (Category::Read, "read"),
+        (Category::Write, "write"),
+        (Category::Keyspace, "keyspace"),
+        (Category::String, "string"),
+        (Category::Set, "set"),
+        (Category::Admin, "admin"),
+        (Category::Connection, "connection"),
+        (Category::Dangerous, "dangerous"),
+    ];
+
+    pub fn from_name(name: &str) -> Option<Category> {
+        Self::NAMES
+            .iter()
+            .find(|(_, n)| n.eq_ignore_ascii_case(name))
+            .map(|(c, _)| *c)
+    }
+
+    pub fn name(&self) -> &'static str {
+        Self::NAMES.iter().find(|(c, _)| c == self).unwrap().1
+    }
+}
+
+/// The categories each command belongs to, or None if there's no such
+/// command. Every command a client can run, whatever protocol it arrives
+/// over, must be listed here.
+pub fn command_categories(command: &str) -> Option<&'static [Category]> {
+    use Category::*;
+
+    let categories: &'static [Category] = match command {
+        "get" | "mget" => &[Read, String],
+        "set" | "incr" | "decr" => &[Write, String],
+        "smembers" | "sinter" | "sunion" => &[Read, Set],
+        "sadd" | "srem" => &[Write, Set],
+        "del" => &[Write, Keyspace],
+        "flushall" => &[Write, Keyspace, Dangerous],
+        "echo" | "command" | "auth" | "waitlsn" => &[Connection],
+        "acl" | "shutdown" | "client" | "rewritelog" => &[Admin, Dangerous],
+        "save" | "bgsave" | "lastsave" | "promote" => &[Admin, Dangerous],
+        "info" => &[Connection, Dangerous],
+        _ => return None,
+    };
+    Some(categories)
+}
+
+/// Whether the command changes data, which is what CLIENT PAUSE WRITE holds
+/// back.
+pub fn is_write_command(command: &str) -> bool {
+    command_categories(command).is_some_and(|categories| categories.contains(&Category::Write))
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum CommandSelector {
+    Category(Category),
+    Name(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct CommandRule {
+    allow: bool,
+    selector: CommandSelector,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct KeyRule {
+    allow: bool,
+    pattern: String,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct User {
+    enabled: bool,
+    nopass: bool,
+    passwords: BTreeSet<String>,
+    commands: Vec<CommandRule>,
+    keys: Vec<KeyRule>,
+}
+
+impl User {
+    /// A user who can do anything without a password, which is what the
+    /// default user is unless configured otherwise.
+    pub fn superuser() -> User {
+        let mut user = User::default();
+        for rule in ["on", "nopass", "~*", "+@all"] {
+            user.apply(rule).unwrap();
+        }
+        user
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn nopass(&self) -> bool {
+        self.nopass
+    }
+
+    pub fn check_password(&self, password: &[u8]) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash_password(password)))
+    }
+
+    pub fn can_run(&self, command: &str) -> bool {
+        let categories = command_categories(command).unwrap_or(&[]);
+        let mut allowed = false;
+        for rule in &self.commands {
+            let matches = match &rule.selector {
+                CommandSelector::Category(Category::All) => true,
+                CommandSelector::Category(c) => categories.contains(c),
+                CommandSelector::Name(name) => name == command,
+            };
+            if matches {
+                allowed = rule.allow;
+            }
+        }
+        allowed
+    }
+
+    pub fn can_access(&self, key: &Key) -> bool {
+        let mut allowed = false;
+        for rule in &self.keys {
+            if glob_match(rule.pattern.as_bytes(), &key.0) {
+                allowed = rule.allow;
+            }
+        }
+        allowed
+    }
+
+    /// Applies a single ACL SETUSER rule.
+    pub fn apply(&mut self, rule: &str) -> Result<(), AclError> {
+        let invalid = || AclError::InvalidRule(rule.to_string());
+        if rule.is_empty() || rule.chars().any(char::is_whitespace) {
+            return Err(invalid());
+        }
+
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => return self.apply("~*"),
+            "resetkeys" => self.keys.clear(),
+            "allcommands" => return self.apply("+@all"),
+            "nocommands" => return self.apply("-@all"),
+            "reset" => *self = User::default(),
+            _ => return self.apply_prefixed(rule).ok_or_else(invalid),
+        }
+        Ok(())
+    }
+
+    fn apply_prefixed(&mut self, rule: &str) -> Option<()> {
+        if let Some(pattern) = rule.strip_prefix("!~") {
+            self.add_key_rule(false, pattern);
+            return Some(());
+        }
+
+        let (prefix, rest) = rule.split_at(rule.chars().next()?.len_utf8());
+        match prefix {
+            ">" => {
+                self.passwords.insert(hash_password(rest.as_bytes()));
+                self.nopass = false;
+            }
+            "<" => {
+                self.passwords.remove(&hash_password(rest.as_bytes()));
+            }
+            "#" => {
+                if rest.len() != 64 || !rest.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return None;
+                }
+                self.passwords.insert(rest.to_lowercase());
+                self.nopass = false;
+            }
+            "~" => self.add_key_rule(true, rest),
+            "+" => self.add_command_rule(true, rest)?,
+            "-" => self.add_command_rule(false, rest)?,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn add_key_rule(&mut self, allow: bool, pattern: &str) {
+        // `*` overrides everything before it, so there's no need to keep it
+        if pattern == "*" {
+            self.keys.clear();
+        }
+        self.keys.push(KeyRule {
+            allow,
+            pattern: pattern.to_string(),
+        });
+    }
+
+    fn add_command_rule(&mut self, allow: bool, selector: &str) -> Option<()> {
+        let selector = match selector.strip_prefix('@') {
+            Some(category) => CommandSelector::Category(Category::from_name(category)?),
+            None => {
+                let name = selector.to_lowercase();
+                command_categories(&name)?;
+                CommandSelector::Name(name)
+            }
+        };
+
+        if selector == CommandSelector::Category(Category::All) {
+            self.commands.clear();
+            if !allow {
+                // denying is the default, so this is the same as no rules
+                return Some(());
+            }
+        }
+        self.commands.push(CommandRule { allow, selector });
+        Some(())
+    }
+
+    /// The rules which recreate this user, as ACL LIST and the ACL file
+    /// show them.
+    pub fn describe(&self) -> String {
+        let mut rules = vec![if self.enabled { "on" } else { "off" }.to_string()];
+        if self.nopass {
+            rules.push("nopass".to_string());
+        }
+        rules.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        rules.extend(self.key_rules());
+        if self.commands.is_empty() {
+            rules.push("-@all".to_string());
+        }
+        rules.extend(self.command_rules());
+        rules.join(" ")
+    }
+
+    pub fn password_hashes(&self) -> impl Iterator<Item = &String> {
+        self.passwords.iter()
+    }
+
+    pub fn key_rules(&self) -> impl Iterator<Item = String> + '_ {
+        self.keys.iter().map(|rule| match rule.allow {
+            true => format!("~{}", rule.pattern),
+            false => format!("!~{}", rule.pattern),
+        })
+    }
+
+    pub fn command_rules(&self) -> impl Iterator<Item = String> + '_ {
+        self.commands.iter().map(|rule| {
+            let sign = if rule.allow { '+' } else { '-' };
+            match &rule.selector {
+                CommandSelector::Category(c) => format!("{}@{}", sign, c.name()),
+                CommandSelector::Name(name) => format!("{}{}", sign, name),
+            }
+        })
+    }
+}
+
+pub fn hash_password(password: &[u8]) -> String {
+    let digest = Sha256::digest(password);
+    let mut hex = String::with_capacity(64);
+    for b in digest {
+        write!(hex, "{:02x}", b).unwrap();
+    }
+    hex
+}
+
+/// Acl is every user the server knows about, along with the file they're
+/// saved to, if there is one.
+#[derive(Clone, Debug)]
+pub struct Acl {
+    users: BTreeMap<String, User>,
+    file: Option<PathBuf>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        let mut users = BTreeMap::new();
+        users.insert(DEFAULT_USER.to_string(), User::superuser());
+        Acl { users, file: None }
+    }
+}
+
+impl Acl {
+    /// Loads the ACL file named in the config. A file which doesn't exist yet
+    /// is treated as empty, so that ACL SAVE can create it.
+    pub fn from_config(config: &Config) -> Result<Acl, AclError> {
+        let path = match &config.acl_file {
+            Some(path) => path,
+            None => return Ok(Acl::default()),
+        };
+
+        let mut acl = match std::fs::read_to_string(path) {
+            Ok(contents) => Acl::parse(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Acl::default(),
+            Err(e) => return Err(e.into()),
+        };
+        acl.file = Some(path.clone());
+        Ok(acl)
+    }
+
+    /// Parses the contents of an ACL file. If it doesn't mention the default
+    /// user, the default user can do anything, as it can without a file.
+    pub fn parse(contents: &str) -> Result<Acl, AclError> {
+        let mut users = BTreeMap::new();
+
+        for (idx, line) in contents.lines().enumerate() {
+            let lineno = idx + 1;
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                None => continue,
+                Some("user") => {}
+                Some(_) => return Err(AclError::Parse(lineno, "expected 'user'".to_string())),
+            }
+
+            let name = parts
+                .next()
+                .ok_or_else(|| AclError::Parse(lineno, "missing username".to_string()))?;
+            if users.contains_key(name) {
+                return Err(AclError::Parse(
+                    lineno,
+                    format!("duplicate user '{}'", name),
+                ));
+            }
+
+            let mut user = User::default();
+            for rule in parts {
+                user.apply(rule)
+                    .map_err(|e| AclError::Parse(lineno, e.to_string()))?;
+            }
+            users.insert(name.to_string(), user);
+        }
+
+        users
+            .entry(DEFAULT_USER.to_string())
+            .or_insert_with(User::superuser);
+        Ok(Acl { users, file: None })
+    }
+
+    /// Writes every user to the ACL file. The file is replaced atomically so
+    /// a crash part way through can't leave it half-written.
+    pub fn save(&self) -> Result<(), AclError> {
+        let path = self.file.as_ref().ok_or(AclError::NoFile)?;
+
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, self.to_file_contents())?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn to_file_contents(&self) -> String {
+        self.list().into_iter().map(|line| line + "\n").collect()
+    }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    /// Applies rules to a user, creating it if needed. Either every rule is
+    /// applied or, if any is invalid, none are.
+    pub fn set_user(&mut self, name: &str, rules: &[String]) -> Result<(), AclError> {
+        if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(AclError::InvalidUsername(name.to_string()));
+        }
+
+        let mut user = self.users.get(name).cloned().unwrap_or_default();
+        for rule in rules {
+            user.apply(rule)?;
+        }
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Deletes users and returns how many existed.
+    pub fn del_users(&mut self, names: &[String]) -> Result<usize, AclError> {
+        if names.iter().any(|name| name == DEFAULT_USER) {
+            return Err(AclError::DeleteDefaultUser);
+        }
+        Ok(names
+            .iter()
+            .filter(|name| self.users.remove(name.as_str()).is_some())
+            .count())
+    }
+
+    /// Each user in the format of the ACL file.
+    pub fn list(&self) -> Vec<String> {
+        self.users
+            .iter()
+            .map(|(name, user)| format!("user {} {}", name, user.describe()))
+            .collect()
+    }
+
+    pub fn authenticate(&self, name: &str, password: &[u8]) -> Result<(), AclError> {
+        match self.users.get(name) {
+            Some(user) if user.check_password(password) => Ok(()),
+            _ => Err(AclError::WrongPass),
+        }
+    }
+
+    /// Checks that a user may run a command against the given keys.
+    pub fn check(&self, name: &str, command: &str, keys: &[&Key]) -> Result<(), AclError> {
+        let user = match self.users.get(name) {
+            Some(user) if user.enabled => user,
+            _ => return Err(AclError::NoAuth),
+        };
+
+        if !user.can_run(command) {
+            return Err(AclError::CommandDenied(
+                name.to_string(),
+                command.to_string(),
+            ));
+        }
+        if !keys.iter().all(|key| user.can_access(key)) {
+            return Err(AclError::KeyDenied);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Blob;
+
+    fn user(rules: &str) -> User {
+        let mut user = User::default();
+        for rule in rules.split_whitespace() {
+            user.apply(rule).unwrap();
+        }
+        user
+    }
+
+    fn key(s: &str) -> Key {
+        Blob(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn the_last_matching_rule_wins() {
+        let u = user("on +@all -@write +set ~* !~secret:* ~secret:shared");
+
+        assert!(u.can_run("get"));
+        assert!(!u.can_run("incr"));
+        assert!(u.can_run("set"));
+        assert!(u.can_access(&key("public")));
+        assert!(!u.can_access(&key("secret:mine")));
+        assert!(u.can_access(&key("secret:shared")));
+    }
+
+    #[test]
+    fn info_is_in_connection_and_dangerous() {
+        assert!(user("on +@connection").can_run("info"));
+        assert!(!user("on +@connection -@dangerous").can_run("info"));
+    }
+
+    #[test]
+    fn everything_is_denied_by_default() {
+        let u = user("on nopass");
+
+        assert!(!u.can_run("get"));
+        assert!(!u.can_access(&key("k")));
+    }
+
+    #[test]
+    fn passwords_are_stored_hashed() {
+        let u = user("on >hunter2");
+
+        assert!(u.check_password(b"hunter2"));
+        assert!(!u.check_password(b"hunter3"));
+        assert!(!u.describe().contains("hunter2"));
+        assert!(!user("off >hunter2").check_password(b"hunter2"));
+    }
+
+    #[test]
+    fn rules_round_trip_through_describe() {
+        let u = user("on >pw ~cache:* !~cache:private:* +@read -smembers +incr");
+        let described = u.describe();
+
+        assert_eq!(u, user(&described));
+        assert_eq!(
+            format!(
+                "on #{} ~cache:* !~cache:private:* +@read -smembers +incr",
+                hash_password(b"pw")
+            ),
+            described
+        );
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let mut u = User::default();
+        for rule in ["+nosuchcommand", "+@nosuchcategory", "#abc", "bogus", ""] {
+            assert!(u.apply(rule).is_err(), "{} should be rejected", rule);
+        }
+    }
+
+    #[test]
+    fn it_parses_acl_files() {
+        let acl = Acl::parse("user default off\n\nuser app on >pw ~app:* +@all\n").unwrap();
+
+        assert!(acl.authenticate("default", b"").is_err());
+        assert!(acl.authenticate("app", b"pw").is_ok());
+        assert!(acl.check("app", "get", &[&key("app:1")]).is_ok());
+        assert!(matches!(
+            acl.check("app", "get", &[&key("other")]),
+            Err(AclError::KeyDenied)
+        ));
+        assert!(matches!(
+            Acl::parse("user a on\nuser a off"),
+            Err(AclError::Parse(2, _))
+        ));
+    }
+}
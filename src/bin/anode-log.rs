@@ -0,0 +1,181 @@
+//! Inspects and repairs log files while the server isn't running. Each
+//! subcommand takes one log file, such as one of a shard's
+//! `<log>.segment<n>` files.
+
+use anode_kv::gateway::blob_to_json;
+use anode_kv::storage::StorageCommand;
+use anode_kv::transaction::{truncate_log, Entry, LogFile, TransactionLogError};
+use anode_kv::types::Value;
+use clap::{Parser, Subcommand};
+use serde_json::json;
+
+#[derive(Debug, Parser)]
+#[command(name = "anode-log")]
+struct Args {
+    #[command(subcommand)]
+    command: LogCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum LogCommand {
+    /// Prints every record with its offset, up to the first bad one
+    Dump {
+        path: String,
+        // One JSON object per record instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Counts the records by command, and the keys by size, up to the first
+    /// bad one
+    Stats { path: String },
+    /// Checks every record's checksum and structure
+    Verify { path: String },
+    /// Cuts the log short at an offset where a record starts, if it's the
+    /// last segment in its manifest
+    Truncate {
+        path: String,
+        #[arg(long)]
+        at: u64,
+    },
+    /// Writes the records for keys with a prefix to a new log
+    Filter {
+        path: String,
+        #[arg(long)]
+        key_prefix: String,
+        // Where to write the new log, which mustn't exist yet
+        #[arg(long)]
+        output: String,
+    },
+}
+
+fn main() {
+    let args = Args::parse();
+    let status = match run(args.command) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            1
+        }
+    };
+    std::process::exit(status);
+}
+
+fn run(command: LogCommand) -> Result<i32, TransactionLogError> {
+    match command {
+        LogCommand::Dump { path, json } => {
+            for entry in LogFile::open(&path)?.entries() {
+                let entry = entry?;
+                match json {
+                    true => println!("{}", entry_to_json(&entry)),
+                    false => println!(
+                        "{:>10} {:>6} lsn={} ts={} {:?}",
+                        entry.offset,
+                        entry.len,
+                        entry.record.stamp.lsn,
+                        entry.record.stamp.timestamp,
+                        entry.record.cmd
+                    ),
+                }
+            }
+            Ok(0)
+        }
+        LogCommand::Stats { path } => {
+            let log = LogFile::open(&path)?;
+            let stats = log.stats();
+            println!("bytes: {}", log.len());
+            println!("records: {}", stats.records);
+            if let Some((first, last)) = stats.lsns {
+                println!("lsns: {}..={}", first, last);
+            }
+            println!("commands:");
+            for (name, count) in &stats.commands {
+                println!("  {:<10} {}", name, count);
+            }
+            println!("key sizes:");
+            for (size, count) in &stats.key_sizes {
+                println!("  <= {:<7} {}", size, count);
+            }
+            match stats.unreadable {
+                Some((offset, reason)) => {
+                    println!("unreadable: from offset {}: {}", offset, reason);
+                    Ok(2)
+                }
+                None => Ok(0),
+            }
+        }
+        LogCommand::Verify { path } => {
+            let report = LogFile::open(&path)?.verify()?;
+            println!("records: {}", report.records);
+            for (offset, len) in &report.skipped {
+                println!("corrupt: {} bytes at offset {}", len, offset);
+            }
+            if let Some(offset) = report.truncate_at {
+                println!("bad tail: from offset {}; truncate there to repair", offset);
+            }
+            match report.skipped.is_empty() && report.truncate_at.is_none() {
+                true => {
+                    println!("ok");
+                    Ok(0)
+                }
+                false => Ok(2),
+            }
+        }
+        LogCommand::Truncate { path, at } => {
+            match truncate_log(&path, at) {
+                Ok(true) => {}
+                Ok(false) => eprintln!(
+                    "warning: {} has no manifest, so it wasn't checked against the rest of its log",
+                    path
+                ),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    return Ok(1);
+                }
+            }
+            println!("truncated to {} bytes", at);
+            Ok(0)
+        }
+        LogCommand::Filter {
+            path,
+            key_prefix,
+            output,
+        } => {
+            // commands without keys, like FLUSHALL, apply to every key, so
+            // they're kept too
+            let prefix = key_prefix.as_bytes();
+            let written = LogFile::open(&path)?.filter(&output, |cmd| {
+                cmd.keys().iter().all(|key| key.0.starts_with(prefix))
+            })?;
+            println!("wrote {} records to {}", written, output);
+            Ok(0)
+        }
+    }
+}
+
+fn entry_to_json(entry: &Entry) -> serde_json::Value {
+    let cmd = &entry.record.cmd;
+    let mut object = json!({
+        "offset": entry.offset,
+        "len": entry.len,
+        "lsn": entry.record.stamp.lsn,
+        "timestamp": entry.record.stamp.timestamp,
+        "command": cmd.name(),
+        "keys": cmd.keys().into_iter().map(blob_to_json).collect::<Vec<_>>(),
+    });
+    match cmd {
+        StorageCommand::Set(_, Value::Int(i))
+        | StorageCommand::SetWithFlags(_, Value::Int(i), _) => object["value"] = json!(i),
+        StorageCommand::Set(_, Value::Blob(blob))
+        | StorageCommand::SetWithFlags(_, Value::Blob(blob), _) => {
+            object["value"] = blob_to_json(blob)
+        }
+        StorageCommand::SetAdd(_, member) | StorageCommand::SetRemove(_, member) => {
+            object["member"] = blob_to_json(member)
+        }
+        _ => {}
+    }
+    if let StorageCommand::SetWithFlags(_, _, flags) = cmd {
+        object["flags"] = json!(flags);
+    }
+    object
+}
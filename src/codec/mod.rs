@@ -38,6 +38,9 @@ pub enum ReadError {
     #[error("insufficient bytes")]
     InsufficientBytes(std::io::Error),
 
+    #[error("{0} exceeds configured limit")]
+    LimitExceeded(&'static str),
+
     #[error("unknown reason: {0}")]
     Failed(#[from] std::io::Error),
 }
@@ -51,6 +54,28 @@ pub enum WriteError {
     Failed(std::io::Error),
 }
 
+/// DecodeLimits bounds the sizes a client is allowed to declare, so that a
+/// single frame can't make us allocate arbitrarily large buffers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DecodeLimits {
+    /// Maximum declared length of a bulk string.
+    pub max_bulk_len: usize,
+    /// Maximum declared element count of an array.
+    pub max_array_len: usize,
+    /// Maximum length of a line-based token (simple strings, errors, integers).
+    pub max_inline_size: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_inline_size: 64 * 1024,
+        }
+    }
+}
+
 /// encode takes in a Write and an Token and encodes it.
 pub fn encode<W: Write>(w: &mut W, token: &Token) -> Result<(), WriteError> {
     let mut buf: Vec<u8> = vec![];
@@ -110,6 +135,12 @@ pub fn encode<W: Write>(w: &mut W, token: &Token) -> Result<(), WriteError> {
 /// decode takes in a Read and returns the first complete message which it
 /// can decode, or an error if the stream is empty or otherwise malformed.
 pub fn decode<T: Read>(s: &mut T) -> Result<Token, ReadError> {
+    decode_with_limits(s, &DecodeLimits::default())
+}
+
+/// decode_with_limits behaves like decode, but rejects any token whose declared
+/// or actual size exceeds the given limits.
+pub fn decode_with_limits<T: Read>(s: &mut T, limits: &DecodeLimits) -> Result<Token, ReadError> {
     let mut buf: [u8; 1] = [0];
 
     s.read_exact(&mut buf)
@@ -117,13 +148,15 @@ pub fn decode<T: Read>(s: &mut T) -> Result<Token, ReadError> {
     let tag = buf[0];
 
     match tag {
-        b':' => Ok(Token::Integer(read_integer(s)?)),
-        b'+' => Ok(Token::SimpleString(read_simple_string(s)?)),
-        b'-' => Ok(Token::Error(read_simple_string(s)?)),
+        b':' => Ok(Token::Integer(read_integer(s, limits)?)),
+        b'+' => Ok(Token::SimpleString(read_simple_string(s, limits)?)),
+        b'-' => Ok(Token::Error(read_simple_string(s, limits)?)),
         b'$' => {
-            let length = read_integer(s)?;
+            let length = read_integer(s, limits)?;
             if length < 0 {
                 Ok(Token::BulkString(None))
+            } else if length as u64 > limits.max_bulk_len as u64 {
+                Err(ReadError::LimitExceeded("bulk length"))
             } else {
                 Ok(Token::BulkString(Some(read_bulk_string(
                     s,
@@ -132,16 +165,19 @@ pub fn decode<T: Read>(s: &mut T) -> Result<Token, ReadError> {
             }
         }
         b'*' => {
-            let length = read_integer(s)?;
+            let length = read_integer(s, limits)?;
+            if length > 0 && length as u64 > limits.max_array_len as u64 {
+                return Err(ReadError::LimitExceeded("array length"));
+            }
             Ok(Token::Array(length))
         }
         _ => Err(ReadError::NotImplemented),
     }
 }
 
-fn read_simple_string<T: Read>(s: &mut T) -> Result<String, ReadError> {
+fn read_simple_string<T: Read>(s: &mut T, limits: &DecodeLimits) -> Result<String, ReadError> {
     let mut buf: [u8; 1] = [0];
-    let mut bytes: Vec<u8> = Vec::with_capacity(1024);
+    let mut bytes: Vec<u8> = Vec::with_capacity(1024.min(limits.max_inline_size));
     loop {
         s.read_exact(&mut buf)
             .map_err(ReadError::InsufficientBytes)?;
@@ -155,6 +191,9 @@ fn read_simple_string<T: Read>(s: &mut T) -> Result<String, ReadError> {
             break;
         }
 
+        if bytes.len() >= limits.max_inline_size {
+            return Err(ReadError::LimitExceeded("inline size"));
+        }
         bytes.push(buf[0]);
     }
 
@@ -162,10 +201,12 @@ fn read_simple_string<T: Read>(s: &mut T) -> Result<String, ReadError> {
     Ok(val)
 }
 
-fn read_integer<T: Read>(s: &mut T) -> Result<i64, ReadError> {
+fn read_integer<T: Read>(s: &mut T, limits: &DecodeLimits) -> Result<i64, ReadError> {
     let mut buf: [u8; 1] = [0];
     let mut val: i64 = 0;
     let mut positive = true;
+    let mut digits: usize = 0;
+    let mut read: usize = 0;
     loop {
         s.read_exact(&mut buf)
             .map_err(ReadError::InsufficientBytes)?;
@@ -174,32 +215,56 @@ fn read_integer<T: Read>(s: &mut T) -> Result<i64, ReadError> {
             s.read_exact(&mut buf)
                 .map_err(ReadError::InsufficientBytes)?;
             return match buf[0] {
-                b'\n' if positive => Ok(val),
-                b'\n' if !positive => Ok(-val),
+                b'\n' if digits == 0 => Err(ReadError::Malformed("expected digits")),
+                b'\n' => Ok(val),
                 _ => Err(ReadError::Malformed("expected \\n after \\r")),
             };
-        } else if buf[0] == b'-' {
-            positive = false;
-        } else {
-            let digit = (buf[0] - b'0') as i64;
-            val = val
-                .checked_mul(10)
-                .ok_or_else(|| ReadError::Malformed("overflowed i64"))?;
-            val = val
-                .checked_add(digit)
-                .ok_or_else(|| ReadError::Malformed("overflowed i64"))?;
+        }
+
+        read += 1;
+        if read > limits.max_inline_size {
+            return Err(ReadError::LimitExceeded("inline size"));
+        }
+
+        match buf[0] {
+            b'-' if read == 1 => positive = false,
+            b'0'..=b'9' => {
+                // accumulate negative values directly so that i64::MIN, which
+                // has no positive counterpart, can still be represented.
+                let digit = (buf[0] - b'0') as i64;
+                val = val
+                    .checked_mul(10)
+                    .ok_or(ReadError::Malformed("overflowed i64"))?;
+                val = if positive {
+                    val.checked_add(digit)
+                } else {
+                    val.checked_sub(digit)
+                }
+                .ok_or(ReadError::Malformed("overflowed i64"))?;
+                digits += 1;
+            }
+            _ => return Err(ReadError::Malformed("expected digit")),
         }
     }
 }
 
 fn read_bulk_string<T: Read>(s: &mut T, length: usize) -> Result<Vec<u8>, ReadError> {
-    let mut buf: Vec<u8> = vec![0; length];
-    s.read_exact(&mut buf[..])
-        .map_err(ReadError::InsufficientBytes)?;
+    // grow the buffer as the bytes actually arrive instead of trusting the
+    // declared length, so a bogus header can't force a huge allocation.
+    let mut buf: Vec<u8> = Vec::new();
+    s.by_ref().take(length as u64).read_to_end(&mut buf)?;
+    if buf.len() < length {
+        return Err(ReadError::InsufficientBytes(
+            std::io::ErrorKind::UnexpectedEof.into(),
+        ));
+    }
 
     let mut crlf_buf: [u8; 2] = [0, 0];
     s.read_exact(&mut crlf_buf)
         .map_err(ReadError::InsufficientBytes)?;
+    if crlf_buf != *b"\r\n" {
+        return Err(ReadError::Malformed("expected \\r\\n after bulk string"));
+    }
 
     Ok(buf)
 }
@@ -248,6 +313,38 @@ mod tests {
         assert!(matches!(decoded, Err(ReadError::Malformed(m)) if m == "overflowed i64"));
     }
 
+    #[test]
+    fn decodes_min_integer() {
+        let encoded = ":-9223372036854775808\r\n";
+        let mut encoded_stream = encoded.as_bytes();
+        let decoded = decode(&mut encoded_stream);
+        assert_eq!(Token::Integer(i64::MIN), decoded.unwrap());
+    }
+
+    #[test]
+    fn decoding_non_digits_fails() {
+        for encoded in [
+            ":12a\r\n", ":\0\r\n", ":1-2\r\n", ":-\r\n", ":\r\n", "$/\r\n",
+        ] {
+            let mut encoded_stream = encoded.as_bytes();
+            let decoded = decode(&mut encoded_stream);
+            assert!(
+                matches!(decoded, Err(ReadError::Malformed(_))),
+                "{:?} decoded as {:?}",
+                encoded,
+                decoded
+            );
+        }
+    }
+
+    #[test]
+    fn decoding_unterminated_bulk_string_fails() {
+        let encoded = "$5\r\nhelloXY";
+        let mut encoded_stream = encoded.as_bytes();
+        let decoded = decode(&mut encoded_stream);
+        assert!(matches!(decoded, Err(ReadError::Malformed(_))));
+    }
+
     #[test]
     fn decodes_basic_string() {
         let encoded = "+hello\r\n";
@@ -323,6 +420,78 @@ mod tests {
         }
     }
 
+    #[test]
+    fn decoding_oversized_bulk_string_fails() {
+        let limits = DecodeLimits {
+            max_bulk_len: 16,
+            ..Default::default()
+        };
+        let encoded = "$17\r\nhello hello hello\r\n";
+        let mut encoded_stream = encoded.as_bytes();
+        let decoded = decode_with_limits(&mut encoded_stream, &limits);
+        assert!(matches!(
+            decoded,
+            Err(ReadError::LimitExceeded("bulk length"))
+        ));
+
+        let encoded = "$9223372036854775807\r\n";
+        let mut encoded_stream = encoded.as_bytes();
+        let decoded = decode(&mut encoded_stream);
+        assert!(matches!(
+            decoded,
+            Err(ReadError::LimitExceeded("bulk length"))
+        ));
+    }
+
+    #[test]
+    fn decoding_oversized_array_fails() {
+        let limits = DecodeLimits {
+            max_array_len: 2,
+            ..Default::default()
+        };
+        let mut encoded_stream = "*2\r\n".as_bytes();
+        let decoded = decode_with_limits(&mut encoded_stream, &limits);
+        assert_eq!(Token::Array(2), decoded.unwrap());
+
+        let mut encoded_stream = "*3\r\n".as_bytes();
+        let decoded = decode_with_limits(&mut encoded_stream, &limits);
+        assert!(matches!(
+            decoded,
+            Err(ReadError::LimitExceeded("array length"))
+        ));
+    }
+
+    #[test]
+    fn decoding_oversized_inline_fails() {
+        let limits = DecodeLimits {
+            max_inline_size: 4,
+            ..Default::default()
+        };
+        let mut encoded_stream = "+hello\r\n".as_bytes();
+        let decoded = decode_with_limits(&mut encoded_stream, &limits);
+        assert!(matches!(
+            decoded,
+            Err(ReadError::LimitExceeded("inline size"))
+        ));
+
+        // unterminated lines are rejected once they pass the limit, rather
+        // than waiting for a terminator that may never come
+        let mut encoded_stream = ":-123456".as_bytes();
+        let decoded = decode_with_limits(&mut encoded_stream, &limits);
+        assert!(matches!(
+            decoded,
+            Err(ReadError::LimitExceeded("inline size"))
+        ));
+    }
+
+    #[test]
+    fn decoding_partial_bulk_string_needs_more_bytes() {
+        let encoded = "$100000000\r\nhello";
+        let mut encoded_stream = encoded.as_bytes();
+        let decoded = decode(&mut encoded_stream);
+        assert!(matches!(decoded, Err(ReadError::InsufficientBytes(_))));
+    }
+
     #[test]
     fn can_encode_decoded_messages() {
         let messages = vec![
@@ -1,24 +1,49 @@
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
+use thiserror::Error;
 use tokio::sync::oneshot;
 
+use crate::acl::{is_write_command, AclError, DEFAULT_USER};
 use crate::codec::Token;
+use crate::connection::Client;
 use crate::server::Context;
-use crate::storage::{StorageCommand, StorageError};
-use crate::types::{Blob, Value};
+use crate::storage::{Item, StorageCommand, StorageError, TransactionLogError};
+use crate::transaction::LogRequest;
+use crate::types::{Blob, Key, Value};
+use crate::worker::{AdmissionError, Priority, WorkerError, WorkerHandle};
 
 mod types;
-pub use types::{Command, CommandError};
+pub use types::{AclCommand, ClientCommand, ClientKill, Command, CommandError};
 
 /// CommandProcessor is responsible for taking a group of tokens, executing them,
-/// and returning the result.
+/// and returning the result. Each client gets its own, which tracks the user
+/// the client is authenticated as.
 pub struct CommandProcessor {
     context: Context,
+    user: Option<String>,
+    client: Option<Arc<Client>>,
+    worker: WorkerHandle,
 }
 
 #[derive(Debug)]
 pub struct ExecutionResult(pub Vec<Token>);
 
+#[derive(Error, Debug)]
+pub enum DispatchError {
+    #[error("timeout while sending to storage")]
+    Timeout,
+
+    #[error("no response from storage")]
+    NoResponse,
+
+    #[error("{0}")]
+    Denied(#[from] AclError),
+
+    #[error("{0}")]
+    Busy(#[from] AdmissionError),
+}
+
 impl IntoIterator for ExecutionResult {
     type Item = Token;
     type IntoIter = std::vec::IntoIter<Self::Item>;
@@ -35,15 +60,108 @@ impl<S: AsRef<str>> From<S> for ExecutionResult {
 }
 
 impl CommandProcessor {
+    /// Clients start out as the default user if it needs no password, and
+    /// unauthenticated otherwise.
     pub fn new(context: Context) -> Self {
-        Self { context }
+        let user = match context.acl.read().unwrap().user(DEFAULT_USER) {
+            Some(user) if user.enabled() && user.nopass() => Some(DEFAULT_USER.to_string()),
+            _ => None,
+        };
+        let worker = WorkerHandle::new(
+            context.connections.detached_id(),
+            context.worker_queue.clone(),
+        );
+        Self {
+            context,
+            user,
+            client: None,
+            worker,
+        }
+    }
+
+    /// Logs the client in as the user named by its identity, if there is one,
+    /// without asking for a password. The identity must have been proven
+    /// some other way, such as with a client certificate.
+    pub fn with_identity(mut self, identity: Option<&str>) -> Self {
+        if let Some(identity) = identity {
+            let acl = self.context.acl.read().unwrap();
+            if acl.user(identity).is_some_and(|user| user.enabled()) {
+                drop(acl);
+                self.user = Some(identity.to_string());
+            }
+        }
+        self
+    }
+
+    /// Attaches the connected client the commands come from, which CLIENT
+    /// commands act on and which is kept up to date as commands run.
+    pub fn with_client(mut self, client: Arc<Client>) -> Self {
+        client.set_user(self.user.as_deref());
+        self.worker.set_connection(client.id());
+        self.client = Some(client);
+        self
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn authenticate(&mut self, user: &str, password: &[u8]) -> Result<(), AclError> {
+        self.context
+            .acl
+            .read()
+            .unwrap()
+            .authenticate(user, password)?;
+        self.user = Some(user.to_string());
+        if let Some(client) = &self.client {
+            client.set_user(self.user.as_deref());
+        }
+        Ok(())
     }
 
-    pub async fn execute_command(&self, command: &Command) -> ExecutionResult {
+    /// Checks the client's user is allowed to run the command. AUTH is always
+    /// allowed, and so is anything else which doesn't touch data or users.
+    fn authorize(&self, command: &Command) -> Result<(), AclError> {
+        if let Command::Auth(..) = command {
+            return Ok(());
+        }
+
+        let user = self.user.as_deref().ok_or(AclError::NoAuth)?;
+        match command {
+            // these only concern the client's own connection
+            Command::Acl(AclCommand::WhoAmI)
+            | Command::Client(
+                ClientCommand::Id
+                | ClientCommand::Info
+                | ClientCommand::SetName(_)
+                | ClientCommand::GetName,
+            )
+            | Command::Unknown(_) => Ok(()),
+            _ => self
+                .context
+                .acl
+                .read()
+                .unwrap()
+                .check(user, command.name(), &command.keys()),
+        }
+    }
+
+    pub async fn execute_command(&mut self, command: &Command) -> ExecutionResult {
+        if let Some(client) = &self.client {
+            client.record_command(command.name());
+        }
+        if let Err(e) = self.authorize(command) {
+            return e.to_string().into();
+        }
+        // CLIENT commands are never held back, so clients can be unpaused
+        if !matches!(command, Command::Client(_)) {
+            self.wait_if_paused(command.name()).await;
+        }
+
         match command {
             Command::Echo(t) => ExecutionResult(vec![t.clone().into()]),
             Command::Command => {
-                let commands = vec![
+                let commands = [
                     Token::BulkString(Some("ECHO".bytes().collect())),
                     Token::BulkString(Some("COMMAND".bytes().collect())),
                     Token::BulkString(Some("GET".bytes().collect())),
@@ -66,6 +184,23 @@ impl CommandProcessor {
                 })
                 .await
             }
+            Command::MultiGet(keys) => {
+                self.execute_command_helper(StorageCommand::MultiGet(keys.clone()), |res| match res
+                {
+                    Ok(Ok(Some(Value::Hash(values)))) => {
+                        let mut reply = Vec::with_capacity(keys.len() + 1);
+                        reply.push(Token::Array(keys.len() as i64));
+                        for key in keys {
+                            reply.push(Token::BulkString(values.get(key).map(|v| v.0.clone())));
+                        }
+                        ExecutionResult(reply)
+                    }
+                    Ok(Ok(_)) => "invalid response from storage".into(),
+                    Ok(Err(err)) => storage_error_to_string(err).into(),
+                    Err(_) => "no response from storage".into(),
+                })
+                .await
+            }
             Command::Set(key, value) => {
                 self.execute_command_helper(
                     StorageCommand::Set(key.clone(), Value::Blob(value.clone())),
@@ -74,7 +209,7 @@ impl CommandProcessor {
                             ExecutionResult(vec![Token::SimpleString("OK".to_string())])
                         }
                         Ok(Ok(Some(value))) => ExecutionResult(value_to_tokens(value)),
-                        Ok(Err(_)) => "internal storage error".into(),
+                        Ok(Err(err)) => storage_error_to_string(err).into(),
                         Err(_) => "no response from storage".into(),
                     },
                 )
@@ -156,29 +291,382 @@ impl CommandProcessor {
                 .await
             }
 
+            Command::Auth(user, password) => {
+                let user = user.as_deref().unwrap_or(DEFAULT_USER);
+                match self.authenticate(user, &password.0) {
+                    Ok(()) => ExecutionResult(vec![Token::SimpleString("OK".to_string())]),
+                    Err(e) => e.to_string().into(),
+                }
+            }
+            Command::Acl(cmd) => self.execute_acl_command(cmd),
+            Command::Client(cmd) => self.execute_client_command(cmd),
+            Command::Shutdown(mode) => {
+                // there's no reply: the connection closes once shutdown starts
+                self.context.shutdown.trigger(*mode);
+                ExecutionResult(vec![])
+            }
+            Command::Info(section) => match self.info(section.as_deref()) {
+                Some(info) => ExecutionResult(vec![Token::BulkString(Some(info.into_bytes()))]),
+                None => "ERR unknown INFO section".into(),
+            },
+
+            Command::RewriteLog => {
+                self.execute_command_helper(StorageCommand::RewriteLog, |res| match res {
+                    Ok(Ok(_)) => ExecutionResult(vec![Token::SimpleString(
+                        "Background log rewriting started".to_string(),
+                    )]),
+                    Ok(Err(err)) => storage_error_to_string(err).into(),
+                    Err(_) => "no response from storage".into(),
+                })
+                .await
+            }
+            Command::Save => {
+                self.execute_command_helper(StorageCommand::Save, |res| match res {
+                    Ok(Ok(_)) => ExecutionResult(vec![Token::SimpleString("OK".to_string())]),
+                    Ok(Err(err)) => storage_error_to_string(err).into(),
+                    Err(_) => "no response from storage".into(),
+                })
+                .await
+            }
+            Command::BgSave => {
+                self.execute_command_helper(StorageCommand::BgSave, |res| match res {
+                    Ok(Ok(_)) => ExecutionResult(vec![Token::SimpleString(
+                        "Background saving started".to_string(),
+                    )]),
+                    Ok(Err(err)) => storage_error_to_string(err).into(),
+                    Err(_) => "no response from storage".into(),
+                })
+                .await
+            }
+            Command::LastSave => {
+                self.execute_command_helper(StorageCommand::LastSave, |res| match res {
+                    Ok(Ok(Some(Value::Int(time)))) => ExecutionResult(vec![Token::Integer(time)]),
+                    Ok(Ok(_)) => "invalid response from storage".into(),
+                    Ok(Err(err)) => storage_error_to_string(err).into(),
+                    Err(_) => "no response from storage".into(),
+                })
+                .await
+            }
+            Command::Promote => {
+                self.execute_command_helper(StorageCommand::Promote, |res| match res {
+                    Ok(Ok(_)) => ExecutionResult(vec![Token::SimpleString("OK".to_string())]),
+                    // the other shards are promoted, and promoting them again
+                    // does nothing, so it's safe to retry
+                    Ok(Err(StorageError::ShardsFailed { shards, reason })) => format!(
+                        "ERR PROMOTE failed on shards {:?} ({}); the rest are writable, and PROMOTE can be retried",
+                        shards, reason
+                    )
+                    .into(),
+                    Ok(Err(err)) => storage_error_to_string(err).into(),
+                    Err(_) => "no response from storage".into(),
+                })
+                .await
+            }
+
+            Command::WaitLsn(lsn, timeout) => {
+                // each shard's worker joined the LSNs in shard order, so its
+                // slot is its shard; without asking, a shard whose log is
+                // fsynced no more than the OS likes might never get there
+                for slot in self.context.lsns.want_durable(*lsn) {
+                    if let Some(queue) = self.context.transaction_queues.get(slot) {
+                        let (tx, _) = oneshot::channel();
+                        let _ = queue.send((LogRequest::Sync { fsync: true }, tx)).await;
+                    }
+                }
+                let durable = self.context.lsns.wait_durable(*lsn, *timeout).await;
+                ExecutionResult(vec![Token::Integer(durable as i64)])
+            }
+
             Command::Unknown(cmd) => format!("{} is not implemented", cmd).into(),
         }
     }
 
-    async fn execute_command_helper(
-        &self,
-        cmd: StorageCommand,
-        f: impl FnOnce(
-            Result<Result<Option<Value>, StorageError>, tokio::sync::oneshot::error::RecvError>,
-        ) -> ExecutionResult,
-    ) -> ExecutionResult {
-        let (tx, rx) = oneshot::channel();
-        let res = self
-            .context
-            .storage_queue
-            .send_timeout((cmd, tx), Duration::from_millis(1_000))
+    fn execute_acl_command(&self, cmd: &AclCommand) -> ExecutionResult {
+        let ok = || ExecutionResult(vec![Token::SimpleString("OK".to_string())]);
+        let bulk = |s: String| Token::BulkString(Some(s.into_bytes()));
+
+        match cmd {
+            AclCommand::SetUser(name, rules) => {
+                match self.context.acl.write().unwrap().set_user(name, rules) {
+                    Ok(()) => ok(),
+                    Err(e) => e.to_string().into(),
+                }
+            }
+            AclCommand::GetUser(name) => {
+                let acl = self.context.acl.read().unwrap();
+                let user = match acl.user(name) {
+                    Some(user) => user,
+                    None => return ExecutionResult(vec![Token::BulkString(None)]),
+                };
+
+                let mut flags = vec![if user.enabled() { "on" } else { "off" }];
+                if user.nopass() {
+                    flags.push("nopass");
+                }
+                let passwords: Vec<&String> = user.password_hashes().collect();
+                let commands: Vec<String> = user.command_rules().collect();
+                let keys: Vec<String> = user.key_rules().collect();
+
+                let mut reply = vec![Token::Array(8), bulk("flags".to_string())];
+                reply.push(Token::Array(flags.len() as i64));
+                reply.extend(flags.into_iter().map(|f| bulk(f.to_string())));
+                reply.push(bulk("passwords".to_string()));
+                reply.push(Token::Array(passwords.len() as i64));
+                reply.extend(passwords.into_iter().map(|p| bulk(p.clone())));
+                reply.push(bulk("commands".to_string()));
+                reply.push(bulk(commands.join(" ")));
+                reply.push(bulk("keys".to_string()));
+                reply.push(bulk(keys.join(" ")));
+                ExecutionResult(reply)
+            }
+            AclCommand::DelUser(names) => {
+                match self.context.acl.write().unwrap().del_users(names) {
+                    Ok(count) => ExecutionResult(vec![Token::Integer(count as i64)]),
+                    Err(e) => e.to_string().into(),
+                }
+            }
+            AclCommand::List => {
+                let users = self.context.acl.read().unwrap().list();
+                let mut reply = vec![Token::Array(users.len() as i64)];
+                reply.extend(users.into_iter().map(bulk));
+                ExecutionResult(reply)
+            }
+            AclCommand::WhoAmI => match &self.user {
+                Some(user) => ExecutionResult(vec![bulk(user.clone())]),
+                None => AclError::NoAuth.to_string().into(),
+            },
+            AclCommand::Save => {
+                // save a copy, so the file write doesn't hold up other clients
+                let acl = self.context.acl.read().unwrap().clone();
+                match acl.save() {
+                    Ok(()) => ok(),
+                    Err(e) => e.to_string().into(),
+                }
+            }
+        }
+    }
+
+    fn execute_client_command(&self, cmd: &ClientCommand) -> ExecutionResult {
+        let ok = || ExecutionResult(vec![Token::SimpleString("OK".to_string())]);
+        let bulk = |s: String| Token::BulkString(Some(s.into_bytes()));
+        let connections = &self.context.connections;
+
+        match cmd {
+            ClientCommand::List(ids) => {
+                let mut list = String::new();
+                for client in connections.clients() {
+                    if ids.as_ref().is_some_and(|ids| !ids.contains(&client.id())) {
+                        continue;
+                    }
+                    list.push_str(&client.info());
+                    list.push('\n');
+                }
+                return ExecutionResult(vec![bulk(list)]);
+            }
+            ClientCommand::Kill(kill) => {
+                let me = self.client.as_ref().map(|client| client.id());
+                let mut killed = 0;
+                for client in connections.clients() {
+                    if kill.matches(&client, me) {
+                        client.kill();
+                        killed += 1;
+                    }
+                }
+
+                return match (kill.legacy, killed) {
+                    (true, 0) => "ERR No such client".into(),
+                    (true, _) => ok(),
+                    (false, killed) => ExecutionResult(vec![Token::Integer(killed)]),
+                };
+            }
+            ClientCommand::Pause(timeout, mode) => {
+                connections.pause().pause(*timeout, *mode);
+                return ok();
+            }
+            ClientCommand::Unpause => {
+                connections.pause().unpause();
+                return ok();
+            }
+            _ => {}
+        }
+
+        // the rest act on the client's own connection
+        let client = match &self.client {
+            Some(client) => client,
+            None => return "ERR CLIENT is only available to connected clients".into(),
+        };
+        match cmd {
+            ClientCommand::Id => ExecutionResult(vec![Token::Integer(client.id() as i64)]),
+            ClientCommand::Info => ExecutionResult(vec![bulk(format!("{}\n", client.info()))]),
+            ClientCommand::SetName(name) => {
+                if name.bytes().any(|c| !(b'!'..=b'~').contains(&c)) {
+                    return "ERR Client names cannot contain spaces, newlines or special characters."
+                        .into();
+                }
+                client.set_name(name);
+                ok()
+            }
+            ClientCommand::GetName => ExecutionResult(vec![Token::BulkString(
+                client.name().map(String::into_bytes),
+            )]),
+            ClientCommand::NoEvict(no_evict) => {
+                client.set_no_evict(*no_evict);
+                ok()
+            }
+            ClientCommand::List(_)
+            | ClientCommand::Kill(_)
+            | ClientCommand::Pause(..)
+            | ClientCommand::Unpause => unreachable!("handled above"),
+        }
+    }
+
+    /// Describes the server in the `# Section` and `field:value` lines Redis
+    /// uses, either the one section asked for or all of them.
+    fn info(&self, section: Option<&str>) -> Option<String> {
+        let sections: &[&str] = match section {
+            None | Some("all" | "everything" | "default") => {
+                &["clients", "admission", "persistence"]
+            }
+            Some(section @ ("clients" | "admission" | "persistence")) => &[section],
+            Some(_) => return None,
+        };
+
+        let mut info = String::new();
+        for section in sections {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            match *section {
+                "clients" => {
+                    let connections = &self.context.connections;
+                    info.push_str("# Clients\r\n");
+                    info.push_str(&format!("connected_clients:{}\r\n", connections.active()));
+                    info.push_str(&format!(
+                        "client_output_buffer_limit_disconnections:{}\r\n",
+                        connections.output_limit_disconnections()
+                    ));
+                }
+                "admission" => {
+                    let stats = self.context.admission.stats();
+                    let queue = &self.context.worker_queue;
+                    info.push_str("# Admission\r\n");
+                    info.push_str(&format!(
+                        "queue_depth:{}\r\n",
+                        queue.max_capacity() - queue.capacity()
+                    ));
+                    info.push_str(&format!("in_flight:{}\r\n", stats.in_flight));
+                    info.push_str(&format!("latency_us:{}\r\n", stats.latency.as_micros()));
+                    info.push_str(&format!("admitted:{}\r\n", stats.admitted));
+                    info.push_str(&format!("delayed:{}\r\n", stats.delayed));
+                    info.push_str(&format!("shed:{}\r\n", stats.shed));
+                    info.push_str(&format!("rate_limited:{}\r\n", stats.rate_limited));
+                }
+                "persistence" => {
+                    let lsns = &self.context.lsns;
+                    info.push_str("# Persistence\r\n");
+                    info.push_str(&format!("current_lsn:{}\r\n", lsns.current()));
+                    info.push_str(&format!("durable_lsn:{}\r\n", lsns.durable()));
+                    let stopped = self
+                        .context
+                        .stopped_logs
+                        .iter()
+                        .filter(|stopped| stopped.load(Ordering::Relaxed))
+                        .count();
+                    info.push_str(&format!("stopped_logs:{}\r\n", stopped));
+                }
+                _ => unreachable!("sections are checked above"),
+            }
+        }
+        Some(info)
+    }
+
+    /// Holds the command back while clients are paused, if it's one the
+    /// pause applies to.
+    async fn wait_if_paused(&self, command: &str) {
+        self.context
+            .connections
+            .pause()
+            .wait(is_write_command(command))
             .await;
+    }
+
+    /// Checks the client may run a single storage command, then sends it to
+    /// storage and waits for its reply. Protocols other than RESP use this
+    /// directly and translate the reply themselves.
+    pub async fn execute_storage_command(
+        &mut self,
+        cmd: StorageCommand,
+    ) -> Result<Result<Option<Value>, StorageError>, DispatchError> {
+        self.check_storage_command(&cmd).await?;
+        self.dispatch(cmd).await
+    }
+
+    async fn dispatch(
+        &mut self,
+        cmd: StorageCommand,
+    ) -> Result<Result<Option<Value>, StorageError>, DispatchError> {
+        self.check_rate_limit()?;
+        // the request counts as in flight until its reply comes back
+        let _permit = self.context.admission.admit(Priority::of(&cmd)).await?;
+
+        // reads of a single key don't need to queue for storage at all
+        if let Some(result) = self.context.reader.read(&cmd) {
+            return Ok(result);
+        }
+
+        self.worker.execute(cmd).await.map_err(|e| match e {
+            WorkerError::Timeout => DispatchError::Timeout,
+            WorkerError::NoResponse => DispatchError::NoResponse,
+        })
+    }
+
+    /// Reads a key's value along with the flags and CAS token memcached keeps
+    /// for it, once the client's passed the same checks as for a GET, and
+    /// been admitted like one. Replies through the worker only carry the
+    /// value, so this always reads the shard's data directly.
+    pub async fn read_item(&mut self, key: &Key) -> Result<Option<Item>, DispatchError> {
+        let cmd = StorageCommand::Get(key.clone());
+        self.check_storage_command(&cmd).await?;
+        self.check_rate_limit()?;
+        let _permit = self.context.admission.admit(Priority::of(&cmd)).await?;
+        Ok(self.context.reader.item(key))
+    }
+
+    async fn check_storage_command(&self, cmd: &StorageCommand) -> Result<(), DispatchError> {
+        let user = self.user.as_deref().ok_or(AclError::NoAuth)?;
+        self.context
+            .acl
+            .read()
+            .unwrap()
+            .check(user, cmd.name(), &cmd.keys())?;
+        if let Some(client) = &self.client {
+            client.record_command(cmd.name());
+        }
+
+        self.wait_if_paused(cmd.name()).await;
+        Ok(())
+    }
 
-        if res.is_err() {
-            return "timeout while sending to storage".into();
+    fn check_rate_limit(&self) -> Result<(), DispatchError> {
+        if let Some(client) = &self.client {
+            if !client.within_rate_limit() {
+                self.context.admission.record_rate_limited();
+                return Err(AdmissionError::RateLimited.into());
+            }
         }
+        Ok(())
+    }
 
-        f(rx.await)
+    async fn execute_command_helper(
+        &mut self,
+        cmd: StorageCommand,
+        f: impl FnOnce(Result<Result<Option<Value>, StorageError>, DispatchError>) -> ExecutionResult,
+    ) -> ExecutionResult {
+        match self.dispatch(cmd).await {
+            Err(DispatchError::Timeout) => "timeout while sending to storage".into(),
+            Err(DispatchError::Busy(e)) => e.to_string().into(),
+            res => f(res),
+        }
     }
 }
 
@@ -218,7 +706,20 @@ fn storage_error_to_string(error: StorageError) -> &'static str {
             "WRONGTYPE Operation against a key holding the wrong kind of value"
         }
         StorageError::Overflow => "ERR increment or decrement would overflow",
+        StorageError::LogError(TransactionLogError::RewriteInProgress) => {
+            "ERR Background log rewriting already in progress"
+        }
+        StorageError::LogError(TransactionLogError::SaveInProgress) => {
+            "ERR Background save already in progress"
+        }
+        StorageError::ReadOnly => {
+            "READONLY The data was recovered to a point in the past; PROMOTE to write"
+        }
+        StorageError::LogStopped => {
+            "READONLY A shard's log stopped after a failed fsync; restart to write"
+        }
         StorageError::LogError(_) => "ERR failure while recording storage operation",
+        StorageError::ShardsFailed { .. } => "ERR the command failed on some shards",
         StorageError::Failed(_) => "ERR unknown storage failure",
     }
 }
@@ -234,8 +735,8 @@ mod tests {
     async fn it_echoes() {
         let (tx, _rx) = mpsc::channel(1);
         let (ttx, _rx) = mpsc::channel(1);
-        let context = Context::new(tx, ttx, Config::default());
-        let cp = CommandProcessor::new(context);
+        let context = Context::new(tx, vec![ttx], Config::default());
+        let mut cp = CommandProcessor::new(context);
 
         let cmd = Command::Echo(Blob(vec![0u8, 1u8, 2u8]));
         let expected = vec![Token::BulkString(Some(vec![0u8, 1u8, 2u8]))];
@@ -244,4 +745,15 @@ mod tests {
 
         assert_eq!(expected, result);
     }
+
+    #[tokio::test]
+    async fn processors_without_clients_are_scheduled_apart() {
+        let (tx, _rx) = mpsc::channel(1);
+        let (ttx, _rx) = mpsc::channel(1);
+        let context = Context::new(tx, vec![ttx], Config::default());
+
+        let first = CommandProcessor::new(context.clone());
+        let second = CommandProcessor::new(context);
+        assert_ne!(first.worker.connection(), second.worker.connection());
+    }
 }
@@ -1,6 +1,10 @@
+use std::time::Duration;
+
 use thiserror::Error;
 
 use crate::codec::Token;
+use crate::connection::{Client, ConnectionId, PauseMode};
+use crate::server::ShutdownMode;
 use crate::types::{Blob, Key};
 
 #[derive(Debug, Eq, PartialEq)]
@@ -10,6 +14,7 @@ pub enum Command {
     Command,
 
     Get(Key),
+    MultiGet(Vec<Key>),
     Set(Key, Blob),
 
     Decr(Key),
@@ -21,9 +26,69 @@ pub enum Command {
     SetUnion(Vec<Key>),
     SetMembers(Key),
 
+    Auth(Option<String>, Blob),
+    Acl(AclCommand),
+    Client(ClientCommand),
+
+    Shutdown(ShutdownMode),
+    Info(Option<String>),
+    RewriteLog,
+    Save,
+    BgSave,
+    LastSave,
+    /// Makes data recovered to a point in the past writable.
+    Promote,
+    /// Waits until an LSN is durable, or for the timeout if there is one.
+    WaitLsn(u64, Option<Duration>),
+
     Unknown(String),
 }
 
+#[derive(Debug, Eq, PartialEq)]
+pub enum AclCommand {
+    SetUser(String, Vec<String>),
+    GetUser(String),
+    DelUser(Vec<String>),
+    List,
+    WhoAmI,
+    Save,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ClientCommand {
+    Id,
+    Info,
+    List(Option<Vec<ConnectionId>>),
+    SetName(String),
+    GetName,
+    Kill(ClientKill),
+    Pause(Duration, PauseMode),
+    Unpause,
+    NoEvict(bool),
+}
+
+/// Which clients CLIENT KILL closes. A client has to match every filter.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ClientKill {
+    pub id: Option<ConnectionId>,
+    pub addr: Option<String>,
+    pub user: Option<String>,
+    pub skip_me: bool,
+    /// The old `CLIENT KILL addr` form, which replies OK rather than a count.
+    pub legacy: bool,
+}
+
+impl ClientKill {
+    /// Whether the filters pick out the client. `me` is the client doing the
+    /// killing, which SKIPME leaves alone.
+    pub fn matches(&self, client: &Client, me: Option<ConnectionId>) -> bool {
+        self.id.is_none_or(|id| id == client.id())
+            && (self.addr.as_ref()).is_none_or(|addr| *addr == client.addr().to_string())
+            && (self.user.as_ref()).is_none_or(|user| Some(user) == client.user().as_ref())
+            && !(self.skip_me && me == Some(client.id()))
+    }
+}
+
 #[derive(Error, Debug, Eq, PartialEq)]
 pub enum CommandError {
     #[error("insufficient tokens")]
@@ -57,6 +122,18 @@ impl Command {
 
                 Ok((Command::Get(key), GET_LENGTH + 1))
             }
+            "MGET" => {
+                if length < 2 {
+                    return Err(CommandError::Malformed);
+                }
+                let mut keys = Vec::with_capacity(length - 1);
+                for token in tokens[2..=length].iter() {
+                    let key = string_token_as_bytes(Some(token))?;
+                    keys.push(key);
+                }
+
+                Ok((Command::MultiGet(keys), length + 1))
+            }
             "SET" => {
                 validate_length(length, SET_LENGTH)?;
                 let key = string_token_as_bytes(tokens.get(2))?;
@@ -114,9 +191,223 @@ impl Command {
 
                 Ok((Command::SetMembers(key), length + 1))
             }
+            "AUTH" => {
+                let (user, password) = match length {
+                    2 => (None, tokens.get(2)),
+                    3 => (Some(string_token_as_string(tokens.get(2))?), tokens.get(3)),
+                    _ => return Err(CommandError::Malformed),
+                };
+                let password = string_token_as_bytes(password)?;
+
+                Ok((Command::Auth(user, password), length + 1))
+            }
+            "ACL" => {
+                let subcommand = string_token_as_string(tokens.get(2))?.to_uppercase();
+                let args = tokens[3..=length]
+                    .iter()
+                    .map(|token| string_token_as_string(Some(token)))
+                    .collect::<Result<Vec<String>, CommandError>>()?;
+
+                let acl = match (subcommand.as_str(), args.as_slice()) {
+                    ("SETUSER", [name, rules @ ..]) => {
+                        AclCommand::SetUser(name.clone(), rules.to_vec())
+                    }
+                    ("GETUSER", [name]) => AclCommand::GetUser(name.clone()),
+                    ("DELUSER", [_, ..]) => AclCommand::DelUser(args),
+                    ("LIST", []) => AclCommand::List,
+                    ("WHOAMI", []) => AclCommand::WhoAmI,
+                    ("SAVE", []) => AclCommand::Save,
+                    ("SETUSER" | "GETUSER" | "DELUSER" | "LIST" | "WHOAMI" | "SAVE", _) => {
+                        return Err(CommandError::Malformed)
+                    }
+                    (unk, _) => return Ok((Command::Unknown(format!("ACL {}", unk)), length + 1)),
+                };
+
+                Ok((Command::Acl(acl), length + 1))
+            }
+            "CLIENT" => {
+                let subcommand = string_token_as_string(tokens.get(2))?.to_uppercase();
+                let args = tokens[3..=length]
+                    .iter()
+                    .map(|token| string_token_as_string(Some(token)))
+                    .collect::<Result<Vec<String>, CommandError>>()?;
+
+                let client = match (subcommand.as_str(), args.as_slice()) {
+                    ("ID", []) => ClientCommand::Id,
+                    ("INFO", []) => ClientCommand::Info,
+                    ("LIST", []) => ClientCommand::List(None),
+                    ("LIST", [option, ids @ ..]) if option.eq_ignore_ascii_case("ID") => {
+                        let ids = ids
+                            .iter()
+                            .map(|id| id.parse().map_err(|_| CommandError::Malformed))
+                            .collect::<Result<Vec<ConnectionId>, CommandError>>()?;
+                        ClientCommand::List(Some(ids))
+                    }
+                    ("LIST", [option, kind]) if option.eq_ignore_ascii_case("TYPE") => {
+                        // every client is a normal one
+                        match kind.to_lowercase().as_str() {
+                            "normal" => ClientCommand::List(None),
+                            "master" | "replica" | "pubsub" => ClientCommand::List(Some(vec![])),
+                            _ => return Err(CommandError::Malformed),
+                        }
+                    }
+                    ("SETNAME", [name]) => ClientCommand::SetName(name.clone()),
+                    ("GETNAME", []) => ClientCommand::GetName,
+                    ("KILL", [addr]) => ClientCommand::Kill(ClientKill {
+                        addr: Some(addr.clone()),
+                        legacy: true,
+                        ..Default::default()
+                    }),
+                    ("KILL", filters) => ClientCommand::Kill(parse_client_kill(filters)?),
+                    ("PAUSE", [timeout, mode @ ..]) => {
+                        let timeout = timeout.parse().map_err(|_| CommandError::Malformed)?;
+                        let mode = match mode {
+                            [] => PauseMode::All,
+                            [mode] if mode.eq_ignore_ascii_case("ALL") => PauseMode::All,
+                            [mode] if mode.eq_ignore_ascii_case("WRITE") => PauseMode::Write,
+                            _ => return Err(CommandError::Malformed),
+                        };
+                        ClientCommand::Pause(Duration::from_millis(timeout), mode)
+                    }
+                    ("UNPAUSE", []) => ClientCommand::Unpause,
+                    ("NO-EVICT", [flag]) => match flag.to_lowercase().as_str() {
+                        "on" => ClientCommand::NoEvict(true),
+                        "off" => ClientCommand::NoEvict(false),
+                        _ => return Err(CommandError::Malformed),
+                    },
+                    (
+                        "ID" | "INFO" | "LIST" | "SETNAME" | "GETNAME" | "PAUSE" | "UNPAUSE"
+                        | "NO-EVICT",
+                        _,
+                    ) => return Err(CommandError::Malformed),
+                    (unk, _) => {
+                        return Ok((Command::Unknown(format!("CLIENT {}", unk)), length + 1))
+                    }
+                };
+
+                Ok((Command::Client(client), length + 1))
+            }
+            "SHUTDOWN" => {
+                let mode = match length {
+                    1 => ShutdownMode::Default,
+                    2 => match string_token_as_string(tokens.get(2))?
+                        .to_uppercase()
+                        .as_str()
+                    {
+                        "SAVE" => ShutdownMode::Save,
+                        "NOSAVE" => ShutdownMode::NoSave,
+                        _ => return Err(CommandError::Malformed),
+                    },
+                    _ => return Err(CommandError::Malformed),
+                };
+
+                Ok((Command::Shutdown(mode), length + 1))
+            }
+            "INFO" => {
+                let section = match length {
+                    1 => None,
+                    2 => Some(string_token_as_string(tokens.get(2))?.to_lowercase()),
+                    _ => return Err(CommandError::Malformed),
+                };
+
+                Ok((Command::Info(section), length + 1))
+            }
+            "REWRITELOG" => {
+                validate_length(length, REWRITELOG_LENGTH)?;
+                Ok((Command::RewriteLog, length + 1))
+            }
+            "SAVE" => {
+                validate_length(length, SAVE_LENGTH)?;
+                Ok((Command::Save, length + 1))
+            }
+            "BGSAVE" => {
+                validate_length(length, BGSAVE_LENGTH)?;
+                Ok((Command::BgSave, length + 1))
+            }
+            "LASTSAVE" => {
+                validate_length(length, LASTSAVE_LENGTH)?;
+                Ok((Command::LastSave, length + 1))
+            }
+            "PROMOTE" => {
+                validate_length(length, PROMOTE_LENGTH)?;
+                Ok((Command::Promote, length + 1))
+            }
+            "WAITLSN" => {
+                validate_length(length, WAITLSN_LENGTH)?;
+                let lsn = string_token_as_string(tokens.get(2))?
+                    .parse()
+                    .map_err(|_| CommandError::Malformed)?;
+                // like WAIT, a timeout of 0 waits for as long as it takes
+                let timeout = match string_token_as_string(tokens.get(3))?.parse() {
+                    Ok(0) => None,
+                    Ok(millis) => Some(Duration::from_millis(millis)),
+                    Err(_) => return Err(CommandError::Malformed),
+                };
+                Ok((Command::WaitLsn(lsn, timeout), length + 1))
+            }
             unk => Ok((Command::Unknown(unk.to_string()), length + 1)),
         }
     }
+
+    /// The lowercase name of the command, as ACL rules refer to it.
+    pub fn name(&self) -> &str {
+        match self {
+            Command::Echo(_) => "echo",
+            Command::Command => "command",
+            Command::Get(_) => "get",
+            Command::MultiGet(_) => "mget",
+            Command::Set(..) => "set",
+            Command::Decr(_) => "decr",
+            Command::Incr(_) => "incr",
+            Command::SetAdd(..) => "sadd",
+            Command::SetRemove(..) => "srem",
+            Command::SetIntersection(_) => "sinter",
+            Command::SetUnion(_) => "sunion",
+            Command::SetMembers(_) => "smembers",
+            Command::Auth(..) => "auth",
+            Command::Acl(_) => "acl",
+            Command::Client(_) => "client",
+            Command::Shutdown(_) => "shutdown",
+            Command::Info(_) => "info",
+            Command::RewriteLog => "rewritelog",
+            Command::Save => "save",
+            Command::BgSave => "bgsave",
+            Command::LastSave => "lastsave",
+            Command::Promote => "promote",
+            Command::WaitLsn(..) => "waitlsn",
+            Command::Unknown(name) => name,
+        }
+    }
+
+    /// The keys the command reads or writes.
+    pub fn keys(&self) -> Vec<&Key> {
+        match self {
+            Command::Get(key)
+            | Command::Set(key, _)
+            | Command::Decr(key)
+            | Command::Incr(key)
+            | Command::SetAdd(key, _)
+            | Command::SetRemove(key, _)
+            | Command::SetMembers(key) => vec![key],
+            Command::MultiGet(keys) | Command::SetIntersection(keys) | Command::SetUnion(keys) => {
+                keys.iter().collect()
+            }
+            Command::Echo(_)
+            | Command::Command
+            | Command::Auth(..)
+            | Command::Acl(_)
+            | Command::Client(_)
+            | Command::Shutdown(_)
+            | Command::Info(_)
+            | Command::RewriteLog
+            | Command::Save
+            | Command::BgSave
+            | Command::LastSave
+            | Command::Promote
+            | Command::WaitLsn(..)
+            | Command::Unknown(_) => vec![],
+        }
+    }
 }
 
 const ECHO_LENGTH: usize = 2;
@@ -128,9 +419,15 @@ const DECR_LENGTH: usize = 2;
 const SADD_LENGTH: usize = 3;
 const SREM_LENGTH: usize = 3;
 const SMEMBERS_LENGTH: usize = 2;
+const REWRITELOG_LENGTH: usize = 1;
+const SAVE_LENGTH: usize = 1;
+const BGSAVE_LENGTH: usize = 1;
+const LASTSAVE_LENGTH: usize = 1;
+const PROMOTE_LENGTH: usize = 1;
+const WAITLSN_LENGTH: usize = 3;
 
 fn get_command(tokens: &[Token]) -> Result<(usize, String), CommandError> {
-    let length = match tokens.get(0) {
+    let length = match tokens.first() {
         Some(Token::Array(l)) if (*l) > 0 => (*l) as usize,
         _ => return Err(CommandError::Malformed),
     };
@@ -159,6 +456,40 @@ fn string_token_as_bytes(token: Option<&Token>) -> Result<Blob, CommandError> {
     }
 }
 
+fn string_token_as_string(token: Option<&Token>) -> Result<String, CommandError> {
+    let Blob(bytes) = string_token_as_bytes(token)?;
+    String::from_utf8(bytes).map_err(|_| CommandError::Malformed)
+}
+
+/// Parses the `CLIENT KILL <filter> <value> ...` form.
+fn parse_client_kill(args: &[String]) -> Result<ClientKill, CommandError> {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(CommandError::Malformed);
+    }
+
+    let mut kill = ClientKill {
+        skip_me: true,
+        ..Default::default()
+    };
+    for pair in args.chunks(2) {
+        let value = pair[1].clone();
+        match pair[0].to_uppercase().as_str() {
+            "ID" => kill.id = Some(value.parse().map_err(|_| CommandError::Malformed)?),
+            "ADDR" => kill.addr = Some(value),
+            "USER" => kill.user = Some(value),
+            "SKIPME" => {
+                kill.skip_me = match value.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(CommandError::Malformed),
+                }
+            }
+            _ => return Err(CommandError::Malformed),
+        }
+    }
+    Ok(kill)
+}
+
 fn validate_length(length: usize, expected_length: usize) -> Result<(), CommandError> {
     if length != expected_length {
         return Err(CommandError::Malformed);
@@ -207,4 +538,38 @@ mod tests {
 
         assert_eq!(expected, Command::from_tokens(&input));
     }
+
+    #[test]
+    fn it_parses_client_kill_filters() {
+        let input: Vec<Token> = ["CLIENT", "KILL", "USER", "app", "skipme", "no"]
+            .iter()
+            .map(|s| Token::SimpleString(s.to_string()))
+            .collect();
+        let input = [vec![Token::Array(6)], input].concat();
+        let expected = Command::Client(ClientCommand::Kill(ClientKill {
+            user: Some("app".to_string()),
+            skip_me: false,
+            ..Default::default()
+        }));
+
+        assert_eq!(Ok((expected, 7)), Command::from_tokens(&input));
+    }
+
+    #[test]
+    fn it_parses_waitlsn_commands() {
+        let waitlsn = |lsn: &str, timeout: &str| {
+            let input: Vec<Token> = ["WAITLSN", lsn, timeout]
+                .iter()
+                .map(|s| Token::SimpleString(s.to_string()))
+                .collect();
+            Command::from_tokens(&[vec![Token::Array(3)], input].concat())
+        };
+
+        assert_eq!(
+            Ok((Command::WaitLsn(12, Some(Duration::from_millis(100))), 4)),
+            waitlsn("12", "100")
+        );
+        assert_eq!(Ok((Command::WaitLsn(12, None), 4)), waitlsn("12", "0"));
+        assert_eq!(Err(CommandError::Malformed), waitlsn("-1", "0"));
+    }
 }
@@ -1,19 +1,199 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
 use clap::Parser;
 
+use crate::codec::DecodeLimits;
+use crate::connection::{ClientClass, OutputBufferLimit};
+use crate::transaction::{Durability, FsyncPolicy, RecoveryMode, RecoveryTarget, SaveRule};
+
 #[derive(Debug, Parser, Clone)]
 pub struct Config {
     // How many worker threads to use
     #[arg(short, long, default_value_t = 8)]
     pub worker_threads: usize,
 
-    // Size channel for sending to the storage processor
-    #[arg(long, default_value_t = 8)]
+    // Size channel for sending batches to the storage processor; kept short, so
+    // commands wait in the worker while storage is busy and batch up there
+    #[arg(long, default_value_t = 2)]
     pub storage_queue_size: usize,
 
+    // Size channel for sending commands to the worker, which batches them for storage
+    #[arg(long, default_value_t = 1024)]
+    pub worker_queue_size: usize,
+
+    // Most commands the worker sends to storage in one batch
+    #[arg(long, default_value_t = 64)]
+    pub max_batch_size: usize,
+
     // Address to bind server to
     #[arg(short, long, default_value = "127.0.0.1:11311")]
     pub address: String,
 
+    // Address to serve the memcached text protocol on, if any
+    #[arg(long)]
+    pub memcached_address: Option<String>,
+
+    // Address to serve the HTTP/JSON gateway on, if any
+    #[arg(long)]
+    pub http_address: Option<String>,
+
+    // Milliseconds an HTTP client has to send a request's headers, counting
+    // from when the connection starts waiting for the request, 0 for no limit
+    #[arg(long, default_value_t = 10_000)]
+    pub http_header_timeout_ms: u64,
+
+    // Unix socket paths to accept RESP connections on, in addition to TCP
+    #[arg(long)]
+    pub unix_socket: Vec<PathBuf>,
+
+    // Permissions for the Unix sockets, in octal (like 660)
+    #[arg(long, value_parser = parse_octal_mode)]
+    pub unix_socket_mode: Option<u32>,
+
+    // Numeric user ID to give ownership of the Unix sockets to
+    #[arg(long)]
+    pub unix_socket_uid: Option<u32>,
+
+    // Numeric group ID to give ownership of the Unix sockets to
+    #[arg(long)]
+    pub unix_socket_gid: Option<u32>,
+
+    // Address to serve RESP over TLS on, if any
+    #[arg(long)]
+    pub tls_address: Option<String>,
+
+    // PEM file with the server's certificate chain
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+
+    // PEM file with the server's private key
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+
+    // PEM file with the CAs trusted to sign client certificates
+    #[arg(long)]
+    pub tls_ca_cert: Option<PathBuf>,
+
+    // Reject TLS clients which don't present a certificate signed by the CA
+    #[arg(long)]
+    pub tls_require_client_cert: bool,
+
+    // How often to check the TLS files for changes, in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    pub tls_reload_interval_ms: u64,
+
+    // File to load ACL users from at startup and write them to on ACL SAVE
+    #[arg(long)]
+    pub acl_file: Option<PathBuf>,
+
+    // How long shutdown waits for clients to finish, in milliseconds
+    #[arg(long, default_value_t = 10_000)]
+    pub shutdown_timeout_ms: u64,
+
+    // Maximum number of clients connected at once, across every listener
+    #[arg(long, default_value_t = 10_000)]
+    pub maxclients: usize,
+
+    // Close clients which send nothing for this many seconds, 0 to never close them
+    #[arg(long, default_value_t = 0)]
+    pub idle_timeout_secs: u64,
+
+    // Seconds a TCP connection is quiet before keepalive probes are sent, 0 to disable them
+    #[arg(long, default_value_t = 300)]
+    pub tcp_keepalive_secs: u64,
+
+    // Send small writes straight away rather than coalescing them (TCP_NODELAY)
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub tcp_nodelay: bool,
+
+    // Output buffer limits for a class of client, as "<class> <hard> <soft> <soft seconds>"
+    // with sizes like 32mb. Can be given once per class: normal, pubsub or replica. An HTTP
+    // response is written in one go, so only the hard limit applies to it
+    #[arg(long)]
+    pub client_output_buffer_limit: Vec<OutputBufferLimit>,
+
+    // Most storage requests in flight at once; past this they're turned away with -BUSY
+    #[arg(long, default_value_t = 1024)]
+    pub admission_max_in_flight: usize,
+
+    // Low priority requests are held back once this many storage requests are in flight
+    #[arg(long, default_value_t = 256)]
+    pub admission_low_priority_in_flight: usize,
+
+    // Low priority requests are held back while storage takes longer than this, in milliseconds
+    #[arg(long, default_value_t = 50)]
+    pub admission_latency_target_ms: u64,
+
+    // How long a held back request waits before it's turned away with -BUSY, in milliseconds
+    #[arg(long, default_value_t = 100)]
+    pub admission_max_delay_ms: u64,
+
+    // Commands a single client may run per second, 0 for no limit
+    #[arg(long, default_value_t = 0)]
+    pub client_rate_limit: u64,
+
+    // Number of shards to split the keyspace over, each run on its own task with its own log
+    #[arg(long, default_value_t = 4)]
+    pub storage_shards: usize,
+
+    // Run reads of a single key straight against the shards, rather than through the worker
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub concurrent_reads: bool,
+
+    // When writes are acknowledged: async (before they're logged), logged (once
+    // they're written to the log) or fsynced (once the log is fsynced)
+    #[arg(long, value_enum, default_value_t = Durability::Async)]
+    pub durability: Durability,
+
+    // When the log is fsynced: always (before writes are acknowledged), everysec
+    // (once a second) or no (left to the OS)
+    #[arg(long, value_enum, default_value_t = FsyncPolicy::Everysec)]
+    pub appendfsync: FsyncPolicy,
+
+    // What to do about bad records when replaying the log: strict (refuse to
+    // start), truncate-tail (drop a partly written last record) or skip-corrupt
+    // (skip to the next good record)
+    #[arg(long, value_enum, default_value_t = RecoveryMode::TruncateTail)]
+    pub log_recovery: RecoveryMode,
+
+    // Recover the data as it was at a point in the past, by replaying the snapshot
+    // and log only up to "lsn:<lsn>" or "time:<unix seconds>", then start read-only
+    // until PROMOTE. Reads the log even without read_log
+    #[arg(long)]
+    pub recover_until: Option<RecoveryTarget>,
+
+    // Rewrite a shard's log once it's grown by this percentage since it was last
+    // rewritten (or since startup), 0 to only rewrite on REWRITELOG
+    #[arg(long, default_value_t = 100)]
+    pub log_rewrite_percentage: u64,
+
+    // Smallest a log gets before it's rewritten automatically, in bytes
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    pub log_rewrite_min_size: u64,
+
+    // Start a new log segment once the current one is this many bytes, 0 for no limit
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    pub log_segment_size: u64,
+
+    // Start a new log segment once the current one is this many seconds old, 0 for no limit
+    #[arg(long, default_value_t = 0)]
+    pub log_segment_secs: u64,
+
+    // Log segments which snapshots cover are removed; keep this many of them anyway
+    #[arg(long, default_value_t = 0)]
+    pub log_retain_segments: usize,
+
+    // Keep log segments which snapshots cover until they're this many seconds old
+    #[arg(long, default_value_t = 0)]
+    pub log_retain_secs: u64,
+
+    // Save a snapshot once this many seconds have passed since the last save, if this
+    // many writes have been made since, as "<seconds> <changes>". Can be given more
+    // than once; with none, snapshots are only saved on SAVE or BGSAVE
+    #[arg(long)]
+    pub save: Vec<SaveRule>,
+
     // Base filepath for durable storage
     #[arg(short, long, default_value = "./tmp/log")]
     pub storage_basepath: String,
@@ -25,17 +205,135 @@ pub struct Config {
     // Size channel for sending transactions to the transaction worker
     #[arg(long, default_value_t = 100)]
     pub transaction_queue_size: usize,
+
+    // Maximum length of a single bulk string sent by a client
+    #[arg(long, default_value_t = 512 * 1024 * 1024)]
+    pub max_bulk_len: usize,
+
+    // Maximum number of elements in a single array sent by a client
+    #[arg(long, default_value_t = 1024 * 1024)]
+    pub max_array_len: usize,
+
+    // Maximum length of a line-based token (simple string, error, integer)
+    #[arg(long, default_value_t = 64 * 1024)]
+    pub max_inline_size: usize,
+
+    // Maximum number of unparsed bytes buffered for a single client
+    #[arg(long, default_value_t = 1024 * 1024 * 1024)]
+    pub max_query_buffer: usize,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             worker_threads: 8,
-            storage_queue_size: 10,
+            storage_queue_size: 2,
+            worker_queue_size: 1024,
+            max_batch_size: 64,
             transaction_queue_size: 100,
             address: "127.0.0.1:11311".to_string(),
+            memcached_address: None,
+            http_address: None,
+            http_header_timeout_ms: 10_000,
+            unix_socket: vec![],
+            unix_socket_mode: None,
+            unix_socket_uid: None,
+            unix_socket_gid: None,
+            tls_address: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca_cert: None,
+            tls_require_client_cert: false,
+            tls_reload_interval_ms: 5000,
+            acl_file: None,
+            shutdown_timeout_ms: 10_000,
+            maxclients: 10_000,
+            idle_timeout_secs: 0,
+            tcp_keepalive_secs: 300,
+            tcp_nodelay: true,
+            client_output_buffer_limit: vec![],
+            admission_max_in_flight: 1024,
+            admission_low_priority_in_flight: 256,
+            admission_latency_target_ms: 50,
+            admission_max_delay_ms: 100,
+            client_rate_limit: 0,
+            storage_shards: 4,
+            concurrent_reads: true,
+            durability: Durability::Async,
+            appendfsync: FsyncPolicy::Everysec,
+            log_recovery: RecoveryMode::TruncateTail,
+            recover_until: None,
+            log_rewrite_percentage: 100,
+            log_rewrite_min_size: 64 * 1024 * 1024,
+            log_segment_size: 64 * 1024 * 1024,
+            log_segment_secs: 0,
+            log_retain_segments: 0,
+            log_retain_secs: 0,
+            save: vec![],
             storage_basepath: "./tmp/log".to_string(),
             read_log: false,
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_inline_size: 64 * 1024,
+            max_query_buffer: 1024 * 1024 * 1024,
         }
     }
 }
+
+impl Config {
+    pub fn decode_limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_bulk_len: self.max_bulk_len,
+            max_array_len: self.max_array_len,
+            max_inline_size: self.max_inline_size,
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+
+    /// How long the HTTP gateway waits for a request's headers. Waiting for a
+    /// request is also when a connection is idle, so this is the shorter of
+    /// the header and idle timeouts.
+    pub fn http_header_timeout(&self) -> Option<Duration> {
+        let header = (self.http_header_timeout_ms > 0)
+            .then(|| Duration::from_millis(self.http_header_timeout_ms));
+        match (header, self.idle_timeout()) {
+            (Some(header), Some(idle)) => Some(header.min(idle)),
+            (header, idle) => header.or(idle),
+        }
+    }
+
+    /// The output buffer limits for a class of client, falling back on the
+    /// defaults for classes which aren't configured.
+    pub fn output_buffer_limit(&self, class: ClientClass) -> OutputBufferLimit {
+        self.client_output_buffer_limit
+            .iter()
+            .rev()
+            .find(|limit| limit.class == class)
+            .copied()
+            .unwrap_or_else(|| OutputBufferLimit::default_for(class))
+    }
+
+    /// The base filepath for a shard's log. A single shard uses the base
+    /// filepath as it is, as it did before there were shards.
+    pub fn shard_basepath(&self, shard: usize) -> String {
+        if self.storage_shards == 1 {
+            self.storage_basepath.clone()
+        } else {
+            format!("{}.shard{}", self.storage_basepath, shard)
+        }
+    }
+
+    pub fn tcp_keepalive(&self) -> Option<Duration> {
+        (self.tcp_keepalive_secs > 0).then(|| Duration::from_secs(self.tcp_keepalive_secs))
+    }
+}
+
+fn parse_octal_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{} is not an octal file mode", s)),
+    }
+}
@@ -0,0 +1,194 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use super::{ConnectionId, PeerAddr, Protocol};
+use crate::worker::RateLimiter;
+
+/// What's known about a connected client. It's shared between the client's
+/// connection, which keeps it up to date, and the CLIENT commands of every
+/// other client, which list and kill clients.
+#[derive(Debug)]
+pub struct Client {
+    id: ConnectionId,
+    addr: PeerAddr,
+    protocol: Protocol,
+    created: Instant,
+    details: Mutex<Details>,
+    killed: watch::Sender<bool>,
+    rate_limit: Option<Mutex<RateLimiter>>,
+}
+
+#[derive(Debug)]
+struct Details {
+    name: Option<String>,
+    user: Option<String>,
+    last_active: Instant,
+    last_command: Option<String>,
+    query_buffer: usize,
+    query_buffer_free: usize,
+    output_buffer: usize,
+    no_evict: bool,
+}
+
+impl Client {
+    pub fn new(id: ConnectionId, addr: PeerAddr, protocol: Protocol) -> Self {
+        let now = Instant::now();
+        let (killed, _) = watch::channel(false);
+        Self {
+            id,
+            addr,
+            protocol,
+            created: now,
+            details: Mutex::new(Details {
+                name: None,
+                user: None,
+                last_active: now,
+                last_command: None,
+                query_buffer: 0,
+                query_buffer_free: 0,
+                output_buffer: 0,
+                no_evict: false,
+            }),
+            killed,
+            rate_limit: None,
+        }
+    }
+
+    /// Limits the client to a number of commands per second, or not at all
+    /// when given 0.
+    pub fn with_rate_limit(mut self, per_second: u64) -> Self {
+        self.rate_limit = (per_second > 0).then(|| Mutex::new(RateLimiter::new(per_second)));
+        self
+    }
+
+    /// Whether the client may run another command, using up some of its rate
+    /// limit if it may.
+    pub fn within_rate_limit(&self) -> bool {
+        match &self.rate_limit {
+            Some(limiter) => limiter.lock().unwrap().try_acquire(),
+            None => true,
+        }
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    pub fn addr(&self) -> &PeerAddr {
+        &self.addr
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.details.lock().unwrap().name.clone()
+    }
+
+    /// Names the client, or clears its name when given an empty one.
+    pub fn set_name(&self, name: &str) {
+        let name = (!name.is_empty()).then(|| name.to_string());
+        self.details.lock().unwrap().name = name;
+    }
+
+    pub fn user(&self) -> Option<String> {
+        self.details.lock().unwrap().user.clone()
+    }
+
+    pub fn set_user(&self, user: Option<&str>) {
+        self.details.lock().unwrap().user = user.map(str::to_string);
+    }
+
+    /// Notes that the client just ran a command.
+    pub fn record_command(&self, name: &str) {
+        let mut details = self.details.lock().unwrap();
+        details.last_active = Instant::now();
+        details.last_command = Some(name.to_string());
+    }
+
+    /// Notes how much of the client's input is waiting to be parsed, and how
+    /// much room the buffer has left for more.
+    pub fn set_query_buffer(&self, len: usize, free: usize) {
+        let mut details = self.details.lock().unwrap();
+        details.query_buffer = len;
+        details.query_buffer_free = free;
+    }
+
+    /// Notes how many reply bytes are waiting to be written to the client.
+    pub fn set_output_buffer(&self, len: usize) {
+        self.details.lock().unwrap().output_buffer = len;
+    }
+
+    pub fn set_no_evict(&self, no_evict: bool) {
+        self.details.lock().unwrap().no_evict = no_evict;
+    }
+
+    /// Asks the client's connection to close. A connection notices between
+    /// commands, so the command it's running, if any, still gets its reply.
+    pub fn kill(&self) {
+        self.killed.send_replace(true);
+    }
+
+    /// Completes once the client has been killed.
+    pub async fn killed(&self) {
+        let mut rx = self.killed.subscribe();
+        let _ = rx.wait_for(|killed| *killed).await;
+    }
+
+    /// Describes the client in the one-line format used by CLIENT LIST.
+    pub fn info(&self) -> String {
+        let details = self.details.lock().unwrap();
+        let now = Instant::now();
+        let flags = if details.no_evict { "e" } else { "N" };
+
+        let mut info = String::new();
+        let _ = write!(
+            info,
+            "id={} addr={} name={} age={} idle={} flags={} db=0 qbuf={} qbuf-free={} obl={} omem={} cmd={} user={} proto={}",
+            self.id,
+            self.addr,
+            details.name.as_deref().unwrap_or(""),
+            now.duration_since(self.created).as_secs(),
+            now.duration_since(details.last_active).as_secs(),
+            flags,
+            details.query_buffer,
+            details.query_buffer_free,
+            details.output_buffer,
+            details.output_buffer,
+            details.last_command.as_deref().unwrap_or("NULL"),
+            details.user.as_deref().unwrap_or(""),
+            self.protocol.name(),
+        );
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn it_describes_the_client() {
+        let client = Client::new(
+            7,
+            PeerAddr::Tcp(([127, 0, 0, 1], 5000).into()),
+            Protocol::Resp,
+        );
+        client.set_name("reporting");
+        client.set_user(Some("default"));
+        client.record_command("get");
+        tokio::time::advance(std::time::Duration::from_secs(3)).await;
+
+        assert_eq!(
+            "id=7 addr=127.0.0.1:5000 name=reporting age=3 idle=3 flags=N db=0 qbuf=0 qbuf-free=0 obl=0 omem=0 cmd=get user=default proto=resp",
+            client.info()
+        );
+
+        client.set_name("");
+        assert_eq!(None, client.name());
+    }
+}
@@ -1,83 +1,233 @@
 use std::io::Cursor;
-use std::net::SocketAddr;
+use std::sync::Arc;
 
 use bytes::{Buf, BytesMut};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::{Duration, Instant};
 
-use crate::codec::{decode, encode, Token};
+use super::{read_or_idle, Client, ClientClass, OutputBuffer, Stream};
+use crate::codec::{decode_with_limits, encode, ReadError, Token};
 use crate::command::{Command, CommandError, CommandProcessor};
+use crate::gateway;
+use crate::memcached;
 use crate::server::Context;
 
 pub type ConnectionId = u64;
 
-pub struct Connection {
-    id: ConnectionId,
-    socket: TcpStream,
-    addr: SocketAddr,
+/// The wire protocol a connection speaks, decided by which listener accepted it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Protocol {
+    Resp,
+    Memcached,
+    Http,
+}
+
+impl Protocol {
+    /// The name CLIENT LIST shows for the protocol.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Resp => "resp",
+            Protocol::Memcached => "memcached",
+            Protocol::Http => "http",
+        }
+    }
+
+    /// What a client turned away by maxclients is told before it's dropped.
+    pub fn max_clients_reply(&self) -> &'static [u8] {
+        match self {
+            Protocol::Resp => b"-ERR max number of clients reached\r\n",
+            Protocol::Memcached => b"SERVER_ERROR max number of clients reached\r\n",
+            Protocol::Http => {
+                b"HTTP/1.1 503 Service Unavailable\r\ncontent-type: text/plain\r\ncontent-length: 31\r\nconnection: close\r\n\r\nmax number of clients reached\r\n"
+            }
+        }
+    }
+}
+
+pub struct Connection<S: Stream> {
+    client: Arc<Client>,
+    socket: S,
+    identity: Option<String>,
     context: Context,
 }
 
-impl Connection {
-    pub fn new(context: Context, id: ConnectionId, socket: TcpStream, addr: SocketAddr) -> Self {
+impl<S: Stream> Connection<S> {
+    pub fn new(context: Context, client: Arc<Client>, socket: S, identity: Option<String>) -> Self {
         Connection {
-            id,
+            client,
             socket,
-            addr,
+            identity,
             context,
         }
     }
 
     pub async fn handle(&mut self) -> std::io::Result<()> {
-        tracing::debug!("(id={}) accepting connection from {}", self.id, self.addr);
+        tracing::debug!(
+            "(id={}) accepting {:?} connection from {} (identity={:?})",
+            self.client.id(),
+            self.client.protocol(),
+            self.client.addr(),
+            self.identity
+        );
 
+        let client = self.client.clone();
+        match client.protocol() {
+            Protocol::Resp => self.handle_resp().await,
+            Protocol::Memcached => {
+                memcached::handle(&mut self.socket, self.context.clone(), client).await
+            }
+            Protocol::Http => gateway::handle(&mut self.socket, self.context.clone(), client).await,
+        }
+    }
+
+    async fn handle_resp(&mut self) -> std::io::Result<()> {
         let mut buffer = BytesMut::with_capacity(4 * 1024);
-        let cp = CommandProcessor::new(self.context.clone());
+        let mut cp = CommandProcessor::new(self.context.clone())
+            .with_identity(self.identity.as_deref())
+            .with_client(self.client.clone());
+        let limits = self.context.config.decode_limits();
+        // there are no pubsub or replica clients yet
+        let mut output =
+            OutputBuffer::new(self.context.config.output_buffer_limit(ClientClass::Normal));
 
         let mut tokens: Vec<Token> = vec![];
 
+        let shutdown = self.context.shutdown.clone();
+        let client = self.client.clone();
+        let (mut reader, mut writer) = tokio::io::split(&mut self.socket);
+
         loop {
-            if 0 == self.socket.read_buf(&mut buffer).await? {
-                break;
-            }
+            let soft_deadline = output.soft_deadline();
 
-            loop {
-                let mut cursor = Cursor::new(&buffer[..]);
-                if let Ok(token) = decode(&mut cursor) {
-                    let pos = cursor.position() as usize;
-                    buffer.advance(pos);
-                    tokens.push(token);
-
-                    if buffer.is_empty() && !tokens.is_empty() {
-                        let (command, consumed) = match Command::from_tokens(&tokens) {
-                            Ok(x) => x,
-                            Err(CommandError::InsufficientTokens) => continue,
-                            Err(e) => {
-                                return Err(std::io::Error::new(std::io::ErrorKind::Other, e))
-                            }
-                        };
+            // stop between commands once the server is shutting down or the
+            // client has been killed, and stop reading commands while their
+            // replies are backed up
+            let read = tokio::select! {
+                biased;
+                _ = shutdown.wait() => break,
+                _ = client.killed() => break,
+                written = writer.write(output.pending()), if !output.is_empty() => {
+                    match written? {
+                        0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                        written => output.consume(written),
+                    }
+                    None
+                }
+                _ = tokio::time::sleep_until(soft_deadline.unwrap_or_else(Instant::now)),
+                    if soft_deadline.is_some() => None,
+                read = read_or_idle(&mut reader, &mut buffer, &self.context.config),
+                    if output.len() < MAX_PENDING_OUTPUT => Some(read?),
+            };
 
-                        if consumed < tokens.len() {
-                            tokens = tokens.split_off(consumed);
-                        } else {
-                            tokens.clear();
-                        }
+            match read {
+                Some(0) => break,
+                Some(_) => loop {
+                    let mut cursor = Cursor::new(&buffer[..]);
+                    match decode_with_limits(&mut cursor, &limits) {
+                        Ok(token) => {
+                            let pos = cursor.position() as usize;
+                            buffer.advance(pos);
+                            tokens.push(token);
+
+                            if buffer.is_empty() && !tokens.is_empty() {
+                                let (command, consumed) = match Command::from_tokens(&tokens) {
+                                    Ok(x) => x,
+                                    Err(CommandError::InsufficientTokens) => continue,
+                                    Err(e) => return Err(std::io::Error::other(e)),
+                                };
 
-                        let resp = cp.execute_command(&command).await;
-                        for token in resp {
-                            let mut write_buf: Vec<u8> = vec![];
-                            encode(&mut write_buf, &token)
-                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?; // TODO: handle error
+                                if consumed < tokens.len() {
+                                    tokens = tokens.split_off(consumed);
+                                } else {
+                                    tokens.clear();
+                                }
 
-                            self.socket.write_all(&write_buf).await?;
+                                let resp = cp.execute_command(&command).await;
+                                let mut write_buf: Vec<u8> = vec![];
+                                for token in resp {
+                                    encode(&mut write_buf, &token)
+                                        .map_err(std::io::Error::other)?; // TODO: handle error
+                                }
+                                output.extend(&write_buf);
+                                // a client sending a pipeline it never reads
+                                // the replies to mustn't get to buffer them all
+                                if over_output_limit(&mut output, &client, &self.context) {
+                                    return Ok(());
+                                }
+                            }
                         }
+                        Err(ReadError::InsufficientBytes(_)) => break,
+                        Err(e) => return reply_protocol_error(&mut writer, &mut output, e).await,
                     }
-                } else {
-                    break;
-                }
+                },
+                None => {}
+            }
+
+            client.set_query_buffer(buffer.len(), buffer.capacity() - buffer.len());
+            if buffer.len() > self.context.config.max_query_buffer {
+                let e = ReadError::LimitExceeded("query buffer");
+                return reply_protocol_error(&mut writer, &mut output, e).await;
+            }
+
+            if over_output_limit(&mut output, &client, &self.context) {
+                return Ok(());
             }
         }
 
-        Ok(())
+        flush(&mut writer, &mut output).await
+    }
+}
+
+/// Stop reading more commands from a client while this much of its output is
+/// waiting to be written.
+pub const MAX_PENDING_OUTPUT: usize = 64 * 1024;
+
+/// Checks the client's output against its limits, and says why it's being
+/// disconnected if it's gone past one. Returns whether it has.
+pub fn over_output_limit(output: &mut OutputBuffer, client: &Client, context: &Context) -> bool {
+    client.set_output_buffer(output.len());
+    let Err(exceeded) = output.check(Instant::now()) else {
+        return false;
+    };
+    tracing::warn!(
+        id = client.id(),
+        addr = %client.addr(),
+        limit = ?exceeded,
+        pending = output.len(),
+        "disconnecting client for going past its output buffer limit"
+    );
+    context.connections.record_output_limit_disconnection();
+    true
+}
+
+/// How long a closing connection waits for its last replies to be written.
+pub const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Writes out whatever's left in the output buffer, giving up if the client
+/// doesn't read it in time.
+pub async fn flush<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    output: &mut OutputBuffer,
+) -> std::io::Result<()> {
+    match tokio::time::timeout(FLUSH_TIMEOUT, writer.write_all(output.pending())).await {
+        Ok(res) => res,
+        Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
     }
 }
+
+/// Tells the client why its input was rejected. The stream can't be
+/// resynchronized after a protocol error, so this always ends the connection
+/// by returning the error.
+async fn reply_protocol_error<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    output: &mut OutputBuffer,
+    err: ReadError,
+) -> std::io::Result<()> {
+    let mut write_buf: Vec<u8> = vec![];
+    let token = Token::Error(format!("ERR Protocol error: {}", err));
+    encode(&mut write_buf, &token).map_err(std::io::Error::other)?;
+    output.extend(&write_buf);
+    flush(writer, output).await?;
+
+    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
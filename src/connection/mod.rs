@@ -1,47 +1,145 @@
+use std::fmt;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
-use tokio::net::TcpStream;
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{Duration, Instant};
 
+mod client;
 mod conn;
+mod output;
+mod pause;
 mod tracker;
 
-pub use conn::{Connection, ConnectionId};
+pub use client::Client;
+pub use conn::{
+    flush, over_output_limit, Connection, ConnectionId, Protocol, FLUSH_TIMEOUT, MAX_PENDING_OUTPUT,
+};
+pub use output::{
+    ClientClass, LimitExceeded, OutputBuffer, OutputBufferLimit, OutputBufferLimitError,
+};
+pub use pause::{ClientPause, PauseMode};
 pub use tracker::ConnectionTracker;
 
+use crate::config::Config;
 use crate::server::Context;
 
+/// Any byte stream a client can connect over, such as a TCP or Unix socket.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for T {}
+
+/// Where a client connected from. Unix socket peers are almost always
+/// unnamed, so they're identified by the socket they connected to instead.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ConnectionManager {
     latest_id: Arc<AtomicU64>,
+    /// Ids for command processors with no client, counted down from the top
+    /// so they never meet a client's.
+    detached_id: Arc<AtomicU64>,
     tracker: Arc<Mutex<ConnectionTracker>>,
+    pause: ClientPause,
+    output_limit_disconnections: Arc<AtomicU64>,
 }
 
 impl Default for ConnectionManager {
     fn default() -> Self {
+        Self::new(ConnectionTracker::default())
+    }
+}
+
+impl ConnectionManager {
+    pub fn new(tracker: ConnectionTracker) -> Self {
         Self {
             latest_id: Arc::new(AtomicU64::new(0)),
-            tracker: Arc::new(Mutex::new(ConnectionTracker::default())),
+            detached_id: Arc::new(AtomicU64::new(u64::MAX)),
+            tracker: Arc::new(Mutex::new(tracker)),
+            pause: ClientPause::default(),
+            output_limit_disconnections: Arc::new(AtomicU64::new(0)),
         }
     }
-}
 
-impl ConnectionManager {
-    pub async fn take_connection(
-        &mut self,
+    /// The number of clients currently connected.
+    pub fn active(&self) -> usize {
+        self.tracker.lock().unwrap().active()
+    }
+
+    /// Every connected client, in the order they connected.
+    pub fn clients(&self) -> Vec<Arc<Client>> {
+        self.tracker.lock().unwrap().clients()
+    }
+
+    /// An id for something which runs commands without a connected client,
+    /// so the worker schedules its commands apart from everyone else's.
+    pub fn detached_id(&self) -> ConnectionId {
+        self.detached_id.fetch_sub(1, Ordering::Relaxed)
+    }
+
+    /// Counts a client disconnected for going past its output buffer limits.
+    pub fn record_output_limit_disconnection(&self) {
+        self.output_limit_disconnections
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How many clients have been disconnected for going past their output
+    /// buffer limits.
+    pub fn output_limit_disconnections(&self) -> u64 {
+        self.output_limit_disconnections.load(Ordering::Relaxed)
+    }
+
+    /// Pauses and unpauses every client, for CLIENT PAUSE.
+    pub fn pause(&self) -> &ClientPause {
+        &self.pause
+    }
+
+    /// Hands the socket off to a new connection task, or turns the client
+    /// away if maxclients has been reached.
+    pub async fn take_connection<S: Stream>(
+        &self,
         context: Context,
-        socket: TcpStream,
-        addr: SocketAddr,
-    ) -> ConnectionId {
+        socket: S,
+        addr: PeerAddr,
+        identity: Option<String>,
+        protocol: Protocol,
+    ) -> Option<ConnectionId> {
+        // hold the lock until the handle is added, so a connection which ends
+        // straight away can't be removed before it's added, and so two clients
+        // can't both take the last slot
+        let tracker = self.tracker.clone();
+        let mut tracked = self.tracker.lock().unwrap();
+        if tracked.is_full() {
+            tracing::warn!(addr=%addr, "rejecting connection, max number of clients reached");
+            tokio::spawn(reject(socket, protocol));
+            return None;
+        }
+
         let id = self.latest_id.fetch_add(1, Ordering::SeqCst);
 
-        let mut connection = Connection::new(context, id, socket, addr);
+        let client = Client::new(id, addr.clone(), protocol)
+            .with_rate_limit(context.config.client_rate_limit);
+        let client = Arc::new(client);
+        let mut connection = Connection::new(context, client.clone(), socket, identity);
         let span = tracing::debug_span!("ConnectionManager::take_connection:1", id=id, addr=?addr);
         let _guard = span.enter();
 
-        let tracker = self.tracker.clone();
-
         let handle = tokio::spawn(async move {
             let span = tracing::debug_span!("ConnectionManager::take_connection::handle", id=id, addr=?addr);
             let _guard = span.enter();
@@ -58,8 +156,58 @@ impl ConnectionManager {
             };
         });
 
-        self.tracker.lock().unwrap().add(id, handle);
+        tracked.add(client, handle);
+
+        Some(id)
+    }
+
+    /// Waits for every connection to finish, and aborts any which are still
+    /// running after the timeout. Returns whether they all finished in time.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if self.tracker.lock().unwrap().is_empty() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut tracker = self.tracker.lock().unwrap();
+        let finished = tracker.is_empty();
+        tracker.abort_all();
+        finished
+    }
+}
 
-        id
+/// Tells a client turned away by maxclients why, in its own protocol.
+async fn reject<S: Stream>(mut socket: S, protocol: Protocol) {
+    let reply = protocol.max_clients_reply();
+    let write = tokio::time::timeout(REJECT_TIMEOUT, socket.write_all(reply));
+    if let Ok(Ok(())) = write.await {
+        let _ = socket.shutdown().await;
+    }
+}
+
+/// How long a rejected client gets to take its error before it's dropped.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reads more from the client, and reports a client which has been silent for
+/// longer than the idle timeout as disconnected.
+pub async fn read_or_idle<R: AsyncRead + Unpin>(
+    socket: &mut R,
+    buffer: &mut BytesMut,
+    config: &Config,
+) -> std::io::Result<usize> {
+    let idle_timeout = match config.idle_timeout() {
+        Some(idle_timeout) => idle_timeout,
+        None => return socket.read_buf(buffer).await,
+    };
+
+    match tokio::time::timeout(idle_timeout, socket.read_buf(buffer)).await {
+        Ok(read) => read,
+        Err(_) => {
+            tracing::info!("closing idle connection");
+            Ok(0)
+        }
     }
 }
@@ -0,0 +1,265 @@
+use std::str::FromStr;
+
+use bytes::{Buf, BytesMut};
+use thiserror::Error;
+use tokio::time::{Duration, Instant};
+
+/// The kinds of client which get their own output buffer limits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClientClass {
+    Normal,
+    Pubsub,
+    Replica,
+}
+
+impl ClientClass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientClass::Normal => "normal",
+            ClientClass::Pubsub => "pubsub",
+            ClientClass::Replica => "replica",
+        }
+    }
+}
+
+/// How much output a client may have waiting before it's disconnected: at
+/// once past the hard limit, or after staying past the soft limit for the
+/// soft duration. A limit of 0 turns it off.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OutputBufferLimit {
+    pub class: ClientClass,
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    /// The limits a class gets unless they're configured, which are the same
+    /// as Redis's.
+    pub fn default_for(class: ClientClass) -> Self {
+        let (hard, soft, soft_seconds) = match class {
+            ClientClass::Normal => (0, 0, 0),
+            ClientClass::Pubsub => (32 * 1024 * 1024, 8 * 1024 * 1024, 60),
+            ClientClass::Replica => (256 * 1024 * 1024, 64 * 1024 * 1024, 60),
+        };
+        Self {
+            class,
+            hard,
+            soft,
+            soft_seconds,
+        }
+    }
+}
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum OutputBufferLimitError {
+    #[error("expected <class> <hard> <soft> <soft seconds>")]
+    Syntax,
+
+    #[error("unknown client class {0}, expected normal, pubsub or replica")]
+    UnknownClass(String),
+
+    #[error("invalid size {0}")]
+    InvalidSize(String),
+}
+
+/// Parses the `<class> <hard> <soft> <soft seconds>` form Redis uses for
+/// client-output-buffer-limit. Sizes can have a unit: k, kb, m, mb, g or gb.
+impl FromStr for OutputBufferLimit {
+    type Err = OutputBufferLimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [class, hard, soft, soft_seconds] = fields[..] else {
+            return Err(OutputBufferLimitError::Syntax);
+        };
+
+        let class = match class.to_lowercase().as_str() {
+            "normal" => ClientClass::Normal,
+            "pubsub" => ClientClass::Pubsub,
+            "replica" | "slave" => ClientClass::Replica,
+            _ => return Err(OutputBufferLimitError::UnknownClass(class.to_string())),
+        };
+        Ok(Self {
+            class,
+            hard: parse_size(hard)?,
+            soft: parse_size(soft)?,
+            soft_seconds: soft_seconds
+                .parse()
+                .map_err(|_| OutputBufferLimitError::InvalidSize(soft_seconds.to_string()))?,
+        })
+    }
+}
+
+fn parse_size(s: &str) -> Result<usize, OutputBufferLimitError> {
+    let lower = s.to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(OutputBufferLimitError::InvalidSize(s.to_string())),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| OutputBufferLimitError::InvalidSize(s.to_string()))
+}
+
+/// Which output buffer limit a client went past.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LimitExceeded {
+    Hard,
+    Soft,
+}
+
+/// Replies waiting to be written to a client, so a client which is slow to
+/// read doesn't hold up everything else its connection does.
+#[derive(Debug)]
+pub struct OutputBuffer {
+    buf: BytesMut,
+    limit: OutputBufferLimit,
+    over_soft_since: Option<Instant>,
+}
+
+impl OutputBuffer {
+    pub fn new(limit: OutputBufferLimit) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            limit,
+            over_soft_since: None,
+        }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The bytes still to be written.
+    pub fn pending(&self) -> &[u8] {
+        &self.buf[..]
+    }
+
+    /// Drops bytes from the front once they've been written.
+    pub fn consume(&mut self, written: usize) {
+        self.buf.advance(written);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Checks the buffer against the client's limits, and notes when it first
+    /// went past the soft limit.
+    pub fn check(&mut self, now: Instant) -> Result<(), LimitExceeded> {
+        let len = self.buf.len();
+        if self.limit.hard > 0 && len > self.limit.hard {
+            return Err(LimitExceeded::Hard);
+        }
+
+        if self.limit.soft > 0 && len > self.limit.soft {
+            let since = *self.over_soft_since.get_or_insert(now);
+            if now.duration_since(since) >= self.soft_duration() {
+                return Err(LimitExceeded::Soft);
+            }
+        } else {
+            self.over_soft_since = None;
+        }
+        Ok(())
+    }
+
+    /// When the buffer will have been past the soft limit for too long, if
+    /// it's past it now.
+    pub fn soft_deadline(&self) -> Option<Instant> {
+        self.over_soft_since
+            .map(|since| since + self.soft_duration())
+    }
+
+    fn soft_duration(&self) -> Duration {
+        Duration::from_secs(self.limit.soft_seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(hard: usize, soft: usize, soft_seconds: u64) -> OutputBufferLimit {
+        OutputBufferLimit {
+            class: ClientClass::Normal,
+            hard,
+            soft,
+            soft_seconds,
+        }
+    }
+
+    #[test]
+    fn it_parses_limits() {
+        assert_eq!(
+            Ok(OutputBufferLimit {
+                class: ClientClass::Pubsub,
+                hard: 32 * 1024 * 1024,
+                soft: 8_000_000,
+                soft_seconds: 60,
+            }),
+            "pubsub 32mb 8m 60".parse()
+        );
+        assert_eq!(Ok(limit(0, 0, 0)), "NORMAL 0 0 0".parse());
+        assert_eq!(
+            Err(OutputBufferLimitError::UnknownClass("master".to_string())),
+            "master 0 0 0".parse::<OutputBufferLimit>()
+        );
+        assert_eq!(
+            Err(OutputBufferLimitError::InvalidSize("12xb".to_string())),
+            "normal 12xb 0 0".parse::<OutputBufferLimit>()
+        );
+        assert_eq!(
+            Err(OutputBufferLimitError::Syntax),
+            "normal 0 0".parse::<OutputBufferLimit>()
+        );
+    }
+
+    #[test]
+    fn it_enforces_the_hard_limit_at_once() {
+        let mut output = OutputBuffer::new(limit(10, 0, 0));
+        output.extend(b"0123456789");
+        assert_eq!(Ok(()), output.check(Instant::now()));
+
+        output.extend(b"!");
+        assert_eq!(Err(LimitExceeded::Hard), output.check(Instant::now()));
+    }
+
+    #[test]
+    fn it_enforces_the_soft_limit_over_time() {
+        let mut output = OutputBuffer::new(limit(0, 4, 10));
+        let start = Instant::now();
+        output.extend(b"01234");
+        assert_eq!(Ok(()), output.check(start));
+        assert_eq!(
+            Some(start + Duration::from_secs(10)),
+            output.soft_deadline()
+        );
+
+        // dropping back under the limit starts the clock again
+        output.consume(3);
+        assert_eq!(Ok(()), output.check(start + Duration::from_secs(5)));
+        assert_eq!(None, output.soft_deadline());
+
+        output.extend(b"567");
+        let later = start + Duration::from_secs(6);
+        assert_eq!(Ok(()), output.check(later));
+        assert_eq!(
+            Err(LimitExceeded::Soft),
+            output.check(later + Duration::from_secs(10))
+        );
+    }
+}
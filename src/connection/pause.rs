@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+/// Which commands CLIENT PAUSE holds back.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum PauseMode {
+    Write,
+    All,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Paused {
+    until: Instant,
+    mode: PauseMode,
+}
+
+/// ClientPause holds commands back while clients are paused, until the pause
+/// runs out or it's lifted.
+#[derive(Clone, Debug)]
+pub struct ClientPause {
+    tx: Arc<watch::Sender<Option<Paused>>>,
+}
+
+impl Default for ClientPause {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(None);
+        Self { tx: Arc::new(tx) }
+    }
+}
+
+impl ClientPause {
+    /// Pauses clients for the duration. Pausing while already paused keeps
+    /// whichever pause lasts longer and whichever mode holds back more.
+    pub fn pause(&self, duration: Duration, mode: PauseMode) {
+        let until = Instant::now() + duration;
+        self.tx.send_modify(|paused| {
+            *paused = match *paused {
+                Some(current) if current.until > Instant::now() => Some(Paused {
+                    until: current.until.max(until),
+                    mode: current.mode.max(mode),
+                }),
+                _ => Some(Paused { until, mode }),
+            };
+        });
+    }
+
+    pub fn unpause(&self) {
+        self.tx.send_replace(None);
+    }
+
+    /// Waits until a command may run, which is straight away unless clients
+    /// are paused in a mode which holds it back.
+    pub async fn wait(&self, write: bool) {
+        let mut rx = self.tx.subscribe();
+        loop {
+            let until = match *rx.borrow_and_update() {
+                Some(paused) if write || paused.mode == PauseMode::All => paused.until,
+                _ => return,
+            };
+            if until <= Instant::now() {
+                return;
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep_until(until) => {}
+                _ = rx.changed() => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn it_holds_writes_until_the_pause_ends() {
+        let pause = ClientPause::default();
+        pause.pause(Duration::from_secs(10), PauseMode::Write);
+
+        let start = Instant::now();
+        pause.wait(false).await;
+        assert_eq!(start, Instant::now());
+
+        pause.wait(true).await;
+        assert_eq!(Duration::from_secs(10), start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_releases_everything_on_unpause() {
+        let pause = ClientPause::default();
+        pause.pause(Duration::from_secs(10), PauseMode::All);
+
+        let start = Instant::now();
+        let waiting = {
+            let pause = pause.clone();
+            tokio::spawn(async move { pause.wait(false).await })
+        };
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!waiting.is_finished());
+
+        pause.unpause();
+        waiting.await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -1,19 +1,61 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
 use tokio::task::JoinHandle;
 
-use super::ConnectionId;
+use super::{Client, ConnectionId};
 
-#[derive(Default)]
 pub struct ConnectionTracker {
-    active_connections: HashSet<ConnectionId>,
+    max_clients: usize,
+    active_connections: BTreeMap<ConnectionId, Arc<Client>>,
     connection_handles: HashMap<ConnectionId, JoinHandle<()>>,
 }
 
+impl Default for ConnectionTracker {
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
+}
+
 impl ConnectionTracker {
-    pub fn add(&mut self, id: ConnectionId, handle: JoinHandle<()>) {
-        self.active_connections.insert(id);
-        self.connection_handles.insert(id, handle);
+    pub fn new(max_clients: usize) -> Self {
+        Self {
+            max_clients,
+            active_connections: BTreeMap::new(),
+            connection_handles: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, client: Arc<Client>, handle: JoinHandle<()>) {
+        self.connection_handles.insert(client.id(), handle);
+        self.active_connections.insert(client.id(), client);
+    }
+
+    /// Every connected client, in the order they connected.
+    pub fn clients(&self) -> Vec<Arc<Client>> {
+        self.active_connections.values().cloned().collect()
+    }
+
+    /// The number of connections which are still running.
+    pub fn active(&self) -> usize {
+        self.active_connections.len()
+    }
+
+    /// Whether another connection would take us past maxclients.
+    pub fn is_full(&self) -> bool {
+        self.active() >= self.max_clients
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active_connections.is_empty()
+    }
+
+    /// Aborts every connection which is still running.
+    pub fn abort_all(&mut self) {
+        for (_, handle) in self.connection_handles.drain() {
+            handle.abort();
+        }
+        self.active_connections.clear();
     }
 
     pub fn remove(&mut self, id: ConnectionId) {
@@ -27,3 +69,31 @@ impl ConnectionTracker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{PeerAddr, Protocol};
+
+    fn client(id: ConnectionId) -> Arc<Client> {
+        let addr = PeerAddr::Tcp(([127, 0, 0, 1], 5000).into());
+        Arc::new(Client::new(id, addr, Protocol::Resp))
+    }
+
+    #[tokio::test]
+    async fn it_counts_active_connections_against_the_limit() {
+        let mut tracker = ConnectionTracker::new(2);
+        assert!(tracker.is_empty());
+
+        tracker.add(client(1), tokio::spawn(async {}));
+        assert!(!tracker.is_full());
+        tracker.add(client(2), tokio::spawn(async {}));
+        assert_eq!(2, tracker.active());
+        assert!(tracker.is_full());
+
+        tracker.remove(1);
+        assert_eq!(1, tracker.active());
+        assert!(!tracker.is_full());
+        assert_eq!(2, tracker.clients()[0].id());
+    }
+}
@@ -0,0 +1,432 @@
+//! An HTTP/JSON gateway to the keyspace, for tools which can't speak RESP.
+//!
+//! Routes:
+//!  - `GET /keys/{key}`: the value at the key
+//!  - `PUT /keys/{key}`: stores the raw request body at the key
+//!  - `DELETE /keys/{key}`
+//!  - `GET /keys/{key}/members`: the members of a set
+//!  - `PUT /keys/{key}/members/{member}`, `DELETE /keys/{key}/members/{member}`
+//!  - `POST /command`: runs a command given as a JSON array of arguments
+//!
+//! Keys and members in paths are percent-decoded. Blobs are encoded as JSON
+//! strings when they're valid UTF-8 and as `{"base64": "..."}` otherwise, and
+//! command arguments may use either form. Hashes are encoded as an array of
+//! `[field, value]` pairs, since fields needn't be valid object keys.
+//!
+//! Requests run as the default user unless they carry HTTP Basic credentials
+//! for another one.
+//!
+//! A connection is closed once it has waited `--http-header-timeout-ms` for a
+//! request's headers, or `--idle-timeout-secs` if that's shorter, whether the
+//! client is sending them slowly or has gone quiet between requests.
+
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Body, Incoming};
+use hyper::header::AUTHORIZATION;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::acl::AclError;
+use crate::codec::Token;
+use crate::command::{Command, CommandProcessor, DispatchError};
+use crate::connection::{Client, ClientClass, Stream, FLUSH_TIMEOUT};
+use crate::server::Context;
+use crate::storage::{StorageCommand, StorageError};
+use crate::types::{Blob, Value};
+use crate::worker::AdmissionError;
+
+pub async fn handle<S: Stream>(
+    socket: &mut S,
+    context: Context,
+    client: Arc<Client>,
+) -> std::io::Result<()> {
+    let shutdown = context.shutdown.clone();
+    let header_timeout = context.config.http_header_timeout();
+    let killed = client.clone();
+    let service = service_fn(move |req| {
+        let context = context.clone();
+        let client = client.clone();
+        async move {
+            let response = route(context.clone(), client.clone(), req).await;
+            within_output_limit(&context, &client, response)
+        }
+    });
+
+    // hyper starts the header timer as soon as it waits for a request, so
+    // this closes idle keep-alive connections as well as slow ones
+    let conn = http1::Builder::new()
+        .timer(TokioTimer::new())
+        .header_read_timeout(header_timeout)
+        .serve_connection(TokioIo::new(socket), service);
+    tokio::pin!(conn);
+
+    // finish the request in progress, if any, and then close, without
+    // waiting on a client which won't read its response
+    let res = tokio::select! {
+        res = conn.as_mut() => res,
+        _ = shutdown.wait() => {
+            conn.as_mut().graceful_shutdown();
+            finish(conn).await?
+        }
+        _ = killed.killed() => {
+            conn.as_mut().graceful_shutdown();
+            finish(conn).await?
+        }
+    };
+    match res {
+        // hyper closes the connection when a response is refused, and why
+        // has been logged already
+        Err(e) if std::error::Error::source(&e).is_some_and(|e| e.is::<OutputLimitExceeded>()) => {
+            Ok(())
+        }
+        res => res.map_err(std::io::Error::other),
+    }
+}
+
+/// Waits for a connection which has been told to shut down, giving up if the
+/// client doesn't read its last response in time.
+async fn finish<F: std::future::Future>(conn: F) -> std::io::Result<F::Output> {
+    tokio::time::timeout(FLUSH_TIMEOUT, conn)
+        .await
+        .map_err(|_| std::io::ErrorKind::TimedOut.into())
+}
+
+#[derive(Error, Debug)]
+#[error("response exceeds the output buffer limit")]
+struct OutputLimitExceeded;
+
+/// Hyper writes each response in full, so only the hard limit of the normal
+/// client class applies: a response past it isn't sent, and the client is
+/// disconnected instead, as a RESP client would be.
+fn within_output_limit(
+    context: &Context,
+    client: &Client,
+    response: Response<Full<Bytes>>,
+) -> Result<Response<Full<Bytes>>, OutputLimitExceeded> {
+    let limit = context.config.output_buffer_limit(ClientClass::Normal);
+    let len = response.body().size_hint().exact().unwrap_or(0) as usize;
+    if limit.hard == 0 || len <= limit.hard {
+        return Ok(response);
+    }
+    tracing::warn!(
+        id = client.id(),
+        addr = %client.addr(),
+        pending = len,
+        "disconnecting client for going past its output buffer limit"
+    );
+    context.connections.record_output_limit_disconnection();
+    Err(OutputLimitExceeded)
+}
+
+async fn route(
+    context: Context,
+    client: Arc<Client>,
+    req: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    let max_body = context.config.max_bulk_len;
+    let mut cp = CommandProcessor::new(context).with_client(client);
+    if let Some(header) = req.headers().get(AUTHORIZATION) {
+        let credentials = header
+            .to_str()
+            .ok()
+            .and_then(|h| h.strip_prefix("Basic "))
+            .and_then(|encoded| BASE64.decode(encoded).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let authenticated = match credentials.as_ref().and_then(|c| c.split_once(':')) {
+            Some((user, password)) => cp.authenticate(user, password.as_bytes()).is_ok(),
+            None => false,
+        };
+        if !authenticated {
+            return error(StatusCode::UNAUTHORIZED, "invalid credentials");
+        }
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    let decoded: Option<Vec<Blob>> = segments
+        .iter()
+        .skip(1)
+        .map(|segment| percent_decode(segment).map(Blob))
+        .collect();
+    let args = match decoded {
+        Some(args) => args,
+        None => return error(StatusCode::BAD_REQUEST, "invalid percent-encoding in path"),
+    };
+
+    match (&method, segments.as_slice()) {
+        (&Method::GET, ["keys", _]) => {
+            let key = args[0].clone();
+            match cp.execute_storage_command(StorageCommand::Get(key)).await {
+                Ok(Ok(Some(value))) => ok(json!({ "value": value_to_json(value) })),
+                Ok(Ok(None)) => error(StatusCode::NOT_FOUND, "no such key"),
+                res => storage_failure(res),
+            }
+        }
+        (&Method::PUT, ["keys", _]) => {
+            let body = match read_body(req, max_body).await {
+                Ok(body) => body,
+                Err(resp) => return resp,
+            };
+            let cmd = StorageCommand::Set(args[0].clone(), Value::Blob(Blob(body)));
+            match cp.execute_storage_command(cmd).await {
+                Ok(Ok(_)) => ok(json!({ "result": "OK" })),
+                res => storage_failure(res),
+            }
+        }
+        (&Method::DELETE, ["keys", _]) => {
+            let cmd = StorageCommand::Delete(vec![args[0].clone()]);
+            match cp.execute_storage_command(cmd).await {
+                Ok(Ok(Some(Value::Int(0)))) => error(StatusCode::NOT_FOUND, "no such key"),
+                Ok(Ok(_)) => ok(json!({ "result": "OK" })),
+                res => storage_failure(res),
+            }
+        }
+        (&Method::GET, ["keys", _, "members"]) => {
+            let cmd = StorageCommand::SetMembers(args[0].clone());
+            match cp.execute_storage_command(cmd).await {
+                Ok(Ok(Some(value))) => ok(json!({ "value": value_to_json(value) })),
+                res => storage_failure(res),
+            }
+        }
+        (&Method::PUT, ["keys", _, "members", _]) => {
+            let cmd = StorageCommand::SetAdd(args[0].clone(), args[2].clone());
+            match cp.execute_storage_command(cmd).await {
+                Ok(Ok(Some(value))) => ok(json!({ "added": value_to_json(value) })),
+                res => storage_failure(res),
+            }
+        }
+        (&Method::DELETE, ["keys", _, "members", _]) => {
+            let cmd = StorageCommand::SetRemove(args[0].clone(), args[2].clone());
+            match cp.execute_storage_command(cmd).await {
+                Ok(Ok(Some(value))) => ok(json!({ "removed": value_to_json(value) })),
+                res => storage_failure(res),
+            }
+        }
+        (&Method::POST, ["command"]) => {
+            let body = match read_body(req, max_body).await {
+                Ok(body) => body,
+                Err(resp) => return resp,
+            };
+            execute_json_command(&mut cp, &body).await
+        }
+        (_, ["keys", _]) | (_, ["keys", _, "members"]) | (_, ["keys", _, "members", _]) => {
+            error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+        (_, ["command"]) => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        _ => error(StatusCode::NOT_FOUND, "no such route"),
+    }
+}
+
+/// Runs a command given as a JSON array of arguments, the same way a RESP
+/// client would send it, and encodes the RESP reply as JSON.
+async fn execute_json_command(cp: &mut CommandProcessor, body: &[u8]) -> Response<Full<Bytes>> {
+    let args: Vec<serde_json::Value> = match serde_json::from_slice(body) {
+        Ok(serde_json::Value::Array(args)) if !args.is_empty() => args,
+        _ => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "expected a non-empty JSON array of arguments",
+            )
+        }
+    };
+
+    let mut tokens = vec![Token::Array(args.len() as i64)];
+    for arg in &args {
+        match json_to_blob(arg) {
+            Some(blob) => tokens.push(blob.into()),
+            None => {
+                return error(
+                    StatusCode::BAD_REQUEST,
+                    "arguments must be strings or {\"base64\": ...} objects",
+                )
+            }
+        }
+    }
+
+    let command = match Command::from_tokens(&tokens) {
+        Ok((command, _)) => command,
+        Err(_) => return error(StatusCode::BAD_REQUEST, "malformed command"),
+    };
+
+    let reply = cp.execute_command(&command).await.0;
+    match reply.first() {
+        Some(Token::Error(e)) if e.starts_with("NOAUTH") => error(StatusCode::UNAUTHORIZED, e),
+        Some(Token::Error(e)) if e.starts_with("NOPERM") => error(StatusCode::FORBIDDEN, e),
+        Some(Token::Error(e)) => error(StatusCode::BAD_REQUEST, e),
+        _ => {
+            let mut tokens = reply.into_iter();
+            ok(json!({ "result": tokens_to_json(&mut tokens) }))
+        }
+    }
+}
+
+async fn read_body(
+    req: Request<Incoming>,
+    max_body: usize,
+) -> Result<Vec<u8>, Response<Full<Bytes>>> {
+    match Limited::new(req.into_body(), max_body).collect().await {
+        Ok(collected) => Ok(collected.to_bytes().to_vec()),
+        Err(_) => Err(error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "request body exceeds configured limit",
+        )),
+    }
+}
+
+fn storage_failure(
+    res: Result<Result<Option<Value>, StorageError>, DispatchError>,
+) -> Response<Full<Bytes>> {
+    match res {
+        Ok(Err(e @ (StorageError::NotASet | StorageError::NotAnInteger))) => {
+            error(StatusCode::CONFLICT, &e.to_string())
+        }
+        Ok(Err(e)) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        Ok(Ok(_)) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "invalid response from storage",
+        ),
+        Err(DispatchError::Denied(e @ AclError::NoAuth)) => {
+            error(StatusCode::UNAUTHORIZED, &e.to_string())
+        }
+        Err(DispatchError::Denied(e)) => error(StatusCode::FORBIDDEN, &e.to_string()),
+        Err(DispatchError::Busy(e @ AdmissionError::RateLimited)) => {
+            error(StatusCode::TOO_MANY_REQUESTS, &e.to_string())
+        }
+        Err(e) => error(StatusCode::SERVICE_UNAVAILABLE, &e.to_string()),
+    }
+}
+
+fn ok(body: serde_json::Value) -> Response<Full<Bytes>> {
+    respond(StatusCode::OK, body)
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    respond(status, json!({ "error": message }))
+}
+
+fn respond(status: StatusCode, body: serde_json::Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+pub fn blob_to_json(blob: &Blob) -> serde_json::Value {
+    match std::str::from_utf8(&blob.0) {
+        Ok(s) => json!(s),
+        Err(_) => json!({ "base64": BASE64.encode(&blob.0) }),
+    }
+}
+
+fn json_to_blob(value: &serde_json::Value) -> Option<Blob> {
+    match value {
+        serde_json::Value::String(s) => Some(Blob(s.clone().into_bytes())),
+        serde_json::Value::Object(map) if map.len() == 1 => {
+            let encoded = map.get("base64")?.as_str()?;
+            BASE64.decode(encoded).ok().map(Blob)
+        }
+        _ => None,
+    }
+}
+
+fn value_to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Blob(b) => blob_to_json(&b),
+        Value::Int(i) => json!(i),
+        Value::Set(members) => {
+            // sort so the output is stable between requests
+            let mut members: Vec<Blob> = members.into_iter().collect();
+            members.sort_by(|a, b| a.0.cmp(&b.0));
+            members.iter().map(blob_to_json).collect()
+        }
+        Value::Hash(map) => {
+            let mut pairs: Vec<(Blob, Blob)> = map.into_iter().collect();
+            pairs.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+            pairs
+                .iter()
+                .map(|(field, value)| json!([blob_to_json(field), blob_to_json(value)]))
+                .collect()
+        }
+    }
+}
+
+/// Converts one RESP reply, which may span several tokens if it's an array,
+/// into JSON.
+fn tokens_to_json(tokens: &mut impl Iterator<Item = Token>) -> serde_json::Value {
+    match tokens.next() {
+        Some(Token::SimpleString(s)) => json!(s),
+        Some(Token::Error(e)) => json!({ "error": e }),
+        Some(Token::Integer(i)) => json!(i),
+        Some(Token::BulkString(Some(b))) => blob_to_json(&Blob(b)),
+        Some(Token::BulkString(None)) | None => serde_json::Value::Null,
+        Some(Token::Array(n)) => (0..n.max(0)).map(|_| tokens_to_json(tokens)).collect(),
+    }
+}
+
+fn percent_decode(segment: &str) -> Option<Vec<u8>> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blobs_fall_back_to_base64() {
+        assert_eq!(json!("hello"), blob_to_json(&Blob(b"hello".to_vec())));
+        assert_eq!(
+            json!({ "base64": "/wA=" }),
+            blob_to_json(&Blob(vec![0xff, 0x00]))
+        );
+        assert_eq!(
+            Some(Blob(vec![0xff, 0x00])),
+            json_to_blob(&json!({ "base64": "/wA=" }))
+        );
+        assert_eq!(None, json_to_blob(&json!(1)));
+    }
+
+    #[test]
+    fn arrays_become_nested_json() {
+        let mut tokens = vec![
+            Token::Array(2),
+            Token::BulkString(Some(b"a".to_vec())),
+            Token::Array(1),
+            Token::Integer(3),
+        ]
+        .into_iter();
+
+        assert_eq!(json!(["a", [3]]), tokens_to_json(&mut tokens));
+    }
+
+    #[test]
+    fn it_percent_decodes_paths() {
+        assert_eq!(Some(b"a b/\xff".to_vec()), percent_decode("a%20b%2F%ff"));
+        assert_eq!(None, percent_decode("a%2"));
+        assert_eq!(None, percent_decode("a%zz"));
+    }
+}
@@ -1,12 +1,16 @@
 #![feature(test)]
 extern crate test;
 
+pub mod acl;
 pub mod codec;
 pub mod command;
 pub mod config;
 pub mod connection;
+pub mod gateway;
+pub mod memcached;
 pub mod server;
 pub mod storage;
+pub mod tls;
 pub mod transaction;
 pub mod types;
 pub mod worker;
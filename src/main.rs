@@ -1,6 +1,7 @@
 use anode_kv::config::Config;
-use anode_kv::server::Server;
+use anode_kv::server::{Server, Shutdown, ShutdownMode};
 use clap::Parser;
+use tokio::signal::unix::{signal, SignalKind};
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::EnvFilter;
 
@@ -13,16 +14,44 @@ fn main() {
     let config: Config = Config::parse();
     tracing::info!(config=?config, "Starting server");
 
-    tokio::runtime::Builder::new_multi_thread()
+    let status = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(config.worker_threads)
         .enable_all()
         .build()
         .unwrap()
         .block_on(async {
             let mut server = Server::create(config).await.expect("should launch");
-            let handle = tokio::spawn(async move {
-                server.run().await;
-            });
-            handle.await.expect("should shut down gracefully");
+            tokio::spawn(shutdown_on_signal(server.shutdown_handle()));
+
+            match server.run().await {
+                Ok(()) => 0,
+                Err(e) => {
+                    tracing::error!(e=?e, "failed to shut down cleanly");
+                    1
+                }
+            }
         });
+
+    std::process::exit(status);
+}
+
+/// Starts a graceful shutdown on SIGINT or SIGTERM. A second signal exits
+/// straight away, for when a graceful shutdown is taking too long.
+async fn shutdown_on_signal(shutdown: Shutdown) {
+    let mut sigint = signal(SignalKind::interrupt()).expect("should install SIGINT handler");
+    let mut sigterm = signal(SignalKind::terminate()).expect("should install SIGTERM handler");
+
+    for signals in 1.. {
+        tokio::select! {
+            _ = sigint.recv() => {}
+            _ = sigterm.recv() => {}
+        }
+
+        if signals > 1 {
+            tracing::warn!("received another signal; exiting without finishing shutdown");
+            std::process::exit(1);
+        }
+        tracing::info!("received signal; shutting down");
+        shutdown.trigger(ShutdownMode::Default);
+    }
 }
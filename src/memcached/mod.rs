@@ -0,0 +1,454 @@
+//! A listener for the memcached ASCII protocol. Requests are translated into
+//! the same StorageCommands that RESP clients use, so both protocols share one
+//! keyspace.
+//!
+//! Client flags are kept with each item, and its CAS token changes whenever
+//! it does. The keyspace has no expiry, so a positive expiration time is
+//! refused; a negative one (meaning "already expired") deletes the item.
+
+use std::sync::Arc;
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+use crate::acl::AclError;
+use crate::command::{CommandProcessor, DispatchError};
+use crate::connection::{
+    flush, over_output_limit, read_or_idle, Client, ClientClass, OutputBuffer, Stream,
+    MAX_PENDING_OUTPUT,
+};
+use crate::server::Context;
+use crate::storage::{Item, SetCondition, StorageCommand, StorageError};
+use crate::types::{Blob, Key, Value};
+
+mod protocol;
+pub use protocol::{parse_request, Parsed, ProtocolError, Request, StoreMode};
+
+/// How many times a read-modify-write (incr, decr, append, prepend) retries
+/// when another client changes the value underneath it.
+const MAX_CAS_ATTEMPTS: usize = 16;
+
+/// Serves the client until it disconnects. Replies go through an output
+/// buffer with the normal client class's limits, in the same way as RESP's,
+/// so a client which sends requests without reading the replies is cut off
+/// rather than holding them all in memory.
+pub async fn handle<S: Stream>(
+    socket: &mut S,
+    context: Context,
+    client: Arc<Client>,
+) -> std::io::Result<()> {
+    let mut buffer = BytesMut::with_capacity(4 * 1024);
+    let limits = context.config.decode_limits();
+    let max_query_buffer = context.config.max_query_buffer;
+    let mut output = OutputBuffer::new(context.config.output_buffer_limit(ClientClass::Normal));
+
+    let config = context.config.clone();
+    let shutdown = context.shutdown.clone();
+    let mut processor = Processor::new(context.clone(), client.clone());
+    let (mut reader, mut writer) = tokio::io::split(socket);
+
+    loop {
+        let soft_deadline = output.soft_deadline();
+
+        let read = tokio::select! {
+            biased;
+            _ = shutdown.wait() => break,
+            _ = client.killed() => break,
+            written = writer.write(output.pending()), if !output.is_empty() => {
+                match written? {
+                    0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                    written => output.consume(written),
+                }
+                None
+            }
+            _ = tokio::time::sleep_until(soft_deadline.unwrap_or_else(Instant::now)),
+                if soft_deadline.is_some() => None,
+            read = read_or_idle(&mut reader, &mut buffer, &config),
+                if output.len() < MAX_PENDING_OUTPUT => Some(read?),
+        };
+
+        match read {
+            Some(0) => break,
+            Some(_) => loop {
+                match parse_request(&buffer[..], &limits) {
+                    Ok(Parsed::Incomplete) => break,
+                    Ok(Parsed::Complete(request, consumed)) => {
+                        buffer.advance(consumed);
+                        output.extend(&processor.execute(request).await);
+                    }
+                    Ok(Parsed::Invalid(e, consumed)) => {
+                        buffer.advance(consumed);
+                        output.extend(&error_line(&e));
+                    }
+                    Err(e) => return reply_protocol_error(&mut writer, &mut output, e).await,
+                }
+                if over_output_limit(&mut output, &client, &context) {
+                    return Ok(());
+                }
+            },
+            None => {}
+        }
+
+        if buffer.len() > max_query_buffer {
+            let e = ProtocolError::Client("query buffer exceeds configured limit");
+            return reply_protocol_error(&mut writer, &mut output, e).await;
+        }
+        if over_output_limit(&mut output, &client, &context) {
+            return Ok(());
+        }
+    }
+
+    flush(&mut writer, &mut output).await
+}
+
+/// Tells the client why its input was rejected, after whatever replies it's
+/// still owed, and ends the connection by returning the error.
+async fn reply_protocol_error<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    output: &mut OutputBuffer,
+    e: ProtocolError,
+) -> std::io::Result<()> {
+    output.extend(&error_line(&e));
+    flush(writer, output).await?;
+    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+fn error_line(e: &ProtocolError) -> Vec<u8> {
+    format!("{}\r\n", e).into_bytes()
+}
+
+struct Processor {
+    cp: CommandProcessor,
+}
+
+impl Processor {
+    fn new(context: Context, client: Arc<Client>) -> Self {
+        Self {
+            cp: CommandProcessor::new(context).with_client(client),
+        }
+    }
+
+    /// Executes a request and returns the bytes to send back, which are empty
+    /// if the client asked for no reply.
+    async fn execute(&mut self, request: Request) -> Vec<u8> {
+        let noreply = request.noreply();
+        let reply = match request {
+            Request::Get { keys, with_cas } => self.get(keys, with_cas).await,
+            Request::Store {
+                mode,
+                key,
+                flags,
+                exptime,
+                data,
+                ..
+            } => self.store(mode, key, flags, exptime, data).await,
+            Request::Delete { key, .. } => self.delete(key).await,
+            Request::Adjust {
+                key, delta, incr, ..
+            } => self.adjust(key, delta, incr).await,
+            Request::Touch { key, exptime, .. } => self.touch(key, exptime).await,
+            Request::FlushAll { .. } => self
+                .storage(StorageCommand::FlushAll)
+                .await
+                .map(|_| line("OK")),
+            Request::Version => Ok(line(&format!("VERSION {}", env!("CARGO_PKG_VERSION")))),
+        };
+
+        match reply {
+            _ if noreply => vec![],
+            Ok(reply) => reply,
+            Err(e) => error_line(&e),
+        }
+    }
+
+    async fn get(&mut self, keys: Vec<Key>, with_cas: bool) -> Result<Vec<u8>, ProtocolError> {
+        let mut reply = vec![];
+        for key in keys {
+            let item = match self.item(&key).await? {
+                Some(item) => item,
+                None => continue,
+            };
+            // sets and hashes have no memcached representation, so they
+            // read as misses
+            let bytes = match value_bytes(&item.value) {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+
+            reply.extend(b"VALUE ");
+            reply.extend(&key.0);
+            reply.extend(format!(" {} {}", item.flags, bytes.len()).bytes());
+            if with_cas {
+                reply.extend(format!(" {}", item.cas).bytes());
+            }
+            reply.extend(b"\r\n");
+            reply.extend(bytes);
+            reply.extend(b"\r\n");
+        }
+        reply.extend(b"END\r\n");
+        Ok(reply)
+    }
+
+    async fn store(
+        &mut self,
+        mode: StoreMode,
+        key: Key,
+        flags: u32,
+        exptime: i64,
+        data: Blob,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        check_exptime(exptime)?;
+        if exptime < 0 {
+            return Ok(line(self.store_expired(mode, key).await?));
+        }
+
+        let reply = match mode {
+            StoreMode::Set => {
+                let value = Value::Blob(data);
+                let cmd = match flags {
+                    0 => StorageCommand::Set(key.clone(), value),
+                    _ => StorageCommand::SetWithFlags(key.clone(), value, flags),
+                };
+                self.storage(cmd).await?;
+                "STORED"
+            }
+            StoreMode::Add => self.set_if(&key, data, flags, SetCondition::Absent).await?,
+            StoreMode::Replace => {
+                self.set_if(&key, data, flags, SetCondition::Present)
+                    .await?
+            }
+            StoreMode::Cas(token) => {
+                self.set_if(&key, data, flags, SetCondition::Matches(token))
+                    .await?
+            }
+            // like memcached, these keep the item's flags and ignore the
+            // ones given
+            StoreMode::Append => self.concat(&key, data, true).await?,
+            StoreMode::Prepend => self.concat(&key, data, false).await?,
+        };
+        Ok(line(reply))
+    }
+
+    /// An item which has already expired goes as soon as it's stored, so
+    /// storing one comes down to removing whatever it would have replaced,
+    /// under the same conditions the store has.
+    async fn store_expired(
+        &mut self,
+        mode: StoreMode,
+        key: Key,
+    ) -> Result<&'static str, ProtocolError> {
+        let condition = match mode {
+            StoreMode::Set => {
+                self.storage(StorageCommand::Delete(vec![key])).await?;
+                return Ok("STORED");
+            }
+            StoreMode::Add => SetCondition::Absent,
+            StoreMode::Replace | StoreMode::Append | StoreMode::Prepend => SetCondition::Present,
+            StoreMode::Cas(token) => SetCondition::Matches(token),
+        };
+        let res = self
+            .storage(StorageCommand::DeleteIf(key, condition))
+            .await?;
+        Ok(stored(condition, res))
+    }
+
+    async fn set_if(
+        &mut self,
+        key: &Key,
+        data: Blob,
+        flags: u32,
+        condition: SetCondition,
+    ) -> Result<&'static str, ProtocolError> {
+        let res = self
+            .storage(StorageCommand::SetIf(
+                key.clone(),
+                Value::Blob(data),
+                flags,
+                condition,
+            ))
+            .await?;
+        Ok(stored(condition, res))
+    }
+
+    async fn concat(
+        &mut self,
+        key: &Key,
+        data: Blob,
+        append: bool,
+    ) -> Result<&'static str, ProtocolError> {
+        let Blob(data) = data;
+        let update = self
+            .read_modify_write(key, |current| {
+                let current = value_bytes(current)?;
+                let updated = if append {
+                    [&current[..], &data[..]].concat()
+                } else {
+                    [&data[..], &current[..]].concat()
+                };
+                Some(Value::Blob(Blob(updated)))
+            })
+            .await?;
+
+        match update {
+            Update::Written(_) => Ok("STORED"),
+            Update::Missing => Ok("NOT_STORED"),
+            Update::Rejected => Err(ProtocolError::Server(
+                "Operation against a key holding the wrong kind of value",
+            )),
+        }
+    }
+
+    async fn delete(&mut self, key: Key) -> Result<Vec<u8>, ProtocolError> {
+        match self.storage(StorageCommand::Delete(vec![key])).await? {
+            Some(Value::Int(1)) => Ok(line("DELETED")),
+            _ => Ok(line("NOT_FOUND")),
+        }
+    }
+
+    /// Counters follow memcached rules rather than RESP ones: values are
+    /// unsigned 64-bit, incr wraps around, decr stops at zero, and missing
+    /// keys aren't created.
+    async fn adjust(&mut self, key: Key, delta: u64, incr: bool) -> Result<Vec<u8>, ProtocolError> {
+        let update = self
+            .read_modify_write(&key, |current| {
+                let bytes = value_bytes(current)?;
+                let n: u64 = std::str::from_utf8(&bytes).ok()?.parse().ok()?;
+                let updated = if incr {
+                    n.wrapping_add(delta)
+                } else {
+                    n.saturating_sub(delta)
+                };
+                Some(Value::Blob(Blob(updated.to_string().into_bytes())))
+            })
+            .await?;
+
+        match update {
+            Update::Written(updated) => {
+                let mut reply = value_bytes(&updated).unwrap_or_default();
+                reply.extend(b"\r\n");
+                Ok(reply)
+            }
+            Update::Missing => Ok(line("NOT_FOUND")),
+            Update::Rejected => Err(ProtocolError::Client(
+                "cannot increment or decrement non-numeric value",
+            )),
+        }
+    }
+
+    async fn touch(&mut self, key: Key, exptime: i64) -> Result<Vec<u8>, ProtocolError> {
+        check_exptime(exptime)?;
+        let exists = if exptime < 0 {
+            self.storage(StorageCommand::Delete(vec![key])).await? == Some(Value::Int(1))
+        } else {
+            self.storage(StorageCommand::Get(key)).await?.is_some()
+        };
+
+        match exists {
+            true => Ok(line("TOUCHED")),
+            false => Ok(line("NOT_FOUND")),
+        }
+    }
+
+    /// Applies f to the current value and stores the result, as long as no
+    /// one else changed the value in between. f returns None to reject the
+    /// current value.
+    async fn read_modify_write(
+        &mut self,
+        key: &Key,
+        f: impl Fn(&Value) -> Option<Value>,
+    ) -> Result<Update, ProtocolError> {
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let current = match self.item(key).await? {
+                Some(current) => current,
+                None => return Ok(Update::Missing),
+            };
+            let Some(updated) = f(&current.value) else {
+                return Ok(Update::Rejected);
+            };
+
+            let cmd = StorageCommand::SetIf(
+                key.clone(),
+                updated.clone(),
+                current.flags,
+                SetCondition::Matches(current.cas),
+            );
+            match self.storage(cmd).await? {
+                Some(Value::Int(1)) => return Ok(Update::Written(updated)),
+                Some(_) => continue,
+                None => return Ok(Update::Missing),
+            }
+        }
+
+        Err(ProtocolError::Server("value changed too often to update"))
+    }
+
+    async fn item(&mut self, key: &Key) -> Result<Option<Item>, ProtocolError> {
+        self.cp.read_item(key).await.map_err(dispatch_error)
+    }
+
+    async fn storage(&mut self, cmd: StorageCommand) -> Result<Option<Value>, ProtocolError> {
+        match self.cp.execute_storage_command(cmd).await {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(StorageError::NotAnInteger)) | Ok(Err(StorageError::NotASet)) => Err(
+                ProtocolError::Server("Operation against a key holding the wrong kind of value"),
+            ),
+            Ok(Err(StorageError::Overflow)) => Err(ProtocolError::Server(
+                "increment or decrement would overflow",
+            )),
+            Ok(Err(e)) => {
+                tracing::error!(e=?e, "storage failure while handling memcached request");
+                Err(ProtocolError::Server("storage failure"))
+            }
+            Err(e) => Err(dispatch_error(e)),
+        }
+    }
+}
+
+fn dispatch_error(e: DispatchError) -> ProtocolError {
+    match e {
+        DispatchError::Timeout => ProtocolError::Server("timeout while sending to storage"),
+        DispatchError::NoResponse => ProtocolError::Server("no response from storage"),
+        DispatchError::Denied(AclError::NoAuth) => ProtocolError::Client("authentication required"),
+        DispatchError::Denied(_) => ProtocolError::Client("permission denied"),
+        DispatchError::Busy(_) => ProtocolError::Server("busy, try again later"),
+    }
+}
+
+/// The keyspace has no expiry, so only an expiration time which has already
+/// passed, or none at all, can be honoured.
+fn check_exptime(exptime: i64) -> Result<(), ProtocolError> {
+    match exptime > 0 {
+        true => Err(ProtocolError::Client("expiration times are not supported")),
+        false => Ok(()),
+    }
+}
+
+/// The reply to a conditional store, from what storage replied.
+fn stored(condition: SetCondition, res: Option<Value>) -> &'static str {
+    match (condition, res) {
+        (_, Some(Value::Int(1))) => "STORED",
+        (SetCondition::Matches(_), Some(_)) => "EXISTS",
+        (SetCondition::Matches(_), None) => "NOT_FOUND",
+        _ => "NOT_STORED",
+    }
+}
+
+enum Update {
+    Written(Value),
+    Missing,
+    Rejected,
+}
+
+fn line(s: &str) -> Vec<u8> {
+    format!("{}\r\n", s).into_bytes()
+}
+
+/// The bytes a memcached client sees for a value, if it has a representation.
+fn value_bytes(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Blob(Blob(b)) => Some(b.clone()),
+        Value::Int(i) => Some(i.to_string().into_bytes()),
+        Value::Set(_) | Value::Hash(_) => None,
+    }
+}
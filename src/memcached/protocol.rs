@@ -0,0 +1,383 @@
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::codec::DecodeLimits;
+use crate::types::{Blob, Key};
+
+pub const MAX_KEY_LENGTH: usize = 250;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Request {
+    Get {
+        keys: Vec<Key>,
+        with_cas: bool,
+    },
+    Store {
+        mode: StoreMode,
+        key: Key,
+        flags: u32,
+        exptime: i64,
+        data: Blob,
+        noreply: bool,
+    },
+    Delete {
+        key: Key,
+        noreply: bool,
+    },
+    Adjust {
+        key: Key,
+        delta: u64,
+        incr: bool,
+        noreply: bool,
+    },
+    Touch {
+        key: Key,
+        exptime: i64,
+        noreply: bool,
+    },
+    FlushAll {
+        noreply: bool,
+    },
+    Version,
+}
+
+impl Request {
+    pub fn noreply(&self) -> bool {
+        match self {
+            Request::Store { noreply, .. }
+            | Request::Delete { noreply, .. }
+            | Request::Adjust { noreply, .. }
+            | Request::Touch { noreply, .. }
+            | Request::FlushAll { noreply } => *noreply,
+            Request::Get { .. } | Request::Version => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StoreMode {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas(u64),
+}
+
+/// ProtocolError displays as the exact line sent back to the client.
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum ProtocolError {
+    #[error("ERROR")]
+    UnknownCommand,
+
+    #[error("CLIENT_ERROR {0}")]
+    Client(&'static str),
+
+    #[error("SERVER_ERROR {0}")]
+    Server(&'static str),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Parsed {
+    /// The buffer doesn't hold a complete request yet.
+    Incomplete,
+    /// A request, and how many bytes of the buffer it used.
+    Complete(Request, usize),
+    /// A request which couldn't be understood, and how many bytes to skip to
+    /// get past it.
+    Invalid(ProtocolError, usize),
+}
+
+/// parse_request reads one request from the front of the buffer. Errors are
+/// returned when the stream can't be resynchronized and the connection should
+/// be closed after replying.
+pub fn parse_request(buf: &[u8], limits: &DecodeLimits) -> Result<Parsed, ProtocolError> {
+    let line_end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos,
+        None if buf.len() > limits.max_inline_size => {
+            return Err(ProtocolError::Client("line too long"))
+        }
+        None => return Ok(Parsed::Incomplete),
+    };
+    if line_end > limits.max_inline_size {
+        return Err(ProtocolError::Client("line too long"));
+    }
+
+    let consumed = line_end + 2;
+    let parts: Vec<&[u8]> = buf[..line_end]
+        .split(|b| *b == b' ')
+        .filter(|part| !part.is_empty())
+        .collect();
+
+    let (cmd, args) = match parts.split_first() {
+        Some(x) => x,
+        None => return Ok(Parsed::Invalid(ProtocolError::UnknownCommand, consumed)),
+    };
+
+    let request = match *cmd {
+        b"get" | b"gets" => parse_get(args, *cmd == b"gets"),
+        b"set" => return parse_store(StoreMode::Set, args, buf, consumed, limits),
+        b"add" => return parse_store(StoreMode::Add, args, buf, consumed, limits),
+        b"replace" => return parse_store(StoreMode::Replace, args, buf, consumed, limits),
+        b"append" => return parse_store(StoreMode::Append, args, buf, consumed, limits),
+        b"prepend" => return parse_store(StoreMode::Prepend, args, buf, consumed, limits),
+        b"cas" => return parse_store(StoreMode::Cas(0), args, buf, consumed, limits),
+        b"delete" => parse_delete(args),
+        b"incr" => parse_adjust(args, true),
+        b"decr" => parse_adjust(args, false),
+        b"touch" => parse_touch(args),
+        b"flush_all" => parse_flush_all(args),
+        b"version" if args.is_empty() => Ok(Request::Version),
+        _ => Err(ProtocolError::UnknownCommand),
+    };
+
+    Ok(match request {
+        Ok(request) => Parsed::Complete(request, consumed),
+        Err(e) => Parsed::Invalid(e, consumed),
+    })
+}
+
+fn parse_get(args: &[&[u8]], with_cas: bool) -> Result<Request, ProtocolError> {
+    if args.is_empty() {
+        return Err(ProtocolError::UnknownCommand);
+    }
+
+    let keys = args
+        .iter()
+        .map(|arg| parse_key(arg))
+        .collect::<Result<Vec<Key>, ProtocolError>>()?;
+
+    Ok(Request::Get { keys, with_cas })
+}
+
+/// Storage commands are followed by a data block, so unlike the others they
+/// may need more bytes than the command line itself.
+fn parse_store(
+    mode: StoreMode,
+    args: &[&[u8]],
+    buf: &[u8],
+    line_len: usize,
+    limits: &DecodeLimits,
+) -> Result<Parsed, ProtocolError> {
+    let (args, noreply) = split_noreply(args);
+    let expected_args = if matches!(mode, StoreMode::Cas(_)) {
+        5
+    } else {
+        4
+    };
+    if args.len() != expected_args {
+        return Ok(Parsed::Invalid(
+            ProtocolError::Client("bad command line format"),
+            line_len,
+        ));
+    }
+
+    let parsed = (|| {
+        let key = parse_key(args[0])?;
+        let flags: u32 = parse_number(args[1])?;
+        let exptime: i64 = parse_number(args[2])?;
+        let length: usize = parse_number(args[3])?;
+        let mode = match mode {
+            StoreMode::Cas(_) => StoreMode::Cas(parse_number(args[4])?),
+            mode => mode,
+        };
+        Ok((key, flags, exptime, length, mode))
+    })();
+
+    let (key, flags, exptime, length, mode) = match parsed {
+        Ok(x) => x,
+        Err(e) => return Ok(Parsed::Invalid(e, line_len)),
+    };
+
+    if length > limits.max_bulk_len {
+        return Err(ProtocolError::Server("object too large for cache"));
+    }
+
+    let data_end = line_len + length;
+    if buf.len() < data_end + 2 {
+        return Ok(Parsed::Incomplete);
+    }
+    if &buf[data_end..data_end + 2] != b"\r\n" {
+        return Ok(Parsed::Invalid(
+            ProtocolError::Client("bad data chunk"),
+            data_end + 2,
+        ));
+    }
+
+    let request = Request::Store {
+        mode,
+        key,
+        flags,
+        exptime,
+        data: Blob(buf[line_len..data_end].to_vec()),
+        noreply,
+    };
+    Ok(Parsed::Complete(request, data_end + 2))
+}
+
+fn parse_delete(args: &[&[u8]]) -> Result<Request, ProtocolError> {
+    let (args, noreply) = split_noreply(args);
+    match args {
+        [key] => Ok(Request::Delete {
+            key: parse_key(key)?,
+            noreply,
+        }),
+        _ => Err(ProtocolError::Client("bad command line format")),
+    }
+}
+
+fn parse_adjust(args: &[&[u8]], incr: bool) -> Result<Request, ProtocolError> {
+    let (args, noreply) = split_noreply(args);
+    match args {
+        [key, delta] => Ok(Request::Adjust {
+            key: parse_key(key)?,
+            delta: parse_number(delta)
+                .map_err(|_| ProtocolError::Client("invalid numeric delta argument"))?,
+            incr,
+            noreply,
+        }),
+        _ => Err(ProtocolError::Client("bad command line format")),
+    }
+}
+
+fn parse_touch(args: &[&[u8]]) -> Result<Request, ProtocolError> {
+    let (args, noreply) = split_noreply(args);
+    match args {
+        [key, exptime] => Ok(Request::Touch {
+            key: parse_key(key)?,
+            exptime: parse_number(exptime)?,
+            noreply,
+        }),
+        _ => Err(ProtocolError::Client("bad command line format")),
+    }
+}
+
+fn parse_flush_all(args: &[&[u8]]) -> Result<Request, ProtocolError> {
+    let (args, noreply) = split_noreply(args);
+    match args {
+        [] => Ok(Request::FlushAll { noreply }),
+        [delay] => {
+            let _: u64 = parse_number(delay)?;
+            Ok(Request::FlushAll { noreply })
+        }
+        _ => Err(ProtocolError::Client("bad command line format")),
+    }
+}
+
+fn split_noreply<'a, 'b>(args: &'a [&'b [u8]]) -> (&'a [&'b [u8]], bool) {
+    match args.split_last() {
+        Some((last, rest)) if *last == b"noreply" => (rest, true),
+        _ => (args, false),
+    }
+}
+
+fn parse_key(arg: &[u8]) -> Result<Key, ProtocolError> {
+    if arg.len() > MAX_KEY_LENGTH || arg.iter().any(|b| b.is_ascii_control()) {
+        return Err(ProtocolError::Client("bad command line format"));
+    }
+    Ok(Blob(arg.to_vec()))
+}
+
+fn parse_number<T: FromStr>(arg: &[u8]) -> Result<T, ProtocolError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(ProtocolError::Client("bad command line format"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<Parsed, ProtocolError> {
+        parse_request(input.as_bytes(), &DecodeLimits::default())
+    }
+
+    #[test]
+    fn parses_get_with_multiple_keys() {
+        let input = "gets a bb\r\n";
+        let expected = Parsed::Complete(
+            Request::Get {
+                keys: vec![Blob(b"a".to_vec()), Blob(b"bb".to_vec())],
+                with_cas: true,
+            },
+            input.len(),
+        );
+
+        assert_eq!(Ok(expected), parse(input));
+    }
+
+    #[test]
+    fn parses_storage_commands_with_data() {
+        let input = "cas k 0 60 5 12345 noreply\r\nhello\r\nget k\r\n";
+        let expected = Parsed::Complete(
+            Request::Store {
+                mode: StoreMode::Cas(12345),
+                key: Blob(b"k".to_vec()),
+                flags: 0,
+                exptime: 60,
+                data: Blob(b"hello".to_vec()),
+                noreply: true,
+            },
+            "cas k 0 60 5 12345 noreply\r\nhello\r\n".len(),
+        );
+
+        assert_eq!(Ok(expected), parse(input));
+    }
+
+    #[test]
+    fn waits_for_the_whole_data_block() {
+        assert_eq!(Ok(Parsed::Incomplete), parse("set k 0 0 5\r\nhel"));
+        assert_eq!(Ok(Parsed::Incomplete), parse("set k 0 0 5\r\nhello"));
+        assert_eq!(Ok(Parsed::Incomplete), parse("get k"));
+    }
+
+    #[test]
+    fn rejects_bad_data_chunks() {
+        let input = "set k 0 0 5\r\nhelloXX";
+        let expected = Parsed::Invalid(ProtocolError::Client("bad data chunk"), input.len());
+
+        assert_eq!(Ok(expected), parse(input));
+    }
+
+    #[test]
+    fn rejects_unknown_commands_and_bad_arguments() {
+        assert_eq!(
+            Ok(Parsed::Invalid(ProtocolError::UnknownCommand, 7)),
+            parse("stats\r\n")
+        );
+        assert_eq!(
+            Ok(Parsed::Invalid(
+                ProtocolError::Client("invalid numeric delta argument"),
+                11
+            )),
+            parse("incr k -1\r\n\r\n")
+        );
+        assert_eq!(
+            Ok(Parsed::Invalid(
+                ProtocolError::Client("bad command line format"),
+                15
+            )),
+            parse("set k 0 0 abc\r\n")
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_input() {
+        let limits = DecodeLimits {
+            max_bulk_len: 4,
+            max_inline_size: 16,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Err(ProtocolError::Server("object too large for cache")),
+            parse_request(b"set k 0 0 5\r\n", &limits)
+        );
+        assert_eq!(
+            Err(ProtocolError::Client("line too long")),
+            parse_request(b"get aaaaaaaaaaaaaaaaaaaa", &limits)
+        );
+    }
+}
@@ -1,19 +1,38 @@
-use std::sync::Arc;
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::Poll;
+use std::time::Duration;
 
-use tokio::net::TcpListener;
-use tokio::sync::mpsc;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
 
+use crate::acl::Acl;
 use crate::config::Config;
-use crate::connection::ConnectionManager;
-use crate::storage::{InMemoryStorage, StorageSendQueue};
-use crate::transaction::{TransactionSendQueue, TransactionWorker};
+use crate::connection::{ConnectionManager, ConnectionTracker, PeerAddr, Protocol, Stream};
+use crate::storage::{check_shard_count, InMemoryStorage, StorageCommand, StorageReader};
+use crate::tls::{peer_identity, TlsState};
+use crate::transaction::{LogRequest, Lsns, TransactionSendQueue, TransactionWorker};
+use crate::worker::{Admission, Worker, WorkerSendQueue};
+
+mod shutdown;
+pub use shutdown::{Shutdown, ShutdownMode};
 
 pub struct Server {
     listener: TcpListener,
-    connection_manager: ConnectionManager,
-    storage: Arc<Mutex<InMemoryStorage>>,
-    transaction_worker: Arc<Mutex<TransactionWorker>>,
+    memcached_listener: Option<TcpListener>,
+    http_listener: Option<TcpListener>,
+    unix_listeners: Vec<(UnixListener, PathBuf)>,
+    tls_listener: Option<(TcpListener, TlsState)>,
+    shards: Vec<Arc<Mutex<InMemoryStorage>>>,
+    worker: Arc<Mutex<Worker>>,
+    transaction_workers: Vec<Arc<Mutex<TransactionWorker>>>,
     context: Context,
 }
 
@@ -25,81 +44,475 @@ pub struct Server {
 ///  - Any shared state will be wrapped in Arc<Mutex<>> or similar to ensure safety
 #[derive(Clone)]
 pub struct Context {
-    pub storage_queue: StorageSendQueue,
-    pub transaction_queue: TransactionSendQueue,
+    pub worker_queue: WorkerSendQueue,
+    /// Each storage shard's transaction worker, in shard order.
+    pub transaction_queues: Vec<TransactionSendQueue>,
     pub config: Config,
+    pub acl: Arc<RwLock<Acl>>,
+    pub shutdown: Shutdown,
+    pub connections: ConnectionManager,
+    pub admission: Admission,
+    pub reader: StorageReader,
+    /// The LSNs every shard's transaction worker takes from.
+    pub lsns: Lsns,
+    /// Whether each shard's transaction worker has stopped, leaving the
+    /// shard read-only.
+    pub stopped_logs: Vec<Arc<AtomicBool>>,
 }
 
 impl Server {
     pub async fn create(config: Config) -> std::io::Result<Server> {
-        let (tx, rx) = mpsc::channel(config.storage_queue_size);
-        let (ttx, trx) = mpsc::channel(config.transaction_queue_size);
-        let context = Context::new(tx, ttx, config.clone());
+        if config.storage_shards == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "storage_shards must be at least 1",
+            ));
+        }
+        check_shard_count(&config)?;
 
-        let listener = TcpListener::bind(&config.address).await?;
-        let connection_manager = ConnectionManager::default();
+        // each shard gets its own queue, its own transaction worker and its
+        // own log, but they share LSNs
+        let lsns = Lsns::default();
+        let mut storage_queues = vec![];
+        let mut shard_data = vec![];
+        let mut shards = vec![];
+        let mut transaction_queues = vec![];
+        let mut transaction_workers = vec![];
+        let mut stopped_logs = vec![];
+        for shard in 0..config.storage_shards {
+            let shard_config = Config {
+                storage_basepath: config.shard_basepath(shard),
+                ..config.clone()
+            };
+            let (tx, rx) = mpsc::channel(config.storage_queue_size);
+            let (ttx, trx) = mpsc::channel(config.transaction_queue_size);
 
-        let mut storage_impl = InMemoryStorage::new(rx, context.clone());
-        if config.read_log {
-            storage_impl.load_from_log(config.clone()).await;
+            let mut storage_impl = InMemoryStorage::new(rx, ttx.clone(), config.durability);
+            if config.read_log || config.recover_until.is_some() {
+                storage_impl
+                    .load_from_log(shard_config.clone())
+                    .await
+                    .map_err(std::io::Error::other)?;
+            }
+            let mut transaction_impl = TransactionWorker::new(trx, shard_config);
+            transaction_impl.share_lsns(lsns.clone());
+            storage_impl.rewrite_log_when(transaction_impl.rewrite_due());
+            storage_impl.save_when(transaction_impl.save_due(), transaction_impl.last_save());
+            storage_impl.read_only_when(transaction_impl.stopped());
+            stopped_logs.push(transaction_impl.stopped());
+
+            storage_queues.push(tx);
+            shard_data.push(storage_impl.data());
+            shards.push(Arc::new(Mutex::new(storage_impl)));
+            transaction_queues.push(ttx);
+            transaction_workers.push(Arc::new(Mutex::new(transaction_impl)));
         }
-        let storage = Arc::new(Mutex::new(storage_impl));
 
-        let transaction_impl = TransactionWorker::new(trx, config.clone());
-        let transaction_worker = Arc::new(Mutex::new(transaction_impl));
+        let (wtx, wrx) = mpsc::channel(config.worker_queue_size);
+        let worker = Arc::new(Mutex::new(Worker::new(wrx, storage_queues, &config)));
+        let mut context = Context::new(wtx, transaction_queues, config.clone());
+        let acl = Acl::from_config(&config).map_err(std::io::Error::other)?;
+        context.acl = Arc::new(RwLock::new(acl));
+        context.connections = ConnectionManager::new(ConnectionTracker::new(config.maxclients));
+        context.admission = Admission::new(&config);
+        context.lsns = lsns;
+        context.stopped_logs = stopped_logs;
+        context.reader = StorageReader::new(shard_data, config.concurrent_reads);
+
+        let listener = TcpListener::bind(&config.address).await?;
+        let memcached_listener = match &config.memcached_address {
+            Some(address) => Some(TcpListener::bind(address).await?),
+            None => None,
+        };
+        let http_listener = match &config.http_address {
+            Some(address) => Some(TcpListener::bind(address).await?),
+            None => None,
+        };
+        let mut unix_listeners = vec![];
+        for path in &config.unix_socket {
+            let listener = bind_unix(path, &config)?;
+            unix_listeners.push((listener, path.clone()));
+        }
+        let tls_listener = match &config.tls_address {
+            Some(address) => {
+                let tls = TlsState::from_config(&config).map_err(std::io::Error::other)?;
+                Some((TcpListener::bind(address).await?, tls))
+            }
+            None => None,
+        };
 
         Ok(Server {
             listener,
-            connection_manager,
-            storage,
+            memcached_listener,
+            http_listener,
+            unix_listeners,
+            tls_listener,
+            shards,
+            worker,
             context,
-            transaction_worker,
+            transaction_workers,
         })
     }
 
-    pub async fn run(&mut self) {
-        let storage = self.storage.clone();
-        let _storage_handle = tokio::spawn(async move {
-            let mut storage = storage.lock().await;
-            storage.run().await;
+    /// Serves clients until shutdown is triggered, then shuts down cleanly:
+    /// it stops accepting connections, lets clients finish what they're doing,
+    /// handles every command already sent to storage, syncs the logs, and
+    /// waits for the transaction workers to finish with them.
+    pub async fn run(&mut self) -> std::io::Result<()> {
+        let (stop_worker, worker_stopped) = oneshot::channel::<()>();
+        let worker = self.worker.clone();
+        let worker_handle = tokio::spawn(async move {
+            let mut worker = worker.lock().await;
+            worker
+                .run(async {
+                    let _ = worker_stopped.await;
+                })
+                .await;
         });
 
-        let transaction_worker = self.transaction_worker.clone();
-        let _transaction_worker_handle = tokio::spawn(async move {
-            let mut transaction_worker = transaction_worker.lock().await;
-            transaction_worker.run().await;
-        });
+        let (stop_storage, _) = watch::channel(false);
+        let mut storage_handles = vec![];
+        for storage in &self.shards {
+            let storage = storage.clone();
+            let mut storage_stopped = stop_storage.subscribe();
+            storage_handles.push(tokio::spawn(async move {
+                let mut storage = storage.lock().await;
+                storage
+                    .run(async {
+                        let _ = storage_stopped.wait_for(|stopped| *stopped).await;
+                    })
+                    .await;
+            }));
+        }
 
-        loop {
-            match self.listener.accept().await {
-                Ok((socket, addr)) => {
-                    self.connection_manager
-                        .take_connection(self.context.clone(), socket, addr)
-                        .await;
+        let (stop_logs, _) = watch::channel(false);
+        let mut log_handles = vec![];
+        for transaction_worker in &self.transaction_workers {
+            let transaction_worker = transaction_worker.clone();
+            let mut log_stopped = stop_logs.subscribe();
+            log_handles.push(tokio::spawn(async move {
+                let mut transaction_worker = transaction_worker.lock().await;
+                transaction_worker
+                    .run(async {
+                        let _ = log_stopped.wait_for(|stopped| *stopped).await;
+                    })
+                    .await;
+            }));
+        }
+
+        if let Some((_, tls)) = &self.tls_listener {
+            let interval = Duration::from_millis(self.context.config.tls_reload_interval_ms);
+            tokio::spawn(watch_tls_files(tls.clone(), interval));
+        }
+
+        let shutdown = self.context.shutdown.clone();
+        let mode = loop {
+            tokio::select! {
+                mode = shutdown.wait() => break mode,
+                res = self.listener.accept() => self.accepted(tcp(res, &self.context.config), Protocol::Resp).await,
+                res = accept_optional(&self.memcached_listener) => {
+                    self.accepted(tcp(res, &self.context.config), Protocol::Memcached).await
+                }
+                res = accept_optional(&self.http_listener) => {
+                    self.accepted(tcp(res, &self.context.config), Protocol::Http).await
                 }
-                Err(e) => {
-                    tracing::error!(e=?e, "error accepting connection");
+                res = accept_unix(&self.unix_listeners) => {
+                    self.accepted(res, Protocol::Resp).await
                 }
+                res = accept_tls(&self.tls_listener) => self.accepted_tls(tcp(res, &self.context.config)),
+            };
+        };
+
+        self.shutdown(
+            mode,
+            (stop_worker, worker_handle),
+            (stop_storage, storage_handles),
+            (stop_logs, log_handles),
+        )
+        .await
+    }
+
+    async fn shutdown(
+        &mut self,
+        mode: ShutdownMode,
+        (stop_worker, worker_handle): (oneshot::Sender<()>, JoinHandle<()>),
+        (stop_storage, storage_handles): (watch::Sender<bool>, Vec<JoinHandle<()>>),
+        (stop_logs, log_handles): (watch::Sender<bool>, Vec<JoinHandle<()>>),
+    ) -> std::io::Result<()> {
+        tracing::info!(mode=?mode, "shutting down");
+
+        let timeout = Duration::from_millis(self.context.config.shutdown_timeout_ms);
+        if !self.context.connections.drain(timeout).await {
+            tracing::warn!("closed connections which did not finish in time");
+        }
+
+        // the worker passes on everything it was given before storage stops
+        let _ = stop_worker.send(());
+        worker_handle.await.map_err(std::io::Error::other)?;
+        stop_storage.send_replace(true);
+        for storage_handle in storage_handles {
+            storage_handle.await.map_err(std::io::Error::other)?;
+        }
+
+        // SHUTDOWN SAVE saves each shard's data as SAVE would, now that
+        // nothing else can change it
+        if mode == ShutdownMode::Save {
+            for shard in &self.shards {
+                shard
+                    .lock()
+                    .await
+                    .handle_cmd(StorageCommand::Save)
+                    .await
+                    .map_err(std::io::Error::other)?;
+            }
+        }
+
+        // everything each shard handled is queued for its log ahead of this
+        let fsync = mode != ShutdownMode::NoSave;
+        for transaction_queue in &self.context.transaction_queues {
+            let (tx, rx) = oneshot::channel();
+            transaction_queue
+                .send((LogRequest::Sync { fsync }, tx))
+                .await
+                .map_err(|_| std::io::Error::other("transaction worker stopped early"))?;
+            rx.await
+                .map_err(|_| std::io::Error::other("transaction worker stopped early"))?
+                .map_err(std::io::Error::other)?;
+        }
+
+        // then the transaction workers see through any rewrite or save still
+        // under way, so nothing's left writing to the logs once we're done
+        stop_logs.send_replace(true);
+        for log_handle in log_handles {
+            log_handle.await.map_err(std::io::Error::other)?;
+        }
+
+        tracing::info!("shutdown complete");
+        Ok(())
+    }
+
+    /// A handle which shuts the server down when triggered.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.context.shutdown.clone()
+    }
+
+    async fn accepted<S: Stream>(
+        &mut self,
+        accepted: std::io::Result<(S, PeerAddr)>,
+        protocol: Protocol,
+    ) {
+        match accepted {
+            Ok((socket, addr)) => {
+                self.context
+                    .connections
+                    .take_connection(self.context.clone(), socket, addr, None, protocol)
+                    .await;
+            }
+            Err(e) => {
+                tracing::error!(e=?e, "error accepting connection");
             }
         }
     }
 
+    /// Hands a TLS connection off once its handshake finishes. The handshake
+    /// runs on its own task so a slow client can't hold up the accept loop.
+    fn accepted_tls(&mut self, accepted: std::io::Result<(TcpStream, PeerAddr)>) {
+        let (socket, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!(e=?e, "error accepting connection");
+                return;
+            }
+        };
+        let acceptor = match &self.tls_listener {
+            Some((_, tls)) => tls.acceptor(),
+            None => return,
+        };
+
+        let context = self.context.clone();
+        tokio::spawn(async move {
+            let handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket));
+            let stream = match handshake.await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    tracing::warn!(e=?e, addr=%addr, "tls handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    tracing::warn!(addr=%addr, "tls handshake timed out");
+                    return;
+                }
+            };
+
+            let identity = peer_identity(&stream);
+            context
+                .connections
+                .take_connection(context.clone(), stream, addr, identity, Protocol::Resp)
+                .await;
+        });
+    }
+
     pub fn addr(&self) -> String {
         let local_addr = self.listener.local_addr().unwrap();
         format!("127.0.0.1:{}", local_addr.port())
     }
+
+    pub fn memcached_addr(&self) -> Option<String> {
+        let local_addr = self.memcached_listener.as_ref()?.local_addr().unwrap();
+        Some(format!("127.0.0.1:{}", local_addr.port()))
+    }
+
+    pub fn tls_addr(&self) -> Option<String> {
+        let local_addr = self.tls_listener.as_ref()?.0.local_addr().unwrap();
+        Some(format!("127.0.0.1:{}", local_addr.port()))
+    }
+
+    pub fn http_addr(&self) -> Option<String> {
+        let local_addr = self.http_listener.as_ref()?.local_addr().unwrap();
+        Some(format!("127.0.0.1:{}", local_addr.port()))
+    }
+}
+
+/// How long a client gets to finish the TLS handshake before it's dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        for (_, path) in &self.unix_listeners {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!(e=?e, path=?path, "failed to remove unix socket");
+            }
+        }
+    }
+}
+
+/// Binds a Unix socket at the path with its permissions and owner already set.
+/// It's bound inside a directory only this process can reach, and linked to
+/// the path once it's ready, so nobody can connect to it while it still has
+/// the umask's permissions. A socket already at the path is only replaced if
+/// nothing is listening on it.
+fn bind_unix(path: &Path, config: &Config) -> std::io::Result<UnixListener> {
+    static BINDS: AtomicU64 = AtomicU64::new(0);
+    remove_stale_socket(path)?;
+
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let bind = BINDS.fetch_add(1, Ordering::Relaxed);
+    let private = parent.join(format!(".anode-{}-{}", std::process::id(), bind));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("s");
+    let listener = (|| {
+        let listener = UnixListener::bind(&bound)?;
+        if let Some(mode) = config.unix_socket_mode {
+            std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+        }
+        if config.unix_socket_uid.is_some() || config.unix_socket_gid.is_some() {
+            std::os::unix::fs::chown(&bound, config.unix_socket_uid, config.unix_socket_gid)?;
+        }
+        // unlike a rename, a link won't replace whatever turned up at the
+        // path in the meantime
+        std::fs::hard_link(&bound, path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_dir_all(&private);
+    listener
+}
+
+/// Removes a socket left at the path by a server that's gone, which is one
+/// that refuses connections. Anything else there is left for binding to fail
+/// on.
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("{} is in use by a running server", path.display()),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// Applies the configured socket options to an accepted TCP connection.
+fn tcp(
+    accepted: std::io::Result<(TcpStream, SocketAddr)>,
+    config: &Config,
+) -> std::io::Result<(TcpStream, PeerAddr)> {
+    let (socket, addr) = accepted?;
+    socket.set_nodelay(config.tcp_nodelay)?;
+    if let Some(time) = config.tcp_keepalive() {
+        SockRef::from(&socket).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+    Ok((socket, PeerAddr::Tcp(addr)))
+}
+
+/// Accepts from whichever Unix listener is ready first, and never completes if
+/// there are none.
+async fn accept_unix(
+    listeners: &[(UnixListener, PathBuf)],
+) -> std::io::Result<(UnixStream, PeerAddr)> {
+    poll_fn(|cx| {
+        for (listener, path) in listeners {
+            if let Poll::Ready(res) = listener.poll_accept(cx) {
+                return Poll::Ready(res.map(|(socket, _)| (socket, PeerAddr::Unix(path.clone()))));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+async fn accept_tls(
+    listener: &Option<(TcpListener, TlsState)>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some((listener, _)) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Periodically reloads the TLS configuration when its files change.
+async fn watch_tls_files(tls: TlsState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match tls.reload_if_changed() {
+            Ok(true) => tracing::info!("reloaded tls certificates"),
+            Ok(false) => {}
+            Err(e) => tracing::error!(e=%e, "failed to reload tls certificates"),
+        }
+    }
+}
+
+/// Accepts from the listener if there is one, and otherwise never completes.
+async fn accept_optional(
+    listener: &Option<TcpListener>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
 }
 
 impl Context {
     pub fn new(
-        storage_queue: StorageSendQueue,
-        transaction_queue: TransactionSendQueue,
+        worker_queue: WorkerSendQueue,
+        transaction_queues: Vec<TransactionSendQueue>,
         config: Config,
     ) -> Self {
         Self {
-            storage_queue,
-            transaction_queue,
+            worker_queue,
+            transaction_queues,
             config,
+            acl: Arc::new(RwLock::new(Acl::default())),
+            shutdown: Shutdown::default(),
+            connections: ConnectionManager::default(),
+            admission: Admission::default(),
+            reader: StorageReader::default(),
+            lsns: Lsns::default(),
+            stopped_logs: vec![],
         }
     }
 }
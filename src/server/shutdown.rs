@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// What to do with the data on the way down.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShutdownMode {
+    /// Flush the log and fsync it.
+    Default,
+    /// Save a snapshot of each shard's data, as SAVE does, then flush the
+    /// log and fsync it.
+    Save,
+    /// Write out what's pending, but skip the fsync.
+    NoSave,
+}
+
+/// Shutdown is shared by everything which needs to stop when the server does.
+/// It's cheap to clone, and the first trigger wins.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<Option<ShutdownMode>>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(None);
+        Self { tx: Arc::new(tx) }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self, mode: ShutdownMode) {
+        self.tx.send_if_modified(|current| match current {
+            Some(_) => false,
+            None => {
+                *current = Some(mode);
+                true
+            }
+        });
+    }
+
+    pub fn mode(&self) -> Option<ShutdownMode> {
+        *self.tx.borrow()
+    }
+
+    /// Completes once shutdown has been triggered, straight away if it
+    /// already has been.
+    pub async fn wait(&self) -> ShutdownMode {
+        let mut rx = self.tx.subscribe();
+        let mode = rx
+            .wait_for(Option::is_some)
+            .await
+            .expect("the sender lives as long as self");
+        mode.expect("waited for a mode")
+    }
+}
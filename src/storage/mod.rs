@@ -1,19 +1,33 @@
 use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use thiserror::Error;
-use tokio::sync::mpsc;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot, Notify};
 
 use crate::config::Config;
-use crate::server::Context;
-use crate::transaction::TransactionLog;
-pub use crate::transaction::{TransactionLogError, TransactionSendQueue};
-use crate::types::{Blob, Key, Value};
+pub use crate::transaction::{Durability, TransactionLogError, TransactionSendQueue};
+use crate::transaction::{LogRequest, TransactionLog};
+use crate::types::{Blob, ItemFlags, Key, Keyspace, Value};
+use crate::worker::{Reply, StorageBatch};
+
+mod reader;
+mod shard;
+pub use reader::StorageReader;
+pub use shard::{check_shard_count, shard_for, Combine, Route};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum StorageCommand {
     Set(Key, Value),
+    /// Sets the value along with the flags a memcached client stores with
+    /// it. After a plain Set, a key has no flags.
+    SetWithFlags(Key, Value, u32),
     Get(Key),
+    /// Replies with a Hash of those of the keys which hold a plain value or
+    /// a number, and their values.
+    MultiGet(Vec<Key>),
     Incr(Key),
     Decr(Key),
     SetAdd(Key, Blob),
@@ -21,6 +35,110 @@ pub enum StorageCommand {
     SetIntersection(Vec<Key>),
     SetUnion(Vec<Key>),
     SetMembers(Key),
+    /// Stores the value, with the memcached flags, only if the condition
+    /// holds for the current value. Replies `Int(1)` if stored, `Int(0)` if
+    /// the key exists but the condition failed, and `None` if the key is
+    /// missing and the condition failed.
+    SetIf(Key, Value, u32, SetCondition),
+    /// Removes the key only if the condition holds for its current value,
+    /// replying like SetIf.
+    DeleteIf(Key, SetCondition),
+    /// Removes the keys, replying with how many existed.
+    Delete(Vec<Key>),
+    FlushAll,
+    /// Starts rewriting the shard's log from its current data.
+    RewriteLog,
+    /// Saves a snapshot of the shard's data, replying once it's saved.
+    Save,
+    /// Starts saving a snapshot of the shard's data in the background.
+    BgSave,
+    /// When the shard's data was last saved, in seconds since the Unix epoch.
+    LastSave,
+    /// Makes a shard recovered to a point in the past writable, dropping
+    /// what its log has after that point.
+    Promote,
+}
+
+impl StorageCommand {
+    /// The client command this corresponds to, for permission checks.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StorageCommand::Set(..)
+            | StorageCommand::SetWithFlags(..)
+            | StorageCommand::SetIf(..) => "set",
+            StorageCommand::Get(_) => "get",
+            StorageCommand::MultiGet(_) => "mget",
+            StorageCommand::Incr(_) => "incr",
+            StorageCommand::Decr(_) => "decr",
+            StorageCommand::SetAdd(..) => "sadd",
+            StorageCommand::SetRemove(..) => "srem",
+            StorageCommand::SetIntersection(_) => "sinter",
+            StorageCommand::SetUnion(_) => "sunion",
+            StorageCommand::SetMembers(_) => "smembers",
+            StorageCommand::Delete(_) | StorageCommand::DeleteIf(..) => "del",
+            StorageCommand::FlushAll => "flushall",
+            StorageCommand::RewriteLog => "rewritelog",
+            StorageCommand::Save => "save",
+            StorageCommand::BgSave => "bgsave",
+            StorageCommand::LastSave => "lastsave",
+            StorageCommand::Promote => "promote",
+        }
+    }
+
+    /// Whether the command only reads. Read-only commands aren't logged, and
+    /// can be run without going through the shard's queue.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            StorageCommand::Get(_)
+                | StorageCommand::MultiGet(_)
+                | StorageCommand::SetMembers(_)
+                | StorageCommand::SetIntersection(_)
+                | StorageCommand::SetUnion(_)
+        )
+    }
+
+    pub fn keys(&self) -> Vec<&Key> {
+        match self {
+            StorageCommand::Set(key, _)
+            | StorageCommand::SetWithFlags(key, ..)
+            | StorageCommand::SetIf(key, ..)
+            | StorageCommand::DeleteIf(key, _)
+            | StorageCommand::Get(key)
+            | StorageCommand::Incr(key)
+            | StorageCommand::Decr(key)
+            | StorageCommand::SetAdd(key, _)
+            | StorageCommand::SetRemove(key, _)
+            | StorageCommand::SetMembers(key) => vec![key],
+            StorageCommand::MultiGet(keys)
+            | StorageCommand::SetIntersection(keys)
+            | StorageCommand::SetUnion(keys)
+            | StorageCommand::Delete(keys) => keys.iter().collect(),
+            StorageCommand::FlushAll
+            | StorageCommand::RewriteLog
+            | StorageCommand::Save
+            | StorageCommand::BgSave
+            | StorageCommand::LastSave
+            | StorageCommand::Promote => vec![],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SetCondition {
+    Absent,
+    Present,
+    /// The key's CAS token must equal this one.
+    Matches(u64),
+}
+
+/// A key's value along with what memcached keeps about it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Item {
+    pub value: Value,
+    pub flags: u32,
+    /// Changes whenever the value does, for compare-and-set.
+    pub cas: u64,
 }
 
 #[derive(Error, Debug)]
@@ -34,6 +152,15 @@ pub enum StorageError {
     #[error("not a set")]
     NotASet,
 
+    #[error("the data was recovered to a point in the past and is read-only until PROMOTE")]
+    ReadOnly,
+
+    #[error("the shard's log stopped after a failed fsync couldn't be undone, so it's read-only")]
+    LogStopped,
+
+    #[error("failed on shards {shards:?}: {reason}")]
+    ShardsFailed { shards: Vec<usize>, reason: String },
+
     #[error("transaction log error: {0}")]
     LogError(#[from] TransactionLogError),
 
@@ -41,127 +168,582 @@ pub enum StorageError {
     Failed(#[from] std::io::Error),
 }
 
+/// A shard's data. The shard's own task is the only writer; readers can take
+/// the read lock from any task, so reads of the shard don't queue behind its
+/// writes.
+pub type ShardData = Arc<RwLock<ShardState>>;
+
+/// A shard's keys and values, and what memcached keeps about them.
+pub struct ShardState {
+    pub data: Keyspace,
+    pub flags: ItemFlags,
+    /// Each key's CAS token. These are only kept in memory, so every key gets
+    /// a new one when the shard is loaded.
+    cas: HashMap<Key, u64>,
+    next_cas: u64,
+}
+
+impl Default for ShardState {
+    fn default() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        Self {
+            data: Keyspace::new(),
+            flags: ItemFlags::new(),
+            cas: HashMap::new(),
+            // tokens start from the clock rather than from 1, so one handed
+            // out before a restart doesn't match a value since, as long as the
+            // shard made fewer than a million changes a millisecond
+            next_cas: now << 20,
+        }
+    }
+}
+
+impl ShardState {
+    /// The key's value with its flags and CAS token.
+    pub fn item(&self, key: &Key) -> Option<Item> {
+        let value = self.data.get(key)?.clone();
+        Some(Item {
+            value,
+            flags: self.flags.get(key).copied().unwrap_or(0),
+            cas: self.cas.get(key).copied().unwrap_or(0),
+        })
+    }
+
+    /// Replaces the data, giving every key a CAS token.
+    fn restore(&mut self, data: Keyspace, flags: ItemFlags) {
+        self.data = data;
+        self.flags = flags;
+        self.cas.clear();
+        let keys: Vec<Key> = self.data.keys().cloned().collect();
+        for key in &keys {
+            self.changed(key);
+        }
+    }
+
+    fn set_flags(&mut self, key: &Key, flags: u32) {
+        match flags {
+            0 => self.flags.remove(key),
+            _ => self.flags.insert(key.clone(), flags),
+        };
+    }
+
+    /// Gives the key a new CAS token if it still has a value, and forgets
+    /// what memcached kept about it if not.
+    fn changed(&mut self, key: &Key) {
+        if self.data.contains_key(key) {
+            self.cas.insert(key.clone(), self.next_cas);
+            self.next_cas += 1;
+        } else {
+            self.cas.remove(key);
+            self.flags.remove(key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+        self.flags.clear();
+        self.cas.clear();
+    }
+}
+
+/// One shard of the keyspace. Each shard handles its own keys on its own
+/// task, with its own queue and its own log.
 pub struct InMemoryStorage {
-    data: HashMap<Key, Value>,
+    data: ShardData,
     recv_queue: StorageRecvQueue,
     transaction_queue: TransactionSendQueue,
+    durability: Durability,
     durable: bool,
+    rewrite_due: Arc<Notify>,
+    save_due: Arc<Notify>,
+    last_save: Arc<AtomicU64>,
+    /// Whether the shard was recovered to a point in the past, and hasn't
+    /// been promoted since.
+    read_only: bool,
+    /// Set by the transaction worker if it stops, after which the shard is
+    /// read-only for good.
+    log_stopped: Arc<AtomicBool>,
 }
 
-pub type StorageRecvQueue = mpsc::Receiver<(
-    StorageCommand,
-    oneshot::Sender<Result<Option<Value>, StorageError>>,
-)>;
-pub type StorageSendQueue = mpsc::Sender<(
-    StorageCommand,
-    oneshot::Sender<Result<Option<Value>, StorageError>>,
-)>;
+pub type StorageRecvQueue = mpsc::Receiver<StorageBatch>;
+pub type StorageSendQueue = mpsc::Sender<StorageBatch>;
 
 impl InMemoryStorage {
-    pub fn new(recv_queue: StorageRecvQueue, context: Context) -> Self {
-        let data = HashMap::new();
+    pub fn new(
+        recv_queue: StorageRecvQueue,
+        transaction_queue: TransactionSendQueue,
+        durability: Durability,
+    ) -> Self {
+        let data = Arc::new(RwLock::new(ShardState::default()));
         let durable = true;
 
         Self {
             data,
             recv_queue,
-            transaction_queue: context.transaction_queue,
+            transaction_queue,
+            durability,
             durable,
+            rewrite_due: Arc::new(Notify::new()),
+            save_due: Arc::new(Notify::new()),
+            last_save: Arc::new(AtomicU64::new(0)),
+            read_only: false,
+            log_stopped: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub async fn load_from_log(&mut self, config: Config) {
-        println!("starting log read");
+    /// Rewrites the log whenever the transaction worker says it's due.
+    pub fn rewrite_log_when(&mut self, rewrite_due: Arc<Notify>) {
+        self.rewrite_due = rewrite_due;
+    }
+
+    /// Saves whenever the transaction worker says it's due, and answers
+    /// LASTSAVE with when the worker last saved.
+    pub fn save_when(&mut self, save_due: Arc<Notify>, last_save: Arc<AtomicU64>) {
+        self.save_due = save_due;
+        self.last_save = last_save;
+    }
+
+    /// The shard's data, for reading it without going through the queue.
+    pub fn data(&self) -> ShardData {
+        self.data.clone()
+    }
+
+    /// Loads the newest snapshot, if there is one, and replays the log
+    /// written after it into the shard, or the whole log if there isn't. Bad
+    /// records are dealt with as `log_recovery` says, and if that's to refuse
+    /// them the error says where they are.
+    ///
+    /// With `recover_until`, only the snapshot and records up to the target
+    /// are used, and the shard is read-only afterwards until it's promoted.
+    pub async fn load_from_log(&mut self, config: Config) -> Result<(), TransactionLogError> {
+        tracing::info!(path = config.storage_basepath, "starting log read");
         self.disable_durability();
-        let mut count: usize = 0;
 
-        let log = TransactionLog::new(config).expect("should be able to read from the log");
-        for cmd in log.read().unwrap() {
-            if let Err(e) = self.handle_cmd(cmd).await {
+        let until = config.recover_until;
+        let log = TransactionLog::new(config.clone())?;
+        let ((records, _), from_snapshot) = match log.load_snapshot(until) {
+            Some(snapshot) => {
+                let mut state = self.data.write().unwrap();
+                state.restore(snapshot.data, snapshot.flags);
+                (
+                    log.recover_from(snapshot.next_seq, config.log_recovery)?,
+                    true,
+                )
+            }
+            None => (log.recover(config.log_recovery)?, false),
+        };
+        if let Some(until) = until {
+            // without a snapshot, the log has to start before the target,
+            // or it's been rewritten since and what came before is gone
+            let first = records.first();
+            if !from_snapshot && first.is_some_and(|record| !until.includes(&record.stamp)) {
+                return Err(TransactionLogError::TargetNotInLog(until));
+            }
+        }
+        let mut count = 0;
+        for record in records {
+            if until.is_some_and(|until| !until.includes(&record.stamp)) {
+                break;
+            }
+            count += 1;
+            if let Err(e) = self.handle_cmd(record.cmd).await {
                 tracing::error!(e=?e, "error while replaying command");
             };
-            count += 1;
         }
 
         self.enable_durability();
-        println!("finished log read; {} records", count);
+        if let Some(until) = until {
+            tracing::info!(%until, "recovered to target; read-only until PROMOTE");
+            self.read_only = true;
+        }
+        tracing::info!(
+            path = config.storage_basepath,
+            records = count,
+            "finished log read"
+        );
+        Ok(())
     }
 
-    pub async fn run(&mut self) {
-        while let Some((cmd, tx)) = self.recv_queue.recv().await {
-            let response = self.handle_cmd(cmd).await;
+    /// Handles batches of commands until stop completes. After that, no new
+    /// batches are accepted but those already queued are still handled.
+    pub async fn run(&mut self, stop: impl Future<Output = ()>) {
+        tokio::pin!(stop);
+        let mut stopping = false;
+        let rewrite_due = self.rewrite_due.clone();
+        let save_due = self.save_due.clone();
 
-            if tx.send(response).is_err() {
-                tracing::error!("could not return value to requester; early disconnection?");
+        loop {
+            let batch = tokio::select! {
+                msg = self.recv_queue.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                // these wait until the shard's promoted, so its log isn't
+                // rewritten or saved while it still has what came after the
+                // recovery target
+                _ = rewrite_due.notified(), if !self.read_only => {
+                    if let Err(e) = self.rewrite_log(false).await {
+                        tracing::error!(e=?e, "could not start log rewrite");
+                    }
+                    continue;
+                }
+                _ = save_due.notified(), if !self.read_only => {
+                    if let Err(e) = self.save(false).await {
+                        tracing::error!(e=?e, "could not start save");
+                    }
+                    continue;
+                }
+                _ = &mut stop, if !stopping => {
+                    stopping = true;
+                    self.recv_queue.close();
+                    continue;
+                }
+            };
+            self.handle_batch(batch).await;
+        }
+    }
+
+    /// Runs a batch of commands in order. The batch's writes are all sent to
+    /// the log before waiting on any of them, so the log commits them
+    /// together, with one fsync between them. Reads wait their turn behind
+    /// the writes before them, and anything which needs those writes applied
+    /// first, like SETIF, an admin command or a part of a write which spans
+    /// shards, waits for them to be.
+    async fn handle_batch(&mut self, batch: StorageBatch) {
+        let mut pending = Vec::with_capacity(batch.len());
+        for (cmd, reply) in batch {
+            if reply.needs_agreement() {
+                self.apply_pending(std::mem::take(&mut pending)).await;
+                let result = self.handle_part(cmd, &reply).await;
+                reply.send(result);
+            } else if cmd.is_read_only() {
+                pending.push(Pending {
+                    cmd,
+                    reply,
+                    recorded: Ok(None),
+                });
+            } else if !self.read_only && !self.log_stopped() && is_plain_write(&cmd) {
+                let recorded = self.send_record(&cmd).await;
+                pending.push(Pending {
+                    cmd,
+                    reply,
+                    recorded,
+                });
+            } else {
+                self.apply_pending(std::mem::take(&mut pending)).await;
+                reply.send(self.handle_cmd(cmd).await);
             }
         }
+        self.apply_pending(pending).await;
+    }
+
+    /// Waits for the log to have the pending writes, then applies the
+    /// commands in order. A write the log failed to record isn't applied,
+    /// and its client hears why.
+    async fn apply_pending(&mut self, pending: Vec<Pending>) {
+        let logged = pending.iter().any(|p| matches!(p.recorded, Ok(Some(_))));
+        let synced = match logged {
+            true => self.send_sync().await,
+            false => Ok(None),
+        };
+        let mut waiting = Vec::with_capacity(pending.len());
+        for Pending {
+            cmd,
+            reply,
+            recorded,
+        } in pending
+        {
+            let logged = match recorded {
+                Ok(Some(rx)) => log_result(rx).await.map(|_| true),
+                Ok(None) => Ok(false),
+                Err(e) => Err(e),
+            };
+            waiting.push((cmd, reply, logged));
+        }
+        let synced = match synced {
+            Ok(Some(rx)) => log_result(rx).await,
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        for (cmd, reply, logged) in waiting {
+            let result = match (logged, &synced) {
+                (Err(e), _) => Err(e.into()),
+                (Ok(true), Err(e)) => Err(e.duplicate().into()),
+                (Ok(_), _) => self.apply(cmd),
+            };
+            reply.send(result);
+        }
     }
 
+    /// Runs a command against the shard. Writes are recorded in the log
+    /// before they're applied, and the write lock is only held while they're
+    /// applied, so readers aren't held up while the log catches up.
     #[tracing::instrument(skip(self), level = "trace")]
     pub async fn handle_cmd(&mut self, cmd: StorageCommand) -> Result<Option<Value>, StorageError> {
-        self.record_cmd(&cmd).await?;
+        if cmd.is_read_only() {
+            return self.apply(cmd);
+        }
+        if self.log_stopped() && !matches!(cmd, StorageCommand::LastSave) {
+            return Err(StorageError::LogStopped);
+        }
+        if self.read_only && !matches!(cmd, StorageCommand::LastSave | StorageCommand::Promote) {
+            return Err(StorageError::ReadOnly);
+        }
+        if let StorageCommand::SetIf(key, value, flags, condition) = cmd {
+            return self.handle_set_if(key, value, flags, condition).await;
+        }
+        if let StorageCommand::DeleteIf(key, condition) = cmd {
+            return self.handle_delete_if(key, condition).await;
+        }
         match cmd {
+            StorageCommand::RewriteLog => return self.rewrite_log(true).await.map(|_| None),
+            StorageCommand::Save => {
+                let saved = self.save(true).await?;
+                return wait_for_log(saved).await.map(|_| None);
+            }
+            StorageCommand::BgSave => {
+                let started = self.save(false).await?;
+                return wait_for_log(started).await.map(|_| None);
+            }
+            StorageCommand::LastSave => {
+                let last_save = self.last_save.load(Ordering::Relaxed);
+                return Ok(Some(Value::Int(last_save as i64)));
+            }
+            StorageCommand::Promote => return self.promote().await.map(|_| None),
+            _ => {}
+        }
+
+        self.record_cmd(&cmd).await?;
+        self.apply(cmd)
+    }
+
+    fn log_stopped(&self) -> bool {
+        self.log_stopped.load(Ordering::Relaxed)
+    }
+
+    /// Runs a read, or applies a plain write which the log already has.
+    /// Every key it writes gets a new CAS token.
+    fn apply(&mut self, cmd: StorageCommand) -> Result<Option<Value>, StorageError> {
+        if cmd.is_read_only() {
+            return read(&self.data.read().unwrap().data, &cmd);
+        }
+        let mut guard = self.data.write().unwrap();
+        let state = &mut *guard;
+        let changed: Vec<Key> = cmd.keys().into_iter().cloned().collect();
+        let data = &mut state.data;
+        let result = match cmd {
             StorageCommand::Set(key, value) => {
-                self.data.insert(key, value);
+                state.flags.remove(&key);
+                data.insert(key, value);
                 Ok(None)
             }
-            StorageCommand::Get(key) => Ok(self.data.get(&key).cloned()),
-            StorageCommand::Incr(key) => self.handle_add(key, 1).await,
-            StorageCommand::Decr(key) => self.handle_add(key, -1).await,
-            StorageCommand::SetAdd(key, blob) => self.handle_set_add(key, blob).await,
-            StorageCommand::SetMembers(key) => self.handle_set_members(key).await,
-            StorageCommand::SetRemove(key, blob) => self.handle_set_remove(key, blob).await,
-            StorageCommand::SetIntersection(keys) => self.handle_set_intersection(keys).await,
-            StorageCommand::SetUnion(keys) => self.handle_set_union(keys).await,
-        }
-    }
-
-    async fn handle_add(&mut self, key: Key, amount: i64) -> Result<Option<Value>, StorageError> {
-        let entry = self.data.entry(key).or_insert_with(|| Value::Int(0));
-        match entry {
-            Value::Int(i) => {
-                *i = safe_add(*i, amount)?;
-                Ok(Some(Value::Int(*i)))
-            }
-            Value::Blob(Blob(b)) => match atoi::atoi::<i64>(b) {
-                None => Err(StorageError::NotAnInteger),
-                Some(i) => {
-                    *entry = Value::Int(safe_add(i, amount)?);
-                    Ok(Some(entry.clone()))
-                }
-            },
-            Value::Set(_) => Err(StorageError::NotAnInteger),
-            Value::Hash(_) => Err(StorageError::NotAnInteger),
+            StorageCommand::SetWithFlags(key, value, flags) => {
+                data.insert(key.clone(), value);
+                state.set_flags(&key, flags);
+                Ok(None)
+            }
+            StorageCommand::Incr(key) => add(data, key, 1),
+            StorageCommand::Decr(key) => add(data, key, -1),
+            StorageCommand::SetAdd(key, blob) => set_add(data, key, blob),
+            StorageCommand::SetRemove(key, blob) => set_remove(data, key, blob),
+            StorageCommand::Delete(keys) => {
+                let removed = keys
+                    .iter()
+                    .filter(|key| data.remove(*key).is_some())
+                    .count();
+                Ok(Some(Value::Int(removed as i64)))
+            }
+            StorageCommand::FlushAll => {
+                state.clear();
+                Ok(None)
+            }
+            StorageCommand::Get(_)
+            | StorageCommand::MultiGet(_)
+            | StorageCommand::SetMembers(_)
+            | StorageCommand::SetIntersection(_)
+            | StorageCommand::SetUnion(_)
+            | StorageCommand::SetIf(..)
+            | StorageCommand::DeleteIf(..)
+            | StorageCommand::RewriteLog
+            | StorageCommand::Save
+            | StorageCommand::BgSave
+            | StorageCommand::LastSave
+            | StorageCommand::Promote => unreachable!("{} is not a plain write", cmd.name()),
+        };
+        if result.is_ok() {
+            for key in &changed {
+                state.changed(key);
+            }
         }
+        result
     }
 
-    async fn handle_set_add(
+    /// Runs the shard's part of a write which spans shards, so that either
+    /// every shard's part applies or none does. If another shard couldn't
+    /// write its part, this one's is undone by writing back what it changed.
+    /// The shard takes nothing else on until the parts agree, and every shard
+    /// gets the parts in the same order, so none of them waits on another
+    /// which is waiting on it.
+    async fn handle_part(
         &mut self,
-        key: Key,
-        value: Blob,
+        cmd: StorageCommand,
+        reply: &Reply,
     ) -> Result<Option<Value>, StorageError> {
-        let entry = self
-            .data
-            .entry(key)
-            .or_insert_with(|| Value::Set(HashSet::new()));
-        match entry {
-            Value::Set(set) => {
-                let added = set.insert(value);
-                Ok(Some(Value::Int(i64::from(added))))
+        let (data, flags) = self.snapshot();
+        let keys: Option<Vec<Key>> = match &cmd {
+            StorageCommand::FlushAll => None,
+            cmd => Some(cmd.keys().into_iter().cloned().collect()),
+        };
+        let result = self.handle_cmd(cmd).await;
+        if reply.agree(result.is_ok()).await || result.is_err() {
+            return result;
+        }
+
+        tracing::warn!("undoing a write another shard couldn't make");
+        let changed = keys.unwrap_or_else(|| data.keys().cloned().collect());
+        for key in changed {
+            let Some(value) = data.get(&key) else {
+                continue;
+            };
+            let undo = match flags.get(&key) {
+                Some(&flags) => StorageCommand::SetWithFlags(key, value.clone(), flags),
+                None => StorageCommand::Set(key, value.clone()),
+            };
+            if let Err(e) = self.handle_cmd(undo).await {
+                tracing::error!(e=?e, "could not undo a write another shard couldn't make");
+                break;
             }
-            _ => Err(StorageError::NotASet),
         }
+        result
     }
 
-    async fn handle_set_members(&mut self, key: Key) -> Result<Option<Value>, StorageError> {
-        let entry = self
-            .data
-            .entry(key)
-            .or_insert_with(|| Value::Set(HashSet::new()));
-        match entry {
-            val @ Value::Set(_) => Ok(Some(val.clone())),
-            _ => Err(StorageError::NotASet),
+    async fn handle_set_if(
+        &mut self,
+        key: Key,
+        value: Value,
+        flags: u32,
+        condition: SetCondition,
+    ) -> Result<Option<Value>, StorageError> {
+        // nothing else writes to the shard, so the condition still holds
+        // once the write is recorded
+        if let Err(reply) = self.check_condition(&key, condition) {
+            return Ok(reply);
         }
+
+        // only the outcome goes in the log, so replay doesn't need to
+        // re-evaluate the condition.
+        let outcome = match flags {
+            0 => StorageCommand::Set(key, value),
+            _ => StorageCommand::SetWithFlags(key, value, flags),
+        };
+        self.record_cmd(&outcome).await?;
+        self.apply(outcome)?;
+        Ok(Some(Value::Int(1)))
+    }
+
+    async fn handle_delete_if(
+        &mut self,
+        key: Key,
+        condition: SetCondition,
+    ) -> Result<Option<Value>, StorageError> {
+        if let Err(reply) = self.check_condition(&key, condition) {
+            return Ok(reply);
+        }
+        // a missing key leaves nothing to remove, or to log
+        if !self.data.read().unwrap().data.contains_key(&key) {
+            return Ok(Some(Value::Int(1)));
+        }
+
+        let outcome = StorageCommand::Delete(vec![key]);
+        self.record_cmd(&outcome).await?;
+        self.apply(outcome)?;
+        Ok(Some(Value::Int(1)))
+    }
+
+    /// Whether the condition holds for the key's current value, or if not,
+    /// what a conditional write replies with.
+    fn check_condition(&self, key: &Key, condition: SetCondition) -> Result<(), Option<Value>> {
+        let state = self.data.read().unwrap();
+        let current = state.data.get(key);
+        let applies = match (condition, current) {
+            (SetCondition::Absent, None) => true,
+            (SetCondition::Present, Some(_)) => true,
+            (SetCondition::Matches(token), Some(_)) => state.cas.get(key) == Some(&token),
+            _ => false,
+        };
+        match applies {
+            true => Ok(()),
+            false => Err(current.map(|_| Value::Int(0))),
+        }
+    }
+
+    /// Hands the log a snapshot of the data to rewrite itself from. Nothing
+    /// else runs on the shard in between, so the snapshot reflects exactly
+    /// the writes sent to the log before it. With wait, this waits to hear
+    /// the rewrite has started, or why it couldn't.
+    async fn rewrite_log(&self, wait: bool) -> Result<(), StorageError> {
+        if !self.durable {
+            return Ok(());
+        }
+        let (data, flags) = self.snapshot();
+        let request = LogRequest::Rewrite {
+            data,
+            flags,
+            wait: false,
+        };
+        let started = self.send_log_request(request).await?;
+        match wait {
+            true => wait_for_log(started).await,
+            false => Ok(()),
+        }
+    }
+
+    /// Refuses writes once the transaction worker says it's stopped, since
+    /// nothing more can go in the log.
+    pub fn read_only_when(&mut self, log_stopped: Arc<AtomicBool>) {
+        self.log_stopped = log_stopped;
+    }
+
+    /// Makes the shard writable again after it was recovered to a point in
+    /// the past. Its log still has the records after that point, so first
+    /// it's rewritten from the data, and the shard stays read-only until
+    /// that's finished. Does nothing if the shard isn't read-only.
+    async fn promote(&mut self) -> Result<(), StorageError> {
+        if !self.read_only {
+            return Ok(());
+        }
+        let (data, flags) = self.snapshot();
+        let request = LogRequest::Rewrite {
+            data,
+            flags,
+            wait: true,
+        };
+        let rewritten = self.send_log_request(request).await?;
+        wait_for_log(rewritten).await?;
+        self.read_only = false;
+        tracing::info!("promoted to read-write");
+        Ok(())
+    }
+
+    /// A copy of the data and its flags, for the log to write out.
+    fn snapshot(&self) -> (Keyspace, ItemFlags) {
+        let state = self.data.read().unwrap();
+        (state.data.clone(), state.flags.clone())
+    }
+
+    /// Hands the log a snapshot of the data to save, in the same way as
+    /// rewrite_log. The snapshot shares the data rather than copying it, so
+    /// taking it hardly holds up the shard; it's written out in the
+    /// background. The log answers once the save has started, or with wait
+    /// once it's finished.
+    async fn save(&self, wait: bool) -> Result<LogReceiver, StorageError> {
+        let (data, flags) = self.snapshot();
+        self.send_log_request(LogRequest::Save { data, flags, wait })
+            .await
     }
 
     fn enable_durability(&mut self) {
@@ -172,75 +754,196 @@ impl InMemoryStorage {
         self.durable = false;
     }
 
+    /// Sends the command to the log. Unless durability is async, it waits
+    /// until the log has it, so a write that fails to be logged is never
+    /// applied and the client hears about the failure.
     async fn record_cmd(&self, cmd: &StorageCommand) -> Result<(), StorageError> {
-        if self.durable {
-            let (tx, _rx) = oneshot::channel();
-            self.transaction_queue
-                .send((vec![cmd.clone()], tx))
-                .await
-                .expect("sending to transaction log failed");
+        let Some(recorded) = self.send_record(cmd).await? else {
+            return Ok(());
+        };
+        let synced = self.send_sync().await?;
+        wait_for_log(recorded).await?;
+        match synced {
+            Some(synced) => wait_for_log(synced).await,
+            None => Ok(()),
         }
-        Ok(())
     }
 
-    async fn handle_set_remove(
-        &mut self,
-        key: Blob,
-        blob: Blob,
-    ) -> Result<Option<Value>, StorageError> {
-        match self.data.get_mut(&key) {
-            Some(Value::Set(val)) => {
-                let removed = val.remove(&blob);
-                Ok(Some(Value::Int(i64::from(removed))))
-            }
-            Some(_) => Err(StorageError::NotASet),
-            None => Err(StorageError::NotASet),
+    /// Sends the command to the log, giving what to wait on to hear it's been
+    /// recorded, or nothing if durability doesn't wait for that.
+    async fn send_record(
+        &self,
+        cmd: &StorageCommand,
+    ) -> Result<Option<LogReceiver>, TransactionLogError> {
+        if !self.durable {
+            return Ok(None);
+        }
+        let recorded = self
+            .request_log(LogRequest::Record(vec![cmd.clone()]))
+            .await?;
+        match self.durability {
+            Durability::Async => Ok(None),
+            Durability::Logged | Durability::Fsynced => Ok(Some(recorded)),
         }
     }
 
-    async fn handle_set_intersection(
-        &self,
-        keys: Vec<Blob>,
-    ) -> Result<Option<Value>, StorageError> {
-        if keys.is_empty() {
-            return Err(StorageError::NotASet);
+    /// With fsynced durability, asks the log to fsync, which covers every
+    /// record sent before, since requests are handled in order.
+    async fn send_sync(&self) -> Result<Option<LogReceiver>, TransactionLogError> {
+        match self.durability {
+            Durability::Fsynced => self
+                .request_log(LogRequest::Sync { fsync: true })
+                .await
+                .map(Some),
+            Durability::Async | Durability::Logged => Ok(None),
         }
-        let mut keys = keys.iter();
-
-        let mut result = self.get_set(keys.next().unwrap())?;
+    }
 
-        for key in keys {
-            // this is probably doing way too many clones. a problem for another
-            // day when we deal with lifetimes and RCs and stuff!
-            result = result.intersection(&self.get_set(key)?).cloned().collect();
-        }
+    async fn send_log_request(&self, request: LogRequest) -> Result<LogReceiver, StorageError> {
+        Ok(self.request_log(request).await?)
+    }
 
-        Ok(Some(Value::Set(result)))
+    async fn request_log(&self, request: LogRequest) -> Result<LogReceiver, TransactionLogError> {
+        let (tx, rx) = oneshot::channel();
+        self.transaction_queue
+            .send((request, tx))
+            .await
+            .map_err(|_| TransactionLogError::Stopped)?;
+        Ok(rx)
     }
+}
 
-    async fn handle_set_union(&self, keys: Vec<Blob>) -> Result<Option<Value>, StorageError> {
-        if keys.is_empty() {
-            return Err(StorageError::NotASet);
-        }
-        let mut keys = keys.iter();
+/// What the log answers a request with.
+type LogReceiver = oneshot::Receiver<Result<(), TransactionLogError>>;
+
+/// A command in a batch, waiting for the writes before it, and itself if
+/// it's a write, to be recorded in the log.
+struct Pending {
+    cmd: StorageCommand,
+    reply: Reply,
+    /// What to wait on for the write to be recorded, or nothing if there's
+    /// nothing to wait for.
+    recorded: Result<Option<LogReceiver>, TransactionLogError>,
+}
+
+/// Whether the command only changes the shard's data, so it can be logged
+/// as it is without looking at the data first.
+fn is_plain_write(cmd: &StorageCommand) -> bool {
+    matches!(
+        cmd,
+        StorageCommand::Set(..)
+            | StorageCommand::SetWithFlags(..)
+            | StorageCommand::Incr(_)
+            | StorageCommand::Decr(_)
+            | StorageCommand::SetAdd(..)
+            | StorageCommand::SetRemove(..)
+            | StorageCommand::Delete(_)
+            | StorageCommand::FlushAll
+    )
+}
+
+async fn log_result(rx: LogReceiver) -> Result<(), TransactionLogError> {
+    rx.await.unwrap_or(Err(TransactionLogError::Stopped))
+}
 
-        let mut result = self.get_set(keys.next().unwrap())?;
+async fn wait_for_log(rx: LogReceiver) -> Result<(), StorageError> {
+    Ok(log_result(rx).await?)
+}
+
+/// Runs a read-only command against a shard's data.
+pub fn read(data: &Keyspace, cmd: &StorageCommand) -> Result<Option<Value>, StorageError> {
+    match cmd {
+        StorageCommand::Get(key) => Ok(data.get(key).cloned()),
+        StorageCommand::MultiGet(keys) => {
+            let values = keys
+                .iter()
+                .filter_map(|key| {
+                    let value = match data.get(key)? {
+                        Value::Blob(blob) => blob.clone(),
+                        Value::Int(i) => Blob(i.to_string().into_bytes()),
+                        Value::Set(_) | Value::Hash(_) => return None,
+                    };
+                    Some((key.clone(), value))
+                })
+                .collect();
+            Ok(Some(Value::Hash(values)))
+        }
+        StorageCommand::SetMembers(key) => match data.get(key) {
+            Some(val @ Value::Set(_)) => Ok(Some(val.clone())),
+            Some(_) => Err(StorageError::NotASet),
+            None => Ok(Some(Value::Set(HashSet::new()))),
+        },
+        StorageCommand::SetIntersection(keys) => {
+            let mut keys = keys.iter();
+            let first = keys.next().ok_or(StorageError::NotASet)?;
+            let mut result = get_set(data, first)?.clone();
+            for key in keys {
+                let set = get_set(data, key)?;
+                result.retain(|member| set.contains(member));
+            }
+            Ok(Some(Value::Set(result)))
+        }
+        StorageCommand::SetUnion(keys) => {
+            if keys.is_empty() {
+                return Err(StorageError::NotASet);
+            }
+            let mut result = HashSet::new();
+            for key in keys {
+                result.extend(get_set(data, key)?.iter().cloned());
+            }
+            Ok(Some(Value::Set(result)))
+        }
+        _ => unreachable!("{} is not read only", cmd.name()),
+    }
+}
 
-        for key in keys {
-            // this is probably doing way too many clones. a problem for another
-            // day when we deal with lifetimes and RCs and stuff!
-            result = result.union(&self.get_set(key)?).cloned().collect();
+fn add(data: &mut Keyspace, key: Key, amount: i64) -> Result<Option<Value>, StorageError> {
+    let entry = data.entry(key).or_insert_with(|| Value::Int(0));
+    match entry {
+        Value::Int(i) => {
+            *i = safe_add(*i, amount)?;
+            Ok(Some(Value::Int(*i)))
         }
+        Value::Blob(Blob(b)) => match atoi::atoi::<i64>(b) {
+            None => Err(StorageError::NotAnInteger),
+            Some(i) => {
+                *entry = Value::Int(safe_add(i, amount)?);
+                Ok(Some(entry.clone()))
+            }
+        },
+        Value::Set(_) => Err(StorageError::NotAnInteger),
+        Value::Hash(_) => Err(StorageError::NotAnInteger),
+    }
+}
 
-        Ok(Some(Value::Set(result)))
+fn set_add(data: &mut Keyspace, key: Key, value: Blob) -> Result<Option<Value>, StorageError> {
+    let entry = data
+        .entry(key)
+        .or_insert_with(|| Value::Set(HashSet::new()));
+    match entry {
+        Value::Set(set) => {
+            let added = set.insert(value);
+            Ok(Some(Value::Int(i64::from(added))))
+        }
+        _ => Err(StorageError::NotASet),
     }
+}
 
-    fn get_set(&self, key: &Blob) -> Result<HashSet<Blob>, StorageError> {
-        if let Some(Value::Set(s)) = self.data.get(key) {
-            Ok(s.clone())
-        } else {
-            Err(StorageError::NotASet)
+fn set_remove(data: &mut Keyspace, key: Key, blob: Blob) -> Result<Option<Value>, StorageError> {
+    match data.get_mut(&key) {
+        Some(Value::Set(val)) => {
+            let removed = val.remove(&blob);
+            Ok(Some(Value::Int(i64::from(removed))))
         }
+        Some(_) => Err(StorageError::NotASet),
+        None => Err(StorageError::NotASet),
+    }
+}
+
+fn get_set<'a>(data: &'a Keyspace, key: &Blob) -> Result<&'a HashSet<Blob>, StorageError> {
+    match data.get(key) {
+        Some(Value::Set(s)) => Ok(s),
+        _ => Err(StorageError::NotASet),
     }
 }
 
@@ -250,3 +953,227 @@ fn safe_add(a: i64, b: i64) -> Result<i64, StorageError> {
         None => Err(StorageError::Overflow),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::transaction::TransactionRecvQueue;
+    use crate::worker::Reply;
+
+    /// A shard whose log answers every request with the given result.
+    fn shard(durability: Durability, fail: bool) -> InMemoryStorage {
+        let (_tx, rx) = mpsc::channel(1);
+        let (ttx, mut trx): (TransactionSendQueue, TransactionRecvQueue) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some((_, reply)) = trx.recv().await {
+                let result = match fail {
+                    true => Err(TransactionLogError::Corrupted("disk on fire")),
+                    false => Ok(()),
+                };
+                let _ = reply.send(result);
+            }
+        });
+        InMemoryStorage::new(rx, ttx, durability)
+    }
+
+    fn set(key: &str) -> StorageCommand {
+        StorageCommand::Set(key.into(), Value::Int(1))
+    }
+
+    #[tokio::test]
+    async fn it_reports_log_failures_when_waiting_for_the_log() {
+        for durability in [Durability::Logged, Durability::Fsynced] {
+            let mut storage = shard(durability, true);
+            assert!(matches!(
+                storage.handle_cmd(set("k")).await,
+                Err(StorageError::LogError(_))
+            ));
+            // and the write isn't applied
+            assert!(storage.data.read().unwrap().data.is_empty());
+
+            let mut storage = shard(durability, false);
+            assert!(storage.handle_cmd(set("k")).await.is_ok());
+            assert_eq!(1, storage.data.read().unwrap().data.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn it_logs_a_batch_of_writes_before_waiting_on_any() {
+        // the log only answers once it has both records and the sync, so
+        // this would never finish if the shard waited on each write in turn
+        let (_tx, rx) = mpsc::channel(1);
+        let (ttx, mut trx): (TransactionSendQueue, TransactionRecvQueue) = mpsc::channel(8);
+        tokio::spawn(async move {
+            let mut requests = vec![];
+            while requests.len() < 3 {
+                requests.push(trx.recv().await.unwrap());
+            }
+            assert!(matches!(requests[2].0, LogRequest::Sync { fsync: true }));
+            for (_, reply) in requests {
+                let _ = reply.send(Ok(()));
+            }
+        });
+        let mut storage = InMemoryStorage::new(rx, ttx, Durability::Fsynced);
+
+        let (reply_tx, mut replies) = mpsc::unbounded_channel();
+        let batch = vec![
+            (set("a"), Reply::to(0, reply_tx.clone())),
+            (
+                StorageCommand::Get("a".into()),
+                Reply::to(1, reply_tx.clone()),
+            ),
+            (set("b"), Reply::to(2, reply_tx)),
+        ];
+        tokio::time::timeout(Duration::from_secs(5), storage.handle_batch(batch))
+            .await
+            .unwrap();
+
+        let (seq, read) = replies.recv().await.unwrap();
+        assert_eq!(0, seq);
+        assert!(read.unwrap().is_ok());
+        // the read sees the write before it
+        let (_, read) = replies.recv().await.unwrap();
+        assert_eq!(Some(Value::Int(1)), read.unwrap().unwrap());
+        assert_eq!(2, storage.data.read().unwrap().data.len());
+    }
+
+    #[tokio::test]
+    async fn it_applies_none_of_a_batch_the_log_fails() {
+        let mut storage = shard(Durability::Fsynced, true);
+        let (reply_tx, mut replies) = mpsc::unbounded_channel();
+        let batch = vec![
+            (set("a"), Reply::to(0, reply_tx.clone())),
+            (set("b"), Reply::to(1, reply_tx)),
+        ];
+        storage.handle_batch(batch).await;
+
+        for _ in 0..2 {
+            let (_, result) = replies.recv().await.unwrap();
+            assert!(matches!(result.unwrap(), Err(StorageError::LogError(_))));
+        }
+        assert!(storage.data.read().unwrap().data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_gives_every_write_a_new_cas_token() {
+        let mut storage = shard(Durability::Async, false);
+        let item = |storage: &InMemoryStorage| storage.data.read().unwrap().item(&"k".into());
+        let flagged = StorageCommand::SetWithFlags("k".into(), Value::Int(1), 9);
+        storage.handle_cmd(flagged).await.unwrap();
+        let first = item(&storage).unwrap();
+        assert_eq!(9, first.flags);
+
+        // an increment keeps the flags, but a value written back to what it
+        // was still gets a new token
+        storage
+            .handle_cmd(StorageCommand::Incr("k".into()))
+            .await
+            .unwrap();
+        storage
+            .handle_cmd(StorageCommand::Decr("k".into()))
+            .await
+            .unwrap();
+        let second = item(&storage).unwrap();
+        assert_eq!((Value::Int(1), 9), (second.value, second.flags));
+        assert_ne!(first.cas, second.cas);
+
+        let stale = StorageCommand::SetIf(
+            "k".into(),
+            Value::Int(2),
+            0,
+            SetCondition::Matches(first.cas),
+        );
+        assert_eq!(
+            Some(Value::Int(0)),
+            storage.handle_cmd(stale).await.unwrap()
+        );
+
+        // a plain set drops the flags
+        storage.handle_cmd(set("k")).await.unwrap();
+        assert_eq!(0, item(&storage).unwrap().flags);
+    }
+
+    #[tokio::test]
+    async fn it_deletes_only_when_the_condition_holds() {
+        let mut storage = shard(Durability::Async, false);
+        let delete_if = |condition| StorageCommand::DeleteIf("k".into(), condition);
+        assert_eq!(
+            None,
+            storage
+                .handle_cmd(delete_if(SetCondition::Present))
+                .await
+                .unwrap()
+        );
+
+        storage.handle_cmd(set("k")).await.unwrap();
+        let cas = storage.data.read().unwrap().item(&"k".into()).unwrap().cas;
+        for condition in [SetCondition::Absent, SetCondition::Matches(cas + 1)] {
+            assert_eq!(
+                Some(Value::Int(0)),
+                storage.handle_cmd(delete_if(condition)).await.unwrap()
+            );
+        }
+        assert_eq!(
+            Some(Value::Int(1)),
+            storage
+                .handle_cmd(delete_if(SetCondition::Matches(cas)))
+                .await
+                .unwrap()
+        );
+        assert!(storage.data.read().unwrap().data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_undoes_its_part_of_a_write_another_shard_fails() {
+        let mut written = shard(Durability::Logged, false);
+        let mut failing = shard(Durability::Logged, true);
+        written.handle_cmd(set("a")).await.unwrap();
+
+        let (reply_tx, mut replies) = mpsc::unbounded_channel();
+        let [first, second] = crate::worker::batches_for(StorageCommand::FlushAll, 2, 0, reply_tx)
+            .try_into()
+            .unwrap_or_else(|_| panic!("expected a part for each shard"));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(written.handle_batch(first), failing.handle_batch(second))
+        })
+        .await
+        .expect("the shards should agree");
+
+        let (_, result) = replies.recv().await.unwrap();
+        assert!(matches!(result.unwrap(), Err(StorageError::LogError(_))));
+        let data = &written.data.read().unwrap().data;
+        assert_eq!(Some(&Value::Int(1)), data.get(&Key::from("a")));
+    }
+
+    #[tokio::test]
+    async fn it_refuses_writes_once_the_log_stops() {
+        let mut storage = shard(Durability::Async, false);
+        storage.handle_cmd(set("a")).await.unwrap();
+        let stopped = Arc::new(AtomicBool::new(false));
+        storage.read_only_when(stopped.clone());
+        stopped.store(true, Ordering::Relaxed);
+
+        assert!(matches!(
+            storage.handle_cmd(set("b")).await,
+            Err(StorageError::LogStopped)
+        ));
+        let (reply_tx, mut replies) = mpsc::unbounded_channel();
+        storage
+            .handle_batch(vec![(set("b"), Reply::to(0, reply_tx))])
+            .await;
+        let (_, result) = replies.recv().await.unwrap();
+        assert!(matches!(result.unwrap(), Err(StorageError::LogStopped)));
+
+        let read = storage.handle_cmd(StorageCommand::Get("a".into())).await;
+        assert_eq!(Some(Value::Int(1)), read.unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_acknowledges_before_logging_when_async() {
+        let mut storage = shard(Durability::Async, true);
+        assert!(storage.handle_cmd(set("k")).await.is_ok());
+        assert_eq!(1, storage.data.read().unwrap().data.len());
+    }
+}
@@ -0,0 +1,54 @@
+use super::{read, shard_for, Item, ShardData, StorageCommand, StorageError};
+use crate::types::{Key, Value};
+
+/// Runs reads of a single key straight against the shards' data, on whichever
+/// task asks, rather than sending them through the worker and a shard's queue.
+/// Reads of different keys, and reads alongside a shard's writes, then run at
+/// the same time.
+///
+/// Reads which span keys still go through the worker, since that's what
+/// makes them see a write spanning shards either entirely or not at all.
+///
+/// Reads here aren't put in order with the commands going through the worker.
+/// One sees whatever its shard has applied when it runs: a connection's own
+/// writes, since it waits for each write's reply before going on, but not
+/// writes other connections sent which are still queued. It can also see one
+/// shard's part of a write spanning shards before the others have theirs,
+/// including a part which is then undone because another shard's failed.
+#[derive(Clone, Default)]
+pub struct StorageReader {
+    shards: Vec<ShardData>,
+    concurrent: bool,
+}
+
+impl StorageReader {
+    /// With concurrent off, only memcached's items are read here, since
+    /// replies through the worker carry just the value.
+    pub fn new(shards: Vec<ShardData>, concurrent: bool) -> Self {
+        Self { shards, concurrent }
+    }
+
+    /// Runs the command if it can be run here, and otherwise returns None so
+    /// it goes through the worker instead.
+    pub fn read(&self, cmd: &StorageCommand) -> Option<Result<Option<Value>, StorageError>> {
+        let key = match cmd {
+            StorageCommand::Get(key) | StorageCommand::SetMembers(key) => key,
+            _ => return None,
+        };
+        if !self.concurrent || self.shards.is_empty() {
+            return None;
+        }
+
+        let shard = &self.shards[shard_for(key, self.shards.len())];
+        Some(read(&shard.read().unwrap().data, cmd))
+    }
+
+    /// The key's value with its memcached flags and CAS token.
+    pub fn item(&self, key: &Key) -> Option<Item> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        let shard = &self.shards[shard_for(key, self.shards.len())];
+        shard.read().unwrap().item(key)
+    }
+}
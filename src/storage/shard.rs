@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use super::{StorageCommand, StorageError};
+use crate::config::Config;
+use crate::transaction::split_unsharded_log;
+use crate::types::{Blob, Key, Value};
+
+/// Which shard a key lives on. This decides which log a key's writes go to,
+/// so it has to give the same answer from one build to the next; FNV-1a is
+/// simple and stable, where std's hashers make no such promise.
+pub fn shard_for(key: &Key, shards: usize) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in &key.0 {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    (hash % shards as u64) as usize
+}
+
+/// How a command is spread over the shards.
+pub enum Route {
+    /// Everything it touches is on one shard.
+    One(usize, StorageCommand),
+    /// It has a part for each shard it touches, and the parts' results are
+    /// combined, in the order of the parts, into its own.
+    Many(Vec<(usize, StorageCommand)>, Combine),
+    /// Like Many, but the parts write, and either all of them apply or none
+    /// do: each shard writes its part, waits to hear whether the others
+    /// wrote theirs, and undoes its own if any of them couldn't.
+    AllOrNothing(Vec<(usize, StorageCommand)>, Combine),
+}
+
+pub type Combine =
+    fn(Vec<Result<Option<Value>, StorageError>>) -> Result<Option<Value>, StorageError>;
+
+impl StorageCommand {
+    /// Splits the command up by shard. Splitting doesn't change what the
+    /// command does: each part sees the same keys the whole would have, and
+    /// combining the parts' results gives the result of the whole.
+    pub fn route(self, shards: usize) -> Route {
+        if shards == 1 {
+            return Route::One(0, self);
+        }
+
+        match self {
+            StorageCommand::MultiGet(keys) => match split_keys(keys, shards) {
+                Ok((shard, keys)) => Route::One(shard, StorageCommand::MultiGet(keys)),
+                Err(parts) => Route::Many(
+                    parts
+                        .into_iter()
+                        .map(|(shard, keys)| (shard, StorageCommand::MultiGet(keys)))
+                        .collect(),
+                    combine_values,
+                ),
+            },
+            StorageCommand::SetIntersection(keys) => match split_keys(keys, shards) {
+                Ok((shard, keys)) => Route::One(shard, StorageCommand::SetIntersection(keys)),
+                Err(parts) => Route::Many(
+                    parts
+                        .into_iter()
+                        .map(|(shard, keys)| (shard, StorageCommand::SetIntersection(keys)))
+                        .collect(),
+                    combine_intersection,
+                ),
+            },
+            StorageCommand::SetUnion(keys) => match split_keys(keys, shards) {
+                Ok((shard, keys)) => Route::One(shard, StorageCommand::SetUnion(keys)),
+                Err(parts) => Route::Many(
+                    parts
+                        .into_iter()
+                        .map(|(shard, keys)| (shard, StorageCommand::SetUnion(keys)))
+                        .collect(),
+                    combine_union,
+                ),
+            },
+            StorageCommand::Delete(keys) => match split_keys(keys, shards) {
+                Ok((shard, keys)) => Route::One(shard, StorageCommand::Delete(keys)),
+                Err(parts) => Route::AllOrNothing(
+                    parts
+                        .into_iter()
+                        .map(|(shard, keys)| (shard, StorageCommand::Delete(keys)))
+                        .collect(),
+                    combine_count,
+                ),
+            },
+            StorageCommand::FlushAll => Route::AllOrNothing(
+                (0..shards).map(|shard| (shard, self.clone())).collect(),
+                combine_ok,
+            ),
+            StorageCommand::RewriteLog | StorageCommand::Save | StorageCommand::BgSave => {
+                Route::Many(
+                    (0..shards).map(|shard| (shard, self.clone())).collect(),
+                    combine_ok,
+                )
+            }
+            StorageCommand::Promote => Route::Many(
+                (0..shards).map(|shard| (shard, self.clone())).collect(),
+                combine_failed_shards,
+            ),
+            StorageCommand::LastSave => Route::Many(
+                (0..shards).map(|shard| (shard, self.clone())).collect(),
+                combine_oldest,
+            ),
+            cmd => {
+                let shard = match cmd.keys().first() {
+                    Some(key) => shard_for(key, shards),
+                    None => 0,
+                };
+                Route::One(shard, cmd)
+            }
+        }
+    }
+}
+
+/// Groups keys by shard, keeping their order within each shard. When they're
+/// all on one shard (or there are none) that shard is returned as Ok.
+#[allow(clippy::type_complexity)]
+fn split_keys(keys: Vec<Key>, shards: usize) -> Result<(usize, Vec<Key>), Vec<(usize, Vec<Key>)>> {
+    let mut parts: Vec<(usize, Vec<Key>)> = vec![];
+    for key in keys {
+        let shard = shard_for(&key, shards);
+        match parts.iter_mut().find(|(s, _)| *s == shard) {
+            Some((_, keys)) => keys.push(key),
+            None => parts.push((shard, vec![key])),
+        }
+    }
+
+    match parts.len() {
+        0 => Ok((0, vec![])),
+        1 => Ok(parts.pop().unwrap()),
+        _ => Err(parts),
+    }
+}
+
+fn sets(
+    results: Vec<Result<Option<Value>, StorageError>>,
+) -> Result<Vec<HashSet<Blob>>, StorageError> {
+    results
+        .into_iter()
+        .map(|result| match result? {
+            Some(Value::Set(set)) => Ok(set),
+            _ => Err(StorageError::NotASet),
+        })
+        .collect()
+}
+
+fn combine_intersection(
+    results: Vec<Result<Option<Value>, StorageError>>,
+) -> Result<Option<Value>, StorageError> {
+    let mut sets = sets(results)?.into_iter();
+    let first = sets.next().ok_or(StorageError::NotASet)?;
+    let result = sets.fold(first, |acc, set| acc.intersection(&set).cloned().collect());
+    Ok(Some(Value::Set(result)))
+}
+
+fn combine_union(
+    results: Vec<Result<Option<Value>, StorageError>>,
+) -> Result<Option<Value>, StorageError> {
+    let result = sets(results)?.into_iter().flatten().collect();
+    Ok(Some(Value::Set(result)))
+}
+
+fn combine_values(
+    results: Vec<Result<Option<Value>, StorageError>>,
+) -> Result<Option<Value>, StorageError> {
+    let mut values = HashMap::new();
+    for result in results {
+        if let Some(Value::Hash(part)) = result? {
+            values.extend(part);
+        }
+    }
+    Ok(Some(Value::Hash(values)))
+}
+
+fn combine_count(
+    results: Vec<Result<Option<Value>, StorageError>>,
+) -> Result<Option<Value>, StorageError> {
+    let mut total = 0;
+    for result in results {
+        if let Some(Value::Int(count)) = result? {
+            total += count;
+        }
+    }
+    Ok(Some(Value::Int(total)))
+}
+
+fn combine_ok(
+    results: Vec<Result<Option<Value>, StorageError>>,
+) -> Result<Option<Value>, StorageError> {
+    for result in results {
+        result?;
+    }
+    Ok(None)
+}
+
+/// Which shards failed, if any did, with why the first of them did. The
+/// parts are one per shard in shard order.
+fn combine_failed_shards(
+    results: Vec<Result<Option<Value>, StorageError>>,
+) -> Result<Option<Value>, StorageError> {
+    let mut shards = vec![];
+    let mut reason = None;
+    for (shard, result) in results.into_iter().enumerate() {
+        if let Err(e) = result {
+            shards.push(shard);
+            reason.get_or_insert(e.to_string());
+        }
+    }
+    match reason {
+        Some(reason) => Err(StorageError::ShardsFailed { shards, reason }),
+        None => Ok(None),
+    }
+}
+
+/// The oldest of the shards' times, which is when all of them had last been
+/// saved.
+fn combine_oldest(
+    results: Vec<Result<Option<Value>, StorageError>>,
+) -> Result<Option<Value>, StorageError> {
+    let mut oldest = None;
+    for result in results {
+        if let Some(Value::Int(time)) = result? {
+            oldest = Some(oldest.map_or(time, |oldest: i64| oldest.min(time)));
+        }
+    }
+    Ok(oldest.map(Value::Int))
+}
+
+/// Checks the logs on disk were written with the number of shards we're
+/// configured with, and records it if nothing's been written yet. Keys are
+/// spread over the shards' logs by hash, so replaying them with a different
+/// number of shards would put keys on the wrong shard. A log from before
+/// sharding is split up over the shards first.
+pub fn check_shard_count(config: &Config) -> std::io::Result<()> {
+    let path = format!("{}.shards", config.storage_basepath);
+    let recorded = match std::fs::read_to_string(&path) {
+        Ok(contents) => Some(contents.trim().parse::<usize>().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} doesn't hold a shard count", path),
+            )
+        })?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            // a log from before sharding is where a single shard's would be,
+            // so it only needs splitting up for more than one
+            let legacy = format!("{}.current", config.storage_basepath);
+            let legacy_len = std::fs::metadata(legacy).map(|m| m.len()).unwrap_or(0);
+            if legacy_len > 0 && config.storage_shards > 1 {
+                split_unsharded_log(config).map_err(std::io::Error::other)?;
+            }
+            None
+        }
+        Err(e) => return Err(e),
+    };
+
+    match recorded {
+        Some(shards) if shards != config.storage_shards => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "the log at {} was written with {} shard(s), but storage_shards is {}",
+                config.storage_basepath, shards, config.storage_shards
+            ),
+        )),
+        Some(_) if Path::new(&path).exists() => Ok(()),
+        _ => std::fs::write(&path, format!("{}\n", config.storage_shards)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(s: &str) -> Key {
+        Blob(s.as_bytes().to_vec())
+    }
+
+    fn set(members: &[&str]) -> Result<Option<Value>, StorageError> {
+        Ok(Some(Value::Set(members.iter().map(|m| key(m)).collect())))
+    }
+
+    #[test]
+    fn it_puts_keys_on_stable_shards() {
+        // these are part of the on-disk format, so mustn't change
+        assert_eq!(0xaf63dc4c8601ec8c % 4, shard_for(&key("a"), 4) as u64);
+        assert_eq!(0, shard_for(&key("anything"), 1));
+    }
+
+    #[test]
+    fn it_splits_multi_key_commands_by_shard() {
+        let keys: Vec<Key> = (0..16).map(|i| key(&format!("k{}", i))).collect();
+        let Route::Many(parts, _) = StorageCommand::SetUnion(keys.clone()).route(4) else {
+            panic!("expected the keys to be spread over shards");
+        };
+
+        let mut seen = vec![];
+        for (shard, part) in parts {
+            let StorageCommand::SetUnion(part_keys) = part else {
+                panic!("expected a union");
+            };
+            for k in part_keys {
+                assert_eq!(shard, shard_for(&k, 4));
+                seen.push(k);
+            }
+        }
+        seen.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut expected = keys;
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(expected, seen);
+
+        assert!(matches!(
+            StorageCommand::Delete(expected).route(4),
+            Route::AllOrNothing(..)
+        ));
+        assert!(matches!(
+            StorageCommand::Get(key("a")).route(4),
+            Route::One(shard, _) if shard == shard_for(&key("a"), 4)
+        ));
+    }
+
+    #[test]
+    fn it_combines_the_parts_results() {
+        assert_eq!(
+            set(&["b"]).unwrap(),
+            combine_intersection(vec![set(&["a", "b"]), set(&["b", "c"])]).unwrap()
+        );
+        assert_eq!(
+            set(&["a", "b", "c"]).unwrap(),
+            combine_union(vec![set(&["a", "b"]), set(&["b", "c"])]).unwrap()
+        );
+        assert!(combine_union(vec![set(&["a"]), Err(StorageError::NotASet)]).is_err());
+        assert_eq!(
+            Some(Value::Int(3)),
+            combine_count(vec![Ok(Some(Value::Int(1))), Ok(Some(Value::Int(2)))]).unwrap()
+        );
+        assert_eq!(
+            Some(Value::Int(1)),
+            combine_oldest(vec![Ok(Some(Value::Int(2))), Ok(Some(Value::Int(1)))]).unwrap()
+        );
+        assert!(combine_failed_shards(vec![Ok(None), Ok(None)]).is_ok());
+        let failed = combine_failed_shards(vec![
+            Ok(None),
+            Err(StorageError::Overflow),
+            Ok(None),
+            Err(StorageError::NotASet),
+        ]);
+        assert!(matches!(
+            failed,
+            Err(StorageError::ShardsFailed { shards, reason })
+                if shards == vec![1, 3] && reason == "integer overflow"
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+//! TLS for client connections. Certificates are loaded from the paths in
+//! Config and reloaded when those files change, so they can be rotated
+//! without a restart. Connections which are already established keep the
+//! session they negotiated; only new handshakes see the new certificates.
+//!
+//! When a CA is configured, clients may present a certificate signed by it
+//! and the connection takes on the identity in the certificate's subject.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{VerifierBuilderError, WebPkiClientVerifier};
+use rustls::{RootCertStore, ServerConfig};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::Config;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("--tls-cert and --tls-key are required to serve tls")]
+    MissingCertificate,
+
+    #[error("failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+
+    #[error("no certificates found in {0}")]
+    NoCertificates(PathBuf),
+
+    #[error("no private key found in {0}")]
+    NoPrivateKey(PathBuf),
+
+    #[error("invalid client certificate authority: {0}")]
+    Verifier(#[from] VerifierBuilderError),
+
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+}
+
+/// The files a TLS configuration is built from.
+#[derive(Clone, Debug)]
+struct TlsPaths {
+    cert: PathBuf,
+    key: PathBuf,
+    ca: Option<PathBuf>,
+    require_client_cert: bool,
+}
+
+impl TlsPaths {
+    fn files(&self) -> impl Iterator<Item = &Path> {
+        [Some(&self.cert), Some(&self.key), self.ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+    }
+
+    /// The modification times of each file, used to notice when they change.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// TlsState holds the current server configuration and swaps it out when
+/// the certificate files change. It's cheap to clone and every clone shares
+/// the same configuration.
+#[derive(Clone)]
+pub struct TlsState {
+    paths: TlsPaths,
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+    modified: Arc<Mutex<Vec<Option<SystemTime>>>>,
+}
+
+impl TlsState {
+    pub fn from_config(config: &Config) -> Result<TlsState, TlsError> {
+        let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+            _ => return Err(TlsError::MissingCertificate),
+        };
+        let paths = TlsPaths {
+            cert,
+            key,
+            ca: config.tls_ca_cert.clone(),
+            require_client_cert: config.tls_require_client_cert,
+        };
+
+        let modified = paths.modified();
+        let server_config = build_server_config(&paths)?;
+
+        Ok(TlsState {
+            paths,
+            current: Arc::new(RwLock::new(Arc::new(server_config))),
+            modified: Arc::new(Mutex::new(modified)),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+
+    /// Rebuilds the configuration if any of the files changed since they were
+    /// last loaded, and returns whether it did. If the new files are invalid,
+    /// perhaps because they're only partly written, the old configuration is
+    /// kept and the load is retried on the next call.
+    pub fn reload_if_changed(&self) -> Result<bool, TlsError> {
+        let modified = self.paths.modified();
+        if *self.modified.lock().unwrap() == modified {
+            return Ok(false);
+        }
+
+        let server_config = build_server_config(&self.paths)?;
+        *self.current.write().unwrap() = Arc::new(server_config);
+        *self.modified.lock().unwrap() = modified;
+        Ok(true)
+    }
+}
+
+fn build_server_config(paths: &TlsPaths) -> Result<ServerConfig, TlsError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &paths.ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match paths.require_client_cert {
+                true => verifier.build()?,
+                false => verifier.allow_unauthenticated().build()?,
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let certs = load_certs(&paths.cert)?;
+    let key = load_key(&paths.key)?;
+    Ok(builder.with_single_cert(certs, key)?)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let file = File::open(path).map_err(|e| TlsError::Read(path.to_path_buf(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Read(path.to_path_buf(), e))?;
+
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let file = File::open(path).map_err(|e| TlsError::Read(path.to_path_buf(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| TlsError::Read(path.to_path_buf(), e))?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_path_buf()))
+}
+
+/// The identity of the client on the other end of the stream, if it presented
+/// a certificate. This is the certificate subject's common name, or the whole
+/// subject if it has no common name.
+pub fn peer_identity<S: AsyncRead + AsyncWrite + Unpin>(stream: &TlsStream<S>) -> Option<String> {
+    let (_, session) = stream.get_ref();
+    let cert = session.peer_certificates()?.first()?;
+    subject_identity(cert)
+}
+
+fn subject_identity(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let subject = cert.subject();
+    let identity = match subject.iter_common_name().next() {
+        Some(cn) => cn.as_str().ok().map(str::to_string),
+        None => Some(subject.to_string()),
+    };
+    identity
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+
+    use super::*;
+
+    fn cert_with_subject(subject: DistinguishedName) -> CertificateDer<'static> {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.distinguished_name = subject;
+        let key = KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().clone()
+    }
+
+    #[test]
+    fn identity_is_the_common_name() {
+        let mut subject = DistinguishedName::new();
+        subject.push(DnType::OrganizationName, "Anode");
+        subject.push(DnType::CommonName, "billing-service");
+
+        assert_eq!(
+            Some("billing-service".to_string()),
+            subject_identity(&cert_with_subject(subject))
+        );
+    }
+
+    #[test]
+    fn identity_falls_back_to_the_whole_subject() {
+        let mut subject = DistinguishedName::new();
+        subject.push(DnType::OrganizationName, "Anode");
+
+        assert_eq!(
+            Some("O=Anode".to_string()),
+            subject_identity(&cert_with_subject(subject))
+        );
+    }
+}
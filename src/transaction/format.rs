@@ -0,0 +1,473 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use super::TransactionLogError;
+use crate::storage::StorageCommand;
+use crate::types::{Blob, Value};
+
+/// Every log starts with this, followed by the format version.
+pub const LOG_MAGIC: &[u8; 8] = b"ANODELOG";
+pub const LOG_VERSION: u32 = 1;
+pub const HEADER_LEN: usize = LOG_MAGIC.len() + 4;
+
+/// What the start of a log file says about it.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Header {
+    /// Nothing's been written, or the header itself was only partly written.
+    Empty,
+    /// A log in the current format.
+    Current,
+    /// A log written before logs had headers.
+    Legacy,
+}
+
+pub fn header() -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..LOG_MAGIC.len()].copy_from_slice(LOG_MAGIC);
+    header[LOG_MAGIC.len()..].copy_from_slice(&LOG_VERSION.to_le_bytes());
+    header
+}
+
+/// Reads the header from the start of a log, leaving the reader just past it
+/// if there is one.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<Header, TransactionLogError> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    reader
+        .by_ref()
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut bytes)?;
+
+    if header().starts_with(&bytes) && bytes.len() < HEADER_LEN {
+        return Ok(Header::Empty);
+    }
+    if !bytes.starts_with(LOG_MAGIC) {
+        return Ok(Header::Legacy);
+    }
+    let version = u32::from_le_bytes(bytes[LOG_MAGIC.len()..].try_into().unwrap());
+    match version {
+        LOG_VERSION => Ok(Header::Current),
+        _ => Err(TransactionLogError::UnsupportedVersion(version)),
+    }
+}
+
+/// Where a logged mutation comes in the order of every mutation, and when it
+/// was logged.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stamp {
+    /// The log sequence number. LSNs go up from 1 across every shard, and
+    /// every record a mutation is written as has the mutation's LSN. Records
+    /// migrated from legacy logs have 0.
+    pub lsn: u64,
+    /// When it was logged, in milliseconds since the Unix epoch, or 0 for
+    /// records migrated from legacy logs.
+    pub timestamp: u64,
+}
+
+/// A record read back from a log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    pub stamp: Stamp,
+    pub cmd: StorageCommand,
+}
+
+/// write_to_log appends a command to the log as a single record: the length
+/// of its payload and the payload's CRC32C, both as little endian u32s, then
+/// the payload. Reads have no effect on the data, so they're skipped, and a
+/// delete is written as one record per key. Returns how many records were
+/// written.
+#[tracing::instrument(skip(log), level = "trace")]
+pub fn write_to_log<W: Write>(
+    log: &mut W,
+    stamp: Stamp,
+    cmd: &StorageCommand,
+) -> Result<u64, TransactionLogError> {
+    match cmd {
+        StorageCommand::Delete(keys) => {
+            for key in keys {
+                write_record(log, stamp, &payload(b'X', &[key]))?;
+            }
+            Ok(keys.len() as u64)
+        }
+        _ => match encode(cmd) {
+            Some(payload) => {
+                write_record(log, stamp, &payload)?;
+                Ok(1)
+            }
+            None => Ok(0),
+        },
+    }
+}
+
+/// Steps over the next `count` records going by their lengths alone, without
+/// checking them, leaving the reader at the first record after them. Stops
+/// early at a record which runs past the end, leaving the reader there.
+pub fn skip_records<R: Read + Seek>(reader: &mut R, count: u64) -> std::io::Result<()> {
+    let mut offset = reader.stream_position()?;
+    let end = reader.seek(SeekFrom::End(0))?;
+    for _ in 0..count {
+        if offset + 8 > end {
+            break;
+        }
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let next = offset + 8 + u32::from_le_bytes(len) as u64;
+        if next > end {
+            break;
+        }
+        offset = next;
+    }
+    reader.seek(SeekFrom::Start(offset))?;
+    Ok(())
+}
+
+/// A record's payload starts with its stamp, the LSN then the timestamp as
+/// little endian u64s, followed by the command.
+fn write_record<W: Write>(
+    log: &mut W,
+    stamp: Stamp,
+    command: &[u8],
+) -> Result<(), TransactionLogError> {
+    let mut payload = Vec::with_capacity(16 + command.len());
+    payload.extend_from_slice(&stamp.lsn.to_le_bytes());
+    payload.extend_from_slice(&stamp.timestamp.to_le_bytes());
+    payload.extend_from_slice(command);
+    let len = u32::try_from(payload.len())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "record too large"))?;
+    // written in one go, so a record is never split between writes
+    let mut record = Vec::with_capacity(8 + payload.len());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&crc32c::crc32c(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    log.write_all(&record)?;
+    Ok(())
+}
+
+/// A command is a tag followed by its fields, each blob written as its
+/// length, a little endian u32, then its bytes.
+fn encode(cmd: &StorageCommand) -> Option<Vec<u8>> {
+    let payload = match cmd {
+        StorageCommand::Incr(key) => payload(b'I', &[key]),
+        StorageCommand::Decr(key) => payload(b'D', &[key]),
+        StorageCommand::Set(key, value) => {
+            let mut payload = payload(b'S', &[key]);
+            put_set_value(&mut payload, value);
+            payload
+        }
+        StorageCommand::SetWithFlags(key, value, flags) => {
+            let mut payload = payload(b'M', &[key]);
+            put_set_value(&mut payload, value);
+            payload.extend_from_slice(&flags.to_le_bytes());
+            payload
+        }
+        StorageCommand::SetAdd(key, value) => payload(b'A', &[key, value]),
+        StorageCommand::SetRemove(key, value) => payload(b'C', &[key, value]),
+        StorageCommand::FlushAll => payload(b'F', &[]),
+        // deletes are written a key at a time by write_to_log
+        StorageCommand::Delete(_) => return None,
+        // conditional writes are recorded as a plain Set or Delete once
+        // they apply
+        StorageCommand::SetIf(..) => return None,
+        StorageCommand::DeleteIf(..) => return None,
+        StorageCommand::SetIntersection(_) => return None,
+        StorageCommand::SetUnion(_) => return None,
+        StorageCommand::Get(_) => return None,
+        StorageCommand::MultiGet(_) => return None,
+        StorageCommand::SetMembers(_) => return None,
+        StorageCommand::RewriteLog => return None,
+        StorageCommand::Save => return None,
+        StorageCommand::BgSave => return None,
+        StorageCommand::LastSave => return None,
+        StorageCommand::Promote => return None,
+    };
+    Some(payload)
+}
+
+fn put_set_value(payload: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Int(i) => {
+            payload.push(b'I');
+            payload.extend_from_slice(&i.to_le_bytes());
+        }
+        Value::Blob(b) => {
+            payload.push(b'B');
+            put_blob(payload, b);
+        }
+        _ => {
+            panic!("unexpected value in transaction log; should only be able to SET ints or blobs");
+        }
+    }
+}
+
+fn payload(tag: u8, blobs: &[&Blob]) -> Vec<u8> {
+    let mut payload = vec![tag];
+    for blob in blobs {
+        put_blob(&mut payload, blob);
+    }
+    payload
+}
+
+pub fn put_blob(payload: &mut Vec<u8>, blob: &Blob) {
+    payload.extend_from_slice(&(blob.0.len() as u32).to_le_bytes());
+    payload.extend_from_slice(&blob.0);
+}
+
+/// Reads the records of a log, from just after its header.
+pub struct LogIterator<R: Read> {
+    reader: R,
+    offset: u64,
+}
+
+impl<R: Read> Iterator for LogIterator<R> {
+    type Item = StorageCommand;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_result() {
+            Err(e) => {
+                // replay goes through recover, which decides what to do about
+                // bad records; this just stops
+                tracing::error!(e=?e, offset = self.offset, "stopping log read on error");
+                None
+            }
+            Ok(cmd) => cmd,
+        }
+    }
+}
+
+impl<R: Read> LogIterator<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, offset: 0 }
+    }
+
+    /// How many bytes of whole records have been read, which is where the
+    /// next record starts, or where the one that couldn't be read started.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// next_result reads the next record from the log, or None at its end.
+    /// Running out of input partway through a record is reported as an
+    /// UnexpectedEof io error; a record whose checksum doesn't match, or which
+    /// can't be a valid record, is reported as corruption.
+    pub fn next_result(&mut self) -> Result<Option<StorageCommand>, TransactionLogError> {
+        Ok(self.next_record()?.map(|record| record.cmd))
+    }
+
+    /// Like next_result, but with the record's stamp as well as its command.
+    pub fn next_record(&mut self) -> Result<Option<Record>, TransactionLogError> {
+        let mut header = [0u8; 8];
+        if !read_record_start(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+        // the buffer grows as bytes are read, so a corrupted length can't
+        // trigger a huge allocation up front
+        let mut payload = Vec::new();
+        self.reader
+            .by_ref()
+            .take(len as u64)
+            .read_to_end(&mut payload)?;
+        if payload.len() < len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        if crc32c::crc32c(&payload) != crc {
+            return Err(TransactionLogError::Corrupted("checksum mismatch"));
+        }
+
+        let record = decode(&payload)?;
+        self.offset += (header.len() + len) as u64;
+        Ok(Some(record))
+    }
+}
+
+/// Fills the buffer with the start of the next record, returning false if
+/// the input has ended before it. Input which ends partway through the
+/// buffer is reported as an UnexpectedEof io error.
+pub fn read_record_start<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    match filled {
+        0 => Ok(false),
+        n if n == buf.len() => Ok(true),
+        _ => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)),
+    }
+}
+
+/// How long the record at the start of `bytes` is, if a whole, valid record
+/// starts there. It's cheap to rule out most places which aren't the start of
+/// a record, by their length and tag, so that's done before the checksum.
+pub fn valid_record_len(bytes: &[u8]) -> Option<usize> {
+    let len = u32::from_le_bytes(bytes.get(..4)?.try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(bytes.get(4..8)?.try_into().unwrap());
+    let payload = bytes.get(8..8usize.checked_add(len)?)?;
+    // the tag comes after the stamp
+    if !payload.get(16).is_some_and(|tag| b"IDXFSMAC".contains(tag)) {
+        return None;
+    }
+    if crc32c::crc32c(payload) != crc {
+        return None;
+    }
+    decode(payload).ok()?;
+    Some(8 + len)
+}
+
+fn decode(payload: &[u8]) -> Result<Record, TransactionLogError> {
+    let mut fields = Fields(payload);
+    let stamp = Stamp {
+        lsn: u64::from_le_bytes(fields.take(8)?.try_into().unwrap()),
+        timestamp: u64::from_le_bytes(fields.take(8)?.try_into().unwrap()),
+    };
+    let cmd = match fields.byte()? {
+        b'I' => StorageCommand::Incr(fields.blob()?),
+        b'D' => StorageCommand::Decr(fields.blob()?),
+        b'X' => StorageCommand::Delete(vec![fields.blob()?]),
+        b'F' => StorageCommand::FlushAll,
+        b'S' => StorageCommand::Set(fields.blob()?, take_set_value(&mut fields)?),
+        b'M' => {
+            let key = fields.blob()?;
+            let value = take_set_value(&mut fields)?;
+            let flags = u32::from_le_bytes(fields.take(4)?.try_into().unwrap());
+            StorageCommand::SetWithFlags(key, value, flags)
+        }
+        b'A' => StorageCommand::SetAdd(fields.blob()?, fields.blob()?),
+        b'C' => StorageCommand::SetRemove(fields.blob()?, fields.blob()?),
+        _ => return Err(TransactionLogError::Corrupted("unknown record tag")),
+    };
+
+    if !fields.0.is_empty() {
+        return Err(TransactionLogError::Corrupted("trailing bytes in record"));
+    }
+    Ok(Record { stamp, cmd })
+}
+
+fn take_set_value(fields: &mut Fields) -> Result<Value, TransactionLogError> {
+    match fields.byte()? {
+        b'I' => Ok(Value::Int(i64::from_le_bytes(
+            fields.take(8)?.try_into().unwrap(),
+        ))),
+        b'B' => Ok(Value::Blob(fields.blob()?)),
+        _ => Err(TransactionLogError::Corrupted("unknown value tag")),
+    }
+}
+
+/// The fields of a payload which are still to be read.
+pub struct Fields<'a>(pub &'a [u8]);
+
+impl<'a> Fields<'a> {
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], TransactionLogError> {
+        if self.0.len() < len {
+            return Err(TransactionLogError::Corrupted("record too short"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub fn byte(&mut self) -> Result<u8, TransactionLogError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn blob(&mut self) -> Result<Blob, TransactionLogError> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        Ok(Blob(self.take(len as usize)?.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_headers() {
+        let mut current = header().to_vec();
+        current.extend(b"records");
+        assert_eq!(Header::Current, read_header(&mut &current[..]).unwrap());
+        assert_eq!(Header::Empty, read_header(&mut &b""[..]).unwrap());
+        assert_eq!(Header::Empty, read_header(&mut &b"ANODE"[..]).unwrap());
+        assert_eq!(
+            Header::Legacy,
+            read_header(&mut &b"I\x01\0\0\0\0\0\0\0a"[..]).unwrap()
+        );
+
+        let mut future = LOG_MAGIC.to_vec();
+        future.extend(2u32.to_le_bytes());
+        assert!(matches!(
+            read_header(&mut &future[..]),
+            Err(TransactionLogError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn reports_checksum_mismatches_as_corruption() {
+        let mut log = vec![];
+        write_to_log(
+            &mut log,
+            Stamp::default(),
+            &StorageCommand::Incr("a".into()),
+        )
+        .unwrap();
+        let last = log.len() - 1;
+        log[last] = b'b';
+
+        let mut iter = LogIterator::new(&log[..]);
+        assert!(matches!(
+            iter.next_result(),
+            Err(TransactionLogError::Corrupted("checksum mismatch"))
+        ));
+    }
+
+    #[test]
+    fn reports_malformed_records_as_corruption() {
+        let mut log = vec![];
+        write_record(&mut log, Stamp::default(), b"?").unwrap();
+        write_record(&mut log, Stamp::default(), b"I\x05\0\0\0a").unwrap();
+        write_record(&mut log, Stamp::default(), b"I\x01\0\0\0ab").unwrap();
+
+        let mut iter = LogIterator::new(&log[..]);
+        for _ in 0..3 {
+            assert!(matches!(
+                iter.next_result(),
+                Err(TransactionLogError::Corrupted(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn reads_back_stamps() {
+        let stamp = Stamp {
+            lsn: 42,
+            timestamp: 1_700_000_000_000,
+        };
+        let mut log = vec![];
+        let del = StorageCommand::Delete(vec!["a".into(), "b".into()]);
+        assert_eq!(2, write_to_log(&mut log, stamp, &del).unwrap());
+
+        // every record of the delete has its LSN
+        let mut iter = LogIterator::new(&log[..]);
+        for key in ["a", "b"] {
+            let record = iter.next_record().unwrap().unwrap();
+            assert_eq!(stamp, record.stamp);
+            assert_eq!(StorageCommand::Delete(vec![key.into()]), record.cmd);
+        }
+    }
+
+    #[test]
+    fn huge_lengths_read_as_truncation() {
+        let mut truncated = u32::MAX.to_le_bytes().to_vec();
+        truncated.extend(0u32.to_le_bytes());
+        truncated.extend(b"abc");
+        let mut iter = LogIterator::new(&truncated[..]);
+        assert!(matches!(
+            iter.next_result(),
+            Err(TransactionLogError::Failed(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+    }
+}
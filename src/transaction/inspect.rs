@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+
+use thiserror::Error;
+
+use super::format::{header, read_header, Header, HEADER_LEN};
+use super::snapshot::{previous_snapshot_filename, read_snapshot_seq, snapshot_filename};
+use super::{recover, write_to_log, LogIterator, Record, RecoveryMode, RecoveryReport};
+use super::{Manifest, TransactionLogError};
+use crate::storage::StorageCommand;
+
+/// A log file read into memory, for inspecting or repairing it while the
+/// server isn't running. Only logs in the current format can be read: the
+/// server migrates older ones when it opens them.
+pub struct LogFile {
+    bytes: Vec<u8>,
+}
+
+/// A record in a log file, with where it starts and how many bytes it takes.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub offset: u64,
+    pub len: u64,
+    pub record: Record,
+}
+
+/// What's in a log file, counted by command and by key size.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct LogStats {
+    pub records: u64,
+    /// The first and last LSNs in the log, if it has any records.
+    pub lsns: Option<(u64, u64)>,
+    pub commands: BTreeMap<&'static str, u64>,
+    /// How many keys there are of each size, each counted under the smallest
+    /// power of two which is at least its length.
+    pub key_sizes: BTreeMap<usize, u64>,
+    /// Where the first record which can't be read starts, and why, if
+    /// there's one. Nothing from there on is counted.
+    pub unreadable: Option<(u64, String)>,
+}
+
+/// Why a log file wasn't cut short.
+#[derive(Error, Debug)]
+pub enum TruncateError {
+    #[error("no record starts at offset {0}")]
+    NotABoundary(u64),
+
+    #[error("segment {segment} isn't the last in its manifest, which goes up to segment {last}, so cutting it short would leave a gap in the log")]
+    NotLastSegment { segment: u64, last: u64 },
+
+    #[error("{path} includes records up to {next_seq}, but the log would end at {ends_at}; remove it first, since it would no longer match the log")]
+    AheadOfLog {
+        path: String,
+        next_seq: u64,
+        ends_at: u64,
+    },
+
+    #[error(transparent)]
+    Log(#[from] TransactionLogError),
+}
+
+impl LogFile {
+    pub fn open(path: &str) -> Result<Self, TransactionLogError> {
+        let bytes = std::fs::read(path)?;
+        match read_header(&mut &bytes[..])? {
+            Header::Current | Header::Empty => Ok(Self { bytes }),
+            Header::Legacy => Err(TransactionLogError::Corrupted("no log header")),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// The records in order, with offsets from the start of the file. Stops
+    /// after the first which can't be read, giving where it is and why.
+    pub fn entries(&self) -> impl Iterator<Item = Result<Entry, TransactionLogError>> + '_ {
+        let mut pos = self.records_start();
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed || pos == self.bytes.len() {
+                return None;
+            }
+            let mut iter = LogIterator::new(&self.bytes[pos..]);
+            let offset = pos as u64;
+            match iter.next_record() {
+                Ok(Some(record)) => {
+                    pos += iter.offset() as usize;
+                    Some(Ok(Entry {
+                        offset,
+                        len: iter.offset(),
+                        record,
+                    }))
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    failed = true;
+                    let reason = match e {
+                        TransactionLogError::Failed(e)
+                            if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                        {
+                            "record runs past the end of the log".to_string()
+                        }
+                        e => e.to_string(),
+                    };
+                    Some(Err(TransactionLogError::CorruptedAt { offset, reason }))
+                }
+            }
+        })
+    }
+
+    /// Checks every record's checksum and structure. Bad stretches are
+    /// skipped over to the next good record, so the report says about all of
+    /// them, and a bad tail is reported as where to truncate.
+    pub fn verify(&self) -> Result<RecoveryReport, TransactionLogError> {
+        let start = self.records_start();
+        let (_, report) = recover(
+            &self.bytes[start..],
+            start as u64,
+            RecoveryMode::SkipCorrupt,
+        )?;
+        Ok(report)
+    }
+
+    /// Counts the records up to the first which can't be read, and says
+    /// where that is.
+    pub fn stats(&self) -> LogStats {
+        let mut stats = LogStats::default();
+        for entry in self.entries() {
+            let Record { stamp, cmd } = match entry {
+                Ok(entry) => entry.record,
+                Err(TransactionLogError::CorruptedAt { offset, reason }) => {
+                    stats.unreadable = Some((offset, reason));
+                    break;
+                }
+                Err(e) => unreachable!("entries only fails with where: {}", e),
+            };
+            stats.records += 1;
+            stats.lsns = match stats.lsns {
+                Some((first, last)) => Some((first.min(stamp.lsn), last.max(stamp.lsn))),
+                None => Some((stamp.lsn, stamp.lsn)),
+            };
+            *stats.commands.entry(cmd.name()).or_default() += 1;
+            for key in cmd.keys() {
+                *stats
+                    .key_sizes
+                    .entry(key.0.len().next_power_of_two())
+                    .or_default() += 1;
+            }
+        }
+        stats
+    }
+
+    /// Whether a record starts at the offset, or the last one ends there, so
+    /// the log can be cut there without leaving part of a record behind.
+    pub fn is_boundary(&self, offset: u64) -> bool {
+        if offset == self.records_start() as u64 {
+            return true;
+        }
+        self.entries()
+            .map_while(Result::ok)
+            .any(|entry| entry.offset + entry.len == offset)
+    }
+
+    /// Writes the records the filter keeps to a new log at `path`, with their
+    /// stamps, returning how many were written. Stops at the first record
+    /// which can't be read, and fails if `path` already exists.
+    pub fn filter(
+        &self,
+        path: &str,
+        keep: impl Fn(&StorageCommand) -> bool,
+    ) -> Result<u64, TransactionLogError> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut out = BufWriter::new(file);
+        out.write_all(&header())?;
+        let mut written = 0;
+        for entry in self.entries() {
+            let record = entry?.record;
+            if keep(&record.cmd) {
+                written += write_to_log(&mut out, record.stamp, &record.cmd)?;
+            }
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(written)
+    }
+
+    fn records_start(&self) -> usize {
+        HEADER_LEN.min(self.bytes.len())
+    }
+}
+
+/// Cuts the log at `path` short at the offset, if that's where a record
+/// starts or the last readable one ends. If it's a segment of a log with a
+/// manifest, it has to be the last segment, and neither of the log's
+/// snapshots can include records past the new end, or the server couldn't
+/// use the log as it is afterwards. Returns whether there was a manifest to
+/// check against.
+pub fn truncate_log(path: &str, offset: u64) -> Result<bool, TruncateError> {
+    let log = LogFile::open(path)?;
+    if !log.is_boundary(offset) {
+        return Err(TruncateError::NotABoundary(offset));
+    }
+    let checked = match segment_of(path) {
+        Some((base, number)) => match Manifest::read(base)? {
+            Some(manifest) => {
+                check_truncation(&log, offset, base, number, &manifest)?;
+                true
+            }
+            None => false,
+        },
+        None => false,
+    };
+
+    let cut = || -> std::io::Result<()> {
+        let file = File::options().write(true).open(path)?;
+        file.set_len(offset)?;
+        file.sync_all()
+    };
+    cut().map_err(TransactionLogError::from)?;
+    Ok(checked)
+}
+
+fn check_truncation(
+    log: &LogFile,
+    offset: u64,
+    base: &str,
+    number: u64,
+    manifest: &Manifest,
+) -> Result<(), TruncateError> {
+    let last = manifest.active();
+    if number != last.number {
+        return Err(TruncateError::NotLastSegment {
+            segment: number,
+            last: last.number,
+        });
+    }
+    let kept = log
+        .entries()
+        .map_while(Result::ok)
+        .take_while(|entry| entry.offset < offset)
+        .count() as u64;
+    let ends_at = last.first_seq + kept;
+    for path in [snapshot_filename(base), previous_snapshot_filename(base)] {
+        match read_snapshot_seq(&path) {
+            Some(next_seq) if next_seq > ends_at => {
+                return Err(TruncateError::AheadOfLog {
+                    path,
+                    next_seq,
+                    ends_at,
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The base filepath and segment number of a segment's path, if it's named
+/// like one.
+fn segment_of(path: &str) -> Option<(&str, u64)> {
+    let (base, number) = path.rsplit_once(".segment")?;
+    Some((base, number.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Stamp;
+    use crate::types::Value;
+
+    fn write_log(name: &str, cmds: &[StorageCommand]) -> String {
+        let dir = format!(".tmp/inspect-test-{}", name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut log = header().to_vec();
+        for (lsn, cmd) in (1..).zip(cmds) {
+            let stamp = Stamp { lsn, timestamp: 0 };
+            write_to_log(&mut log, stamp, cmd).unwrap();
+        }
+        let path = format!("{}/log", dir);
+        std::fs::write(&path, log).unwrap();
+        path
+    }
+
+    fn cmds() -> Vec<StorageCommand> {
+        vec![
+            StorageCommand::Set("user:1".into(), Value::Blob("a".into())),
+            StorageCommand::Incr("hits".into()),
+            StorageCommand::SetAdd("user:2".into(), "x".into()),
+            StorageCommand::FlushAll,
+        ]
+    }
+
+    #[test]
+    fn it_reads_records_with_their_offsets() {
+        let path = write_log("entries", &cmds());
+        let log = LogFile::open(&path).unwrap();
+        let entries: Vec<Entry> = log.entries().map(Result::unwrap).collect();
+
+        assert_eq!(
+            cmds(),
+            entries
+                .iter()
+                .map(|e| e.record.cmd.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(HEADER_LEN as u64, entries[0].offset);
+        for pair in entries.windows(2) {
+            assert_eq!(pair[0].offset + pair[0].len, pair[1].offset);
+        }
+        let last = entries.last().unwrap();
+        assert_eq!(log.len(), last.offset + last.len);
+    }
+
+    #[test]
+    fn it_counts_commands_and_key_sizes() {
+        let path = write_log("stats", &cmds());
+        let stats = LogFile::open(&path).unwrap().stats();
+
+        assert_eq!(4, stats.records);
+        assert_eq!(Some((1, 4)), stats.lsns);
+        assert_eq!(Some(&1), stats.commands.get("set"));
+        assert_eq!(Some(&1), stats.commands.get("flushall"));
+        // "hits" is 4 bytes, and "user:1" and "user:2" are 6
+        assert_eq!(BTreeMap::from([(4, 1), (8, 2)]), stats.key_sizes);
+    }
+
+    #[test]
+    fn it_reports_and_truncates_a_torn_tail() {
+        let path = write_log("torn", &cmds());
+        let whole = std::fs::metadata(&path).unwrap().len();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend(&bytes[HEADER_LEN..HEADER_LEN + 10].to_vec());
+        std::fs::write(&path, bytes).unwrap();
+
+        let log = LogFile::open(&path).unwrap();
+        assert_eq!(Some(whole), log.verify().unwrap().truncate_at);
+        let stats = log.stats();
+        assert_eq!(4, stats.records);
+        assert_eq!(Some(whole), stats.unreadable.map(|(offset, _)| offset));
+        let last = log.entries().last().unwrap();
+        assert!(
+            matches!(last, Err(TransactionLogError::CorruptedAt { offset, .. }) if offset == whole)
+        );
+
+        assert!(matches!(
+            truncate_log(&path, whole - 1),
+            Err(TruncateError::NotABoundary(_))
+        ));
+        // there's no manifest to check it against
+        assert!(!truncate_log(&path, whole).unwrap());
+        let log = LogFile::open(&path).unwrap();
+        assert_eq!(None, log.verify().unwrap().truncate_at);
+        assert_eq!(4, log.entries().filter(Result::is_ok).count());
+    }
+
+    #[test]
+    fn it_filters_records_into_a_new_log() {
+        let path = write_log("filter", &cmds());
+        let filtered = format!("{}.filtered", path);
+        let log = LogFile::open(&path).unwrap();
+        let keep = |cmd: &StorageCommand| cmd.keys().iter().all(|key| key.0.starts_with(b"user:"));
+        assert_eq!(3, log.filter(&filtered, keep).unwrap());
+        // it won't overwrite what's there
+        assert!(log.filter(&filtered, keep).is_err());
+
+        let records: Vec<Record> = LogFile::open(&filtered)
+            .unwrap()
+            .entries()
+            .map(|entry| entry.unwrap().record)
+            .collect();
+        let lsns: Vec<u64> = records.iter().map(|r| r.stamp.lsn).collect();
+        assert_eq!(vec![1, 3, 4], lsns);
+    }
+}
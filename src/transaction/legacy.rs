@@ -0,0 +1,300 @@
+use std::io::Read;
+
+use super::format::read_record_start;
+use super::TransactionLogError;
+use crate::storage::StorageCommand;
+use crate::types::{Blob, Value};
+
+// Logs from before the format was versioned: no header and no checksums,
+// with lengths as native usizes. They're only read, to migrate them.
+
+pub struct LegacyLogIterator<R: Read> {
+    reader: Counted<R>,
+    offset: u64,
+}
+
+/// Counts the bytes read through it.
+struct Counted<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read> Iterator for LegacyLogIterator<R> {
+    type Item = StorageCommand;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_result() {
+            Err(e) => {
+                // migration reads with next_result, which decides what to do
+                // about bad records; this just stops
+                tracing::error!(e=?e, offset = self.offset, "stopping log read on error");
+                None
+            }
+            Ok(cmd) => cmd,
+        }
+    }
+}
+
+impl<R: Read> LegacyLogIterator<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: Counted {
+                inner: reader,
+                count: 0,
+            },
+            offset: 0,
+        }
+    }
+
+    /// How many bytes of whole records have been read, which is where the
+    /// next record starts, or where the one that couldn't be read started.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// next_result reads the next record from the log, or None at its end.
+    /// Running out of input partway through a record is reported as an
+    /// UnexpectedEof io error; anything that can't be a valid record is
+    /// reported as corruption.
+    pub fn next_result(&mut self) -> Result<Option<StorageCommand>, TransactionLogError> {
+        let cmd = self.read_record()?;
+        self.offset = self.reader.count;
+        Ok(cmd)
+    }
+
+    fn read_record(&mut self) -> Result<Option<StorageCommand>, TransactionLogError> {
+        let mut header: [u8; 9] = [0; 9];
+        if !read_record_start(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+
+        let tag = header[0];
+
+        let (key_len_bytes, _) = header[1..].split_at(std::mem::size_of::<usize>());
+        let key_len = usize::from_le_bytes(key_len_bytes.try_into().unwrap());
+
+        let key = self.read_blob(key_len)?;
+
+        match tag {
+            b'I' => Ok(Some(StorageCommand::Incr(key))),
+            b'D' => Ok(Some(StorageCommand::Decr(key))),
+            b'X' => Ok(Some(StorageCommand::Delete(vec![key]))),
+            b'F' => Ok(Some(StorageCommand::FlushAll)),
+            b'S' => Ok(Some(StorageCommand::Set(key, self.read_set_value()?))),
+            b'M' => {
+                let value = self.read_set_value()?;
+                let mut flags = [0; 4];
+                self.reader.read_exact(&mut flags[..])?;
+
+                Ok(Some(StorageCommand::SetWithFlags(
+                    key,
+                    value,
+                    u32::from_le_bytes(flags),
+                )))
+            }
+            b'A' => {
+                self.reader.read_exact(&mut header[1..])?;
+                let (len_bytes, _) = header[1..].split_at(std::mem::size_of::<usize>());
+                let value_len = usize::from_le_bytes(len_bytes.try_into().unwrap());
+                let value = self.read_blob(value_len)?;
+
+                Ok(Some(StorageCommand::SetAdd(key, value)))
+            }
+            b'C' => {
+                self.reader.read_exact(&mut header[1..])?;
+                let (len_bytes, _) = header[1..].split_at(std::mem::size_of::<usize>());
+                let value_len = usize::from_le_bytes(len_bytes.try_into().unwrap());
+                let value = self.read_blob(value_len)?;
+
+                Ok(Some(StorageCommand::SetRemove(key, value)))
+            }
+
+            _ => Err(TransactionLogError::Corrupted("unknown record tag")),
+        }
+    }
+
+    /// Reads the value of a Set, tagged with whether it's an int or a blob.
+    fn read_set_value(&mut self) -> Result<Value, TransactionLogError> {
+        let mut header: [u8; 9] = [0; 9];
+        self.reader.read_exact(&mut header[..])?;
+        let value_tag = header[0];
+
+        match value_tag {
+            b'I' => {
+                let (int_bytes, _) = header[1..].split_at(std::mem::size_of::<usize>());
+                let val = i64::from_le_bytes(int_bytes.try_into().unwrap());
+
+                Ok(Value::Int(val))
+            }
+            b'B' => {
+                let (len_bytes, _) = header[1..].split_at(std::mem::size_of::<usize>());
+                let value_len = usize::from_le_bytes(len_bytes.try_into().unwrap());
+
+                Ok(Value::Blob(self.read_blob(value_len)?))
+            }
+            _ => Err(TransactionLogError::Corrupted("unknown value tag")),
+        }
+    }
+
+    /// Reads a blob of the given length. The buffer grows as bytes are read,
+    /// so a corrupted length can't trigger a huge allocation up front.
+    fn read_blob(&mut self, len: usize) -> Result<Blob, TransactionLogError> {
+        let mut bytes: Vec<u8> = Vec::new();
+        self.reader
+            .by_ref()
+            .take(len as u64)
+            .read_to_end(&mut bytes)?;
+
+        if bytes.len() < len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok(Blob(bytes))
+    }
+}
+
+/// Writes a command the way logs were written before they had a format
+/// version, so there's something to test migration against.
+#[cfg(test)]
+pub fn write_to_legacy_log<W: std::io::Write>(
+    log: &mut W,
+    cmd: &StorageCommand,
+) -> Result<(), TransactionLogError> {
+    match cmd {
+        StorageCommand::Incr(key) => {
+            log.write_all(b"I")?;
+            log.write_all(&key.0.len().to_le_bytes()[..])?;
+            log.write_all(&key.0[..])?;
+        }
+        StorageCommand::Decr(key) => {
+            log.write_all(b"D")?;
+            log.write_all(&key.0.len().to_le_bytes()[..])?;
+            log.write_all(&key.0[..])?;
+        }
+        StorageCommand::Set(key, value) => {
+            log.write_all(b"S")?;
+            log.write_all(&key.0.len().to_le_bytes()[..])?;
+            log.write_all(&key.0[..])?;
+            write_set_value(log, value)?;
+        }
+        StorageCommand::SetWithFlags(key, value, flags) => {
+            log.write_all(b"M")?;
+            log.write_all(&key.0.len().to_le_bytes()[..])?;
+            log.write_all(&key.0[..])?;
+            write_set_value(log, value)?;
+            log.write_all(&flags.to_le_bytes()[..])?;
+        }
+        StorageCommand::SetAdd(key, value) => {
+            log.write_all(b"A")?;
+            log.write_all(&key.0.len().to_le_bytes()[..])?;
+            log.write_all(&key.0[..])?;
+            log.write_all(&value.0.len().to_le_bytes()[..])?;
+            log.write_all(&value.0[..])?;
+        }
+        StorageCommand::SetRemove(key, value) => {
+            log.write_all(b"C")?;
+            log.write_all(&key.0.len().to_le_bytes()[..])?;
+            log.write_all(&key.0[..])?;
+            log.write_all(&value.0.len().to_le_bytes()[..])?;
+            log.write_all(&value.0[..])?;
+        }
+        StorageCommand::Delete(keys) => {
+            for key in keys {
+                log.write_all(b"X")?;
+                log.write_all(&key.0.len().to_le_bytes()[..])?;
+                log.write_all(&key.0[..])?;
+            }
+        }
+        StorageCommand::FlushAll => {
+            log.write_all(b"F")?;
+            log.write_all(&0usize.to_le_bytes()[..])?;
+        }
+        // conditional writes are recorded as a plain Set or Delete once
+        // they apply
+        StorageCommand::SetIf(..) => {}
+        StorageCommand::DeleteIf(..) => {}
+        StorageCommand::SetIntersection(_) => {}
+        StorageCommand::SetUnion(_) => {}
+        StorageCommand::Get(_) => {}
+        StorageCommand::MultiGet(_) => {}
+        StorageCommand::SetMembers(_) => {}
+        StorageCommand::RewriteLog => {}
+        StorageCommand::Save => {}
+        StorageCommand::BgSave => {}
+        StorageCommand::LastSave => {}
+        StorageCommand::Promote => {}
+    };
+    Ok(())
+}
+
+/// Writes the value of a Set, tagged with whether it's an int or a blob.
+#[cfg(test)]
+fn write_set_value<W: std::io::Write>(
+    log: &mut W,
+    value: &Value,
+) -> Result<(), TransactionLogError> {
+    match value {
+        Value::Int(i) => {
+            log.write_all(b"I")?;
+            log.write_all(&i.to_le_bytes()[..])?;
+        }
+        Value::Blob(b) => {
+            log.write_all(b"B")?;
+            log.write_all(&b.0.len().to_le_bytes()[..])?;
+            log.write_all(&b.0[..])?;
+        }
+        _ => {
+            panic!("unexpected value in transaction log; should only be able to SET ints or blobs");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_corruption_instead_of_panicking() {
+        let mut corrupt = vec![b'?'];
+        corrupt.extend(1usize.to_le_bytes());
+        corrupt.push(b'a');
+        let mut iter = LegacyLogIterator::new(&corrupt[..]);
+        assert!(matches!(
+            iter.next_result(),
+            Err(TransactionLogError::Corrupted(_))
+        ));
+
+        let mut corrupt = vec![b'S'];
+        corrupt.extend(1usize.to_le_bytes());
+        corrupt.push(b'a');
+        corrupt.push(b'Z');
+        corrupt.extend(1usize.to_le_bytes());
+        let mut iter = LegacyLogIterator::new(&corrupt[..]);
+        assert!(matches!(
+            iter.next_result(),
+            Err(TransactionLogError::Corrupted(_))
+        ));
+        assert!(LegacyLogIterator::new(&corrupt[..]).next().is_none());
+    }
+
+    #[test]
+    fn huge_lengths_read_as_truncation() {
+        let mut truncated = vec![b'I'];
+        truncated.extend(usize::MAX.to_le_bytes());
+        truncated.extend(b"abc");
+        let mut iter = LegacyLogIterator::new(&truncated[..]);
+        assert!(matches!(
+            iter.next_result(),
+            Err(TransactionLogError::Failed(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+    }
+}
@@ -0,0 +1,194 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+/// Hands out log sequence numbers to the transaction workers, and keeps track
+/// of how far they're durable. Every shard's worker shares one, so LSNs put
+/// the mutations of every shard in a single order. An LSN is durable once
+/// every record with it or an earlier one has been fsynced, on whichever
+/// shard it was logged.
+#[derive(Clone)]
+pub struct Lsns {
+    inner: Arc<LsnsInner>,
+}
+
+struct LsnsInner {
+    state: Mutex<LsnState>,
+    durable: watch::Sender<u64>,
+}
+
+struct LsnState {
+    /// The last LSN handed out.
+    last: u64,
+    /// The first LSN each worker has written which hasn't been fsynced yet,
+    /// by the worker's slot.
+    unsynced: Vec<Option<u64>>,
+    /// The last LSN someone's waiting to be durable.
+    wanted: u64,
+}
+
+impl Default for Lsns {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(LsnsInner {
+                state: Mutex::new(LsnState {
+                    last: 0,
+                    unsynced: vec![],
+                    wanted: 0,
+                }),
+                durable: watch::Sender::new(0),
+            }),
+        }
+    }
+}
+
+impl Lsns {
+    /// Adds a worker whose log already goes up to `last`, so the LSNs handed
+    /// out from now on come after it. Returns the worker's slot.
+    pub fn join(&self, last: u64) -> usize {
+        let mut state = self.inner.state.lock().unwrap();
+        state.last = state.last.max(last);
+        state.unsynced.push(None);
+        self.update_durable(&state);
+        state.unsynced.len() - 1
+    }
+
+    /// Hands out `count` LSNs, in order, to the worker in the slot. Returns
+    /// the first of them.
+    pub fn assign(&self, slot: usize, count: u64) -> u64 {
+        let mut state = self.inner.state.lock().unwrap();
+        let first = state.last + 1;
+        state.last += count;
+        state.unsynced[slot].get_or_insert(first);
+        first
+    }
+
+    /// Marks everything the worker in the slot has written as fsynced.
+    pub fn synced(&self, slot: usize) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.unsynced[slot] = None;
+        self.update_durable(&state);
+    }
+
+    /// Says the LSN is wanted durable, so a worker which writes it or an
+    /// earlier one fsyncs afterwards, whatever its fsync policy. Returns the
+    /// slots of the workers which have written such LSNs already, since they
+    /// have to be asked to fsync.
+    pub fn want_durable(&self, lsn: u64) -> Vec<usize> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.wanted = state.wanted.max(lsn);
+        state
+            .unsynced
+            .iter()
+            .enumerate()
+            .filter(|(_, first)| first.is_some_and(|first| first <= lsn))
+            .map(|(slot, _)| slot)
+            .collect()
+    }
+
+    /// Whether the worker in the slot has written an LSN someone's waiting
+    /// for, and so should fsync.
+    pub fn sync_wanted(&self, slot: usize) -> bool {
+        let state = self.inner.state.lock().unwrap();
+        state.unsynced[slot].is_some_and(|first| first <= state.wanted)
+    }
+
+    /// The last LSN handed out.
+    pub fn current(&self) -> u64 {
+        self.inner.state.lock().unwrap().last
+    }
+
+    /// The last LSN which is durable.
+    pub fn durable(&self) -> u64 {
+        *self.inner.durable.borrow()
+    }
+
+    /// Waits until the LSN is durable, or until the timeout passes if there
+    /// is one. Returns the last durable LSN, which is before the one waited
+    /// for if it timed out.
+    pub async fn wait_durable(&self, lsn: u64, timeout: Option<Duration>) -> u64 {
+        let mut durable = self.inner.durable.subscribe();
+        let reached = durable.wait_for(|durable| *durable >= lsn);
+        match timeout {
+            Some(timeout) => drop(tokio::time::timeout(timeout, reached).await),
+            None => drop(reached.await),
+        }
+        self.durable()
+    }
+
+    /// Everything before the first LSN still waiting to be fsynced is
+    /// durable, and if nothing's waiting, everything handed out is.
+    fn update_durable(&self, state: &LsnState) {
+        let durable = match state.unsynced.iter().flatten().min() {
+            Some(first) => first - 1,
+            None => state.last,
+        };
+        self.inner.durable.send_if_modified(|current| {
+            let advanced = durable > *current;
+            *current = (*current).max(durable);
+            advanced
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_is_durable_up_to_the_first_unsynced_lsn() {
+        let lsns = Lsns::default();
+        let a = lsns.join(4);
+        let b = lsns.join(2);
+        assert_eq!(4, lsns.current());
+        assert_eq!(4, lsns.durable());
+
+        assert_eq!(5, lsns.assign(a, 2));
+        assert_eq!(7, lsns.assign(b, 1));
+        assert_eq!(8, lsns.assign(a, 1));
+        assert_eq!(8, lsns.current());
+
+        // b's record is fsynced, but a's first isn't, so nothing after it is
+        // durable either
+        lsns.synced(b);
+        assert_eq!(4, lsns.durable());
+        lsns.synced(a);
+        assert_eq!(8, lsns.durable());
+    }
+
+    #[tokio::test]
+    async fn it_waits_for_lsns_to_be_durable() {
+        let lsns = Lsns::default();
+        let slot = lsns.join(0);
+        lsns.assign(slot, 3);
+
+        let timeout = Some(Duration::from_millis(10));
+        assert_eq!(0, lsns.wait_durable(2, timeout).await);
+
+        let waiting = tokio::spawn({
+            let lsns = lsns.clone();
+            async move { lsns.wait_durable(2, None).await }
+        });
+        lsns.synced(slot);
+        assert_eq!(3, waiting.await.unwrap());
+    }
+
+    #[test]
+    fn it_says_who_has_to_fsync_for_a_wanted_lsn() {
+        let lsns = Lsns::default();
+        let a = lsns.join(0);
+        let b = lsns.join(0);
+        lsns.assign(a, 2);
+        lsns.assign(b, 1);
+
+        assert_eq!(vec![a], lsns.want_durable(2));
+        assert!(lsns.sync_wanted(a));
+        assert!(!lsns.sync_wanted(b));
+        // an LSN not handed out yet is synced once it's written
+        assert_eq!(vec![a, b], lsns.want_durable(5));
+        lsns.synced(b);
+        lsns.assign(b, 2);
+        assert!(lsns.sync_wanted(b));
+    }
+}
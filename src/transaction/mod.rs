@@ -1,284 +1,1363 @@
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Read, Write};
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::future::Future;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, MissedTickBehavior};
 
 use crate::config::Config;
-use crate::storage::StorageCommand;
-use crate::types::{Blob, Value};
+use crate::storage::{shard_for, StorageCommand};
+use crate::types::{ItemFlags, Keyspace};
+
+mod format;
+mod inspect;
+mod legacy;
+mod lsn;
+mod recovery;
+mod rewrite;
+mod segment;
+mod snapshot;
+use format::{header, read_header, skip_records, Header, HEADER_LEN};
+pub use format::{write_to_log, LogIterator, Record, Stamp, LOG_MAGIC, LOG_VERSION};
+pub use inspect::{truncate_log, Entry, LogFile, LogStats, TruncateError};
+use legacy::LegacyLogIterator;
+pub use lsn::Lsns;
+pub use recovery::{
+    recover, recover_with, RecoveryMode, RecoveryReport, RecoveryTarget, RecoveryTargetError,
+};
+pub use rewrite::write_snapshot;
+use rewrite::Rewrite;
+use segment::remove_orphans;
+pub use segment::{manifest_filename, segment_filename, Manifest, Segment, SegmentReader};
+pub use snapshot::{
+    previous_snapshot_filename, read_snapshot_file, snapshot_filename, unix_time, unix_time_ms,
+    write_snapshot_file, SaveRule, SaveRuleError, Snapshot,
+};
+use snapshot::{read_snapshot_lsn, read_snapshot_seq, remove_snapshots, Save};
 
 #[derive(Error, Debug)]
 pub enum TransactionLogError {
+    #[error("log corrupted: {0}")]
+    Corrupted(&'static str),
+
+    #[error("log corrupted at offset {offset}: {reason}")]
+    CorruptedAt { offset: u64, reason: String },
+
+    #[error("transaction log is not running")]
+    Stopped,
+
+    #[error("log format version {0} is not supported")]
+    UnsupportedVersion(u32),
+
+    #[error("a log rewrite is already in progress")]
+    RewriteInProgress,
+
+    #[error("a save is already in progress")]
+    SaveInProgress,
+
+    #[error("log starts at record {first_seq}, and no snapshot covers the records before it")]
+    MissingRecords { first_seq: u64 },
+
+    #[error("the log and snapshots don't go back as far as {0}")]
+    TargetNotInLog(RecoveryTarget),
+
+    #[error("the log has no records for {0} values")]
+    UnsupportedValue(&'static str),
+
     #[error("unknown reason: {0}")]
     Failed(#[from] std::io::Error),
 }
 
+/// How far a write has to get before it's acknowledged to the client.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum Durability {
+    /// Acknowledged once it's applied in memory, and logged afterwards. Log
+    /// failures are only reported in the server's own logs.
+    #[default]
+    Async,
+    /// Acknowledged once it's been written to the log.
+    Logged,
+    /// Acknowledged once it's been written to the log and fsynced.
+    Fsynced,
+}
+
+/// When the log is forced to disk, like Redis's appendfsync.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    /// Before anything written is acknowledged.
+    Always,
+    /// Once a second, if anything's been written since the last time.
+    #[default]
+    Everysec,
+    /// Only when asked to, leaving the rest to the OS.
+    No,
+}
+
 pub struct TransactionWorker {
     log: TransactionLog,
     recv_queue: TransactionRecvQueue,
+    policy: FsyncPolicy,
+    max_group_size: usize,
+    /// Whether anything's been written since the last fsync.
+    dirty: bool,
+    lsns: Lsns,
+    /// Which worker this is to the LSNs.
+    lsn_slot: usize,
+
+    rewrite: Option<Rewrite>,
+    /// Tells the shard it's time to rewrite the log, since the snapshot has
+    /// to come from the shard.
+    rewrite_due: Arc<Notify>,
+    rewrite_requested: bool,
+    rewrite_percentage: u64,
+    rewrite_min_size: u64,
+    /// The log's size after the last rewrite or removal of segments, or at
+    /// startup.
+    base_size: u64,
+
+    segment_size: u64,
+    segment_age: Option<Duration>,
+    segment_started: Instant,
+    retain_segments: usize,
+    retain_age: Duration,
+    /// The sequence numbers the newest snapshot and the one before go up
+    /// to. Segments are removed once both cover them, so either can be
+    /// recovered from.
+    snapshot_seqs: (Option<u64>, Option<u64>),
+
+    save: Option<Save>,
+    /// Tells the shard it's time to save a snapshot, by the save rules.
+    save_due: Arc<Notify>,
+    save_requested: bool,
+    save_rules: Vec<SaveRule>,
+    /// How many changes have been logged since the last save.
+    changes: u64,
+    /// When the last save finished, in seconds since the Unix epoch, or
+    /// when the server started if nothing's been saved since.
+    last_save: Arc<AtomicU64>,
+    /// Set once the worker has stopped because its log can't be trusted.
+    stopped: Arc<AtomicBool>,
 }
 
-pub type TransactionRecvQueue = mpsc::Receiver<(
-    Vec<StorageCommand>,
-    oneshot::Sender<Result<(), TransactionLogError>>,
-)>;
-pub type TransactionSendQueue = mpsc::Sender<(
-    Vec<StorageCommand>,
-    oneshot::Sender<Result<(), TransactionLogError>>,
-)>;
+/// LogRequest is what the transaction worker is asked to do. Requests are
+/// handled in order, so a Sync covers everything recorded before it.
+#[derive(Debug)]
+pub enum LogRequest {
+    Record(Vec<StorageCommand>),
+    /// Flushes everything recorded so far, and fsyncs it if asked to.
+    Sync {
+        fsync: bool,
+    },
+    /// Rewrites the log as the snapshot, which must reflect exactly the
+    /// records sent before this request, or which is to replace them all.
+    /// Answered once the rewrite starts, or with wait once it's finished.
+    Rewrite {
+        data: Keyspace,
+        flags: ItemFlags,
+        wait: bool,
+    },
+    /// Saves the data as a snapshot, which must reflect exactly the records
+    /// sent before this request. Answered once the save starts, or with wait
+    /// once it's finished.
+    Save {
+        data: Keyspace,
+        flags: ItemFlags,
+        wait: bool,
+    },
+}
+
+pub type LogResponder = oneshot::Sender<Result<(), TransactionLogError>>;
+pub type TransactionRecvQueue = mpsc::Receiver<(LogRequest, LogResponder)>;
+pub type TransactionSendQueue = mpsc::Sender<(LogRequest, LogResponder)>;
 
 impl TransactionWorker {
     pub fn new(recv_queue: TransactionRecvQueue, config: Config) -> Self {
+        let log =
+            TransactionLog::new(config.clone()).expect("creating transaction log shold not fail");
+        let base_size = log.size().unwrap_or(0);
+        let base = &config.storage_basepath;
+        let snapshot_seqs = (
+            read_snapshot_seq(&snapshot_filename(base)),
+            read_snapshot_seq(&previous_snapshot_filename(base)),
+        );
+        let lsns = Lsns::default();
+        let lsn_slot = lsns.join(log.last_stamp().lsn);
         TransactionWorker {
             recv_queue,
-            log: TransactionLog::new(config).expect("creating transaction log shold not fail"),
+            policy: config.appendfsync,
+            max_group_size: config.transaction_queue_size.max(1),
+            dirty: false,
+            lsns,
+            lsn_slot,
+            rewrite: None,
+            rewrite_due: Arc::new(Notify::new()),
+            rewrite_requested: false,
+            rewrite_percentage: config.log_rewrite_percentage,
+            rewrite_min_size: config.log_rewrite_min_size,
+            base_size,
+            segment_size: config.log_segment_size,
+            segment_age: (config.log_segment_secs > 0)
+                .then(|| Duration::from_secs(config.log_segment_secs)),
+            segment_started: Instant::now(),
+            retain_segments: config.log_retain_segments,
+            retain_age: Duration::from_secs(config.log_retain_secs),
+            snapshot_seqs,
+            save: None,
+            save_due: Arc::new(Notify::new()),
+            save_requested: false,
+            save_rules: config.save.clone(),
+            changes: 0,
+            last_save: Arc::new(AtomicU64::new(unix_time())),
+            stopped: Arc::new(AtomicBool::new(false)),
+            log,
+        }
+    }
+
+    /// Notified when the log has grown enough to be rewritten. The shard
+    /// should answer by sending a Rewrite.
+    pub fn rewrite_due(&self) -> Arc<Notify> {
+        self.rewrite_due.clone()
+    }
+
+    /// Notified when a save rule says it's time to save. The shard should
+    /// answer by sending a Save.
+    pub fn save_due(&self) -> Arc<Notify> {
+        self.save_due.clone()
+    }
+
+    /// When the last save finished, in seconds since the Unix epoch.
+    pub fn last_save(&self) -> Arc<AtomicU64> {
+        self.last_save.clone()
+    }
+
+    /// Whether the worker has stopped because a group whose fsync failed
+    /// couldn't be cut off the log again. Nothing more goes in the log after
+    /// that, so the shard should refuse writes.
+    pub fn stopped(&self) -> Arc<AtomicBool> {
+        self.stopped.clone()
+    }
+
+    /// Takes LSNs from the ones given, which other shards' workers share,
+    /// rather than from its own.
+    pub fn share_lsns(&mut self, lsns: Lsns) {
+        self.lsn_slot = lsns.join(self.log.last_stamp().lsn);
+        self.lsns = lsns;
+    }
+
+    /// Handles requests until stop completes. After that, no new requests
+    /// are accepted but those already queued are still handled, and a
+    /// rewrite or save under way is seen through.
+    pub async fn run(&mut self, stop: impl Future<Output = ()>) {
+        tokio::pin!(stop);
+        let mut stopping = false;
+        let mut everysec = tokio::time::interval(Duration::from_secs(1));
+        everysec.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut housekeeping = tokio::time::interval(Duration::from_secs(1));
+        housekeeping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut group = Vec::with_capacity(self.max_group_size);
+
+        loop {
+            tokio::select! {
+                received = self.recv_queue.recv_many(&mut group, self.max_group_size) => {
+                    if received == 0 {
+                        break;
+                    }
+                    if let Err(e) = self.commit(group.drain(..)) {
+                        // the log may still replay writes whose clients
+                        // heard they failed, so nothing more goes in it
+                        tracing::error!(e=?e, "could not undo a group whose fsync failed; stopping the log");
+                        self.stopped.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                    self.check_segment();
+                    self.check_growth();
+                }
+                _ = everysec.tick(), if self.policy == FsyncPolicy::Everysec && self.dirty => {
+                    if let Err(e) = self.fsync() {
+                        tracing::error!(e=?e, "periodic fsync of the log failed");
+                    }
+                }
+                _ = housekeeping.tick() => {
+                    self.check_segment();
+                    self.check_save_rules();
+                }
+                written = finished(self.rewrite.as_mut().map(|r| &mut r.task)) => {
+                    self.finish_rewrite(written);
+                }
+                saved = finished(self.save.as_mut().map(|s| &mut s.task)) => {
+                    self.finish_save(saved);
+                }
+                _ = &mut stop, if !stopping => {
+                    stopping = true;
+                    self.recv_queue.close();
+                }
+            }
+        }
+
+        if self.rewrite.is_some() {
+            let written = finished(self.rewrite.as_mut().map(|r| &mut r.task)).await;
+            self.finish_rewrite(written);
+        }
+        if self.save.is_some() {
+            let saved = finished(self.save.as_mut().map(|s| &mut s.task)).await;
+            self.finish_save(saved);
         }
     }
 
-    pub async fn run(&mut self) {
-        while let Some((cmds, tx)) = self.recv_queue.recv().await {
-            let response = self.handle_transaction(cmds).await;
+    /// Group commit: handles every request that was queued together, writing
+    /// them all and then fsyncing at most once, before answering any of them.
+    /// Each command recorded is a mutation, and gets the next LSN. LSNs are
+    /// handed out before the records are written, so a record which fails to
+    /// be written leaves a gap in them.
+    ///
+    /// If the fsync fails, the group's records are cut off the log again
+    /// before their writers hear they failed, so they aren't replayed after
+    /// a restart. If that fails too, the error is returned and the log has
+    /// to stop.
+    fn commit(
+        &mut self,
+        group: impl Iterator<Item = (LogRequest, LogResponder)>,
+    ) -> Result<(), TransactionLogError> {
+        let mut fsync = self.policy == FsyncPolicy::Always;
+        let end = self.log.end();
+        let buffered = self.rewrite.as_ref().map(|r| r.buffer.len());
+        let mut written = vec![];
+        for (request, tx) in group {
+            let result = match request {
+                LogRequest::Record(cmds) => {
+                    self.dirty = true;
+                    self.changes += cmds.len() as u64;
+                    let first = self.lsns.assign(self.lsn_slot, cmds.len() as u64);
+                    let timestamp = unix_time_ms();
+                    let stamped: Vec<(Stamp, StorageCommand)> = (first..)
+                        .map(|lsn| Stamp { lsn, timestamp })
+                        .zip(cmds)
+                        .collect();
+                    let result = self.log.record_batch(&stamped[..]);
+                    if let (Ok(()), Some(rewrite)) = (&result, &mut self.rewrite) {
+                        // the new log needs these too, after the snapshot
+                        for (stamp, cmd) in &stamped {
+                            write_to_log(&mut rewrite.buffer, *stamp, cmd)
+                                .expect("writing to memory can't fail");
+                        }
+                    }
+                    result
+                }
+                LogRequest::Sync { fsync: wanted } => {
+                    fsync |= wanted;
+                    Ok(())
+                }
+                LogRequest::Rewrite { data, flags, wait } => {
+                    match self.start_rewrite(data, flags) {
+                        Ok(()) if wait => {
+                            self.rewrite
+                                .as_mut()
+                                .expect("the rewrite just started")
+                                .waiter = Some(tx);
+                            continue;
+                        }
+                        result => result,
+                    }
+                }
+                LogRequest::Save { data, flags, wait } => match self.start_save(data, flags) {
+                    Ok(()) if wait => {
+                        self.save.as_mut().expect("the save just started").waiter = Some(tx);
+                        continue;
+                    }
+                    result => result,
+                },
+            };
+            written.push((result, tx));
+        }
 
+        // someone waiting on an LSN this worker has is owed an fsync too
+        fsync |= self.lsns.sync_wanted(self.lsn_slot);
+        let synced = match fsync {
+            true => self.fsync(),
+            false => self.log.sync(false),
+        };
+        let undone = match &synced {
+            Ok(()) => Ok(()),
+            Err(_) => self.undo_group(end, buffered),
+        };
+        for (result, tx) in written {
+            let response = result.and_then(|_| match &synced {
+                Ok(()) => Ok(()),
+                Err(e) => Err(e.duplicate()),
+            });
             if tx.send(response).is_err() {
                 tracing::debug!("could not return value to requester; presuming they did not want a value returned");
             }
         }
+        undone
     }
 
-    async fn handle_transaction(
-        &self,
-        cmds: Vec<StorageCommand>,
+    /// Cuts a group's records off the log, and out of a rewrite's buffer if
+    /// one's running, leaving both as they were before it.
+    fn undo_group(
+        &mut self,
+        end: Result<LogEnd, TransactionLogError>,
+        buffered: Option<usize>,
     ) -> Result<(), TransactionLogError> {
-        self.log.record_batch(&cmds[..])?;
+        let end = end?;
+        self.log.truncate_to(end)?;
+        if let Some(rewrite) = &mut self.rewrite {
+            match buffered {
+                Some(len) => rewrite.buffer.truncate(len),
+                // it started part way through the group, after records
+                // which are gone now
+                None => {
+                    rewrite.buffer.clear();
+                    rewrite.seq = end.next_seq;
+                }
+            }
+        }
+        tracing::warn!(
+            path = self.log.path(),
+            len = end.len,
+            "fsync failed; cut the log back to before the writes it failed"
+        );
         Ok(())
     }
+
+    /// Asks for a rewrite once the log has grown by the configured percentage
+    /// since the last one, and is at least the minimum size.
+    fn check_growth(&mut self) {
+        if self.rewrite_percentage == 0
+            || self.rewrite.is_some()
+            || self.rewrite_requested
+            || self.save.is_some()
+        {
+            return;
+        }
+        let size = match self.log.size() {
+            Ok(size) => size,
+            Err(e) => {
+                tracing::error!(e=?e, "could not check the size of the log");
+                return;
+            }
+        };
+        let threshold = self.base_size + self.base_size * self.rewrite_percentage / 100;
+        if size >= self.rewrite_min_size && size > threshold {
+            tracing::info!(
+                size,
+                base_size = self.base_size,
+                "log has grown; rewriting it"
+            );
+            self.rewrite_requested = true;
+            self.rewrite_due.notify_one();
+        }
+    }
+
+    fn start_rewrite(
+        &mut self,
+        data: Keyspace,
+        flags: ItemFlags,
+    ) -> Result<(), TransactionLogError> {
+        if self.rewrite.is_some() {
+            return Err(TransactionLogError::RewriteInProgress);
+        }
+        if self.save.is_some() {
+            return Err(TransactionLogError::SaveInProgress);
+        }
+        self.rewrite_requested = false;
+        let segment = self.log.next_segment();
+        let path = segment_filename(&self.log.base_path(), segment);
+        self.rewrite = Some(Rewrite::start(
+            segment,
+            path,
+            self.log.next_seq(),
+            self.log.last_stamp(),
+            data,
+            flags,
+        ));
+        Ok(())
+    }
+
+    /// Once the snapshot's written, appends what was logged meanwhile and
+    /// swaps the new segment in for the old ones. Nothing else is written to
+    /// the log while this runs, so nothing can be missed. The snapshot's
+    /// records are numbered so the ones logged meanwhile keep their sequence
+    /// numbers.
+    fn finish_rewrite(&mut self, written: Result<u64, TransactionLogError>) {
+        let rewrite = self.rewrite.take().expect("a rewrite was running");
+        let swapped = written.and_then(|records| {
+            let mut new_log = OpenOptions::new().append(true).open(&rewrite.path)?;
+            new_log.write_all(&rewrite.buffer)?;
+            new_log.sync_data()?;
+            // snapshots name records of the old segments, which the new one
+            // numbers differently, so they go first
+            remove_snapshots(&self.log.base_path())?;
+            self.snapshot_seqs = (None, None);
+            let first_seq = rewrite.seq.saturating_sub(records);
+            self.log.replace(rewrite.segment, new_log, first_seq)
+        });
+
+        let result = match swapped {
+            Ok(size) => {
+                tracing::info!(path = self.log.path(), size, "rewrote log");
+                self.base_size = size;
+                self.segment_started = Instant::now();
+                // the new segment was fsynced with everything in it
+                self.mark_synced();
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!(e=?e, "log rewrite failed; keeping the old log");
+                let _ = std::fs::remove_file(&rewrite.path);
+                Err(e)
+            }
+        };
+        if let Some(waiter) = rewrite.waiter {
+            let _ = waiter.send(result);
+        }
+    }
+
+    /// Saves the rules say are due, unless something's already running in
+    /// the background.
+    fn check_save_rules(&mut self) {
+        if self.save.is_some() || self.rewrite.is_some() || self.save_requested {
+            return;
+        }
+        let elapsed = unix_time().saturating_sub(self.last_save.load(Ordering::Relaxed));
+        let due = self
+            .save_rules
+            .iter()
+            .any(|rule| elapsed >= rule.seconds && self.changes >= rule.changes);
+        if due {
+            tracing::info!(changes = self.changes, elapsed, "saving by save rule");
+            self.save_requested = true;
+            self.save_due.notify_one();
+        }
+    }
+
+    /// Starts saving the data as of the end of the log. The log is fsynced
+    /// first, so the log on disk always reaches as far as the snapshot says.
+    fn start_save(&mut self, data: Keyspace, flags: ItemFlags) -> Result<(), TransactionLogError> {
+        if self.save.is_some() {
+            return Err(TransactionLogError::SaveInProgress);
+        }
+        if self.rewrite.is_some() {
+            return Err(TransactionLogError::RewriteInProgress);
+        }
+        self.fsync()?;
+        self.save_requested = false;
+        let snapshot = Snapshot {
+            saved_at: unix_time_ms(),
+            next_seq: self.log.next_seq(),
+            lsn: self.log.last_stamp().lsn,
+            data,
+            flags,
+        };
+        self.save = Some(Save::start(&self.log.base_path(), snapshot, self.changes));
+        Ok(())
+    }
+
+    fn finish_save(&mut self, saved: Result<(), TransactionLogError>) {
+        let save = self.save.take().expect("a save was running");
+        match &saved {
+            Ok(()) => {
+                tracing::info!(path = self.log.path(), "saved snapshot");
+                self.changes -= save.changes;
+                self.last_save.store(save.saved_at, Ordering::Relaxed);
+                self.snapshot_seqs = (Some(save.next_seq), self.snapshot_seqs.0);
+                self.remove_covered_segments();
+            }
+            Err(e) => tracing::error!(e=?e, "save failed"),
+        }
+        if let Some(waiter) = save.waiter {
+            let _ = waiter.send(saved);
+        }
+    }
+
+    /// Starts a new segment once the one being written to is too big or too
+    /// old. Not while a rewrite's running, since that's writing what will be
+    /// the next segment.
+    fn check_segment(&mut self) {
+        if self.rewrite.is_some() {
+            return;
+        }
+        let size = match self.log.active_size() {
+            Ok(size) => size,
+            Err(e) => {
+                tracing::error!(e=?e, "could not check the size of the log segment");
+                return;
+            }
+        };
+        let full = self.segment_size > 0 && size >= self.segment_size;
+        let old = self
+            .segment_age
+            .is_some_and(|age| self.segment_started.elapsed() >= age);
+        if !full && !old {
+            return;
+        }
+
+        // an old segment with nothing in it can carry on as it is
+        if full || size > HEADER_LEN as u64 {
+            if let Err(e) = self.log.roll() {
+                tracing::error!(e=?e, "could not start a new log segment");
+                return;
+            }
+            self.mark_synced();
+        }
+        self.segment_started = Instant::now();
+    }
+
+    /// Removes the segments both snapshots cover, as far as the retention
+    /// settings allow.
+    fn remove_covered_segments(&mut self) {
+        let (Some(newest), Some(previous)) = self.snapshot_seqs else {
+            return;
+        };
+        let covered = newest.min(previous);
+        match self
+            .log
+            .remove_segments_before(covered, self.retain_segments, self.retain_age)
+        {
+            Ok(0) => {}
+            Ok(_) => self.base_size = self.log.size().unwrap_or(self.base_size),
+            Err(e) => tracing::error!(e=?e, "could not remove covered log segments"),
+        }
+    }
+
+    fn fsync(&mut self) -> Result<(), TransactionLogError> {
+        self.log.sync(true)?;
+        self.mark_synced();
+        Ok(())
+    }
+
+    /// Everything written so far has reached the disk, so its LSNs are
+    /// durable.
+    fn mark_synced(&mut self) {
+        self.dirty = false;
+        self.lsns.synced(self.lsn_slot);
+    }
 }
 
+/// Waits for a background task, if there is one, to finish.
+async fn finished<T>(
+    task: Option<&mut JoinHandle<Result<T, TransactionLogError>>>,
+) -> Result<T, TransactionLogError> {
+    match task {
+        Some(task) => match task.await {
+            Ok(written) => written,
+            Err(e) => Err(std::io::Error::other(e).into()),
+        },
+        None => std::future::pending().await,
+    }
+}
+
+impl TransactionLogError {
+    /// A copy of the error for each of the requests it failed, since io
+    /// errors can't be cloned.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            TransactionLogError::Corrupted(reason) => TransactionLogError::Corrupted(reason),
+            TransactionLogError::CorruptedAt { offset, reason } => {
+                TransactionLogError::CorruptedAt {
+                    offset: *offset,
+                    reason: reason.clone(),
+                }
+            }
+            TransactionLogError::Stopped => TransactionLogError::Stopped,
+            TransactionLogError::UnsupportedVersion(v) => {
+                TransactionLogError::UnsupportedVersion(*v)
+            }
+            TransactionLogError::RewriteInProgress => TransactionLogError::RewriteInProgress,
+            TransactionLogError::SaveInProgress => TransactionLogError::SaveInProgress,
+            TransactionLogError::MissingRecords { first_seq } => {
+                TransactionLogError::MissingRecords {
+                    first_seq: *first_seq,
+                }
+            }
+            TransactionLogError::TargetNotInLog(target) => {
+                TransactionLogError::TargetNotInLog(*target)
+            }
+            TransactionLogError::UnsupportedValue(kind) => {
+                TransactionLogError::UnsupportedValue(kind)
+            }
+            TransactionLogError::Failed(e) => {
+                TransactionLogError::Failed(std::io::Error::new(e.kind(), e.to_string()))
+            }
+        }
+    }
+}
+
+/// Where a log ended at some point: its active segment's length, and the
+/// sequence number and stamp that went with it.
+#[derive(Clone, Copy, Debug)]
+pub struct LogEnd {
+    len: u64,
+    next_seq: u64,
+    last_stamp: Stamp,
+}
+
+/// A log, split into segments. Records are only ever appended, to the last
+/// segment; a new segment is started once that one's big enough, and old
+/// segments are removed once snapshots cover them.
 pub struct TransactionLog {
     config: Config,
     current_log: Arc<Mutex<File>>,
+    manifest: Mutex<Manifest>,
+    /// The sequence number the next record gets.
+    next_seq: AtomicU64,
+    /// The stamp of the last record logged.
+    last_stamp: Mutex<Stamp>,
 }
 
 impl TransactionLog {
     pub fn new(config: Config) -> Result<Self, TransactionLogError> {
-        let log_filename = current_log_filename(&config.storage_basepath);
-        let current_log = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .append(true)
-            .open(&log_filename)?;
-        let current_log = Arc::new(Mutex::new(current_log));
+        let base = &config.storage_basepath;
+        let manifest = open_manifest(base, config.log_recovery)?;
+        let active = segment_filename(base, manifest.active().number);
+        let current_log = open_log(&active, config.log_recovery)?;
+        let (count, last) = count_records(&active)?;
+        let next_seq = manifest.active().first_seq + count;
+        let last_stamp = match last {
+            Some(stamp) => stamp,
+            None => last_stamp_before(base, &manifest)?,
+        };
 
         Ok(Self {
             config,
-            current_log,
+            current_log: Arc::new(Mutex::new(current_log)),
+            manifest: Mutex::new(manifest),
+            next_seq: AtomicU64::new(next_seq),
+            last_stamp: Mutex::new(last_stamp),
         })
     }
 
-    pub fn record(&self, cmd: &StorageCommand) -> Result<(), TransactionLogError> {
+    /// The segment being written to.
+    pub fn path(&self) -> String {
+        let active = self.manifest.lock().unwrap().active();
+        segment_filename(&self.config.storage_basepath, active.number)
+    }
+
+    /// The base filepath the log's segments and snapshots are named from.
+    pub fn base_path(&self) -> String {
+        self.config.storage_basepath.clone()
+    }
+
+    /// The sequence number the next record gets.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq.load(Ordering::Relaxed)
+    }
+
+    /// The stamp of the last record logged, which has the LSN of the last
+    /// mutation in the log.
+    pub fn last_stamp(&self) -> Stamp {
+        *self.last_stamp.lock().unwrap()
+    }
+
+    /// The sequence number of the oldest record still in the log.
+    pub fn first_seq(&self) -> u64 {
+        self.manifest.lock().unwrap().first_seq()
+    }
+
+    /// The number the next segment gets.
+    pub fn next_segment(&self) -> u64 {
+        self.manifest.lock().unwrap().next_number()
+    }
+
+    /// How many bytes are in the log, across every segment.
+    pub fn size(&self) -> Result<u64, TransactionLogError> {
+        let manifest = self.manifest.lock().unwrap();
+        let mut size = 0;
+        for segment in &manifest.segments {
+            let path = segment_filename(&self.config.storage_basepath, segment.number);
+            size += std::fs::metadata(path)?.len();
+        }
+        Ok(size)
+    }
+
+    /// How many bytes are in the segment being written to.
+    pub fn active_size(&self) -> Result<u64, TransactionLogError> {
+        Ok(self.current_log.lock().unwrap().metadata()?.len())
+    }
+
+    /// Starts a new segment and appends to it from then on. The segment
+    /// being written to is fsynced first, since nothing will fsync it later.
+    pub fn roll(&self) -> Result<(), TransactionLogError> {
         let mut log = self.current_log.lock().unwrap();
-        self.write_to_log(&mut log, cmd)?;
+        let mut manifest = self.manifest.lock().unwrap();
+        log.sync_data()?;
+
+        let base = &self.config.storage_basepath;
+        let segment = Segment {
+            number: manifest.next_number(),
+            first_seq: self.next_seq(),
+        };
+        let new_log = open_log(
+            &segment_filename(base, segment.number),
+            self.config.log_recovery,
+        )?;
+        let mut rolled = manifest.clone();
+        rolled.segments.push(segment);
+        rolled.write(base)?;
+
+        *manifest = rolled;
+        *log = new_log;
+        tracing::info!(
+            path = segment_filename(base, segment.number),
+            first_seq = segment.first_seq,
+            "started log segment"
+        );
         Ok(())
     }
 
-    pub fn record_batch(&self, cmds: &[StorageCommand]) -> Result<(), TransactionLogError> {
+    /// Replaces every segment with a new one, whose first record has the
+    /// given sequence number, and appends to it from then on. Switching
+    /// manifests is atomic, so the log on disk is always either the old
+    /// segments or the new one. Returns the log's new size.
+    fn replace(
+        &self,
+        number: u64,
+        new_log: File,
+        first_seq: u64,
+    ) -> Result<u64, TransactionLogError> {
+        let mut log = self.current_log.lock().unwrap();
+        let mut manifest = self.manifest.lock().unwrap();
+        let base = &self.config.storage_basepath;
+        let replacement = Manifest {
+            segments: vec![Segment { number, first_seq }],
+        };
+        replacement.write(base)?;
+
+        let old = std::mem::replace(&mut *manifest, replacement);
+        let size = new_log.metadata()?.len();
+        *log = new_log;
+        remove_segments(base, &old.segments);
+        Ok(size)
+    }
+
+    /// Removes the oldest segments whose records all come before seq, apart
+    /// from the newest `keep` of them and any written to more recently than
+    /// `min_age` ago. The segment being written to is never removed. Returns
+    /// how many were removed.
+    pub fn remove_segments_before(
+        &self,
+        seq: u64,
+        keep: usize,
+        min_age: Duration,
+    ) -> Result<usize, TransactionLogError> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let base = &self.config.storage_basepath;
+
+        // a segment is covered once the one after it starts at or before seq
+        let covered = manifest
+            .segments
+            .windows(2)
+            .take_while(|pair| pair[1].first_seq <= seq)
+            .count();
+        let removable = manifest.segments[..covered.saturating_sub(keep)]
+            .iter()
+            .take_while(|segment| last_written(base, segment).is_some_and(|age| age >= min_age))
+            .count();
+        if removable == 0 {
+            return Ok(0);
+        }
+
+        let mut retained = manifest.clone();
+        let removed: Vec<Segment> = retained.segments.drain(..removable).collect();
+        retained.write(base)?;
+        *manifest = retained;
+        remove_segments(base, &removed);
+        tracing::info!(
+            base,
+            removed = removable,
+            first_seq = manifest.first_seq(),
+            "removed log segments covered by snapshots"
+        );
+        Ok(removable)
+    }
+
+    pub fn record(&self, stamp: Stamp, cmd: &StorageCommand) -> Result<(), TransactionLogError> {
+        self.record_batch(&[(stamp, cmd.clone())])
+    }
+
+    pub fn record_batch(
+        &self,
+        cmds: &[(Stamp, StorageCommand)],
+    ) -> Result<(), TransactionLogError> {
         let mut log = self.current_log.lock().unwrap();
-        for cmd in cmds {
-            self.write_to_log(&mut log, cmd)?;
+        let len = log.metadata()?.len();
+        let mut written = 0;
+        for (stamp, cmd) in cmds {
+            match write_to_log(&mut *log, *stamp, cmd) {
+                Ok(records) => written += records,
+                Err(e) => {
+                    // the whole batch fails, so none of it stays behind, and
+                    // what comes next doesn't follow part of a record
+                    log.set_len(len)?;
+                    return Err(e);
+                }
+            }
+        }
+        self.next_seq.fetch_add(written, Ordering::Relaxed);
+        if let Some((stamp, _)) = cmds.last() {
+            *self.last_stamp.lock().unwrap() = *stamp;
         }
         Ok(())
     }
 
-    // TODO: compaction.
-    //fn compact(&self) -> Result<(), TransactionLogError>;
-    // TODO: force to disk
-    //fn fsync(&self) -> Result<(), TransactionLogError>;
+    /// Where the log ends now, to cut it back to with truncate_to.
+    pub fn end(&self) -> Result<LogEnd, TransactionLogError> {
+        let log = self.current_log.lock().unwrap();
+        Ok(LogEnd {
+            len: log.metadata()?.len(),
+            next_seq: self.next_seq(),
+            last_stamp: self.last_stamp(),
+        })
+    }
 
-    pub fn read(&self) -> Result<LogIterator, TransactionLogError> {
-        // acquire the lock for the write log to ensure that there are no writes
-        // while we read the log.
-        let write_lock = self.current_log.lock().unwrap();
+    /// Cuts off everything written to the segment being written to since
+    /// `end`, and fsyncs that, so none of it is replayed.
+    pub fn truncate_to(&self, end: LogEnd) -> Result<(), TransactionLogError> {
+        let log = self.current_log.lock().unwrap();
+        log.set_len(end.len)?;
+        log.sync_data()?;
+        self.next_seq.store(end.next_seq, Ordering::Relaxed);
+        *self.last_stamp.lock().unwrap() = end.last_stamp;
+        Ok(())
+    }
 
-        let log_filename = current_log_filename(&self.config.storage_basepath);
-        let read_log = OpenOptions::new().read(true).open(&log_filename)?;
+    /// Flushes anything written so far to the OS and, with fsync, forces it
+    /// to disk.
+    pub fn sync(&self, fsync: bool) -> Result<(), TransactionLogError> {
+        let mut log = self.current_log.lock().unwrap();
+        log.flush()?;
+        if fsync {
+            // the log is only appended to, so its data and length are all
+            // that need to reach the disk
+            log.sync_data()?;
+        }
+        Ok(())
+    }
 
-        let reader = BufReader::new(read_log);
+    /// Reads the records of every segment in turn.
+    pub fn read(&self) -> Result<SegmentReader, TransactionLogError> {
+        // acquire the lock for the write log to ensure that there are no writes
+        // while we read the list of segments.
+        let write_lock = self.current_log.lock().unwrap();
+        let reader = SegmentReader::new(
+            &self.config.storage_basepath,
+            &self.manifest.lock().unwrap(),
+        );
 
         // explicitly drop it so that it isn't released early
         drop(write_lock);
 
-        Ok(LogIterator { reader })
+        Ok(reader)
     }
 
-    #[tracing::instrument(skip(self, log), level = "trace")]
-    fn write_to_log(
-        &self,
-        log: &mut MutexGuard<File>,
-        cmd: &StorageCommand,
-    ) -> Result<(), TransactionLogError> {
-        match cmd {
-            StorageCommand::Incr(key) => {
-                log.write_all(b"I")?;
-                log.write_all(&key.0.len().to_le_bytes()[..])?;
-                log.write_all(&key.0[..])?;
+    /// The newest snapshot which can be read and which fits the log, if
+    /// there is one. One which doesn't is logged and passed over, since the
+    /// log can be replayed without it as long as nothing's been removed. So
+    /// is one which goes past the target, if there is one.
+    pub fn load_snapshot(&self, until: Option<RecoveryTarget>) -> Option<Snapshot> {
+        let seqs = self.first_seq()..=self.next_seq();
+        let base = &self.config.storage_basepath;
+        for path in [snapshot_filename(base), previous_snapshot_filename(base)] {
+            let bytes = match std::fs::read(&path) {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    tracing::error!(path, e=?e, "could not read snapshot");
+                    continue;
+                }
+            };
+            match read_snapshot_file(&bytes) {
+                Ok(snapshot) if until.is_some_and(|until| !until.includes_snapshot(&snapshot)) => {
+                    tracing::info!(
+                        path,
+                        lsn = snapshot.lsn,
+                        "snapshot is past the recovery target; ignoring it"
+                    )
+                }
+                Ok(snapshot) if seqs.contains(&snapshot.next_seq) => {
+                    tracing::info!(path, keys = snapshot.data.len(), "loaded snapshot");
+                    return Some(snapshot);
+                }
+                Ok(snapshot) => tracing::error!(
+                    path,
+                    next_seq = snapshot.next_seq,
+                    first_seq = seqs.start(),
+                    log_next_seq = seqs.end(),
+                    "snapshot doesn't fit the log; ignoring it"
+                ),
+                Err(e) => tracing::error!(path, e=?e, "ignoring unreadable snapshot"),
             }
-            StorageCommand::Decr(key) => {
-                log.write_all(b"D")?;
-                log.write_all(&key.0.len().to_le_bytes()[..])?;
-                log.write_all(&key.0[..])?;
+        }
+        None
+    }
+
+    /// Reads back everything in the log to replay it, dealing with bad
+    /// records as the mode says. A bad tail is truncated away, so new records
+    /// follow on from the last good one.
+    pub fn recover(
+        &self,
+        mode: RecoveryMode,
+    ) -> Result<(Vec<Record>, RecoveryReport), TransactionLogError> {
+        self.recover_from(self.first_seq(), mode)
+    }
+
+    /// Like recover, but only returns the records from the sequence number
+    /// on. Segments wholly before it aren't read at all.
+    pub fn recover_from(
+        &self,
+        seq: u64,
+        mode: RecoveryMode,
+    ) -> Result<(Vec<Record>, RecoveryReport), TransactionLogError> {
+        let log = self.current_log.lock().unwrap();
+        let manifest = self.manifest.lock().unwrap();
+        let base = &self.config.storage_basepath;
+        if seq < manifest.first_seq() {
+            return Err(TransactionLogError::MissingRecords {
+                first_seq: manifest.first_seq(),
+            });
+        }
+
+        let mut records = vec![];
+        let mut total = RecoveryReport::default();
+        for (i, segment) in manifest.segments.iter().enumerate() {
+            let next = manifest.segments.get(i + 1);
+            if next.is_some_and(|next| next.first_seq <= seq) {
+                continue;
             }
-            StorageCommand::Set(key, value) => {
-                log.write_all(b"S")?;
-                log.write_all(&key.0.len().to_le_bytes()[..])?;
-                log.write_all(&key.0[..])?;
-                match value {
-                    Value::Int(i) => {
-                        log.write_all(b"I")?;
-                        log.write_all(&i.to_le_bytes()[..])?;
-                    }
-                    Value::Blob(b) => {
-                        log.write_all(b"B")?;
-                        log.write_all(&b.0.len().to_le_bytes()[..])?;
-                        log.write_all(&b.0[..])?;
-                    }
-                    _ => {
-                        panic!("unexpected value in transaction log; should only be able to SET ints or blobs");
-                    }
-                }
+
+            let path = segment_filename(base, segment.number);
+            let mut file = File::open(&path)?;
+            let len = file.metadata()?.len();
+            if read_header(&mut file)? != Header::Current {
+                return Err(TransactionLogError::Corrupted("segment has no header"));
             }
-            StorageCommand::SetAdd(key, value) => {
-                log.write_all(b"A")?;
-                log.write_all(&key.0.len().to_le_bytes()[..])?;
-                log.write_all(&key.0[..])?;
-                log.write_all(&value.0.len().to_le_bytes()[..])?;
-                log.write_all(&value.0[..])?;
+            // records before seq are already in the data, so they're only
+            // stepped over, and a bad one among them doesn't matter
+            skip_records(&mut file, seq.saturating_sub(segment.first_seq))?;
+            let report = recover_with(&mut file, 0, mode, |record| records.push(record))
+                .inspect_err(|e| tracing::error!(path, e=?e, "refusing to replay log"))?;
+            for (offset, len) in &report.skipped {
+                tracing::error!(path, offset, len, "skipped corrupt records in log");
             }
-            StorageCommand::SetRemove(key, value) => {
-                log.write_all(b"C")?;
-                log.write_all(&key.0.len().to_le_bytes()[..])?;
-                log.write_all(&key.0[..])?;
-                log.write_all(&value.0.len().to_le_bytes()[..])?;
-                log.write_all(&value.0[..])?;
+            match (report.truncate_at, next) {
+                (None, _) => {}
+                // a segment is fsynced before the next one's started, so a
+                // bad end to one isn't a torn write
+                (Some(offset), Some(_)) if mode != RecoveryMode::SkipCorrupt => {
+                    tracing::error!(path, offset, "refusing to replay log");
+                    return Err(TransactionLogError::CorruptedAt {
+                        offset,
+                        reason: format!("{} ends part way through a record", path),
+                    });
+                }
+                (Some(offset), Some(_)) => {
+                    let len = len - offset;
+                    tracing::error!(path, offset, len, "skipped corrupt records in log");
+                    total.skipped.push((offset, len));
+                }
+                (Some(offset), None) => {
+                    tracing::warn!(
+                        path,
+                        offset,
+                        dropped = len - offset,
+                        "truncating partly written record at end of log"
+                    );
+                    log.set_len(offset)?;
+                    log.sync_data()?;
+                    total.truncate_at = Some(offset);
+                }
             }
-            StorageCommand::SetIntersection(_) => {}
-            StorageCommand::SetUnion(_) => {}
-            StorageCommand::Get(_) => {}
-            StorageCommand::SetMembers(_) => {}
-        };
-        Ok(())
-    }
-}
 
-pub struct LogIterator {
-    reader: BufReader<File>,
+            total.skipped.extend(report.skipped);
+        }
+        total.records = records.len();
+        tracing::info!(base, from = seq, records = total.records, "read log");
+
+        Ok((records, total))
+    }
 }
 
-impl Iterator for LogIterator {
-    type Item = StorageCommand;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.next_result() {
-            Err(TransactionLogError::Failed(io_err)) => {
-                if io_err.kind() == std::io::ErrorKind::UnexpectedEof {
-                    // TODO: use logs/tracing
-                    println!("reached end of log!");
-                } else {
-                    // TODO: log the error, this means the log had some error
-                    // while reading. we can have a setting for whether to
-                    // eat the error or panic.
-                    panic!("encountered unexpected log error")
-                }
-                None
+/// Opens a log's manifest. A log from before logs had segments gets one,
+/// with its single file as the first segment, migrating it first if it's a
+/// legacy log, dealing with bad records as the mode says.
+fn open_manifest(base: &str, mode: RecoveryMode) -> Result<Manifest, TransactionLogError> {
+    let manifest = match Manifest::read(base)? {
+        Some(manifest) => manifest,
+        None => {
+            let current = current_log_filename(base);
+            if Path::new(&current).exists() {
+                // brings a legacy log up to the current format first
+                drop(open_log(&current, mode)?);
+                let first = segment_filename(base, 0);
+                std::fs::rename(&current, &first)?;
+                sync_parent_dir(&first);
+                tracing::info!(path = first, "moved log into its first segment");
             }
-            Ok(cmd) => cmd,
+            let manifest = Manifest {
+                segments: vec![Segment {
+                    number: 0,
+                    first_seq: 0,
+                }],
+            };
+            manifest.write(base)?;
+            manifest
         }
-    }
+    };
+    remove_orphans(base, &manifest)?;
+    Ok(manifest)
 }
 
-impl LogIterator {
-    fn next_result(&mut self) -> Result<Option<StorageCommand>, TransactionLogError> {
-        let mut header: [u8; 9] = [0; 9];
-        self.reader.read_exact(&mut header[..])?;
+/// How many good records a segment holds, skipping over bad ones, and the
+/// stamp of the last of them.
+fn count_records(path: &str) -> Result<(u64, Option<Stamp>), TransactionLogError> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+    let mut last = None;
+    let report = recover_with(&mut file, 0, RecoveryMode::SkipCorrupt, |record| {
+        last = Some(record.stamp)
+    })?;
+    Ok((report.records as u64, last))
+}
 
-        let tag = header[0];
+/// The stamp of the last record before the segment being written to, for
+/// when that has none: from the newest segment which has any, or failing
+/// that the newest snapshot.
+fn last_stamp_before(base: &str, manifest: &Manifest) -> Result<Stamp, TransactionLogError> {
+    for segment in manifest.segments.iter().rev().skip(1) {
+        let path = segment_filename(base, segment.number);
+        if let (_, Some(stamp)) = count_records(&path)? {
+            return Ok(stamp);
+        }
+    }
+    Ok(Stamp {
+        lsn: read_snapshot_lsn(&snapshot_filename(base)).unwrap_or(0),
+        timestamp: 0,
+    })
+}
 
-        let (key_len_bytes, _) = header[1..].split_at(std::mem::size_of::<usize>());
-        let key_len = usize::from_le_bytes(key_len_bytes.try_into().unwrap());
+/// How long ago the segment was last written to.
+fn last_written(base: &str, segment: &Segment) -> Option<Duration> {
+    let modified = std::fs::metadata(segment_filename(base, segment.number))
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    modified.elapsed().ok()
+}
 
-        let mut key_bytes: Vec<u8> = vec![0; key_len];
-        self.reader.read_exact(&mut key_bytes[..])?;
-        let key = Blob(key_bytes);
+/// Removes segments which are no longer in the manifest. Any that can't be
+/// removed now are cleared away the next time the log's opened.
+fn remove_segments(base: &str, segments: &[Segment]) {
+    for segment in segments {
+        let path = segment_filename(base, segment.number);
+        if let Err(e) = std::fs::remove_file(&path) {
+            tracing::warn!(path, e=?e, "could not remove log segment");
+        }
+    }
+}
 
-        match tag {
-            b'I' => Ok(Some(StorageCommand::Incr(key))),
-            b'D' => Ok(Some(StorageCommand::Decr(key))),
-            b'S' => {
-                // reuse the header bytes since we're done with the previous one
-                self.reader.read_exact(&mut header[..])?;
-                let value_tag = header[0];
+/// Opens a log to append to, writing its header if it's new, and migrating it
+/// to the current format first if it's a legacy log.
+fn open_log(path: &str, mode: RecoveryMode) -> Result<File, TransactionLogError> {
+    let open = || {
+        OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+    };
+    let mut log = open()?;
+    match read_header(&mut log)? {
+        Header::Current => {}
+        Header::Empty => {
+            log.set_len(0)?;
+            log.write_all(&header())?;
+            log.sync_data()?;
+        }
+        Header::Legacy => {
+            drop(log);
+            migrate_log(path, mode)?;
+            log = open()?;
+        }
+    }
+    Ok(log)
+}
 
-                match value_tag {
-                    b'I' => {
-                        let (int_bytes, _) = header[1..].split_at(std::mem::size_of::<usize>());
-                        let val = i64::from_le_bytes(int_bytes.try_into().unwrap());
+/// Rewrites a legacy log in the current format. The new log is written
+/// alongside and renamed over the old one once it's complete, so a crash part
+/// way through leaves the old log to migrate again. The old log is kept as
+/// `{path}.legacy`, which matters most when bad records had to be left out.
+fn migrate_log(path: &str, mode: RecoveryMode) -> Result<(), TransactionLogError> {
+    tracing::info!(path, "migrating log to format version {}", LOG_VERSION);
+    let migrating = format!("{}.migrating", path);
+    let mut out = BufWriter::new(File::create(&migrating)?);
+    out.write_all(&header())?;
 
-                        Ok(Some(StorageCommand::Set(key, Value::Int(val))))
-                    }
-                    b'B' => {
-                        let (len_bytes, _) = header[1..].split_at(std::mem::size_of::<usize>());
-                        let value_len = usize::from_le_bytes(len_bytes.try_into().unwrap());
-                        let mut value_bytes: Vec<u8> = vec![0; value_len];
-                        self.reader.read_exact(&mut value_bytes[..])?;
+    let mut count: usize = 0;
+    let unconverted = read_log_file(path, &Header::Legacy, mode, |record| {
+        // legacy logs have no stamps, so these get none
+        write_to_log(&mut out, record.stamp, &record.cmd)?;
+        count += 1;
+        Ok(())
+    })
+    .inspect_err(|e| {
+        tracing::error!(path, e=?e, "refusing to migrate log");
+        let _ = std::fs::remove_file(&migrating);
+    })?;
+    let out = out.into_inner().map_err(|e| e.into_error())?;
+    out.sync_all()?;
 
-                        let value = Blob(value_bytes);
+    let kept = format!("{}.legacy", path);
+    std::fs::copy(path, &kept)?;
+    std::fs::rename(&migrating, path)?;
+    sync_parent_dir(path);
+    if unconverted > 0 {
+        tracing::warn!(
+            path,
+            unconverted,
+            kept,
+            "left bad records out of the migrated log"
+        );
+    }
+    tracing::info!(path, records = count, unconverted, "migrated log");
+    Ok(())
+}
 
-                        Ok(Some(StorageCommand::Set(key, Value::Blob(value))))
-                    }
-                    _ => {
-                        // TODO: log the error, this means the log is corrupted.
-                        // once this is logged, we can have a setting for whether
-                        // to panic on corruption or swallow the error.
-                        panic!("encountered log corruption")
-                    }
+/// Reads the records in a log file in any format, with its header already
+/// read as `from`, dealing with bad records as the mode says. Returns how many
+/// bytes were left unread because they were bad. Legacy logs have no
+/// checksums, so there's no finding a good record after a bad one: a partly
+/// written last record can be dropped, but damage anywhere else is refused.
+fn read_log_file(
+    path: &str,
+    from: &Header,
+    mode: RecoveryMode,
+    mut each: impl FnMut(Record) -> Result<(), TransactionLogError>,
+) -> Result<u64, TransactionLogError> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    match from {
+        Header::Empty => return Ok(0),
+        Header::Current => {
+            read_header(&mut file)?;
+            let mut written = Ok(());
+            let report = recover_with(&mut file, 0, mode, |record| {
+                if written.is_ok() {
+                    written = each(record);
                 }
-            }
-            b'A' => {
-                self.reader.read_exact(&mut header[1..])?;
-                let (len_bytes, _) = header[1..].split_at(std::mem::size_of::<usize>());
-                let value_len = usize::from_le_bytes(len_bytes.try_into().unwrap());
-                let mut value_bytes: Vec<u8> = vec![0; value_len];
-                self.reader.read_exact(&mut value_bytes[..])?;
-
-                let value = Blob(value_bytes);
+            })?;
+            written?;
+            let skipped: u64 = report.skipped.iter().map(|(_, len)| len).sum();
+            return Ok(skipped + report.truncate_at.map_or(0, |offset| len - offset));
+        }
+        Header::Legacy => {}
+    }
 
-                Ok(Some(StorageCommand::SetAdd(key, value)))
+    let mut records = LegacyLogIterator::new(BufReader::new(file));
+    loop {
+        let e = match records.next_result() {
+            Ok(Some(cmd)) => {
+                each(Record {
+                    stamp: Stamp::default(),
+                    cmd,
+                })?;
+                continue;
             }
-            b'C' => {
-                self.reader.read_exact(&mut header[1..])?;
-                let (len_bytes, _) = header[1..].split_at(std::mem::size_of::<usize>());
-                let value_len = usize::from_le_bytes(len_bytes.try_into().unwrap());
-                let mut value_bytes: Vec<u8> = vec![0; value_len];
-                self.reader.read_exact(&mut value_bytes[..])?;
+            Ok(None) => return Ok(0),
+            Err(e) => e,
+        };
+        let offset = records.offset();
+        let torn = matches!(&e, TransactionLogError::Failed(e) if e.kind() == std::io::ErrorKind::UnexpectedEof);
+        if torn && mode != RecoveryMode::Strict {
+            return Ok(len - offset);
+        }
+        return Err(TransactionLogError::CorruptedAt {
+            offset,
+            reason: match torn {
+                true => e.to_string(),
+                false => format!("{} (legacy logs can't be read past a bad record)", e),
+            },
+        });
+    }
+}
 
-                let value = Blob(value_bytes);
+/// Splits a log from before the keyspace was sharded into a log for each
+/// shard, holding the records for the shard's keys, so it's replayed with
+/// however many shards there are now. Records without keys, like FLUSHALL,
+/// go to every shard. Each shard's log goes where logs were kept before
+/// segments, to be taken up as its first segment, and the old log is kept as
+/// `{base}.unsharded`. A crash part way through leaves the old log in place
+/// to split again.
+pub fn split_unsharded_log(config: &Config) -> Result<(), TransactionLogError> {
+    let base = &config.storage_basepath;
+    let path = current_log_filename(base);
+    let shards = config.storage_shards;
+    tracing::info!(path, shards, "splitting log from before sharding");
 
-                Ok(Some(StorageCommand::SetRemove(key, value)))
+    let from = read_header(&mut File::open(&path)?)?;
+    let mut splitting = vec![];
+    let mut outs = vec![];
+    for shard in 0..shards {
+        let shard_path = format!(
+            "{}.splitting",
+            current_log_filename(&config.shard_basepath(shard))
+        );
+        let mut out = BufWriter::new(File::create(&shard_path)?);
+        out.write_all(&header())?;
+        splitting.push(shard_path);
+        outs.push(out);
+    }
+    let mut count: usize = 0;
+    let unconverted = read_log_file(&path, &from, config.log_recovery, |record| {
+        match record.cmd.keys().first() {
+            Some(key) => {
+                write_to_log(&mut outs[shard_for(key, shards)], record.stamp, &record.cmd)?;
             }
-
-            _ => {
-                // TODO: log the error, this means the log is corrupted.
-                // once this is logged, we can have a setting for whether
-                // to panic on corruption or swallow the error.
-                panic!("encountered log corruption")
+            None => {
+                for out in &mut outs {
+                    write_to_log(out, record.stamp, &record.cmd)?;
+                }
             }
         }
+        count += 1;
+        Ok(())
+    })?;
+    for out in outs {
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
     }
+
+    for shard_path in &splitting {
+        let split = shard_path.trim_end_matches(".splitting");
+        std::fs::rename(shard_path, split)?;
+        sync_parent_dir(split);
+    }
+    std::fs::rename(&path, format!("{}.unsharded", base))?;
+    sync_parent_dir(&path);
+    if unconverted > 0 {
+        tracing::warn!(path, unconverted, "left bad records out of the split logs");
+    }
+    tracing::info!(path, shards, records = count, "split log over shards");
+    Ok(())
 }
 
+/// Fsyncs the directory holding the file, so a rename into it survives a
+/// crash. It's best effort, since not every platform can fsync a directory.
+fn sync_parent_dir(path: &str) {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// Where the log was kept before it was split into segments.
 fn current_log_filename(base: &str) -> String {
     format!("{}.current", base)
 }
@@ -286,6 +1365,7 @@ fn current_log_filename(base: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::Value;
 
     #[test]
     fn writes_out_commands() {
@@ -301,20 +1381,119 @@ mod tests {
         ];
 
         let log = TransactionLog::new(config).expect("should create log");
-        for cmd in commands {
-            log.record(&cmd).expect("should record command");
+        for (lsn, cmd) in (1..).zip(commands) {
+            let stamp = Stamp { lsn, timestamp: 7 };
+            log.record(stamp, &cmd).expect("should record command");
         }
 
-        //let content = std::fs::read(current_log_filename(&base_path)).expect("should read the file");
-        let content = std::fs::read_to_string(current_log_filename(&base_path))
-            .expect("should read the file");
+        let content = std::fs::read(segment_filename(&base_path, 0)).expect("should read the file");
 
-        let expected_log = "S\u{1}\0\0\0\0\0\0\0aB\u{1}\0\0\0\0\0\0\01I\u{1}\0\0\0\0\0\0\0a";
+        let mut expected_log = b"ANODELOG\x01\0\0\0".to_vec();
+        for (lsn, command) in [
+            (1u64, &b"S\x01\0\0\0aB\x01\0\0\0\x31"[..]),
+            (2, &b"I\x01\0\0\0a"[..]),
+        ] {
+            let mut payload = lsn.to_le_bytes().to_vec();
+            payload.extend(7u64.to_le_bytes());
+            payload.extend(command);
+            expected_log.extend((payload.len() as u32).to_le_bytes());
+            expected_log.extend(crc32c::crc32c(&payload).to_le_bytes());
+            expected_log.extend(payload);
+        }
         assert_eq!(expected_log, content);
 
         cleanup_tmp_dir(tmp);
     }
 
+    #[test]
+    fn migrates_legacy_logs() {
+        let tmp = ".tmp/tlog-test-migrate/";
+        setup_tmp_dir(tmp);
+        let base_path = format!("{}/log", tmp);
+        let config = create_config(base_path.clone());
+
+        let commands = vec![
+            StorageCommand::Set("a".into(), Value::Int(7)),
+            StorageCommand::SetAdd("x".into(), "z".into()),
+            StorageCommand::Delete(vec!["a".into()]),
+        ];
+        let mut legacy = vec![];
+        for cmd in &commands {
+            legacy::write_to_legacy_log(&mut legacy, cmd).unwrap();
+        }
+        let path = current_log_filename(&base_path);
+        std::fs::write(&path, &legacy).unwrap();
+
+        let log = TransactionLog::new(config.clone()).expect("should migrate log");
+        log.record(Stamp::default(), &StorageCommand::Incr("b".into()))
+            .unwrap();
+        let recorded: Vec<StorageCommand> = log.read().unwrap().collect();
+        assert_eq!(commands, recorded[..3]);
+        assert_eq!(StorageCommand::Incr("b".into()), recorded[3]);
+
+        // the legacy log is kept, and the migrated one becomes the first
+        // segment and isn't migrated again
+        assert_eq!(legacy, std::fs::read(format!("{}.legacy", path)).unwrap());
+        assert!(std::fs::read(segment_filename(&base_path, 0))
+            .unwrap()
+            .starts_with(LOG_MAGIC));
+        let reopened = TransactionLog::new(config).expect("should open log");
+        assert_eq!(4, reopened.read().unwrap().count());
+
+        cleanup_tmp_dir(tmp);
+    }
+
+    #[test]
+    fn migrates_damaged_legacy_logs_as_the_recovery_mode_says() {
+        let tmp = ".tmp/tlog-test-migrate-damaged/";
+        setup_tmp_dir(tmp);
+        let base_path = format!("{}/log", tmp);
+        let path = current_log_filename(&base_path);
+
+        let mut legacy = vec![];
+        for key in ["a", "b"] {
+            legacy::write_to_legacy_log(&mut legacy, &StorageCommand::Incr(key.into())).unwrap();
+        }
+        let whole = legacy.len();
+
+        // a partly written last record is only dropped if the mode allows it
+        let mut torn = legacy.clone();
+        legacy::write_to_legacy_log(&mut torn, &StorageCommand::Incr("c".into())).unwrap();
+        torn.truncate(torn.len() - 1);
+        std::fs::write(&path, &torn).unwrap();
+        let strict = Config {
+            log_recovery: RecoveryMode::Strict,
+            ..create_config(base_path.clone())
+        };
+        assert!(matches!(
+            TransactionLog::new(strict),
+            Err(TransactionLogError::CorruptedAt { offset, .. }) if offset == whole as u64
+        ));
+        assert_eq!(torn, std::fs::read(&path).unwrap());
+
+        let log = TransactionLog::new(create_config(base_path.clone())).expect("should migrate");
+        assert_eq!(2, log.read().unwrap().count());
+        assert_eq!(torn, std::fs::read(format!("{}.legacy", path)).unwrap());
+        cleanup_tmp_dir(tmp);
+
+        // damage before the end can't be skipped, so it's always refused
+        setup_tmp_dir(tmp);
+        let mut damaged = legacy.clone();
+        damaged[whole / 2] = b'?';
+        std::fs::write(&path, &damaged).unwrap();
+        let skip = Config {
+            log_recovery: RecoveryMode::SkipCorrupt,
+            ..create_config(base_path.clone())
+        };
+        assert!(matches!(
+            TransactionLog::new(skip),
+            Err(TransactionLogError::CorruptedAt { offset, .. }) if offset == (whole / 2) as u64
+        ));
+        assert_eq!(damaged, std::fs::read(&path).unwrap());
+
+        cleanup_tmp_dir(tmp);
+    }
+
     #[test]
     fn reads_commands_back() {
         let tmp = ".tmp/tlog-test-read/";
@@ -329,13 +1508,96 @@ mod tests {
         ];
 
         let log = TransactionLog::new(config.clone()).expect("should create log");
-        for cmd in &commands {
-            log.record(cmd).expect("should record command");
+        for (lsn, cmd) in (1..).zip(&commands) {
+            let stamp = Stamp { lsn, timestamp: 0 };
+            log.record(stamp, cmd).expect("should record command");
         }
 
         let read_log = TransactionLog::new(config).expect("should create log");
         let recorded_commands: Vec<StorageCommand> = read_log.read().unwrap().into_iter().collect();
         assert_eq!(commands, recorded_commands);
+        // and it carries on from the last LSN
+        assert_eq!(3, read_log.last_stamp().lsn);
+
+        cleanup_tmp_dir(tmp);
+    }
+
+    #[test]
+    fn commits_queued_requests_together() {
+        let tmp = ".tmp/tlog-test-group-commit/";
+        setup_tmp_dir(tmp);
+
+        for policy in [FsyncPolicy::Always, FsyncPolicy::Everysec, FsyncPolicy::No] {
+            let config = Config {
+                appendfsync: policy,
+                ..create_config(format!("{}/log-{:?}", tmp, policy))
+            };
+            let (_tx, rx) = mpsc::channel(1);
+            let mut worker = TransactionWorker::new(rx, config.clone());
+
+            let mut waiters = vec![];
+            let group: Vec<_> = (0..3)
+                .map(|i| {
+                    let (tx, rx) = oneshot::channel();
+                    waiters.push(rx);
+                    let cmd = StorageCommand::Incr(format!("k{}", i).as_str().into());
+                    (LogRequest::Record(vec![cmd]), tx)
+                })
+                .collect();
+            worker.commit(group.into_iter()).unwrap();
+
+            for mut waiter in waiters {
+                assert!(matches!(waiter.try_recv(), Ok(Ok(()))));
+            }
+            // only always fsyncs before answering
+            assert_eq!(policy != FsyncPolicy::Always, worker.dirty);
+            assert_eq!(3, worker.lsns.current());
+            let durable = if policy == FsyncPolicy::Always { 3 } else { 0 };
+            assert_eq!(durable, worker.lsns.durable());
+
+            let read_log = TransactionLog::new(config).expect("should create log");
+            let (records, _) = read_log.recover(RecoveryMode::Strict).unwrap();
+            let lsns: Vec<u64> = records.iter().map(|record| record.stamp.lsn).collect();
+            assert_eq!(vec![1, 2, 3], lsns);
+        }
+
+        cleanup_tmp_dir(tmp);
+    }
+
+    #[test]
+    fn undoes_a_group_whose_fsync_failed() {
+        let tmp = ".tmp/tlog-test-undo-group/";
+        setup_tmp_dir(tmp);
+        let config = create_config(format!("{}/log", tmp));
+        let (_tx, rx) = mpsc::channel(1);
+        let mut worker = TransactionWorker::new(rx, config.clone());
+        let record = |key: &str| {
+            let (tx, _) = oneshot::channel();
+            (
+                LogRequest::Record(vec![StorageCommand::Incr(key.into())]),
+                tx,
+            )
+        };
+
+        worker.commit([record("a")].into_iter()).unwrap();
+        let end = worker.log.end();
+        worker
+            .commit([record("b"), record("c")].into_iter())
+            .unwrap();
+        worker.undo_group(end, None).unwrap();
+        worker.commit([record("d")].into_iter()).unwrap();
+
+        let read_log = TransactionLog::new(config).expect("should create log");
+        let (records, _) = read_log.recover(RecoveryMode::Strict).unwrap();
+        let cmds: Vec<StorageCommand> = records.into_iter().map(|r| r.cmd).collect();
+        assert_eq!(
+            vec![
+                StorageCommand::Incr("a".into()),
+                StorageCommand::Incr("d".into())
+            ],
+            cmds
+        );
+        assert_eq!(2, read_log.next_seq());
 
         cleanup_tmp_dir(tmp);
     }
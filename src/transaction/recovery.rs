@@ -0,0 +1,338 @@
+use std::fmt;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use super::format::valid_record_len;
+use super::{LogIterator, Record, Snapshot, Stamp, TransactionLogError};
+
+/// What to do with records which can't be read when replaying a log.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum RecoveryMode {
+    /// Refuse to start, reporting where the bad record is.
+    Strict,
+    /// Drop a bad record at the end of the log, which is what a crash part
+    /// way through a write leaves behind, and truncate the log before it.
+    /// Anything bad earlier in the log is refused, like strict.
+    #[default]
+    TruncateTail,
+    /// Skip past bad records to the next record whose checksum is good, as
+    /// well as truncating a bad tail.
+    SkipCorrupt,
+}
+
+/// How far to replay a log, for recovering the data as it was at a point in
+/// the past.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RecoveryTarget {
+    /// Up to and including the mutation with this LSN.
+    Lsn(u64),
+    /// Up to and including mutations logged at this time, in milliseconds
+    /// since the Unix epoch.
+    Time(u64),
+}
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum RecoveryTargetError {
+    #[error("expected lsn:<lsn> or time:<unix seconds>")]
+    Syntax,
+}
+
+impl RecoveryTarget {
+    /// Whether the record comes at or before the target. Records from before
+    /// there were stamps come before every target.
+    pub fn includes(&self, stamp: &Stamp) -> bool {
+        match self {
+            RecoveryTarget::Lsn(lsn) => stamp.lsn <= *lsn,
+            RecoveryTarget::Time(time) => stamp.timestamp <= *time,
+        }
+    }
+
+    /// Whether everything in the snapshot comes at or before the target. A
+    /// snapshot is only known to be old enough for a time if it was saved by
+    /// then.
+    pub fn includes_snapshot(&self, snapshot: &Snapshot) -> bool {
+        match self {
+            RecoveryTarget::Lsn(lsn) => snapshot.lsn <= *lsn,
+            RecoveryTarget::Time(time) => snapshot.saved_at <= *time,
+        }
+    }
+}
+
+/// Parses `lsn:<lsn>`, or `time:<unix seconds>` with up to millisecond
+/// precision, like `time:1700000000.250`.
+impl FromStr for RecoveryTarget {
+    type Err = RecoveryTargetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(lsn) = s.strip_prefix("lsn:") {
+            return lsn
+                .parse()
+                .map(RecoveryTarget::Lsn)
+                .map_err(|_| RecoveryTargetError::Syntax);
+        }
+        let time = s.strip_prefix("time:").ok_or(RecoveryTargetError::Syntax)?;
+        let (secs, millis) = time.split_once('.').unwrap_or((time, ""));
+        if millis.len() > 3 || !millis.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(RecoveryTargetError::Syntax);
+        }
+        let secs: u64 = secs.parse().map_err(|_| RecoveryTargetError::Syntax)?;
+        let millis: u64 = format!("{:0<3}", millis).parse().unwrap();
+        secs.checked_mul(1000)
+            .and_then(|ms| ms.checked_add(millis))
+            .map(RecoveryTarget::Time)
+            .ok_or(RecoveryTargetError::Syntax)
+    }
+}
+
+impl fmt::Display for RecoveryTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecoveryTarget::Lsn(lsn) => write!(f, "lsn:{}", lsn),
+            RecoveryTarget::Time(time) => write!(f, "time:{}.{:03}", time / 1000, time % 1000),
+        }
+    }
+}
+
+/// What replaying a log found.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct RecoveryReport {
+    /// How many records were read.
+    pub records: usize,
+    /// The offset and length of each stretch of the log which was skipped.
+    pub skipped: Vec<(u64, u64)>,
+    /// Where the log should be truncated, if its tail was bad.
+    pub truncate_at: Option<u64>,
+}
+
+/// Reads the records in a log's bytes, from just after its header, dealing
+/// with bad records as the mode says. Offsets are reported from the start of
+/// the file, which is `base` bytes before `records`.
+pub fn recover(
+    records: &[u8],
+    base: u64,
+    mode: RecoveryMode,
+) -> Result<(Vec<Record>, RecoveryReport), TransactionLogError> {
+    let mut read = vec![];
+    let report = recover_with(&mut Cursor::new(records), base, mode, |record| {
+        read.push(record)
+    })?;
+    Ok((read, report))
+}
+
+/// Like recover, but reads the records from the reader's position on, handing each one over as it's read. Offsets are the
+/// reader's positions plus `base`. Records are read one at a time, and only
+/// once a bad one turns up is the rest read into memory, to look for a good
+/// record after it.
+pub fn recover_with<R: Read + Seek>(
+    reader: &mut R,
+    base: u64,
+    mode: RecoveryMode,
+    mut each: impl FnMut(Record),
+) -> Result<RecoveryReport, TransactionLogError> {
+    let mut report = RecoveryReport::default();
+    let start = reader.stream_position()?;
+    let mut iter = LogIterator::new(BufReader::new(&mut *reader));
+    let failed_at = loop {
+        match iter.next_record() {
+            Ok(Some(record)) => {
+                report.records += 1;
+                each(record);
+            }
+            Ok(None) => return Ok(report),
+            Err(_) => break start + iter.offset(),
+        }
+    };
+    drop(iter);
+
+    reader.seek(SeekFrom::Start(failed_at))?;
+    let mut rest = vec![];
+    reader.read_to_end(&mut rest)?;
+    let mut pos = 0;
+    while pos < rest.len() {
+        let mut iter = LogIterator::new(&rest[pos..]);
+        let err = match iter.next_record() {
+            Ok(Some(record)) => {
+                report.records += 1;
+                each(record);
+                pos += iter.offset() as usize;
+                continue;
+            }
+            Ok(None) => break,
+            Err(e) => e,
+        };
+
+        let offset = base + failed_at + pos as u64;
+        let refuse = || TransactionLogError::CorruptedAt {
+            offset,
+            reason: err.to_string(),
+        };
+        if mode == RecoveryMode::Strict {
+            return Err(refuse());
+        }
+        match (mode, next_record(&rest, pos + 1)) {
+            (RecoveryMode::Strict | RecoveryMode::TruncateTail, Some(_)) => return Err(refuse()),
+            (_, None) => {
+                report.truncate_at = Some(offset);
+                break;
+            }
+            (RecoveryMode::SkipCorrupt, Some(next)) => {
+                report.skipped.push((offset, (next - pos) as u64));
+                pos = next;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Finds the next position at or after `from` where a whole, valid record
+/// starts.
+fn next_record(records: &[u8], from: usize) -> Option<usize> {
+    (from..records.len()).find(|&pos| valid_record_len(&records[pos..]).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageCommand;
+    use crate::transaction::{write_to_log, Stamp};
+
+    fn log(cmds: &[StorageCommand]) -> (Vec<u8>, Vec<usize>) {
+        let mut bytes = vec![];
+        let mut offsets = vec![];
+        for cmd in cmds {
+            offsets.push(bytes.len());
+            write_to_log(&mut bytes, Stamp::default(), cmd).unwrap();
+        }
+        (bytes, offsets)
+    }
+
+    fn cmds_of(records: Vec<Record>) -> Vec<StorageCommand> {
+        records.into_iter().map(|record| record.cmd).collect()
+    }
+
+    fn cmds() -> Vec<StorageCommand> {
+        vec![
+            StorageCommand::Incr("a".into()),
+            StorageCommand::Incr("b".into()),
+            StorageCommand::Incr("c".into()),
+        ]
+    }
+
+    #[test]
+    fn it_parses_recovery_targets() {
+        assert_eq!(Ok(RecoveryTarget::Lsn(42)), "lsn:42".parse());
+        assert_eq!(
+            Ok(RecoveryTarget::Time(1_700_000_000_000)),
+            "time:1700000000".parse()
+        );
+        assert_eq!(
+            Ok(RecoveryTarget::Time(1_700_000_000_250)),
+            "time:1700000000.25".parse()
+        );
+        for bad in ["42", "lsn:", "lsn:-1", "time:1.2345", "time:1.-5", "when:1"] {
+            assert_eq!(
+                Err(RecoveryTargetError::Syntax),
+                bad.parse::<RecoveryTarget>()
+            );
+        }
+        assert_eq!(
+            "time:1700000000.250",
+            RecoveryTarget::Time(1_700_000_000_250).to_string()
+        );
+    }
+
+    #[test]
+    fn it_only_takes_snapshots_saved_by_the_target() {
+        let snapshot = Snapshot {
+            saved_at: 1_700_000_000_250,
+            lsn: 7,
+            ..Default::default()
+        };
+        assert!(RecoveryTarget::Time(1_700_000_000_250).includes_snapshot(&snapshot));
+        assert!(!RecoveryTarget::Time(1_700_000_000_249).includes_snapshot(&snapshot));
+        assert!(!RecoveryTarget::Time(1_700_000_000_000).includes_snapshot(&snapshot));
+        assert!(RecoveryTarget::Lsn(7).includes_snapshot(&snapshot));
+        assert!(!RecoveryTarget::Lsn(6).includes_snapshot(&snapshot));
+    }
+
+    #[test]
+    fn it_reads_a_good_log_in_every_mode() {
+        let (bytes, _) = log(&cmds());
+        for mode in [
+            RecoveryMode::Strict,
+            RecoveryMode::TruncateTail,
+            RecoveryMode::SkipCorrupt,
+        ] {
+            let (read, report) = recover(&bytes, 12, mode).unwrap();
+            assert_eq!(cmds(), cmds_of(read));
+            assert_eq!(3, report.records);
+            assert_eq!(None, report.truncate_at);
+        }
+    }
+
+    #[test]
+    fn it_deals_with_a_partial_final_record() {
+        let (mut bytes, offsets) = log(&cmds());
+        bytes.truncate(bytes.len() - 2);
+
+        assert!(matches!(
+            recover(&bytes, 12, RecoveryMode::Strict),
+            Err(TransactionLogError::CorruptedAt { offset, .. }) if offset == 12 + offsets[2] as u64
+        ));
+        for mode in [RecoveryMode::TruncateTail, RecoveryMode::SkipCorrupt] {
+            let (read, report) = recover(&bytes, 12, mode).unwrap();
+            assert_eq!(cmds()[..2], cmds_of(read));
+            assert_eq!(Some(12 + offsets[2] as u64), report.truncate_at);
+        }
+    }
+
+    #[test]
+    fn it_deals_with_corruption_mid_log() {
+        let (mut bytes, offsets) = log(&cmds());
+        bytes[offsets[2] - 1] ^= 0xff;
+
+        assert!(recover(&bytes, 12, RecoveryMode::Strict).is_err());
+        assert!(matches!(
+            recover(&bytes, 12, RecoveryMode::TruncateTail),
+            Err(TransactionLogError::CorruptedAt { offset, .. }) if offset == 12 + offsets[1] as u64
+        ));
+
+        let (read, report) = recover(&bytes, 12, RecoveryMode::SkipCorrupt).unwrap();
+        assert_eq!(vec![cmds()[0].clone(), cmds()[2].clone()], cmds_of(read));
+        assert_eq!(
+            vec![(12 + offsets[1] as u64, (offsets[2] - offsets[1]) as u64)],
+            report.skipped
+        );
+        assert_eq!(None, report.truncate_at);
+    }
+
+    #[test]
+    fn it_skips_a_large_damaged_stretch_in_one_pass() {
+        // lengths read from inside the damage run far past the end, which
+        // must be ruled out without reading to the end from every byte
+        let (good, _) = log(&cmds());
+        let mut bytes = good.clone();
+        bytes.extend(vec![0xab; 1 << 20]);
+        bytes.extend(&good);
+
+        let started = std::time::Instant::now();
+        let mut read = vec![];
+        let report = recover_with(
+            &mut Cursor::new(&bytes),
+            12,
+            RecoveryMode::SkipCorrupt,
+            |record| read.push(record),
+        )
+        .unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(6, report.records);
+        assert_eq!(vec![(12 + good.len() as u64, 1 << 20)], report.skipped);
+
+        assert!(matches!(
+            recover(&bytes, 12, RecoveryMode::TruncateTail),
+            Err(TransactionLogError::CorruptedAt { offset, .. }) if offset == 12 + good.len() as u64
+        ));
+    }
+}
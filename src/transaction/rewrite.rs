@@ -0,0 +1,178 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use tokio::task::JoinHandle;
+
+use super::{header, write_to_log, LogResponder, Stamp, TransactionLogError};
+use crate::storage::StorageCommand;
+use crate::types::{Blob, ItemFlags, Keyspace, Value};
+
+/// A log rewrite in progress. The snapshot is written out as a new segment
+/// on a blocking thread, while records logged after the snapshot was taken
+/// are kept here to be appended once it's done.
+pub struct Rewrite {
+    pub segment: u64,
+    pub path: String,
+    /// The sequence number of the first record logged after the snapshot.
+    pub seq: u64,
+    pub buffer: Vec<u8>,
+    /// Who to tell once it's finished, if anyone.
+    pub waiter: Option<LogResponder>,
+    /// Finishes with how many records the snapshot took.
+    pub task: JoinHandle<Result<u64, TransactionLogError>>,
+}
+
+impl Rewrite {
+    /// Starts writing the snapshot to the segment in the background. Its
+    /// records are stamped with the last mutation it includes.
+    pub fn start(
+        segment: u64,
+        path: String,
+        seq: u64,
+        stamp: Stamp,
+        snapshot: Keyspace,
+        flags: ItemFlags,
+    ) -> Self {
+        let task = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || write_snapshot(&path, stamp, snapshot, &flags))
+        };
+        Self {
+            segment,
+            path,
+            seq,
+            buffer: vec![],
+            waiter: None,
+            task,
+        }
+    }
+}
+
+/// Writes a new log holding the fewest commands that rebuild the snapshot:
+/// a set for each string or integer, with its flags if it has any, and an
+/// add for each member of a set.
+/// An empty set takes an add and a remove, since it still exists. Every
+/// record gets the same stamp. Returns how many records that took, or fails
+/// if there's a value the log has no records for, rather than leave it out.
+pub fn write_snapshot(
+    path: &str,
+    stamp: Stamp,
+    snapshot: Keyspace,
+    flags: &ItemFlags,
+) -> Result<u64, TransactionLogError> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&header())?;
+    let mut records = 0;
+    for (key, value) in snapshot {
+        match value {
+            Value::Blob(_) | Value::Int(_) => {
+                let cmd = match flags.get(&key) {
+                    Some(&flags) => StorageCommand::SetWithFlags(key, value, flags),
+                    None => StorageCommand::Set(key, value),
+                };
+                records += write_to_log(&mut out, stamp, &cmd)?;
+            }
+            Value::Set(members) if members.is_empty() => {
+                let member = Blob(vec![]);
+                let add = StorageCommand::SetAdd(key.clone(), member.clone());
+                records += write_to_log(&mut out, stamp, &add)?;
+                let remove = StorageCommand::SetRemove(key, member);
+                records += write_to_log(&mut out, stamp, &remove)?;
+            }
+            Value::Set(members) => {
+                for member in members {
+                    let cmd = StorageCommand::SetAdd(key.clone(), member);
+                    records += write_to_log(&mut out, stamp, &cmd)?;
+                }
+            }
+            Value::Hash(_) => return Err(TransactionLogError::UnsupportedValue("hash")),
+        }
+    }
+
+    let out = out.into_inner().map_err(|e| e.into_error())?;
+    out.sync_all()?;
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::storage::InMemoryStorage;
+    use crate::transaction::TransactionLog;
+
+    #[test]
+    fn writes_the_fewest_commands_for_the_data() {
+        let tmp = ".tmp/tlog-test-snapshot";
+        let _ = std::fs::remove_dir_all(tmp);
+        std::fs::create_dir_all(tmp).unwrap();
+        let base_path = format!("{}/log", tmp);
+
+        let mut snapshot = Keyspace::new();
+        snapshot.insert("count".into(), Value::Int(10_000_000));
+        snapshot.insert("name".into(), Value::Blob("anode".into()));
+        let members: HashSet<_> = ["a".into(), "b".into()].into_iter().collect();
+        snapshot.insert("set".into(), Value::Set(members));
+        let mut flags = ItemFlags::new();
+        flags.insert("name".into(), 42);
+        let path = format!("{}.current", base_path);
+        let records = write_snapshot(&path, Stamp::default(), snapshot, &flags).unwrap();
+        assert_eq!(4, records);
+
+        let log = TransactionLog::new(Config {
+            storage_basepath: base_path,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut cmds: Vec<StorageCommand> = log.read().unwrap().collect();
+        cmds.sort_by_key(|cmd| format!("{:?}", cmd));
+        assert_eq!(
+            vec![
+                StorageCommand::Set("count".into(), Value::Int(10_000_000)),
+                StorageCommand::SetAdd("set".into(), "a".into()),
+                StorageCommand::SetAdd("set".into(), "b".into()),
+                StorageCommand::SetWithFlags("name".into(), Value::Blob("anode".into()), 42),
+            ],
+            cmds
+        );
+
+        let _ = std::fs::remove_dir_all(tmp);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_empty_sets_and_refuses_hashes() {
+        let tmp = ".tmp/tlog-test-snapshot-empty";
+        let _ = std::fs::remove_dir_all(tmp);
+        std::fs::create_dir_all(tmp).unwrap();
+        let base_path = format!("{}/log", tmp);
+        let path = format!("{}.current", base_path);
+
+        let hash = HashMap::from([("f".into(), "v".into())]);
+        let snapshot = Keyspace::from_iter([("hash".into(), Value::Hash(hash))]);
+        assert!(matches!(
+            write_snapshot(&path, Stamp::default(), snapshot, &ItemFlags::new()),
+            Err(TransactionLogError::UnsupportedValue("hash"))
+        ));
+
+        let snapshot = Keyspace::from_iter([
+            ("empty".into(), Value::Set(HashSet::new())),
+            ("full".into(), Value::Set(HashSet::from(["a".into()]))),
+        ]);
+        write_snapshot(&path, Stamp::default(), snapshot.clone(), &ItemFlags::new()).unwrap();
+
+        // replaying the log gives back the same data
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let (ttx, _trx) = tokio::sync::mpsc::channel(1);
+        let mut storage = InMemoryStorage::new(rx, ttx, Default::default());
+        let config = Config {
+            storage_basepath: base_path,
+            ..Default::default()
+        };
+        storage.load_from_log(config).await.unwrap();
+        assert_eq!(snapshot, storage.data().read().unwrap().data);
+
+        let _ = std::fs::remove_dir_all(tmp);
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::Path;
+
+use super::format::{read_header, Header, LogIterator};
+use super::{sync_parent_dir, TransactionLogError};
+use crate::storage::StorageCommand;
+
+/// One file of a log. Records are numbered in the order they're written,
+/// from 0, across every segment of the log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub number: u64,
+    /// The sequence number of the segment's first record.
+    pub first_seq: u64,
+}
+
+/// The segments which make up a log, oldest first. The last is the one
+/// being written to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Manifest {
+    pub segments: Vec<Segment>,
+}
+
+pub fn segment_filename(base: &str, number: u64) -> String {
+    format!("{}.segment{}", base, number)
+}
+
+pub fn manifest_filename(base: &str) -> String {
+    format!("{}.manifest", base)
+}
+
+impl Manifest {
+    /// Reads a log's manifest, which is a line per segment giving its number
+    /// and the sequence number it starts at. None if the log has none yet.
+    pub fn read(base: &str) -> Result<Option<Self>, TransactionLogError> {
+        let contents = match std::fs::read_to_string(manifest_filename(base)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut segments = vec![];
+        for line in contents.lines().filter(|line| !line.starts_with('#')) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [number, first_seq] = fields[..] else {
+                return Err(TransactionLogError::Corrupted("malformed manifest"));
+            };
+            let parse = |field: &str| {
+                field
+                    .parse::<u64>()
+                    .map_err(|_| TransactionLogError::Corrupted("malformed manifest"))
+            };
+            segments.push(Segment {
+                number: parse(number)?,
+                first_seq: parse(first_seq)?,
+            });
+        }
+        if segments.is_empty() {
+            return Err(TransactionLogError::Corrupted("manifest lists no segments"));
+        }
+        Ok(Some(Self { segments }))
+    }
+
+    /// Writes the manifest alongside the current one and renames it into
+    /// place, so the manifest on disk is always one or the other.
+    pub fn write(&self, base: &str) -> Result<(), TransactionLogError> {
+        let path = manifest_filename(base);
+        let writing = format!("{}.writing", path);
+        let mut out = File::create(&writing)?;
+        let mut contents =
+            "# segment number, then the sequence number of its first record\n".to_string();
+        for segment in &self.segments {
+            contents.push_str(&format!("{} {}\n", segment.number, segment.first_seq));
+        }
+        out.write_all(contents.as_bytes())?;
+        out.sync_all()?;
+        std::fs::rename(&writing, &path)?;
+        sync_parent_dir(&path);
+        Ok(())
+    }
+
+    pub fn active(&self) -> Segment {
+        *self.segments.last().expect("a log always has a segment")
+    }
+
+    /// The number the next segment gets.
+    pub fn next_number(&self) -> u64 {
+        self.active().number + 1
+    }
+
+    /// The first sequence number the log still has.
+    pub fn first_seq(&self) -> u64 {
+        self.segments[0].first_seq
+    }
+}
+
+/// Removes any segment files the manifest doesn't list, which are left
+/// behind by a crash part way through starting or removing segments.
+pub fn remove_orphans(base: &str, manifest: &Manifest) -> Result<(), TransactionLogError> {
+    let base_path = Path::new(base);
+    let dir = match base_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Some(prefix) = base_path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(());
+    };
+    let prefix = format!("{}.segment", prefix);
+
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let Some(number) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|number| number.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        if manifest.segments.iter().all(|s| s.number != number) {
+            let path = segment_filename(base, number);
+            tracing::warn!(path, "removing segment left out of the manifest");
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the records of every segment of a log, in order, as if they were
+/// one file.
+pub struct SegmentReader {
+    base: String,
+    segments: VecDeque<Segment>,
+    current: Option<LogIterator<BufReader<File>>>,
+}
+
+impl SegmentReader {
+    pub fn new(base: &str, manifest: &Manifest) -> Self {
+        Self {
+            base: base.to_string(),
+            segments: manifest.segments.iter().copied().collect(),
+            current: None,
+        }
+    }
+
+    fn open(&self, segment: Segment) -> Result<LogIterator<BufReader<File>>, TransactionLogError> {
+        let mut reader = BufReader::new(File::open(segment_filename(&self.base, segment.number))?);
+        if read_header(&mut reader)? != Header::Current {
+            return Err(TransactionLogError::Corrupted("segment has no header"));
+        }
+        Ok(LogIterator::new(reader))
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = StorageCommand;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(cmd) = self.current.as_mut().and_then(|records| records.next()) {
+                return Some(cmd);
+            }
+            let segment = self.segments.pop_front()?;
+            match self.open(segment) {
+                Ok(records) => self.current = Some(records),
+                Err(e) => {
+                    tracing::error!(e=?e, segment = segment.number, "stopping log read on error");
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_writes_and_reads_manifests() {
+        let tmp = ".tmp/tlog-test-manifest";
+        let _ = std::fs::remove_dir_all(tmp);
+        std::fs::create_dir_all(tmp).unwrap();
+        let base = format!("{}/log", tmp);
+
+        assert_eq!(None, Manifest::read(&base).unwrap());
+        let manifest = Manifest {
+            segments: vec![
+                Segment {
+                    number: 3,
+                    first_seq: 0,
+                },
+                Segment {
+                    number: 4,
+                    first_seq: 120,
+                },
+            ],
+        };
+        manifest.write(&base).unwrap();
+        assert_eq!(Some(manifest.clone()), Manifest::read(&base).unwrap());
+        assert_eq!(5, manifest.next_number());
+
+        // segments the manifest doesn't list are cleared away
+        std::fs::write(segment_filename(&base, 4), b"").unwrap();
+        std::fs::write(segment_filename(&base, 5), b"").unwrap();
+        remove_orphans(&base, &manifest).unwrap();
+        assert!(Path::new(&segment_filename(&base, 4)).exists());
+        assert!(!Path::new(&segment_filename(&base, 5)).exists());
+
+        let _ = std::fs::remove_dir_all(tmp);
+    }
+}
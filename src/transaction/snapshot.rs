@@ -0,0 +1,393 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+use super::format::{put_blob, Fields};
+use super::{sync_parent_dir, LogResponder, TransactionLogError};
+use crate::types::{ItemFlags, Keyspace, Value};
+
+/// Every snapshot starts with this, followed by the format version.
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"ANODESNP";
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// A shard's data as of a point in its log.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Snapshot {
+    /// When it was taken, in milliseconds since the Unix epoch.
+    pub saved_at: u64,
+    /// The sequence number of the first record the data doesn't include.
+    /// Replaying the log from there on top of the data brings it up to date.
+    pub next_seq: u64,
+    /// The LSN of the last mutation the data includes.
+    pub lsn: u64,
+    pub data: Keyspace,
+    /// The flags memcached clients stored, for the keys whose flags aren't 0.
+    pub flags: ItemFlags,
+}
+
+/// Writes a snapshot: the magic and version, when it was taken, the sequence
+/// number it goes up to, its LSN and how many keys there are, then each key,
+/// its value and its flags, and last a CRC32C of everything before it.
+/// Counts, sequence numbers and LSNs are little endian u64s, and flags a
+/// little endian u32.
+pub fn write_snapshot_file<W: Write>(
+    out: W,
+    snapshot: &Snapshot,
+) -> Result<(), TransactionLogError> {
+    let mut out = Checksummed { out, crc: 0 };
+    out.write_all(SNAPSHOT_MAGIC)?;
+    out.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    out.write_all(&snapshot.saved_at.to_le_bytes())?;
+    out.write_all(&snapshot.next_seq.to_le_bytes())?;
+    out.write_all(&snapshot.lsn.to_le_bytes())?;
+    out.write_all(&(snapshot.data.len() as u64).to_le_bytes())?;
+
+    let mut entry = vec![];
+    for (key, value) in &snapshot.data {
+        entry.clear();
+        put_blob(&mut entry, key);
+        put_value(&mut entry, value);
+        let flags = snapshot.flags.get(key).copied().unwrap_or(0);
+        entry.extend_from_slice(&flags.to_le_bytes());
+        out.write_all(&entry)?;
+    }
+
+    let crc = out.crc;
+    out.out.write_all(&crc.to_le_bytes())?;
+    Ok(())
+}
+
+/// A value is a tag followed by its contents: an i64 for an integer, a blob
+/// for a string, and a count followed by the members or field-value pairs for
+/// a set or hash.
+fn put_value(entry: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Int(i) => {
+            entry.push(b'I');
+            entry.extend_from_slice(&i.to_le_bytes());
+        }
+        Value::Blob(blob) => {
+            entry.push(b'B');
+            put_blob(entry, blob);
+        }
+        Value::Set(members) => {
+            entry.push(b'S');
+            entry.extend_from_slice(&(members.len() as u64).to_le_bytes());
+            for member in members {
+                put_blob(entry, member);
+            }
+        }
+        Value::Hash(fields) => {
+            entry.push(b'H');
+            entry.extend_from_slice(&(fields.len() as u64).to_le_bytes());
+            for (field, value) in fields {
+                put_blob(entry, field);
+                put_blob(entry, value);
+            }
+        }
+    }
+}
+
+/// Reads a snapshot back, refusing it unless its checksum matches.
+pub fn read_snapshot_file(bytes: &[u8]) -> Result<Snapshot, TransactionLogError> {
+    if !bytes.starts_with(SNAPSHOT_MAGIC) {
+        return Err(TransactionLogError::Corrupted("not a snapshot"));
+    }
+    let Some((body, crc)) = bytes.split_last_chunk::<4>() else {
+        return Err(TransactionLogError::Corrupted("snapshot too short"));
+    };
+    if crc32c::crc32c(body) != u32::from_le_bytes(*crc) {
+        return Err(TransactionLogError::Corrupted("snapshot checksum mismatch"));
+    }
+
+    let mut fields = Fields(&body[SNAPSHOT_MAGIC.len()..]);
+    let version = u32::from_le_bytes(fields.take(4)?.try_into().unwrap());
+    if version != SNAPSHOT_VERSION {
+        return Err(TransactionLogError::UnsupportedVersion(version));
+    }
+    let saved_at = take_u64(&mut fields)?;
+    let next_seq = take_u64(&mut fields)?;
+    let lsn = take_u64(&mut fields)?;
+    let count = take_u64(&mut fields)?;
+
+    let mut data = Keyspace::new();
+    let mut flags = ItemFlags::new();
+    for _ in 0..count {
+        let key = fields.blob()?;
+        let value = take_value(&mut fields)?;
+        let item_flags = u32::from_le_bytes(fields.take(4)?.try_into().unwrap());
+        if item_flags != 0 {
+            flags.insert(key.clone(), item_flags);
+        }
+        data.insert(key, value);
+    }
+    if !fields.0.is_empty() {
+        return Err(TransactionLogError::Corrupted("trailing bytes in snapshot"));
+    }
+
+    Ok(Snapshot {
+        saved_at,
+        next_seq,
+        lsn,
+        data,
+        flags,
+    })
+}
+
+/// Reads just the sequence number a snapshot goes up to, without checking
+/// the rest of it.
+pub fn read_snapshot_seq(path: &str) -> Option<u64> {
+    read_snapshot_start(path).map(|(next_seq, _)| next_seq)
+}
+
+/// Reads just a snapshot's LSN, without checking the rest of it.
+pub fn read_snapshot_lsn(path: &str) -> Option<u64> {
+    read_snapshot_start(path).map(|(_, lsn)| lsn)
+}
+
+/// The sequence number and LSN from the start of a snapshot.
+fn read_snapshot_start(path: &str) -> Option<(u64, u64)> {
+    let mut start = vec![];
+    File::open(path)
+        .ok()?
+        .take(36)
+        .read_to_end(&mut start)
+        .ok()?;
+    let mut fields = Fields(start.strip_prefix(SNAPSHOT_MAGIC)?);
+    let version = u32::from_le_bytes(fields.take(4).ok()?.try_into().unwrap());
+    if version != SNAPSHOT_VERSION {
+        return None;
+    }
+    take_u64(&mut fields).ok()?;
+    let next_seq = take_u64(&mut fields).ok()?;
+    let lsn = take_u64(&mut fields).ok()?;
+    Some((next_seq, lsn))
+}
+
+fn take_u64(fields: &mut Fields) -> Result<u64, TransactionLogError> {
+    Ok(u64::from_le_bytes(fields.take(8)?.try_into().unwrap()))
+}
+
+fn take_value(fields: &mut Fields) -> Result<Value, TransactionLogError> {
+    let value = match fields.byte()? {
+        b'I' => Value::Int(i64::from_le_bytes(fields.take(8)?.try_into().unwrap())),
+        b'B' => Value::Blob(fields.blob()?),
+        b'S' => {
+            let count = take_u64(fields)?;
+            let mut members = HashSet::new();
+            for _ in 0..count {
+                members.insert(fields.blob()?);
+            }
+            Value::Set(members)
+        }
+        b'H' => {
+            let count = take_u64(fields)?;
+            let mut hash = HashMap::new();
+            for _ in 0..count {
+                hash.insert(fields.blob()?, fields.blob()?);
+            }
+            Value::Hash(hash)
+        }
+        _ => return Err(TransactionLogError::Corrupted("unknown value tag")),
+    };
+    Ok(value)
+}
+
+/// Keeps a running CRC32C of everything written through it.
+struct Checksummed<W: Write> {
+    out: W,
+    crc: u32,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.crc = crc32c::crc32c_append(self.crc, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Where a log's newest snapshot is kept.
+pub fn snapshot_filename(base: &str) -> String {
+    format!("{}.snapshot", base)
+}
+
+/// Where the snapshot before the newest is kept, to fall back on if the
+/// newest can't be read.
+pub fn previous_snapshot_filename(base: &str) -> String {
+    format!("{}.snapshot.prev", base)
+}
+
+/// A save in progress. The snapshot is written out on a blocking thread.
+pub struct Save {
+    /// When the snapshot was taken, in seconds since the Unix epoch.
+    pub saved_at: u64,
+    pub next_seq: u64,
+    /// How many changes had been logged when the snapshot was taken.
+    pub changes: u64,
+    /// Who to tell once it's finished, if anyone.
+    pub waiter: Option<LogResponder>,
+    pub task: JoinHandle<Result<(), TransactionLogError>>,
+}
+
+impl Save {
+    /// Starts saving the snapshot as the log's newest in the background.
+    pub fn start(base: &str, snapshot: Snapshot, changes: u64) -> Self {
+        let saved_at = snapshot.saved_at / 1000;
+        let next_seq = snapshot.next_seq;
+        let base = base.to_string();
+        let task = tokio::task::spawn_blocking(move || save(&base, &snapshot));
+        Self {
+            saved_at,
+            next_seq,
+            changes,
+            waiter: None,
+            task,
+        }
+    }
+}
+
+/// Writes the snapshot alongside the newest and then renames it into place,
+/// keeping the newest as the previous. At every point there's a whole
+/// snapshot in one place or the other.
+fn save(base: &str, snapshot: &Snapshot) -> Result<(), TransactionLogError> {
+    let path = snapshot_filename(base);
+    let saving = format!("{}.saving", path);
+    let mut out = BufWriter::new(File::create(&saving)?);
+    write_snapshot_file(&mut out, snapshot)?;
+    let out = out.into_inner().map_err(|e| e.into_error())?;
+    out.sync_all()?;
+
+    match std::fs::rename(&path, previous_snapshot_filename(base)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    std::fs::rename(&saving, &path)?;
+    sync_parent_dir(&path);
+    Ok(())
+}
+
+/// Removes a log's snapshots, once they no longer match the log.
+pub fn remove_snapshots(base: &str) -> Result<(), TransactionLogError> {
+    for path in [snapshot_filename(base), previous_snapshot_filename(base)] {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    sync_parent_dir(&snapshot_filename(base));
+    Ok(())
+}
+
+/// The time now, in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The time now, in milliseconds since the Unix epoch.
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Save once this many seconds have passed since the last save, if at least
+/// this many changes have been made since, like Redis's save rules.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum SaveRuleError {
+    #[error("expected <seconds> <changes>")]
+    Syntax,
+}
+
+/// Parses the `<seconds> <changes>` form Redis uses for a save rule.
+impl FromStr for SaveRule {
+    type Err = SaveRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [seconds, changes] = fields[..] else {
+            return Err(SaveRuleError::Syntax);
+        };
+        Ok(Self {
+            seconds: seconds.parse().map_err(|_| SaveRuleError::Syntax)?,
+            changes: changes.parse().map_err(|_| SaveRuleError::Syntax)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        let mut data = Keyspace::new();
+        data.insert("count".into(), Value::Int(-3));
+        data.insert("name".into(), Value::Blob("anode".into()));
+        let members: HashSet<_> = ["a".into(), "b".into()].into_iter().collect();
+        data.insert("set".into(), Value::Set(members));
+        let fields: HashMap<_, _> = [("f".into(), "v".into())].into_iter().collect();
+        data.insert("hash".into(), Value::Hash(fields));
+        let flags: ItemFlags = [("name".into(), 42)].into_iter().collect();
+        Snapshot {
+            saved_at: 1_700_000_000_250,
+            next_seq: 12,
+            lsn: 40,
+            data,
+            flags,
+        }
+    }
+
+    #[test]
+    fn it_reads_back_every_kind_of_value() {
+        let mut bytes = vec![];
+        write_snapshot_file(&mut bytes, &snapshot()).unwrap();
+        assert_eq!(snapshot(), read_snapshot_file(&bytes).unwrap());
+    }
+
+    #[test]
+    fn it_refuses_damaged_snapshots() {
+        let mut bytes = vec![];
+        write_snapshot_file(&mut bytes, &snapshot()).unwrap();
+
+        let mut flipped = bytes.clone();
+        flipped[30] ^= 0xff;
+        assert!(matches!(
+            read_snapshot_file(&flipped),
+            Err(TransactionLogError::Corrupted("snapshot checksum mismatch"))
+        ));
+        assert!(read_snapshot_file(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_snapshot_file(b"ANODELOG").is_err());
+    }
+
+    #[test]
+    fn it_parses_save_rules() {
+        assert_eq!(
+            Ok(SaveRule {
+                seconds: 900,
+                changes: 1
+            }),
+            "900 1".parse()
+        );
+        assert_eq!(Err(SaveRuleError::Syntax), "900".parse::<SaveRule>());
+        assert_eq!(Err(SaveRuleError::Syntax), "900 lots".parse::<SaveRule>());
+    }
+}
@@ -12,6 +12,14 @@ impl From<Vec<u8>> for Blob {
 
 pub type Key = Blob;
 
+/// A shard's keys and their values. Clones share whatever neither has changed
+/// since, so snapshotting a shard doesn't copy its data.
+pub type Keyspace = imbl::HashMap<Key, Value>;
+
+/// The flags memcached clients stored along with their values, for the keys
+/// whose flags aren't 0. Shared between clones in the same way.
+pub type ItemFlags = imbl::HashMap<Key, u32>;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Value {
     Blob(Blob),
@@ -0,0 +1,353 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use thiserror::Error;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::storage::StorageCommand;
+
+/// How urgent a storage request is. Low priority work is the first to be held
+/// back, and then turned away, when storage falls behind.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Priority {
+    High,
+    Low,
+}
+
+impl Priority {
+    /// Commands which can touch any number of values are low priority, since
+    /// a few of them can hold up everything else.
+    pub fn of(cmd: &StorageCommand) -> Priority {
+        match cmd {
+            StorageCommand::MultiGet(_)
+            | StorageCommand::SetIntersection(_)
+            | StorageCommand::SetUnion(_)
+            | StorageCommand::SetMembers(_)
+            | StorageCommand::FlushAll
+            | StorageCommand::RewriteLog
+            | StorageCommand::Save
+            | StorageCommand::BgSave
+            | StorageCommand::Promote => Priority::Low,
+            _ => Priority::High,
+        }
+    }
+}
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum AdmissionError {
+    #[error("BUSY server is overloaded, try again later")]
+    Overloaded,
+
+    #[error("BUSY client rate limit exceeded, try again later")]
+    RateLimited,
+}
+
+/// Admission sits in front of the storage queue and decides whether each
+/// request goes in. It tracks how many requests are in flight and how long
+/// storage takes to answer them, and sheds work once either gets too high,
+/// so the requests it does let in are answered promptly.
+#[derive(Clone, Debug)]
+pub struct Admission {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    max_in_flight: usize,
+    low_priority_in_flight: usize,
+    latency_target: Duration,
+    max_delay: Duration,
+
+    in_flight: AtomicUsize,
+    /// A moving average of how long requests take, in microseconds.
+    latency_micros: AtomicU64,
+    /// When the average last took a sample, in microseconds since `started`.
+    sampled_micros: AtomicU64,
+    started: Instant,
+    released: Notify,
+
+    admitted: AtomicU64,
+    delayed: AtomicU64,
+    shed: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+/// A snapshot of what admission is doing, for INFO.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AdmissionStats {
+    pub in_flight: usize,
+    pub latency: Duration,
+    pub admitted: u64,
+    pub delayed: u64,
+    pub shed: u64,
+    pub rate_limited: u64,
+}
+
+impl Default for Admission {
+    fn default() -> Self {
+        Self::new(&Config::default())
+    }
+}
+
+impl Admission {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                max_in_flight: config.admission_max_in_flight,
+                low_priority_in_flight: config.admission_low_priority_in_flight,
+                latency_target: Duration::from_millis(config.admission_latency_target_ms),
+                max_delay: Duration::from_millis(config.admission_max_delay_ms),
+                in_flight: AtomicUsize::new(0),
+                latency_micros: AtomicU64::new(0),
+                sampled_micros: AtomicU64::new(0),
+                started: Instant::now(),
+                released: Notify::new(),
+                admitted: AtomicU64::new(0),
+                delayed: AtomicU64::new(0),
+                shed: AtomicU64::new(0),
+                rate_limited: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Lets a request in, or turns it away if storage is overloaded. Low
+    /// priority requests wait a little for things to improve before they're
+    /// turned away. The request counts as in flight until the permit drops.
+    pub async fn admit(&self, priority: Priority) -> Result<Permit, AdmissionError> {
+        let inner = &self.inner;
+        if priority == Priority::Low && self.congested() {
+            inner.delayed.fetch_add(1, Ordering::Relaxed);
+            let deadline = Instant::now() + inner.max_delay;
+            loop {
+                // listen before checking, so a release in between isn't missed
+                let released = inner.released.notified();
+                tokio::pin!(released);
+                released.as_mut().enable();
+                if !self.congested() {
+                    break;
+                }
+                if tokio::time::timeout_at(deadline, released).await.is_err() {
+                    inner.shed.fetch_add(1, Ordering::Relaxed);
+                    return Err(AdmissionError::Overloaded);
+                }
+            }
+        }
+
+        let admitted = inner
+            .in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < inner.max_in_flight).then_some(n + 1)
+            });
+        if admitted.is_err() {
+            inner.shed.fetch_add(1, Ordering::Relaxed);
+            return Err(AdmissionError::Overloaded);
+        }
+
+        inner.admitted.fetch_add(1, Ordering::Relaxed);
+        Ok(Permit {
+            inner: self.inner.clone(),
+            started: Instant::now(),
+        })
+    }
+
+    /// Counts a request turned away by a client's rate limit.
+    pub fn record_rate_limited(&self) {
+        self.inner.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> AdmissionStats {
+        let inner = &self.inner;
+        AdmissionStats {
+            in_flight: inner.in_flight.load(Ordering::Acquire),
+            latency: Duration::from_micros(inner.latency()),
+            admitted: inner.admitted.load(Ordering::Relaxed),
+            delayed: inner.delayed.load(Ordering::Relaxed),
+            shed: inner.shed.load(Ordering::Relaxed),
+            rate_limited: inner.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether low priority work should be held back.
+    fn congested(&self) -> bool {
+        let inner = &self.inner;
+        let latency = Duration::from_micros(inner.latency());
+        inner.in_flight.load(Ordering::Acquire) >= inner.low_priority_in_flight
+            || latency > inner.latency_target
+    }
+}
+
+impl Inner {
+    /// The latency average, in microseconds, as of now.
+    fn latency(&self) -> u64 {
+        self.decay(
+            self.latency_micros.load(Ordering::Relaxed),
+            self.now_micros(),
+        )
+    }
+
+    /// Ages the average for the time since it last took a sample, as if a
+    /// request which took no time finished every latency target. Otherwise
+    /// only finished requests would bring it down, so after a spike with
+    /// nothing but low priority work coming in, which is all turned away,
+    /// it would stay high for good.
+    fn decay(&self, average: u64, now_micros: u64) -> u64 {
+        let idle = now_micros.saturating_sub(self.sampled_micros.load(Ordering::Relaxed));
+        let periods = idle / (self.latency_target.as_micros() as u64).max(1);
+        (average as f64 * 0.875f64.powf(periods as f64)) as u64
+    }
+
+    fn now_micros(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+}
+
+/// A request which has been let in. Dropping it marks the request finished,
+/// and feeds how long it took into the latency average.
+#[derive(Debug)]
+pub struct Permit {
+    inner: Arc<Inner>,
+    started: Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let inner = &self.inner;
+        let sample = self.started.elapsed().as_micros() as u64;
+        let now = inner.now_micros();
+        // an exponentially weighted moving average, weighting each new
+        // sample by 1/8
+        let _ =
+            inner
+                .latency_micros
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                    let average = inner.decay(average, now);
+                    Some(average - average / 8 + sample / 8)
+                });
+        inner.sampled_micros.fetch_max(now, Ordering::Relaxed);
+
+        self.inner.in_flight.fetch_sub(1, Ordering::AcqRel);
+        self.inner.released.notify_waiters();
+    }
+}
+
+/// A token bucket which lets a client run a number of commands per second,
+/// in bursts of up to a second's worth.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: u64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u64) -> Self {
+        Self {
+            per_second,
+            tokens: per_second as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token if there's one to take.
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.per_second as f64;
+        self.tokens = (self.tokens + refill).min(self.per_second as f64);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission(max_in_flight: usize, low_priority_in_flight: usize) -> Admission {
+        Admission::new(&Config {
+            admission_max_in_flight: max_in_flight,
+            admission_low_priority_in_flight: low_priority_in_flight,
+            admission_latency_target_ms: 1_000,
+            admission_max_delay_ms: 100,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_sheds_work_past_the_in_flight_limit() {
+        let admission = admission(2, 2);
+        let first = admission.admit(Priority::High).await.unwrap();
+        let _second = admission.admit(Priority::High).await.unwrap();
+        assert_eq!(
+            Err(AdmissionError::Overloaded),
+            admission.admit(Priority::High).await.map(|_| ())
+        );
+
+        drop(first);
+        assert!(admission.admit(Priority::High).await.is_ok());
+        assert_eq!(1, admission.stats().shed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_delays_low_priority_work_while_congested() {
+        let admission = admission(10, 1);
+        let busy = admission.admit(Priority::High).await.unwrap();
+
+        // nothing frees up in time, so it's turned away
+        let start = Instant::now();
+        assert!(admission.admit(Priority::Low).await.is_err());
+        assert_eq!(Duration::from_millis(100), start.elapsed());
+
+        // and when something does, it goes in
+        let waiting = {
+            let admission = admission.clone();
+            tokio::spawn(async move { admission.admit(Priority::Low).await.map(|_| ()) })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(busy);
+        assert_eq!(Ok(()), waiting.await.unwrap());
+
+        let stats = admission.stats();
+        assert_eq!(2, stats.delayed);
+        assert_eq!(1, stats.shed);
+        assert_eq!(0, stats.in_flight);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_recovers_from_a_latency_spike() {
+        let admission = admission(10, 10);
+        let slow = admission.admit(Priority::High).await.unwrap();
+        tokio::time::advance(Duration::from_secs(16)).await;
+        drop(slow);
+        assert_eq!(Duration::from_secs(2), admission.stats().latency);
+
+        // only low priority work comes in, and none of it is let through to
+        // bring the average down
+        assert!(admission.admit(Priority::Low).await.is_err());
+        assert!(admission.admit(Priority::Low).await.is_err());
+
+        // but as time passes without a slow request, it's let in again
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert!(admission.stats().latency < Duration::from_secs(1));
+        assert!(admission.admit(Priority::Low).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_limits_the_rate_of_commands() {
+        let mut limiter = RateLimiter::new(2);
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+    }
+}
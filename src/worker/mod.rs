@@ -1 +1,658 @@
-pub struct Worker {}
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::sync::{mpsc, watch};
+
+use crate::config::Config;
+use crate::connection::ConnectionId;
+use crate::storage::{Combine, Route, StorageCommand, StorageError, StorageSendQueue};
+use crate::types::Value;
+
+mod admission;
+pub use admission::{Admission, AdmissionError, AdmissionStats, Permit, Priority, RateLimiter};
+
+pub type StorageResult = Result<Option<Value>, StorageError>;
+
+pub type WorkerRecvQueue = mpsc::Receiver<Submission>;
+pub type WorkerSendQueue = mpsc::Sender<Submission>;
+
+/// A group of commands sent to a storage shard as a single message. The shard
+/// runs them in order and answers each through its reply.
+pub type StorageBatch = Vec<(StorageCommand, Reply)>;
+
+/// A command on its way from a connection to storage.
+pub struct Submission {
+    pub connection: ConnectionId,
+    pub command: StorageCommand,
+    pub reply: Reply,
+}
+
+/// Where a command's result goes. Every command processor has a single
+/// channel its results come back on, rather than a oneshot per command.
+pub struct Reply {
+    target: Option<ReplyTarget>,
+}
+
+enum ReplyTarget {
+    Processor {
+        seq: u64,
+        tx: mpsc::UnboundedSender<(u64, Option<StorageResult>)>,
+    },
+    /// One shard's part of a command which spans shards, and which part.
+    Part(Arc<Gather>, usize),
+}
+
+impl Reply {
+    /// A reply which goes straight back on the channel, tagged with seq.
+    #[cfg(test)]
+    pub(crate) fn to(seq: u64, tx: mpsc::UnboundedSender<(u64, Option<StorageResult>)>) -> Self {
+        Reply {
+            target: Some(ReplyTarget::Processor { seq, tx }),
+        }
+    }
+
+    pub fn send(mut self, result: StorageResult) {
+        match self.target.take() {
+            Some(ReplyTarget::Processor { seq, tx }) => {
+                if tx.send((seq, Some(result))).is_err() {
+                    tracing::error!("could not return value to requester; early disconnection?");
+                }
+            }
+            Some(ReplyTarget::Part(gather, part)) => gather.add(part, Some(result)),
+            None => {}
+        }
+    }
+
+    /// Whether this answers one part of a write which spans shards, so the
+    /// shard has to agree with the others on whether the write goes ahead.
+    pub fn needs_agreement(&self) -> bool {
+        matches!(&self.target, Some(ReplyTarget::Part(gather, _)) if gather.decided.is_some())
+    }
+
+    /// Says whether this part of a write which spans shards was written, and
+    /// waits for the other parts to say the same. Returns whether every part
+    /// was written. Anything else is agreed to straight away.
+    pub async fn agree(&self, written: bool) -> bool {
+        let Some(ReplyTarget::Part(gather, part)) = &self.target else {
+            return written;
+        };
+        let Some(mut decided) = gather.vote(*part, written) else {
+            return written;
+        };
+        // the gather holds the sender for as long as this reply holds it
+        let agreed = decided.wait_for(Option::is_some).await;
+        agreed.is_ok_and(|agreed| *agreed == Some(true))
+    }
+}
+
+/// A command dropped without being answered, say because storage stopped,
+/// still gets a reply, so its connection isn't left waiting.
+impl Drop for Reply {
+    fn drop(&mut self) {
+        match self.target.take() {
+            Some(ReplyTarget::Processor { seq, tx }) => {
+                let _ = tx.send((seq, None));
+            }
+            Some(ReplyTarget::Part(gather, part)) => gather.add(part, None),
+            None => {}
+        }
+    }
+}
+
+/// Collects the results of a command's parts, one from each shard it spans,
+/// and answers the command once they're all in.
+struct Gather {
+    state: Mutex<GatherState>,
+    /// For a write, whether every part was written, once they all say.
+    decided: Option<watch::Sender<Option<bool>>>,
+}
+
+struct GatherState {
+    remaining: usize,
+    /// Each part's result, in the order of the parts.
+    results: Vec<Option<StorageResult>>,
+    missing: bool,
+    combine: Combine,
+    reply: Option<Reply>,
+    /// Which parts have said whether they were written, for a write.
+    voted: Vec<bool>,
+    written: bool,
+}
+
+impl Gather {
+    fn new(parts: usize, combine: Combine, reply: Reply, write: bool) -> Self {
+        Self {
+            state: Mutex::new(GatherState {
+                remaining: parts,
+                results: (0..parts).map(|_| None).collect(),
+                missing: false,
+                combine,
+                reply: Some(reply),
+                voted: vec![false; parts],
+                written: true,
+            }),
+            decided: write.then(|| watch::channel(None).0),
+        }
+    }
+
+    /// Records whether a part of a write was written, deciding the write once
+    /// every part has said. Returns where the decision turns up.
+    fn vote(&self, part: usize, written: bool) -> Option<watch::Receiver<Option<bool>>> {
+        let decided = self.decided.as_ref()?;
+        let mut state = self.state.lock().unwrap();
+        if !std::mem::replace(&mut state.voted[part], true) {
+            state.written &= written;
+            if state.voted.iter().all(|voted| *voted) {
+                decided.send_replace(Some(state.written));
+            }
+        }
+        Some(decided.subscribe())
+    }
+
+    fn add(&self, part: usize, result: Option<StorageResult>) {
+        // a part answered without saying whether it was written, say because
+        // its shard stopped, wasn't
+        self.vote(part, false);
+        let mut state = self.state.lock().unwrap();
+        state.remaining -= 1;
+        match result {
+            Some(result) => state.results[part] = Some(result),
+            None => state.missing = true,
+        }
+        if state.remaining > 0 {
+            return;
+        }
+
+        let results = std::mem::take(&mut state.results);
+        let combined =
+            (!state.missing).then(|| (state.combine)(results.into_iter().flatten().collect()));
+        let reply = state.reply.take();
+        drop(state);
+        // a part going unanswered leaves the whole unanswered
+        if let (Some(reply), Some(combined)) = (reply, combined) {
+            reply.send(combined);
+        }
+    }
+}
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum WorkerError {
+    #[error("timeout while sending to storage")]
+    Timeout,
+
+    #[error("no response from storage")]
+    NoResponse,
+}
+
+/// A command processor's way in to the worker. It submits one command at a
+/// time and waits for the result.
+pub struct WorkerHandle {
+    connection: ConnectionId,
+    queue: WorkerSendQueue,
+    next_seq: u64,
+    tx: mpsc::UnboundedSender<(u64, Option<StorageResult>)>,
+    rx: mpsc::UnboundedReceiver<(u64, Option<StorageResult>)>,
+}
+
+impl WorkerHandle {
+    pub fn new(connection: ConnectionId, queue: WorkerSendQueue) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            connection,
+            queue,
+            next_seq: 0,
+            tx,
+            rx,
+        }
+    }
+
+    /// Which connection the handle's commands are scheduled as.
+    pub fn connection(&self) -> ConnectionId {
+        self.connection
+    }
+
+    pub fn set_connection(&mut self, connection: ConnectionId) {
+        self.connection = connection;
+    }
+
+    pub async fn execute(&mut self, command: StorageCommand) -> Result<StorageResult, WorkerError> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let submission = Submission {
+            connection: self.connection,
+            command,
+            reply: Reply {
+                target: Some(ReplyTarget::Processor {
+                    seq,
+                    tx: self.tx.clone(),
+                }),
+            },
+        };
+        self.queue
+            .send_timeout(submission, SEND_TIMEOUT)
+            .await
+            .map_err(|_| WorkerError::Timeout)?;
+
+        // replies to earlier commands, abandoned part way, can still turn up
+        loop {
+            match self.rx.recv().await {
+                Some((reply_seq, _)) if reply_seq < seq => continue,
+                Some((_, Some(result))) => return Ok(result),
+                Some((_, None)) | None => return Err(WorkerError::NoResponse),
+            }
+        }
+    }
+}
+
+/// How long a command waits for room in the worker's queue.
+const SEND_TIMEOUT: Duration = Duration::from_millis(1_000);
+
+/// How many batches a shard can fall behind by, waiting for room in its
+/// queue, before the worker stops taking new commands.
+const MAX_BACKLOG: usize = 64;
+
+/// The process manager: it takes commands from every connection and sends
+/// them on to the storage shards in batches, so a shard handles one message
+/// per batch instead of one per command. Connections take turns within a
+/// batch, so a busy connection can't crowd out the others.
+///
+/// The worker is also what keeps commands which span shards atomic. It's the
+/// one place commands are put in order, and each shard runs its commands in
+/// the order it's sent them, so a command's parts run at the same point in
+/// that order on every shard: they see everything sent before the command,
+/// and nothing sent after it. A shard whose queue is full doesn't hold up the
+/// others: its batches wait in its backlog, in order, while the rest go on.
+pub struct Worker {
+    submissions: WorkerRecvQueue,
+    storage_queues: Vec<StorageSendQueue>,
+    /// Batches waiting for room in each shard's queue.
+    backlogs: Vec<VecDeque<StorageBatch>>,
+    max_batch_size: usize,
+    /// Commands waiting for a batch, by connection.
+    pending: HashMap<ConnectionId, VecDeque<Submission>>,
+    /// Connections with commands waiting, in the order they take turns.
+    turns: VecDeque<ConnectionId>,
+    queued: usize,
+}
+
+impl Worker {
+    pub fn new(
+        submissions: WorkerRecvQueue,
+        storage_queues: Vec<StorageSendQueue>,
+        config: &Config,
+    ) -> Self {
+        Self {
+            submissions,
+            backlogs: storage_queues.iter().map(|_| VecDeque::new()).collect(),
+            storage_queues,
+            max_batch_size: config.max_batch_size.max(1),
+            pending: HashMap::new(),
+            turns: VecDeque::new(),
+            queued: 0,
+        }
+    }
+
+    /// Batches commands until stop completes. After that, no new commands are
+    /// accepted but those already submitted are still sent on to the shards.
+    pub async fn run(&mut self, stop: impl Future<Output = ()>) {
+        tokio::pin!(stop);
+        let mut stopping = false;
+
+        loop {
+            let behind = self.backlogs.iter().any(|backlog| !backlog.is_empty());
+            let full = self
+                .backlogs
+                .iter()
+                .any(|backlog| backlog.len() >= MAX_BACKLOG);
+            if self.queued == 0 || full {
+                let submission = tokio::select! {
+                    submission = self.submissions.recv(), if !full => match submission {
+                        Some(submission) => submission,
+                        None => break,
+                    },
+                    sent = send_one(&self.storage_queues, &mut self.backlogs), if behind => {
+                        if sent.is_err() {
+                            tracing::error!("storage stopped before the worker");
+                            return;
+                        }
+                        continue;
+                    }
+                    _ = &mut stop, if !stopping => {
+                        stopping = true;
+                        self.submissions.close();
+                        continue;
+                    }
+                };
+                self.enqueue(submission);
+            }
+
+            // whatever else has arrived shares the batch; while storage is
+            // busy, submissions pile up here and the batches grow
+            while self.queued < self.max_batch_size {
+                match self.submissions.try_recv() {
+                    Ok(submission) => self.enqueue(submission),
+                    Err(_) => break,
+                }
+            }
+
+            let batches = self.next_batches();
+            for (backlog, batch) in self.backlogs.iter_mut().zip(batches) {
+                if !batch.is_empty() {
+                    backlog.push_back(batch);
+                }
+            }
+            if self.send_backlogs().is_err() {
+                tracing::error!("storage stopped before the worker");
+                return;
+            }
+        }
+
+        // what's left is sent on before the worker finishes
+        for (queue, backlog) in self.storage_queues.iter().zip(&mut self.backlogs) {
+            for batch in backlog.drain(..) {
+                if queue.send(batch).await.is_err() {
+                    tracing::error!("storage stopped before the worker");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Sends each shard as much of its backlog as its queue has room for.
+    fn send_backlogs(&mut self) -> Result<(), StorageStopped> {
+        for (queue, backlog) in self.storage_queues.iter().zip(&mut self.backlogs) {
+            while let Some(batch) = backlog.pop_front() {
+                match queue.try_send(batch) {
+                    Ok(()) => {}
+                    Err(mpsc::error::TrySendError::Full(batch)) => {
+                        backlog.push_front(batch);
+                        break;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => return Err(StorageStopped),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn enqueue(&mut self, submission: Submission) {
+        let queue = self.pending.entry(submission.connection).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(submission.connection);
+        }
+        queue.push_back(submission);
+        self.queued += 1;
+    }
+
+    /// Takes up to a batch of commands, one from each connection in turn, and
+    /// splits them up by shard.
+    fn next_batches(&mut self) -> Vec<StorageBatch> {
+        let shards = self.storage_queues.len();
+        let mut batches: Vec<StorageBatch> = (0..shards).map(|_| vec![]).collect();
+        let mut taken = 0;
+        while taken < self.max_batch_size {
+            let Some(connection) = self.turns.pop_front() else {
+                break;
+            };
+            let Some(queue) = self.pending.get_mut(&connection) else {
+                continue;
+            };
+            if let Some(submission) = queue.pop_front() {
+                route(&mut batches, submission);
+                taken += 1;
+                self.queued -= 1;
+            }
+            if queue.is_empty() {
+                self.pending.remove(&connection);
+            } else {
+                self.turns.push_back(connection);
+            }
+        }
+        batches
+    }
+}
+
+/// A shard's queue closed before the worker was done with it.
+struct StorageStopped;
+
+/// Waits for room in the queue of any shard which has a backlog, and sends
+/// that shard the first batch in it.
+async fn send_one(
+    queues: &[StorageSendQueue],
+    backlogs: &mut [VecDeque<StorageBatch>],
+) -> Result<(), StorageStopped> {
+    let mut waiting: Vec<_> = queues
+        .iter()
+        .zip(backlogs.iter())
+        .enumerate()
+        .filter(|(_, (_, backlog))| !backlog.is_empty())
+        .map(|(shard, (queue, _))| Box::pin(async move { (shard, queue.reserve().await) }))
+        .collect();
+    let (shard, permit) = std::future::poll_fn(|cx| {
+        for reserve in &mut waiting {
+            if let Poll::Ready(reserved) = reserve.as_mut().poll(cx) {
+                return Poll::Ready(reserved);
+            }
+        }
+        Poll::Pending
+    })
+    .await;
+    let permit = permit.map_err(|_| StorageStopped)?;
+    if let Some(batch) = backlogs[shard].pop_front() {
+        permit.send(batch);
+    }
+    Ok(())
+}
+
+/// The batches a command is split into for each shard, with its result going
+/// back on the channel, tagged with seq.
+#[cfg(test)]
+pub(crate) fn batches_for(
+    command: StorageCommand,
+    shards: usize,
+    seq: u64,
+    tx: mpsc::UnboundedSender<(u64, Option<StorageResult>)>,
+) -> Vec<StorageBatch> {
+    let mut batches: Vec<StorageBatch> = (0..shards).map(|_| vec![]).collect();
+    let submission = Submission {
+        connection: 0,
+        command,
+        reply: Reply::to(seq, tx),
+    };
+    route(&mut batches, submission);
+    batches
+}
+
+/// Adds a command to the batch for each shard it touches.
+fn route(batches: &mut [StorageBatch], submission: Submission) {
+    match submission.command.route(batches.len()) {
+        Route::One(shard, command) => batches[shard].push((command, submission.reply)),
+        Route::Many(parts, combine) => split(batches, parts, combine, submission.reply, false),
+        Route::AllOrNothing(parts, combine) => {
+            split(batches, parts, combine, submission.reply, true)
+        }
+    }
+}
+
+/// Adds each part of a command to its shard's batch, with a reply which
+/// gathers up their results.
+fn split(
+    batches: &mut [StorageBatch],
+    parts: Vec<(usize, StorageCommand)>,
+    combine: Combine,
+    reply: Reply,
+    write: bool,
+) {
+    let gather = Arc::new(Gather::new(parts.len(), combine, reply, write));
+    for (part, (shard, command)) in parts.into_iter().enumerate() {
+        let reply = Reply {
+            target: Some(ReplyTarget::Part(gather.clone(), part)),
+        };
+        batches[shard].push((command, reply));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::storage::shard_for;
+    use crate::types::Blob;
+
+    fn key(key: &str) -> Blob {
+        Blob(key.as_bytes().to_vec())
+    }
+
+    fn get(k: &str) -> StorageCommand {
+        StorageCommand::Get(key(k))
+    }
+
+    fn submission(connection: ConnectionId, key: &str) -> Submission {
+        Submission {
+            connection,
+            command: get(key),
+            reply: Reply { target: None },
+        }
+    }
+
+    /// Starts a worker in front of shards which answer a GET with the key,
+    /// and a union with the keys as members. Anything else goes unanswered.
+    fn start_worker(shards: usize) -> WorkerSendQueue {
+        let (tx, rx) = mpsc::channel(16);
+        let mut storage_queues = vec![];
+        for _ in 0..shards {
+            let (storage_tx, mut storage_rx) = mpsc::channel::<StorageBatch>(1);
+            storage_queues.push(storage_tx);
+            tokio::spawn(async move {
+                while let Some(batch) = storage_rx.recv().await {
+                    for (command, reply) in batch {
+                        match command {
+                            StorageCommand::Get(key) => reply.send(Ok(Some(Value::Blob(key)))),
+                            StorageCommand::SetUnion(keys) => {
+                                reply.send(Ok(Some(Value::Set(keys.into_iter().collect()))))
+                            }
+                            _ => drop(reply),
+                        }
+                    }
+                }
+            });
+        }
+
+        let mut worker = Worker::new(rx, storage_queues, &Config::default());
+        tokio::spawn(async move { worker.run(std::future::pending()).await });
+        tx
+    }
+
+    #[tokio::test]
+    async fn it_batches_connections_in_turn() {
+        let (_tx, rx) = mpsc::channel(1);
+        let (storage_tx, _storage_rx) = mpsc::channel(1);
+        let mut worker = Worker::new(
+            rx,
+            vec![storage_tx],
+            &Config {
+                max_batch_size: 3,
+                ..Default::default()
+            },
+        );
+
+        for key in ["a1", "a2", "a3", "a4"] {
+            worker.enqueue(submission(1, key));
+        }
+        worker.enqueue(submission(2, "b1"));
+        worker.enqueue(submission(3, "c1"));
+
+        let mut next_batch = || -> Vec<StorageCommand> {
+            let [batch] = &mut worker.next_batches()[..] else {
+                panic!("expected a batch for the one shard");
+            };
+            batch.drain(..).map(|(command, _)| command).collect()
+        };
+        assert_eq!(vec![get("a1"), get("b1"), get("c1")], next_batch());
+        assert_eq!(vec![get("a2"), get("a3"), get("a4")], next_batch());
+        assert!(next_batch().is_empty());
+        assert_eq!(0, worker.queued);
+    }
+
+    #[tokio::test]
+    async fn it_returns_results_to_the_right_command() {
+        let queue = start_worker(3);
+
+        let mut clients = vec![];
+        for connection in 0..8 {
+            let mut handle = WorkerHandle::new(connection, queue.clone());
+            clients.push(tokio::spawn(async move {
+                for i in 0..10 {
+                    let k = format!("{}-{}", connection, i);
+                    let result = handle.execute(get(&k)).await.unwrap().unwrap();
+                    assert_eq!(Some(Value::Blob(key(&k))), result);
+                }
+                assert_eq!(
+                    Err(WorkerError::NoResponse),
+                    handle.execute(StorageCommand::FlushAll).await.map(|_| ())
+                );
+            }));
+        }
+        for client in clients {
+            client.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn it_keeps_sending_to_other_shards_while_one_is_full() {
+        let (tx, rx) = mpsc::channel(16);
+        // the first shard never takes anything off its queue
+        let (stuck_tx, _stuck_rx) = mpsc::channel::<StorageBatch>(1);
+        let (storage_tx, mut storage_rx) = mpsc::channel::<StorageBatch>(1);
+        tokio::spawn(async move {
+            while let Some(batch) = storage_rx.recv().await {
+                for (command, reply) in batch {
+                    if let StorageCommand::Get(key) = command {
+                        reply.send(Ok(Some(Value::Blob(key))));
+                    }
+                }
+            }
+        });
+        let mut worker = Worker::new(rx, vec![stuck_tx, storage_tx], &Config::default());
+        tokio::spawn(async move { worker.run(std::future::pending()).await });
+
+        let on_shard = |shard| {
+            (0..)
+                .map(|i| format!("k{}", i))
+                .find(|k| shard_for(&key(k), 2) == shard)
+                .unwrap()
+        };
+        let stuck = on_shard(0);
+        for connection in 0..4 {
+            let mut handle = WorkerHandle::new(connection, tx.clone());
+            let stuck = stuck.clone();
+            tokio::spawn(async move { handle.execute(get(&stuck)).await });
+        }
+
+        let mut handle = WorkerHandle::new(4, tx);
+        let k = on_shard(1);
+        let result = tokio::time::timeout(Duration::from_secs(1), handle.execute(get(&k)))
+            .await
+            .expect("the full shard held up the other");
+        assert_eq!(Some(Value::Blob(key(&k))), result.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_combines_commands_which_span_shards() {
+        let mut handle = WorkerHandle::new(0, start_worker(4));
+
+        let keys: Vec<Blob> = (0..16).map(|i| key(&format!("k{}", i))).collect();
+        let result = handle
+            .execute(StorageCommand::SetUnion(keys.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+        let expected: HashSet<Blob> = keys.into_iter().collect();
+        assert_eq!(Some(Value::Set(expected)), result);
+    }
+}
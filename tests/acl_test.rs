@@ -0,0 +1,200 @@
+mod common;
+
+use std::path::PathBuf;
+
+use anode_kv::acl::hash_password;
+use anode_kv::config::Config;
+use anode_kv::server::Server;
+use common::{connect, expect, launch_server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const ACL_FILE: &str = "user default off\nuser admin on >adminpw ~* +@all\n";
+
+#[tokio::test]
+async fn it_requires_authentication() {
+    let addr = launch_server(config(acl_file("auth"))).await;
+    let mut stream = connect(&addr).await;
+
+    expect(
+        &mut stream,
+        b"*2\r\n+GET\r\n+k\r\n",
+        b"-NOAUTH Authentication required.\r\n",
+    )
+    .await;
+    expect(
+        &mut stream,
+        b"*3\r\n+AUTH\r\n+admin\r\n+wrong\r\n",
+        b"-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+    )
+    .await;
+    expect(
+        &mut stream,
+        b"*3\r\n+AUTH\r\n+admin\r\n+adminpw\r\n",
+        b"+OK\r\n",
+    )
+    .await;
+    expect(
+        &mut stream,
+        b"*2\r\n+ACL\r\n+WHOAMI\r\n",
+        b"$5\r\nadmin\r\n",
+    )
+    .await;
+    expect(&mut stream, b"*2\r\n+GET\r\n+k\r\n", b"$-1\r\n").await;
+}
+
+#[tokio::test]
+async fn it_enforces_command_and_key_rules() {
+    let addr = launch_server(config(acl_file("rules"))).await;
+    let mut admin = connect(&addr).await;
+    let mut reader = connect(&addr).await;
+
+    expect(
+        &mut admin,
+        b"*3\r\n+AUTH\r\n+admin\r\n+adminpw\r\n",
+        b"+OK\r\n",
+    )
+    .await;
+    expect(
+        &mut admin,
+        b"*7\r\n+ACL\r\n+SETUSER\r\n+reader\r\n+on\r\n+>pw\r\n+~cache:*\r\n+@read\r\n",
+        b"-ERR Error in ACL SETUSER modifier '@read': Syntax error\r\n",
+    )
+    .await;
+    expect(
+        &mut admin,
+        b"*8\r\n+ACL\r\n+SETUSER\r\n+reader\r\n+on\r\n+>pw\r\n+~cache:*\r\n+!~cache:private:*\r\n++@read\r\n",
+        b"+OK\r\n",
+    )
+    .await;
+    expect(&mut admin, b"*3\r\n+SET\r\n+cache:1\r\n+v\r\n", b"+OK\r\n").await;
+
+    expect(
+        &mut reader,
+        b"*3\r\n+AUTH\r\n+reader\r\n+pw\r\n",
+        b"+OK\r\n",
+    )
+    .await;
+    expect(&mut reader, b"*2\r\n+GET\r\n+cache:1\r\n", b"$1\r\nv\r\n").await;
+    expect(
+        &mut reader,
+        b"*2\r\n+GET\r\n+cache:private:1\r\n",
+        b"-NOPERM No permissions to access a key\r\n",
+    )
+    .await;
+    expect(
+        &mut reader,
+        b"*3\r\n+SET\r\n+cache:1\r\n+w\r\n",
+        b"-NOPERM User reader has no permissions to run the 'set' command\r\n",
+    )
+    .await;
+    expect(
+        &mut reader,
+        b"*2\r\n+ACL\r\n+LIST\r\n",
+        b"-NOPERM User reader has no permissions to run the 'acl' command\r\n",
+    )
+    .await;
+
+    expect(
+        &mut admin,
+        b"*3\r\n+ACL\r\n+DELUSER\r\n+default\r\n",
+        b"-ERR The 'default' user cannot be removed\r\n",
+    )
+    .await;
+    expect(
+        &mut admin,
+        b"*4\r\n+ACL\r\n+DELUSER\r\n+reader\r\n+nobody\r\n",
+        b":1\r\n",
+    )
+    .await;
+    expect(
+        &mut reader,
+        b"*2\r\n+GET\r\n+cache:1\r\n",
+        b"-NOAUTH Authentication required.\r\n",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn it_saves_and_reloads_users() {
+    let path = acl_file("save");
+    let addr = launch_server(config(path.clone())).await;
+    let mut admin = connect(&addr).await;
+
+    expect(
+        &mut admin,
+        b"*3\r\n+AUTH\r\n+admin\r\n+adminpw\r\n",
+        b"+OK\r\n",
+    )
+    .await;
+    expect(
+        &mut admin,
+        b"*6\r\n+ACL\r\n+SETUSER\r\n+app\r\n+on\r\n+>secret\r\n+allkeys\r\n",
+        b"+OK\r\n",
+    )
+    .await;
+    expect(&mut admin, b"*2\r\n+ACL\r\n+SAVE\r\n", b"+OK\r\n").await;
+
+    let saved = std::fs::read_to_string(&path).unwrap();
+    assert!(!saved.contains("secret"));
+    assert!(saved.contains(&format!(
+        "user app on #{} ~* -@all\n",
+        hash_password(b"secret")
+    )));
+
+    let addr = launch_server(config(path)).await;
+    let mut app = connect(&addr).await;
+    expect(&mut app, b"*3\r\n+AUTH\r\n+app\r\n+secret\r\n", b"+OK\r\n").await;
+}
+
+#[tokio::test]
+async fn the_http_gateway_accepts_basic_auth() {
+    let mut server = Server::create(Config {
+        http_address: Some("127.0.0.1:0".to_string()),
+        ..config(acl_file("http"))
+    })
+    .await
+    .unwrap();
+    let http_addr = server.http_addr().unwrap();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    assert_eq!(401, http_status(&http_addr, None).await);
+    assert_eq!(401, http_status(&http_addr, Some("YWRtaW46d3Jvbmc=")).await);
+    // admin:adminpw
+    assert_eq!(
+        404,
+        http_status(&http_addr, Some("YWRtaW46YWRtaW5wdw==")).await
+    );
+}
+
+async fn http_status(addr: &str, credentials: Option<&str>) -> u16 {
+    let mut stream = connect(addr).await;
+    let auth = match credentials {
+        Some(credentials) => format!("authorization: Basic {}\r\n", credentials),
+        None => String::new(),
+    };
+    let request = format!(
+        "GET /keys/k HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n{}\r\n",
+        auth
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response[9..12]).parse().unwrap()
+}
+
+fn acl_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("anode-{}-{}.acl", std::process::id(), name));
+    std::fs::write(&path, ACL_FILE).unwrap();
+    path
+}
+
+fn config(acl_file: PathBuf) -> Config {
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        acl_file: Some(acl_file),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use anode_kv::config::Config;
+use common::{bulk_reply, config, connect, expect, launch_server};
+
+#[tokio::test]
+async fn it_rate_limits_clients() {
+    let addr = launch_server(Config {
+        client_rate_limit: 2,
+        ..config()
+    })
+    .await;
+    let mut limited = connect(&addr).await;
+
+    expect(&mut limited, b"*2\r\n+GET\r\n+k\r\n", b"$-1\r\n").await;
+    expect(&mut limited, b"*2\r\n+GET\r\n+k\r\n", b"$-1\r\n").await;
+    expect(
+        &mut limited,
+        b"*2\r\n+GET\r\n+k\r\n",
+        b"-BUSY client rate limit exceeded, try again later\r\n",
+    )
+    .await;
+
+    // the limit is per client, so others carry on
+    let mut other = connect(&addr).await;
+    expect(&mut other, b"*2\r\n+GET\r\n+k\r\n", b"$-1\r\n").await;
+
+    let info = bulk_reply(&mut other, b"*2\r\n+INFO\r\n+admission\r\n").await;
+    assert!(info.starts_with("# Admission\r\n"));
+    assert!(info.contains("\r\nrate_limited:1\r\n"));
+    assert!(info.contains("\r\nin_flight:0\r\n"));
+}
+
+#[tokio::test]
+async fn it_admits_reads_which_skip_the_queue() {
+    let addr = launch_server(config()).await;
+    let mut stream = connect(&addr).await;
+
+    // single key reads never queue for storage, but are admitted all the same
+    expect(&mut stream, b"*3\r\n+SET\r\n+k\r\n+v\r\n", b"+OK\r\n").await;
+    expect(&mut stream, b"*2\r\n+GET\r\n+k\r\n", b"$1\r\nv\r\n").await;
+    expect(&mut stream, b"*2\r\n+GET\r\n+k\r\n", b"$1\r\nv\r\n").await;
+
+    let info = bulk_reply(&mut stream, b"*2\r\n+INFO\r\n+admission\r\n").await;
+    assert!(info.contains("\r\nadmitted:3\r\n"));
+}
+
+#[tokio::test]
+async fn it_reports_info_sections() {
+    let addr = launch_server(config()).await;
+    let mut stream = connect(&addr).await;
+
+    let info = bulk_reply(&mut stream, b"*1\r\n+INFO\r\n").await;
+    assert!(info.contains("# Clients\r\nconnected_clients:1\r\n"));
+    assert!(info.contains("# Admission\r\n"));
+
+    expect(
+        &mut stream,
+        b"*2\r\n+INFO\r\n+bogus\r\n",
+        b"-ERR unknown INFO section\r\n",
+    )
+    .await;
+}
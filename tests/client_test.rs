@@ -0,0 +1,123 @@
+mod common;
+
+use common::{assert_closed, bulk_reply, config, connect, expect, launch_server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn it_names_and_lists_clients() {
+    let addr = launch_server(config()).await;
+    let mut first = connect(&addr).await;
+    let mut second = connect(&addr).await;
+
+    expect(&mut first, b"*2\r\n+CLIENT\r\n+GETNAME\r\n", b"$-1\r\n").await;
+    expect(
+        &mut first,
+        b"*3\r\n+CLIENT\r\n+SETNAME\r\n+has space\r\n",
+        b"-ERR Client names cannot contain spaces, newlines or special characters.\r\n",
+    )
+    .await;
+    expect(
+        &mut first,
+        b"*3\r\n+CLIENT\r\n+SETNAME\r\n+reporting\r\n",
+        b"+OK\r\n",
+    )
+    .await;
+    expect(
+        &mut first,
+        b"*2\r\n+CLIENT\r\n+GETNAME\r\n",
+        b"$9\r\nreporting\r\n",
+    )
+    .await;
+
+    let first_id = client_id(&mut first).await;
+    let second_id = client_id(&mut second).await;
+    assert!(first_id < second_id);
+
+    let list = bulk_reply(&mut second, b"*2\r\n+CLIENT\r\n+LIST\r\n").await;
+    let lines: Vec<&str> = list.lines().collect();
+    assert_eq!(2, lines.len());
+    assert!(lines[0].starts_with(&format!("id={} ", first_id)));
+    assert!(lines[0].contains(" name=reporting "));
+    assert!(lines[0].contains(" cmd=client "));
+    assert!(lines[1].starts_with(&format!("id={} ", second_id)));
+
+    let info = bulk_reply(&mut first, b"*2\r\n+CLIENT\r\n+INFO\r\n").await;
+    assert!(info.starts_with(&format!("id={} ", first_id)));
+    assert!(info.ends_with('\n'));
+}
+
+#[tokio::test]
+async fn it_kills_clients() {
+    let addr = launch_server(config()).await;
+    let mut admin = connect(&addr).await;
+    let mut victim = connect(&addr).await;
+    let victim_id = client_id(&mut victim).await;
+
+    expect(
+        &mut admin,
+        b"*3\r\n+CLIENT\r\n+KILL\r\n+127.0.0.1:1\r\n",
+        b"-ERR No such client\r\n",
+    )
+    .await;
+
+    let kill = format!("*4\r\n+CLIENT\r\n+KILL\r\n+ID\r\n+{}\r\n", victim_id);
+    expect(&mut admin, kill.as_bytes(), b":1\r\n").await;
+    assert_closed(&mut victim).await;
+
+    // SKIPME defaults to yes, so a client can't kill itself by accident
+    expect(
+        &mut admin,
+        b"*4\r\n+CLIENT\r\n+KILL\r\n+USER\r\n+default\r\n",
+        b":0\r\n",
+    )
+    .await;
+    expect(
+        &mut admin,
+        b"*6\r\n+CLIENT\r\n+KILL\r\n+USER\r\n+default\r\n+SKIPME\r\n+no\r\n",
+        b":1\r\n",
+    )
+    .await;
+    assert_closed(&mut admin).await;
+}
+
+#[tokio::test]
+async fn it_holds_writes_while_paused() {
+    let addr = launch_server(config()).await;
+    let mut admin = connect(&addr).await;
+    let mut writer = connect(&addr).await;
+
+    expect(
+        &mut admin,
+        b"*4\r\n+CLIENT\r\n+PAUSE\r\n+10000\r\n+WRITE\r\n",
+        b"+OK\r\n",
+    )
+    .await;
+
+    // reads carry on as normal
+    expect(&mut writer, b"*2\r\n+GET\r\n+k\r\n", b"$-1\r\n").await;
+
+    writer
+        .write_all(b"*3\r\n+SET\r\n+k\r\n+v\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0u8; 5];
+    let held = tokio::time::timeout(Duration::from_millis(100), writer.read_exact(&mut buffer));
+    assert!(held.await.is_err(), "write ran while paused");
+
+    expect(&mut admin, b"*2\r\n+CLIENT\r\n+UNPAUSE\r\n", b"+OK\r\n").await;
+    let released = tokio::time::timeout(Duration::from_millis(100), writer.read_exact(&mut buffer));
+    released.await.expect("write was not released").unwrap();
+    assert_eq!(b"+OK\r\n", &buffer);
+}
+
+async fn client_id(stream: &mut TcpStream) -> u64 {
+    stream.write_all(b"*2\r\n+CLIENT\r\n+ID\r\n").await.unwrap();
+    let mut reply = vec![];
+    while !reply.ends_with(b"\r\n") {
+        reply.push(stream.read_u8().await.unwrap());
+    }
+    let reply = String::from_utf8(reply).unwrap();
+    reply[1..reply.len() - 2].parse().unwrap()
+}
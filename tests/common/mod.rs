@@ -0,0 +1,82 @@
+//! Helpers shared by the integration tests. Each test binary compiles its own
+//! copy, so not every binary uses every helper.
+#![allow(dead_code)]
+
+use anode_kv::config::Config;
+use anode_kv::server::Server;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+pub fn config() -> Config {
+    // ensure that the tmp directory exists, since the server expects it to
+    // already be created
+    std::fs::create_dir_all("./tmp").unwrap();
+
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        ..Default::default()
+    }
+}
+
+pub async fn launch_server(config: Config) -> String {
+    let mut server = Server::create(config).await.unwrap();
+    let addr = server.addr();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    addr
+}
+
+pub async fn connect(addr: &str) -> TcpStream {
+    TcpStream::connect(addr)
+        .await
+        .expect("failed to connect to server")
+}
+
+pub async fn expect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &[u8],
+    expected: &[u8],
+) {
+    stream
+        .write_all(request)
+        .await
+        .expect("failed write into stream");
+
+    let mut buffer = vec![0; expected.len()];
+    let stream_read_promise = stream.read_exact(&mut buffer[..]);
+
+    if tokio::time::timeout(Duration::from_millis(500), stream_read_promise)
+        .await
+        .is_err()
+    {
+        panic!("response did not return within 500ms");
+    }
+
+    assert_eq!(
+        String::from_utf8_lossy(&buffer),
+        String::from_utf8_lossy(expected)
+    );
+}
+
+pub async fn bulk_reply(stream: &mut TcpStream, request: &[u8]) -> String {
+    stream.write_all(request).await.unwrap();
+    let mut header = vec![];
+    while !header.ends_with(b"\r\n") {
+        header.push(stream.read_u8().await.unwrap());
+    }
+    let len: usize = String::from_utf8_lossy(&header[1..header.len() - 2])
+        .parse()
+        .unwrap();
+    let mut body = vec![0; len + 2];
+    stream.read_exact(&mut body).await.unwrap();
+    String::from_utf8(body[..len].to_vec()).unwrap()
+}
+
+pub async fn assert_closed(stream: &mut TcpStream) {
+    let mut buffer = vec![];
+    let read = tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut buffer));
+    assert!(read.await.is_ok(), "connection was left open");
+}
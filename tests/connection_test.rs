@@ -1,15 +1,18 @@
+mod common;
+
 use anode_kv::config::Config;
 use anode_kv::server::Server;
+use common::config;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::time::Duration;
 
 #[tokio::test]
 async fn it_can_accept_connections() {
-    let mut server = Server::create(create_config()).await.unwrap();
+    let mut server = Server::create(config()).await.unwrap();
     let addr = server.addr();
     tokio::spawn(async move {
-        server.run().await;
+        server.run().await.unwrap();
     });
 
     let connection1 = connect_and_request(addr);
@@ -18,10 +21,10 @@ async fn it_can_accept_connections() {
 
 #[tokio::test]
 async fn it_can_accept_multiple_connections() {
-    let mut server = Server::create(create_config()).await.unwrap();
+    let mut server = Server::create(config()).await.unwrap();
     let addr = server.addr();
     tokio::spawn(async move {
-        server.run().await;
+        server.run().await.unwrap();
     });
 
     let connection1 = tokio::spawn(connect_and_request(addr.clone()));
@@ -34,6 +37,100 @@ async fn it_can_accept_multiple_connections() {
     drop(stream2);
 }
 
+#[tokio::test]
+async fn it_rejects_clients_past_maxclients() {
+    let mut server = Server::create(Config {
+        maxclients: 1,
+        ..config()
+    })
+    .await
+    .unwrap();
+    let addr = server.addr();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    let first = connect_and_request(addr.clone()).await;
+
+    let mut second = TcpStream::connect(&addr).await.unwrap();
+    let mut response = vec![];
+    tokio::time::timeout(Duration::from_secs(1), second.read_to_end(&mut response))
+        .await
+        .expect("rejected client was not disconnected")
+        .unwrap();
+    assert_eq!(b"-ERR max number of clients reached\r\n".to_vec(), response);
+
+    // the slot frees up once the first client leaves
+    drop(first);
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let mut retry = TcpStream::connect(&addr).await.unwrap();
+        retry.write_all(b"*2\r\n+ECHO\r\n+hi\r\n").await.unwrap();
+        let mut buffer = [0u8; 8];
+        let n = retry.read(&mut buffer).await.unwrap();
+        if &buffer[..n] == b"$2\r\nhi\r\n" {
+            return;
+        }
+    }
+    panic!("maxclients slot was never freed");
+}
+
+#[tokio::test]
+async fn it_closes_idle_clients() {
+    let mut server = Server::create(Config {
+        idle_timeout_secs: 1,
+        ..config()
+    })
+    .await
+    .unwrap();
+    let addr = server.addr();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    let mut stream = connect_and_request(addr).await;
+    let mut buffer = vec![];
+    let closed = tokio::time::timeout(Duration::from_secs(3), stream.read_to_end(&mut buffer));
+    assert_eq!(0, closed.await.expect("idle client was kept open").unwrap());
+}
+
+#[tokio::test]
+async fn it_disconnects_clients_past_their_output_buffer_limit() {
+    let mut server = Server::create(Config {
+        client_output_buffer_limit: vec!["normal 64 0 0".parse().unwrap()],
+        ..config()
+    })
+    .await
+    .unwrap();
+    let addr = server.addr();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    let mut stream = connect_and_request(addr.clone()).await;
+    for member in 0..20 {
+        let sadd = format!("*3\r\n+SADD\r\n+big\r\n+member-{:02}\r\n", member);
+        stream.write_all(sadd.as_bytes()).await.unwrap();
+        let mut reply = [0u8; 7];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(b"$1\r\n1\r\n", &reply);
+    }
+
+    // the reply is far past the hard limit, so it's never sent
+    stream
+        .write_all(b"*2\r\n+SMEMBERS\r\n+big\r\n")
+        .await
+        .unwrap();
+    let mut buffer = vec![];
+    tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut buffer))
+        .await
+        .expect("client was not disconnected")
+        .unwrap();
+    assert!(buffer.is_empty());
+
+    connect_and_request(addr).await;
+}
+
 async fn connect_and_request(addr: String) -> TcpStream {
     let mut stream = tokio::net::TcpStream::connect(&addr)
         .await
@@ -59,14 +156,3 @@ async fn connect_and_request(addr: String) -> TcpStream {
 
     stream
 }
-
-fn create_config() -> Config {
-    let mut config = Config::default();
-    config.address = "127.0.0.1:0".to_string();
-
-    // ensure that the tmp directory exists, since the server expects it to
-    // already be created
-    std::fs::create_dir_all("./tmp").unwrap();
-
-    config
-}
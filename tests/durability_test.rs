@@ -0,0 +1,106 @@
+mod common;
+
+use anode_kv::config::Config;
+use anode_kv::transaction::{segment_filename, Durability, FsyncPolicy};
+use common::{config, connect, expect, launch_server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn it_acknowledges_writes_once_logged() {
+    for (name, durability) in [
+        ("logged", Durability::Logged),
+        ("fsynced", Durability::Fsynced),
+    ] {
+        let dir = format!(".tmp/durability-test-{}", name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let basepath = format!("{}/log", dir);
+
+        let addr = launch_server(Config {
+            storage_basepath: basepath.clone(),
+            storage_shards: 1,
+            durability,
+            ..config()
+        })
+        .await;
+        let mut stream = connect(&addr).await;
+
+        expect(
+            &mut stream,
+            b"*3\r\n+SET\r\n+durable\r\n+value\r\n",
+            b"+OK\r\n",
+        )
+        .await;
+
+        // it's in the log as soon as the client hears back
+        let log = std::fs::read(segment_filename(&basepath, 0)).unwrap();
+        assert!(log.windows(7).any(|w| w == b"durable"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn it_waits_for_lsns_to_be_fsynced() {
+    for (name, appendfsync) in [
+        ("always", FsyncPolicy::Always),
+        ("everysec", FsyncPolicy::Everysec),
+        ("no", FsyncPolicy::No),
+    ] {
+        let dir = format!(".tmp/durability-test-lsn-{}", name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let addr = launch_server(Config {
+            storage_basepath: format!("{}/log", dir),
+            durability: Durability::Logged,
+            appendfsync,
+            ..config()
+        })
+        .await;
+        let mut stream = connect(&addr).await;
+
+        expect(&mut stream, b"*3\r\n+SET\r\n+a\r\n+1\r\n", b"+OK\r\n").await;
+        expect(&mut stream, b"*2\r\n+INCR\r\n+a\r\n", b"$1\r\n2\r\n").await;
+
+        // only always has fsynced the writes by the time they're acknowledged,
+        // and everysec may or may not have got to them yet
+        if appendfsync != FsyncPolicy::Everysec {
+            let durable = if appendfsync == FsyncPolicy::Always {
+                2
+            } else {
+                0
+            };
+            let info = format!(
+                "# Persistence\r\ncurrent_lsn:2\r\ndurable_lsn:{}\r\nstopped_logs:0\r\n",
+                durable
+            );
+            expect(
+                &mut stream,
+                b"*2\r\n+INFO\r\n+persistence\r\n",
+                format!("${}\r\n{}\r\n", info.len(), info).as_bytes(),
+            )
+            .await;
+        }
+        // but waiting for an LSN has it fsynced, whatever the policy
+        expect(&mut stream, b"*3\r\n+WAITLSN\r\n+2\r\n+0\r\n", b":2\r\n").await;
+
+        // and so does waiting for one which hasn't been handed out yet
+        let mut waiter = connect(&addr).await;
+        waiter
+            .write_all(b"*3\r\n+WAITLSN\r\n+3\r\n+0\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        expect(&mut stream, b"*2\r\n+INCR\r\n+b\r\n", b"$1\r\n1\r\n").await;
+        let mut reply = [0; 4];
+        tokio::time::timeout(Duration::from_millis(500), waiter.read_exact(&mut reply))
+            .await
+            .expect("WAITLSN did not return within 500ms")
+            .unwrap();
+        assert_eq!(b":3\r\n", &reply);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
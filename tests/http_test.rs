@@ -0,0 +1,214 @@
+use anode_kv::config::Config;
+use anode_kv::server::Server;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn it_gets_puts_and_deletes_keys() {
+    let (resp_addr, http_addr) = launch_server().await;
+
+    let (status, body) = request(&http_addr, "PUT", "/keys/greeting", b"hello").await;
+    assert_eq!((200, r#"{"result":"OK"}"#.to_string()), (status, body));
+
+    let (status, body) = request(&http_addr, "GET", "/keys/greeting", b"").await;
+    assert_eq!((200, r#"{"value":"hello"}"#.to_string()), (status, body));
+
+    // the gateway and RESP clients see the same keyspace
+    let mut stream = tokio::net::TcpStream::connect(&resp_addr).await.unwrap();
+    stream
+        .write_all(b"*2\r\n+GET\r\n+greeting\r\n")
+        .await
+        .unwrap();
+    let mut buffer = vec![0; b"$5\r\nhello\r\n".len()];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(b"$5\r\nhello\r\n".to_vec(), buffer);
+
+    let (status, _) = request(&http_addr, "DELETE", "/keys/greeting", b"").await;
+    assert_eq!(200, status);
+
+    let (status, body) = request(&http_addr, "GET", "/keys/greeting", b"").await;
+    assert_eq!(
+        (404, r#"{"error":"no such key"}"#.to_string()),
+        (status, body)
+    );
+}
+
+#[tokio::test]
+async fn it_encodes_binary_values_as_base64() {
+    let (_, http_addr) = launch_server().await;
+
+    let (status, _) = request(&http_addr, "PUT", "/keys/bin%00ary", &[0xff, 0x00]).await;
+    assert_eq!(200, status);
+
+    let (status, body) = request(&http_addr, "GET", "/keys/bin%00ary", b"").await;
+    assert_eq!(
+        (200, r#"{"value":{"base64":"/wA="}}"#.to_string()),
+        (status, body)
+    );
+}
+
+#[tokio::test]
+async fn it_manages_set_members() {
+    let (_, http_addr) = launch_server().await;
+
+    let (status, body) = request(&http_addr, "PUT", "/keys/s/members/b", b"").await;
+    assert_eq!((200, r#"{"added":1}"#.to_string()), (status, body));
+    request(&http_addr, "PUT", "/keys/s/members/a", b"").await;
+    request(&http_addr, "PUT", "/keys/s/members/c", b"").await;
+
+    let (status, body) = request(&http_addr, "DELETE", "/keys/s/members/c", b"").await;
+    assert_eq!((200, r#"{"removed":1}"#.to_string()), (status, body));
+
+    let (status, body) = request(&http_addr, "GET", "/keys/s/members", b"").await;
+    assert_eq!((200, r#"{"value":["a","b"]}"#.to_string()), (status, body));
+
+    request(&http_addr, "PUT", "/keys/plain", b"x").await;
+    let (status, _) = request(&http_addr, "PUT", "/keys/plain/members/a", b"").await;
+    assert_eq!(409, status);
+}
+
+#[tokio::test]
+async fn it_runs_json_commands() {
+    let (_, http_addr) = launch_server().await;
+
+    let (status, body) = request(&http_addr, "POST", "/command", br#"["INCR", "n"]"#).await;
+    assert_eq!((200, r#"{"result":"1"}"#.to_string()), (status, body));
+
+    let cmd = br#"["ECHO", {"base64": "/wA="}]"#;
+    let (status, body) = request(&http_addr, "POST", "/command", cmd).await;
+    assert_eq!(
+        (200, r#"{"result":{"base64":"/wA="}}"#.to_string()),
+        (status, body)
+    );
+
+    let (status, _) = request(&http_addr, "POST", "/command", br#"{"not": "an array"}"#).await;
+    assert_eq!(400, status);
+
+    let (status, _) = request(&http_addr, "POST", "/command", br#"["NOPE"]"#).await;
+    assert_eq!(400, status);
+
+    let (status, _) = request(&http_addr, "GET", "/command", b"").await;
+    assert_eq!(405, status);
+
+    let (status, _) = request(&http_addr, "GET", "/elsewhere", b"").await;
+    assert_eq!(404, status);
+}
+
+#[tokio::test]
+async fn it_closes_connections_which_are_slow_to_send_a_request() {
+    let config = Config {
+        http_header_timeout_ms: 100,
+        ..http_config()
+    };
+    let http_addr = launch(config).await.1;
+
+    // one which never finishes its headers, and one left idle after a request
+    let mut slow = tokio::net::TcpStream::connect(&http_addr).await.unwrap();
+    slow.write_all(b"GET /keys/a HTTP/1.1\r\nhost: loc")
+        .await
+        .unwrap();
+    let mut idle = tokio::net::TcpStream::connect(&http_addr).await.unwrap();
+    idle.write_all(b"GET /keys/a HTTP/1.1\r\nhost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut responses = vec![];
+    for stream in [&mut slow, &mut idle] {
+        let mut response = vec![];
+        let read = stream.read_to_end(&mut response);
+        tokio::time::timeout(Duration::from_secs(2), read)
+            .await
+            .expect("connection wasn't closed")
+            .unwrap();
+        responses.push(String::from_utf8(response).unwrap());
+    }
+    // the idle one was answered before it was closed
+    assert!(responses[1].starts_with("HTTP/1.1 404"));
+}
+
+#[tokio::test]
+async fn it_disconnects_clients_past_their_output_buffer_limit() {
+    let config = Config {
+        client_output_buffer_limit: vec!["normal 64 0 0".parse().unwrap()],
+        ..http_config()
+    };
+    let http_addr = launch(config).await.1;
+    let value = "x".repeat(100);
+    assert_eq!(
+        200,
+        request(&http_addr, "PUT", "/keys/big", value.as_bytes())
+            .await
+            .0
+    );
+
+    // the response is past the hard limit, so it's never sent
+    let mut stream = tokio::net::TcpStream::connect(&http_addr).await.unwrap();
+    stream
+        .write_all(b"GET /keys/big HTTP/1.1\r\nhost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = vec![];
+    tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut response))
+        .await
+        .expect("client was not disconnected")
+        .unwrap();
+    assert!(response.is_empty());
+}
+
+/// Sends a single request on a fresh connection and returns the status code
+/// and body.
+async fn request(addr: &str, method: &str, path: &str, body: &[u8]) -> (u16, String) {
+    let mut stream = tokio::net::TcpStream::connect(addr)
+        .await
+        .expect("failed to connect to server");
+
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\ncontent-length: {}\r\n\r\n",
+        method,
+        path,
+        body.len()
+    )
+    .into_bytes();
+    req.extend(body);
+    stream
+        .write_all(&req)
+        .await
+        .expect("failed write into stream");
+
+    let mut response = vec![];
+    let read = stream.read_to_end(&mut response);
+    if tokio::time::timeout(Duration::from_millis(500), read)
+        .await
+        .is_err()
+    {
+        panic!("response did not return within 500ms");
+    }
+
+    let response = String::from_utf8(response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap().to_string();
+    (status, body)
+}
+
+fn http_config() -> Config {
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        http_address: Some("127.0.0.1:0".to_string()),
+        ..Default::default()
+    }
+}
+
+async fn launch_server() -> (String, String) {
+    launch(http_config()).await
+}
+
+async fn launch(config: Config) -> (String, String) {
+    let mut server = Server::create(config).await.unwrap();
+    let addr = server.addr();
+    let http_addr = server.http_addr().unwrap();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    (addr, http_addr)
+}
@@ -1,14 +1,16 @@
-use anode_kv::config::Config;
+mod common;
+
 use anode_kv::server::Server;
+use common::config;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time::Duration;
 
 #[tokio::test]
 async fn it_can_incr_and_decr_keys() {
-    let mut server = Server::create(create_config()).await.unwrap();
+    let mut server = Server::create(config()).await.unwrap();
     let addr = server.addr();
     tokio::spawn(async move {
-        server.run().await;
+        server.run().await.unwrap();
     });
 
     test_command_response(&addr, cmd_incr("x").as_bytes(), resp_bulk("1").as_bytes()).await;
@@ -76,9 +78,3 @@ fn resp_simple(value: &str) -> String {
 fn resp_bulk(value: &str) -> String {
     format!("${}\r\n{}\r\n", value.len(), value)
 }
-
-fn create_config() -> Config {
-    let mut config = Config::default();
-    config.address = "127.0.0.1:0".to_string();
-    config
-}
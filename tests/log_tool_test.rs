@@ -0,0 +1,148 @@
+use std::process::{Command, Output};
+
+use anode_kv::storage::StorageCommand;
+use anode_kv::transaction::{
+    segment_filename, snapshot_filename, write_snapshot_file, write_to_log, Manifest, Segment,
+    Snapshot, Stamp, LOG_MAGIC, LOG_VERSION,
+};
+use anode_kv::types::{Blob, Value};
+
+fn anode_log(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_anode-log"))
+        .args(args)
+        .output()
+        .expect("failed to run anode-log")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+/// Writes a log holding the commands, followed by the start of a record cut
+/// short, and returns how long it was before that.
+fn write_torn_log(path: &str, cmds: &[StorageCommand]) -> usize {
+    let mut log = LOG_MAGIC.to_vec();
+    log.extend(LOG_VERSION.to_le_bytes());
+    for (lsn, cmd) in (1..).zip(cmds) {
+        write_to_log(&mut log, Stamp { lsn, timestamp: 0 }, cmd).unwrap();
+    }
+    let whole = log.len();
+    log.extend(&[9, 0, 0, 0, 1, 2]);
+    std::fs::write(path, &log).unwrap();
+    whole
+}
+
+#[test]
+fn it_dumps_verifies_and_repairs_a_log() {
+    let dir = ".tmp/log-tool-test";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let path = format!("{}/log.segment0", dir);
+    let whole = write_torn_log(
+        &path,
+        &[
+            StorageCommand::Set(Blob(b"a:1".to_vec()), Value::Int(7)),
+            StorageCommand::SetAdd(Blob(b"b:1".to_vec()), Blob(vec![0xff])),
+        ],
+    );
+
+    let dumped = anode_log(&["dump", "--json", &path]);
+    let lines: Vec<serde_json::Value> = stdout(&dumped)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(2, lines.len());
+    assert_eq!(12, lines[0]["offset"]);
+    assert_eq!("set", lines[0]["command"]);
+    assert_eq!(7, lines[0]["value"]);
+    assert_eq!(2, lines[1]["lsn"]);
+    assert_eq!("/w==", lines[1]["member"]["base64"]);
+    // the torn record stops the dump with an error
+    assert!(!dumped.status.success());
+
+    let verified = anode_log(&["verify", &path]);
+    assert_eq!(Some(2), verified.status.code());
+    assert!(stdout(&verified).contains(&format!("from offset {}", whole)));
+
+    let refused = anode_log(&["truncate", &path, "--at", &(whole - 1).to_string()]);
+    assert!(!refused.status.success());
+    let truncated = anode_log(&["truncate", &path, "--at", &whole.to_string()]);
+    assert!(truncated.status.success());
+    assert!(stdout(&anode_log(&["verify", &path])).contains("ok"));
+
+    let filtered = format!("{}/filtered", dir);
+    let output = anode_log(&["filter", &path, "--key-prefix", "b:", "--output", &filtered]);
+    assert!(stdout(&output).contains("wrote 1 records"));
+    let stats = stdout(&anode_log(&["stats", &filtered]));
+    assert!(stats.contains("records: 1"));
+    assert!(stats.contains("sadd"));
+}
+
+#[test]
+fn it_counts_up_to_a_bad_record() {
+    let dir = ".tmp/log-tool-test-stats";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let path = format!("{}/log.segment0", dir);
+    let whole = write_torn_log(
+        &path,
+        &[StorageCommand::Set(Blob(b"a".to_vec()), Value::Int(1))],
+    );
+
+    let output = anode_log(&["stats", &path]);
+    assert_eq!(Some(2), output.status.code());
+    let stats = stdout(&output);
+    assert!(stats.contains("records: 1"));
+    assert!(stats.contains(&format!("unreadable: from offset {}", whole)));
+}
+
+#[test]
+fn it_only_truncates_the_end_of_a_log() {
+    let dir = ".tmp/log-tool-test-segments";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let base = format!("{}/log", dir);
+    let set = |key: &[u8]| StorageCommand::Set(Blob(key.to_vec()), Value::Int(1));
+    let first = write_torn_log(&segment_filename(&base, 0), &[set(b"a"), set(b"b")]);
+    let last = write_torn_log(&segment_filename(&base, 1), &[set(b"c"), set(b"d")]);
+    Manifest {
+        segments: vec![
+            Segment {
+                number: 0,
+                first_seq: 0,
+            },
+            Segment {
+                number: 1,
+                first_seq: 2,
+            },
+        ],
+    }
+    .write(&base)
+    .unwrap();
+
+    let truncate = |number, at: usize| {
+        anode_log(&[
+            "truncate",
+            &segment_filename(&base, number),
+            "--at",
+            &at.to_string(),
+        ])
+    };
+    let refused = truncate(0, first);
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("isn't the last"));
+
+    // a snapshot of all four records would no longer match a log of three
+    let snapshot = Snapshot {
+        next_seq: 4,
+        ..Default::default()
+    };
+    let file = std::fs::File::create(snapshot_filename(&base)).unwrap();
+    write_snapshot_file(file, &snapshot).unwrap();
+    let after_one = 12 + (last - 12) / 2;
+    assert!(!truncate(1, after_one).status.success());
+    assert!(truncate(1, last).status.success());
+
+    std::fs::remove_file(snapshot_filename(&base)).unwrap();
+    assert!(truncate(1, after_one).status.success());
+}
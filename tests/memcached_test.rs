@@ -0,0 +1,234 @@
+mod common;
+
+use anode_kv::config::Config;
+use anode_kv::server::Server;
+use common::{connect, expect};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn it_shares_the_keyspace_with_resp() {
+    let (resp_addr, memcached_addr) = launch_server().await;
+    let mut mc = connect(&memcached_addr).await;
+    let mut resp = connect(&resp_addr).await;
+
+    expect(&mut mc, b"set shared 0 0 5\r\nhello\r\n", b"STORED\r\n").await;
+    expect(&mut resp, b"*2\r\n+GET\r\n+shared\r\n", b"$5\r\nhello\r\n").await;
+
+    expect(&mut resp, b"*3\r\n+SET\r\n+counter\r\n+41\r\n", b"+OK\r\n").await;
+    expect(&mut mc, b"incr counter 1\r\n", b"42\r\n").await;
+    expect(&mut resp, b"*2\r\n+INCR\r\n+counter\r\n", b"$2\r\n43\r\n").await;
+    expect(
+        &mut mc,
+        b"get counter\r\n",
+        b"VALUE counter 0 2\r\n43\r\nEND\r\n",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn it_handles_storage_commands() {
+    let (_, memcached_addr) = launch_server().await;
+    let mut mc = connect(&memcached_addr).await;
+
+    expect(&mut mc, b"add k 0 0 1\r\na\r\n", b"STORED\r\n").await;
+    expect(&mut mc, b"add k 0 0 1\r\nb\r\n", b"NOT_STORED\r\n").await;
+    expect(
+        &mut mc,
+        b"replace missing 0 0 1\r\nb\r\n",
+        b"NOT_STORED\r\n",
+    )
+    .await;
+    expect(&mut mc, b"replace k 0 0 1\r\nb\r\n", b"STORED\r\n").await;
+    expect(&mut mc, b"append k 0 0 2\r\ncd\r\n", b"STORED\r\n").await;
+    expect(&mut mc, b"prepend k 0 0 1\r\na\r\n", b"STORED\r\n").await;
+    expect(&mut mc, b"append missing 0 0 1\r\na\r\n", b"NOT_STORED\r\n").await;
+    expect(
+        &mut mc,
+        b"get k missing\r\n",
+        b"VALUE k 0 4\r\nabcd\r\nEND\r\n",
+    )
+    .await;
+    expect(&mut mc, b"set k 42 0 1\r\na\r\n", b"STORED\r\n").await;
+    // appending keeps the item's flags
+    expect(&mut mc, b"append k 7 0 1\r\nb\r\n", b"STORED\r\n").await;
+    expect(&mut mc, b"get k\r\n", b"VALUE k 42 2\r\nab\r\nEND\r\n").await;
+    expect(&mut mc, b"replace k 0 0 1\r\nc\r\n", b"STORED\r\n").await;
+    expect(&mut mc, b"get k\r\n", b"VALUE k 0 1\r\nc\r\nEND\r\n").await;
+    expect(
+        &mut mc,
+        b"set k 0 60 1\r\na\r\n",
+        b"CLIENT_ERROR expiration times are not supported\r\n",
+    )
+    .await;
+    expect(
+        &mut mc,
+        b"set expired 0 -1 1\r\na\r\nget expired\r\n",
+        b"STORED\r\nEND\r\n",
+    )
+    .await;
+    // an expired add still can't replace what's there, while an expired
+    // replace removes it
+    expect(
+        &mut mc,
+        b"add k 0 -1 1\r\na\r\nget k\r\n",
+        b"NOT_STORED\r\nVALUE k 0 1\r\nc\r\nEND\r\n",
+    )
+    .await;
+    expect(
+        &mut mc,
+        b"replace k 0 -1 1\r\na\r\nget k\r\n",
+        b"STORED\r\nEND\r\n",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn it_supports_compare_and_set() {
+    let (_, memcached_addr) = launch_server().await;
+    let mut mc = connect(&memcached_addr).await;
+
+    expect(&mut mc, b"set k 0 0 1\r\na\r\n", b"STORED\r\n").await;
+    let token = gets_token(&mut mc, "k").await;
+
+    expect(&mut mc, b"set k 0 0 1\r\nb\r\n", b"STORED\r\n").await;
+    let stale = format!("cas k 0 0 1 {}\r\nc\r\n", token);
+    expect(&mut mc, stale.as_bytes(), b"EXISTS\r\n").await;
+
+    let token = gets_token(&mut mc, "k").await;
+    let fresh = format!("cas k 0 0 1 {}\r\nc\r\n", token);
+    expect(&mut mc, fresh.as_bytes(), b"STORED\r\n").await;
+    expect(&mut mc, b"cas missing 0 0 1 1\r\nc\r\n", b"NOT_FOUND\r\n").await;
+
+    // the token changes with every write, even one back to an earlier value
+    let token = gets_token(&mut mc, "k").await;
+    expect(&mut mc, b"set k 0 0 1\r\nd\r\n", b"STORED\r\n").await;
+    expect(&mut mc, b"set k 0 0 1\r\nc\r\n", b"STORED\r\n").await;
+    let stale = format!("cas k 0 0 1 {}\r\ne\r\n", token);
+    expect(&mut mc, stale.as_bytes(), b"EXISTS\r\n").await;
+}
+
+#[tokio::test]
+async fn it_follows_memcached_counter_rules() {
+    let (_, memcached_addr) = launch_server().await;
+    let mut mc = connect(&memcached_addr).await;
+
+    expect(&mut mc, b"incr missing 1\r\n", b"NOT_FOUND\r\n").await;
+    expect(&mut mc, b"set n 0 0 1\r\n5\r\n", b"STORED\r\n").await;
+    expect(&mut mc, b"decr n 10\r\n", b"0\r\n").await;
+    expect(
+        &mut mc,
+        b"set n 0 0 20\r\n18446744073709551615\r\n",
+        b"STORED\r\n",
+    )
+    .await;
+    expect(&mut mc, b"incr n 2\r\n", b"1\r\n").await;
+    expect(&mut mc, b"set s 0 0 3\r\nabc\r\n", b"STORED\r\n").await;
+    expect(
+        &mut mc,
+        b"incr s 1\r\n",
+        b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn it_handles_keyspace_commands() {
+    let (_, memcached_addr) = launch_server().await;
+    let mut mc = connect(&memcached_addr).await;
+
+    expect(
+        &mut mc,
+        b"set a 0 0 1\r\n1\r\nset b 0 0 1\r\n2\r\n",
+        b"STORED\r\nSTORED\r\n",
+    )
+    .await;
+    expect(&mut mc, b"touch a 0\r\n", b"TOUCHED\r\n").await;
+    expect(&mut mc, b"touch missing 0\r\n", b"NOT_FOUND\r\n").await;
+    expect(
+        &mut mc,
+        b"touch a 100\r\n",
+        b"CLIENT_ERROR expiration times are not supported\r\n",
+    )
+    .await;
+    expect(&mut mc, b"delete a\r\n", b"DELETED\r\n").await;
+    expect(&mut mc, b"delete a\r\n", b"NOT_FOUND\r\n").await;
+    expect(&mut mc, b"delete b noreply\r\nget b\r\n", b"END\r\n").await;
+    expect(
+        &mut mc,
+        b"set c 0 0 1\r\n3\r\nflush_all\r\n",
+        b"STORED\r\nOK\r\n",
+    )
+    .await;
+    expect(&mut mc, b"get c\r\n", b"END\r\n").await;
+    expect(&mut mc, b"bogus\r\n", b"ERROR\r\n").await;
+
+    let version = format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"));
+    expect(&mut mc, b"version\r\n", version.as_bytes()).await;
+}
+
+#[tokio::test]
+async fn it_disconnects_clients_past_their_output_buffer_limit() {
+    let (_, memcached_addr) = launch(Config {
+        client_output_buffer_limit: vec!["normal 64 0 0".parse().unwrap()],
+        ..memcached_config()
+    })
+    .await;
+    let mut mc = connect(&memcached_addr).await;
+    let store = format!("set big 0 0 100\r\n{}\r\n", "x".repeat(100));
+    expect(&mut mc, store.as_bytes(), b"STORED\r\n").await;
+
+    // the reply is past the hard limit, so it's never sent
+    mc.write_all(b"get big\r\n").await.unwrap();
+    let mut buffer = vec![];
+    tokio::time::timeout(Duration::from_secs(1), mc.read_to_end(&mut buffer))
+        .await
+        .expect("client was not disconnected")
+        .unwrap();
+    assert!(buffer.is_empty());
+}
+
+async fn gets_token(stream: &mut TcpStream, key: &str) -> u64 {
+    stream
+        .write_all(format!("gets {}\r\n", key).as_bytes())
+        .await
+        .expect("failed write into stream");
+
+    let mut reply = vec![];
+    while !reply.ends_with(b"END\r\n") {
+        let mut buf = [0u8; 256];
+        let read = tokio::time::timeout(Duration::from_millis(100), stream.read(&mut buf))
+            .await
+            .expect("response did not return within 100ms")
+            .expect("failed to read from stream");
+        reply.extend(&buf[..read]);
+    }
+
+    let reply = String::from_utf8(reply).unwrap();
+    let header = reply.lines().next().unwrap();
+    header.rsplit(' ').next().unwrap().parse().unwrap()
+}
+
+fn memcached_config() -> Config {
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        memcached_address: Some("127.0.0.1:0".to_string()),
+        ..Default::default()
+    }
+}
+
+async fn launch_server() -> (String, String) {
+    launch(memcached_config()).await
+}
+
+async fn launch(config: Config) -> (String, String) {
+    let mut server = Server::create(config).await.unwrap();
+    let addr = server.addr();
+    let memcached_addr = server.memcached_addr().unwrap();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    (addr, memcached_addr)
+}
@@ -0,0 +1,197 @@
+mod common;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::Cursor;
+
+use anode_kv::codec::{decode, decode_with_limits, DecodeLimits, ReadError, Token};
+use anode_kv::config::Config;
+use common::{config, launch_server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Duration;
+
+/// Records the largest single allocation made on each thread, so tests can
+/// check that decoding never trusts a declared length up front.
+struct TrackingAllocator;
+
+thread_local! {
+    static LARGEST_ALLOCATION: Cell<usize> = const { Cell::new(0) };
+}
+
+fn record_allocation(size: usize) {
+    let _ = LARGEST_ALLOCATION.try_with(|largest| largest.set(largest.get().max(size)));
+}
+
+fn reset_largest_allocation() {
+    LARGEST_ALLOCATION.with(|largest| largest.set(0));
+}
+
+fn largest_allocation() -> usize {
+    LARGEST_ALLOCATION.with(|largest| largest.get())
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record_allocation(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record_allocation(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record_allocation(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+/// Small deterministic PRNG (xorshift64) so failures are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[test]
+fn declared_lengths_do_not_drive_allocation() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let limits = DecodeLimits::default();
+
+    for _ in 0..10_000 {
+        let tag = if rng.next().is_multiple_of(2) {
+            '$'
+        } else {
+            '*'
+        };
+        let declared = rng.next() >> (rng.next() % 64);
+        let payload_len = (rng.next() % 64) as usize;
+
+        let mut frame = format!("{}{}\r\n", tag, declared).into_bytes();
+        frame.extend(std::iter::repeat_n(b'x', payload_len));
+
+        reset_largest_allocation();
+        let decoded = decode_with_limits(&mut Cursor::new(&frame[..]), &limits);
+        assert!(
+            largest_allocation() < 64 * 1024,
+            "decoding {:?} allocated {} bytes",
+            String::from_utf8_lossy(&frame),
+            largest_allocation()
+        );
+
+        match decoded {
+            Ok(Token::Array(n)) => assert!(n as u64 <= limits.max_array_len as u64),
+            Ok(Token::BulkString(Some(b))) => assert!(b.len() <= payload_len),
+            Ok(token) => panic!("unexpected token {:?}", token),
+            Err(ReadError::LimitExceeded(_)) => assert!(declared > limits.max_array_len as u64),
+            Err(ReadError::InsufficientBytes(_)) => {
+                assert!(declared.saturating_add(2) > payload_len as u64)
+            }
+            Err(ReadError::Malformed(m)) => assert!(
+                m == "overflowed i64"
+                    || (tag == '$' && declared.saturating_add(2) <= payload_len as u64)
+            ),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+}
+
+#[test]
+fn oversized_frames_are_rejected() {
+    let frames = [
+        "$536870913\r\n",
+        "$9223372036854775807\r\n",
+        "*1048577\r\n",
+        "*9223372036854775807\r\n",
+    ];
+
+    for frame in frames {
+        reset_largest_allocation();
+        let decoded = decode(&mut frame.as_bytes());
+        assert!(matches!(decoded, Err(ReadError::LimitExceeded(_))));
+        assert!(largest_allocation() < 64 * 1024);
+    }
+}
+
+#[tokio::test]
+async fn server_rejects_oversized_bulk_strings() {
+    let config = Config {
+        max_bulk_len: 16,
+        ..config()
+    };
+    let addr = launch_server(config).await;
+
+    let reply = send_and_read_to_end(&addr, b"*2\r\n$4\r\nECHO\r\n$17\r\n").await;
+    assert_eq!(
+        reply,
+        b"-ERR Protocol error: bulk length exceeds configured limit\r\n"
+    );
+}
+
+#[tokio::test]
+async fn server_rejects_oversized_arrays() {
+    let config = Config {
+        max_array_len: 4,
+        ..config()
+    };
+    let addr = launch_server(config).await;
+
+    let reply = send_and_read_to_end(&addr, b"*5\r\n").await;
+    assert_eq!(
+        reply,
+        b"-ERR Protocol error: array length exceeds configured limit\r\n"
+    );
+}
+
+#[tokio::test]
+async fn server_rejects_overfull_query_buffer() {
+    let config = Config {
+        max_query_buffer: 1024,
+        ..config()
+    };
+    let addr = launch_server(config).await;
+
+    let mut request = b"*2\r\n$4\r\nECHO\r\n$4096\r\n".to_vec();
+    request.extend(std::iter::repeat_n(b'x', 2048));
+
+    let reply = send_and_read_to_end(&addr, &request).await;
+    assert_eq!(
+        reply,
+        b"-ERR Protocol error: query buffer exceeds configured limit\r\n"
+    );
+}
+
+async fn send_and_read_to_end(addr: &str, request: &[u8]) -> Vec<u8> {
+    let mut stream = tokio::net::TcpStream::connect(addr)
+        .await
+        .expect("failed to connect to server");
+
+    stream
+        .write_all(request)
+        .await
+        .expect("failed write into stream");
+
+    let mut buffer = vec![];
+    let read = stream.read_to_end(&mut buffer);
+    if tokio::time::timeout(Duration::from_millis(500), read)
+        .await
+        .is_err()
+    {
+        panic!("connection was not closed within 500ms");
+    }
+
+    buffer
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use anode_kv::config::Config;
+use anode_kv::server::Server;
+use anode_kv::storage::StorageCommand;
+use anode_kv::transaction::{
+    segment_filename, write_to_log, Durability, RecoveryMode, RecoveryTarget, Stamp, LOG_MAGIC,
+    LOG_VERSION,
+};
+use anode_kv::types::{Blob, Value};
+use common::{connect, expect, launch_server};
+use tokio::net::TcpStream;
+
+/// Writes a single shard's log holding the commands, with the last record
+/// cut short as if the server crashed while writing it. It's written where
+/// logs were kept before segments, so it's taken up as the first segment.
+fn write_torn_log(basepath: &str, cmds: &[StorageCommand]) -> usize {
+    let mut log = LOG_MAGIC.to_vec();
+    log.extend(LOG_VERSION.to_le_bytes());
+    for (lsn, cmd) in (1..).zip(cmds) {
+        write_to_log(&mut log, Stamp { lsn, timestamp: 0 }, cmd).unwrap();
+    }
+    let whole = log.len();
+    let torn = StorageCommand::Incr(Blob(b"torn".to_vec()));
+    write_to_log(&mut log, Stamp::default(), &torn).unwrap();
+    log.truncate(log.len() - 3);
+
+    std::fs::write(format!("{}.current", basepath), &log).unwrap();
+    std::fs::write(format!("{}.shards", basepath), "1\n").unwrap();
+    whole
+}
+
+#[tokio::test]
+async fn it_recovers_from_a_torn_final_record() {
+    let dir = ".tmp/recovery-test-torn";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let basepath = format!("{}/log", dir);
+    let whole = write_torn_log(
+        &basepath,
+        &[StorageCommand::Set(
+            Blob(b"k".to_vec()),
+            Value::Blob(Blob(b"v".to_vec())),
+        )],
+    );
+
+    let config = Config {
+        address: "127.0.0.1:0".to_string(),
+        storage_basepath: basepath.clone(),
+        storage_shards: 1,
+        read_log: true,
+        ..Default::default()
+    };
+
+    // strict refuses to start, saying where the bad record is
+    let refused = Server::create(Config {
+        log_recovery: RecoveryMode::Strict,
+        ..config.clone()
+    })
+    .await;
+    let Err(e) = refused else {
+        panic!("expected strict recovery to refuse the log");
+    };
+    assert!(e.to_string().contains(&format!("offset {}", whole)));
+
+    // truncate-tail drops the torn record and carries on
+    let mut server = Server::create(Config {
+        log_recovery: RecoveryMode::TruncateTail,
+        ..config
+    })
+    .await
+    .unwrap();
+    let addr = server.addr();
+    tokio::spawn(async move { server.run().await.unwrap() });
+
+    let log_len = std::fs::metadata(segment_filename(&basepath, 0))
+        .unwrap()
+        .len();
+    assert_eq!(whole as u64, log_len);
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    expect(&mut stream, b"*2\r\n+GET\r\n+k\r\n", b"$1\r\nv\r\n").await;
+    expect(&mut stream, b"*2\r\n+GET\r\n+torn\r\n", b"$-1\r\n").await;
+}
+
+#[tokio::test]
+async fn it_recovers_to_an_lsn_and_promotes() {
+    let dir = ".tmp/recovery-test-until";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let config = Config {
+        address: "127.0.0.1:0".to_string(),
+        storage_basepath: format!("{}/log", dir),
+        storage_shards: 1,
+        durability: Durability::Logged,
+        ..Default::default()
+    };
+
+    let mut stream = connect(&launch_server(config.clone()).await).await;
+    expect(&mut stream, b"*3\r\n+SET\r\n+a\r\n+1\r\n", b"+OK\r\n").await;
+    expect(&mut stream, b"*3\r\n+SET\r\n+a\r\n+2\r\n", b"+OK\r\n").await;
+    expect(&mut stream, b"*3\r\n+SET\r\n+b\r\n+3\r\n", b"+OK\r\n").await;
+
+    // only the first write is replayed, and nothing can be written
+    let until = Some(RecoveryTarget::Lsn(1));
+    let mut recovered = connect(
+        &launch_server(Config {
+            recover_until: until,
+            ..config.clone()
+        })
+        .await,
+    )
+    .await;
+    expect(&mut recovered, b"*2\r\n+GET\r\n+a\r\n", b"$1\r\n1\r\n").await;
+    expect(&mut recovered, b"*2\r\n+GET\r\n+b\r\n", b"$-1\r\n").await;
+    expect(
+        &mut recovered,
+        b"*3\r\n+SET\r\n+c\r\n+4\r\n",
+        b"-READONLY The data was recovered to a point in the past; PROMOTE to write\r\n",
+    )
+    .await;
+
+    expect(&mut recovered, b"*1\r\n+PROMOTE\r\n", b"+OK\r\n").await;
+    expect(&mut recovered, b"*3\r\n+SET\r\n+c\r\n+4\r\n", b"+OK\r\n").await;
+
+    // promoting dropped the later writes from the log for good
+    let mut restarted = connect(
+        &launch_server(Config {
+            read_log: true,
+            ..config.clone()
+        })
+        .await,
+    )
+    .await;
+    expect(&mut restarted, b"*2\r\n+GET\r\n+a\r\n", b"$1\r\n1\r\n").await;
+    expect(&mut restarted, b"*2\r\n+GET\r\n+b\r\n", b"$-1\r\n").await;
+    expect(&mut restarted, b"*2\r\n+GET\r\n+c\r\n", b"$1\r\n4\r\n").await;
+
+    // and the rewritten log no longer goes back as far as the target
+    let refused = Server::create(Config {
+        recover_until: until,
+        ..config
+    })
+    .await;
+    let Err(e) = refused else {
+        panic!("expected recovery to a rewritten-over LSN to fail");
+    };
+    assert!(e.to_string().contains("lsn:1"));
+}
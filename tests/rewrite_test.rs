@@ -0,0 +1,128 @@
+mod common;
+
+use anode_kv::config::Config;
+use anode_kv::transaction::{segment_filename, Manifest};
+use common::{connect, expect, launch_server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+
+#[tokio::test]
+async fn it_rewrites_the_log_on_request() {
+    let config = config("manual", 0);
+    let log = config.storage_basepath.clone();
+    let mut stream = connect(&launch_server(config.clone()).await).await;
+
+    for _ in 0..100 {
+        write(&mut stream, b"*2\r\n+INCR\r\n+counter\r\n").await;
+    }
+    let grown = log_size(&log);
+
+    expect(
+        &mut stream,
+        b"*1\r\n+REWRITELOG\r\n",
+        b"+Background log rewriting started\r\n",
+    )
+    .await;
+    wait_for_log_below(&log, grown).await;
+
+    // what's in the rewritten log, plus what's written after, replays to
+    // the same data
+    expect(
+        &mut stream,
+        b"*2\r\n+INCR\r\n+counter\r\n",
+        b"$3\r\n101\r\n",
+    )
+    .await;
+    let mut restarted = connect(
+        &launch_server(Config {
+            read_log: true,
+            ..config
+        })
+        .await,
+    )
+    .await;
+    expect(
+        &mut restarted,
+        b"*2\r\n+GET\r\n+counter\r\n",
+        b"$3\r\n101\r\n",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn it_rewrites_the_log_as_it_grows() {
+    let config = config("growth", 500);
+    let log = config.storage_basepath.clone();
+    let mut stream = connect(&launch_server(config.clone()).await).await;
+
+    for _ in 0..100 {
+        write(&mut stream, b"*2\r\n+INCR\r\n+counter\r\n").await;
+    }
+
+    // the log passes 500 bytes a seventh of the way through, so it's been
+    // rewritten to a set plus whatever was logged while that was written,
+    // which is less than the header and a hundred 36 byte increments
+    wait_for_log_below(&log, 12 + 100 * 36).await;
+    let mut restarted = connect(
+        &launch_server(Config {
+            read_log: true,
+            ..config
+        })
+        .await,
+    )
+    .await;
+    expect(
+        &mut restarted,
+        b"*2\r\n+GET\r\n+counter\r\n",
+        b"$3\r\n100\r\n",
+    )
+    .await;
+}
+
+/// A single shard logging to its own directory, rewriting automatically once
+/// the log's at least min_size bytes, or never if that's 0.
+fn config(name: &str, min_size: u64) -> Config {
+    let dir = format!(".tmp/rewrite-test-{}", name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        storage_basepath: format!("{}/log", dir),
+        storage_shards: 1,
+        durability: anode_kv::transaction::Durability::Logged,
+        log_rewrite_percentage: if min_size == 0 { 0 } else { 100 },
+        log_rewrite_min_size: min_size,
+        ..Default::default()
+    }
+}
+
+async fn wait_for_log_below(log: &str, size: u64) {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while log_size(log) >= size {
+        assert!(Instant::now() < deadline, "log was not rewritten");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// The size of every segment of the log. A rewrite writes a new segment and
+/// removes the old ones.
+fn log_size(base: &str) -> u64 {
+    let manifest = Manifest::read(base).unwrap().unwrap();
+    manifest
+        .segments
+        .iter()
+        .filter_map(|segment| std::fs::metadata(segment_filename(base, segment.number)).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Sends a command and reads its bulk string reply.
+async fn write(stream: &mut TcpStream, request: &[u8]) {
+    stream.write_all(request).await.unwrap();
+    let mut reply = vec![];
+    while reply.iter().filter(|&&b| b == b'\n').count() < 2 {
+        reply.push(stream.read_u8().await.unwrap());
+    }
+    assert_eq!(b'$', reply[0]);
+}
@@ -0,0 +1,77 @@
+use std::io::Cursor;
+
+use anode_kv::codec::{decode, encode, Token};
+use anode_kv::storage::StorageCommand;
+use anode_kv::transaction::{write_to_log, LogIterator, Stamp};
+use anode_kv::types::{Blob, Value};
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+fn token() -> impl Strategy<Value = Token> {
+    prop_oneof![
+        "[^\r\n]*".prop_map(Token::SimpleString),
+        "[^\r\n]*".prop_map(Token::Error),
+        any::<i64>().prop_map(Token::Integer),
+        proptest::option::of(vec(any::<u8>(), 0..256)).prop_map(Token::BulkString),
+        (-1i64..=1024 * 1024).prop_map(Token::Array),
+    ]
+}
+
+fn blob() -> impl Strategy<Value = Blob> {
+    vec(any::<u8>(), 0..64).prop_map(Blob)
+}
+
+/// Only commands which change the data are written to the log, so those are
+/// the only ones which can make the round trip.
+fn logged_command() -> impl Strategy<Value = StorageCommand> {
+    prop_oneof![
+        (blob(), blob()).prop_map(|(k, v)| StorageCommand::Set(k, Value::Blob(v))),
+        (blob(), any::<i64>()).prop_map(|(k, i)| StorageCommand::Set(k, Value::Int(i))),
+        (blob(), blob(), any::<u32>()).prop_map(|(k, v, f)| StorageCommand::SetWithFlags(
+            k,
+            Value::Blob(v),
+            f
+        )),
+        blob().prop_map(StorageCommand::Incr),
+        blob().prop_map(StorageCommand::Decr),
+        (blob(), blob()).prop_map(|(k, v)| StorageCommand::SetAdd(k, v)),
+        (blob(), blob()).prop_map(|(k, v)| StorageCommand::SetRemove(k, v)),
+        blob().prop_map(|k| StorageCommand::Delete(vec![k])),
+        Just(StorageCommand::FlushAll),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn tokens_survive_encode_then_decode(tokens in vec(token(), 1..16)) {
+        let mut buf: Vec<u8> = vec![];
+        for token in &tokens {
+            encode(&mut buf, token).unwrap();
+        }
+
+        let mut cursor = Cursor::new(&buf[..]);
+        for token in &tokens {
+            prop_assert_eq!(token, &decode(&mut cursor).unwrap());
+        }
+        prop_assert_eq!(buf.len() as u64, cursor.position());
+    }
+
+    #[test]
+    fn commands_survive_write_then_read(
+        cmds in vec((any::<u64>(), any::<u64>(), logged_command()), 1..16)
+    ) {
+        let mut buf: Vec<u8> = vec![];
+        for (lsn, timestamp, cmd) in &cmds {
+            let stamp = Stamp { lsn: *lsn, timestamp: *timestamp };
+            write_to_log(&mut buf, stamp, cmd).unwrap();
+        }
+
+        let mut iter = LogIterator::new(&buf[..]);
+        for (lsn, timestamp, cmd) in cmds {
+            let record = iter.next_record().unwrap().unwrap();
+            prop_assert_eq!(Stamp { lsn, timestamp }, record.stamp);
+            prop_assert_eq!(cmd, record.cmd);
+        }
+        prop_assert_eq!(buf.len() as u64, iter.offset());
+    }
+}
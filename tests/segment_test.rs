@@ -0,0 +1,97 @@
+mod common;
+
+use anode_kv::config::Config;
+use anode_kv::transaction::{segment_filename, Manifest};
+use common::{connect, expect, launch_server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn it_rolls_over_segments_and_reads_across_them() {
+    let config = config("rollover");
+    let mut stream = connect(&launch_server(config.clone()).await).await;
+
+    for _ in 0..30 {
+        write(&mut stream, b"*2\r\n+INCR\r\n+counter\r\n").await;
+    }
+    let manifest = Manifest::read(&config.storage_basepath).unwrap().unwrap();
+    assert!(manifest.segments.len() > 1, "log did not roll over");
+    assert_eq!(0, manifest.first_seq());
+
+    let mut restarted = connect(
+        &launch_server(Config {
+            read_log: true,
+            ..config
+        })
+        .await,
+    )
+    .await;
+    expect(
+        &mut restarted,
+        b"*2\r\n+GET\r\n+counter\r\n",
+        b"$2\r\n30\r\n",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn it_removes_segments_once_snapshots_cover_them() {
+    let config = config("retention");
+    let base = config.storage_basepath.clone();
+    let mut stream = connect(&launch_server(config.clone()).await).await;
+
+    for _ in 0..30 {
+        write(&mut stream, b"*2\r\n+INCR\r\n+counter\r\n").await;
+    }
+    expect(&mut stream, b"*1\r\n+SAVE\r\n", b"+OK\r\n").await;
+    // one snapshot isn't enough, as the previous is kept to fall back on
+    assert!(std::path::Path::new(&segment_filename(&base, 0)).exists());
+
+    write(&mut stream, b"*2\r\n+INCR\r\n+counter\r\n").await;
+    expect(&mut stream, b"*1\r\n+SAVE\r\n", b"+OK\r\n").await;
+    let manifest = Manifest::read(&base).unwrap().unwrap();
+    assert!(manifest.first_seq() > 0, "no segments were removed");
+    assert!(!std::path::Path::new(&segment_filename(&base, 0)).exists());
+
+    let mut restarted = connect(
+        &launch_server(Config {
+            read_log: true,
+            ..config
+        })
+        .await,
+    )
+    .await;
+    expect(
+        &mut restarted,
+        b"*2\r\n+GET\r\n+counter\r\n",
+        b"$2\r\n31\r\n",
+    )
+    .await;
+}
+
+/// A single shard logging to its own directory, starting a new segment about
+/// every ten increments.
+fn config(name: &str) -> Config {
+    let dir = format!(".tmp/segment-test-{}", name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        storage_basepath: format!("{}/log", dir),
+        storage_shards: 1,
+        durability: anode_kv::transaction::Durability::Logged,
+        log_rewrite_percentage: 0,
+        log_segment_size: 200,
+        ..Default::default()
+    }
+}
+
+/// Sends a command and reads its bulk string reply.
+async fn write(stream: &mut TcpStream, request: &[u8]) {
+    stream.write_all(request).await.unwrap();
+    let mut reply = vec![];
+    while reply.iter().filter(|&&b| b == b'\n').count() < 2 {
+        reply.push(stream.read_u8().await.unwrap());
+    }
+    assert_eq!(b'$', reply[0]);
+}
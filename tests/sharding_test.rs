@@ -0,0 +1,198 @@
+mod common;
+
+use std::collections::HashSet;
+
+use anode_kv::config::Config;
+use anode_kv::server::Server;
+use anode_kv::storage::shard_for;
+use anode_kv::types::Blob;
+use common::connect;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+const SHARDS: usize = 4;
+
+#[tokio::test]
+async fn it_spreads_keys_over_shards_and_keeps_them() {
+    let dir = std::env::temp_dir().join(format!("anode-sharding-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let basepath = dir.join("log").to_string_lossy().to_string();
+
+    let keys: Vec<String> = (0..8).map(|i| format!("s{}", i)).collect();
+    let shards: HashSet<usize> = keys
+        .iter()
+        .map(|key| shard_for(&Blob(key.as_bytes().to_vec()), SHARDS))
+        .collect();
+    assert!(shards.len() > 1, "expected the keys to span shards");
+
+    let mut server = Server::create(config(&basepath, SHARDS, false))
+        .await
+        .unwrap();
+    let addr = server.addr();
+    let running = tokio::spawn(async move { server.run().await });
+
+    let mut client = connect(&addr).await;
+    for key in &keys {
+        sadd(&mut client, key, "shared").await;
+        sadd(&mut client, key, &format!("only-{}", key)).await;
+    }
+    assert_sinter_and_sunion(&mut client, &keys).await;
+    assert_mget(&mut client, &keys).await;
+
+    client.write_all(b"*1\r\n+SHUTDOWN\r\n").await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), running)
+        .await
+        .expect("server did not shut down within 5s")
+        .unwrap()
+        .unwrap();
+    for shard in 0..SHARDS {
+        let log = format!("{}.shard{}.manifest", basepath, shard);
+        assert!(std::path::Path::new(&log).exists(), "missing {}", log);
+    }
+
+    // a different number of shards would put keys on the wrong shard
+    let resharded = Server::create(config(&basepath, 2, true)).await;
+    assert!(resharded.is_err());
+
+    let mut server = Server::create(config(&basepath, SHARDS, true))
+        .await
+        .unwrap();
+    let addr = server.addr();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let mut client = connect(&addr).await;
+    assert_sinter_and_sunion(&mut client, &keys).await;
+}
+
+#[tokio::test]
+async fn it_splits_a_log_from_before_sharding() {
+    let dir = ".tmp/sharding-test-upgrade";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let basepath = format!("{}/log", dir);
+
+    // a log as it was written before logs had headers or shards: a tag, then
+    // each field as a native usize length and its bytes
+    let keys: Vec<String> = (0..8).map(|i| format!("k{}", i)).collect();
+    let mut legacy = vec![];
+    for key in &keys {
+        legacy.push(b'S');
+        legacy.extend(key.len().to_le_bytes());
+        legacy.extend(key.as_bytes());
+        legacy.push(b'B');
+        legacy.extend(1usize.to_le_bytes());
+        legacy.push(b'v');
+    }
+    std::fs::write(format!("{}.current", basepath), &legacy).unwrap();
+
+    let mut server = Server::create(config(&basepath, SHARDS, true))
+        .await
+        .expect("should start on a log from before sharding");
+    let addr = server.addr();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    let mut client = connect(&addr).await;
+    for key in &keys {
+        let get = format!("*2\r\n+GET\r\n+{}\r\n", key);
+        client.write_all(get.as_bytes()).await.unwrap();
+        assert_eq!("$1", read_line(&mut client).await);
+        assert_eq!("v", read_line(&mut client).await);
+    }
+    assert_eq!(
+        legacy,
+        std::fs::read(format!("{}.unsharded", basepath)).unwrap()
+    );
+    assert_eq!(
+        format!("{}\n", SHARDS),
+        std::fs::read_to_string(format!("{}.shards", basepath)).unwrap()
+    );
+}
+
+async fn assert_sinter_and_sunion(client: &mut TcpStream, keys: &[String]) {
+    let intersection = set_reply(client, "SINTER", keys).await;
+    assert_eq!(HashSet::from(["shared".to_string()]), intersection);
+
+    let union = set_reply(client, "SUNION", keys).await;
+    let mut expected: HashSet<String> = keys.iter().map(|key| format!("only-{}", key)).collect();
+    expected.insert("shared".to_string());
+    assert_eq!(expected, union);
+}
+
+/// MGET gathers values from every shard, in the order asked for, with nil for
+/// keys which are missing or hold a set.
+async fn assert_mget(client: &mut TcpStream, set_keys: &[String]) {
+    let keys: Vec<String> = (0..8).map(|i| format!("m{}", i)).collect();
+    for key in &keys {
+        let request = format!("*3\r\n+SET\r\n+{}\r\n+v{}\r\n", key, key);
+        client.write_all(request.as_bytes()).await.unwrap();
+        assert_eq!("+OK", read_line(client).await);
+    }
+
+    let mut request = format!("*{}\r\n+MGET\r\n", keys.len() + 4);
+    for key in keys.iter().chain([&set_keys[0], &"missing".to_string()]) {
+        request.push_str(&format!("+{}\r\n", key));
+    }
+    request.push_str(&format!("+{}\r\n", keys[0]));
+    client.write_all(request.as_bytes()).await.unwrap();
+
+    assert_eq!(format!("*{}", keys.len() + 3), read_line(client).await);
+    for key in &keys {
+        assert_eq!("$3", read_line(client).await);
+        assert_eq!(format!("v{}", key), read_line(client).await);
+    }
+    assert_eq!("$-1", read_line(client).await);
+    assert_eq!("$-1", read_line(client).await);
+    assert_eq!("$3", read_line(client).await);
+    assert_eq!(format!("v{}", keys[0]), read_line(client).await);
+}
+
+async fn sadd(stream: &mut TcpStream, key: &str, member: &str) {
+    let request = format!("*3\r\n+SADD\r\n+{}\r\n+{}\r\n", key, member);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    assert_eq!("$1", read_line(stream).await);
+    assert_eq!("1", read_line(stream).await);
+}
+
+async fn set_reply(stream: &mut TcpStream, command: &str, keys: &[String]) -> HashSet<String> {
+    let mut request = format!("*{}\r\n+{}\r\n", keys.len() + 1, command);
+    for key in keys {
+        request.push_str(&format!("+{}\r\n", key));
+    }
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let header = read_line(stream).await;
+    let count: usize = header.strip_prefix('*').unwrap().parse().unwrap();
+    let mut members = HashSet::new();
+    for _ in 0..count {
+        read_line(stream).await;
+        members.insert(read_line(stream).await);
+    }
+    members
+}
+
+async fn read_line(stream: &mut TcpStream) -> String {
+    let mut line = vec![];
+    while !line.ends_with(b"\r\n") {
+        let byte = tokio::time::timeout(Duration::from_secs(1), stream.read_u8())
+            .await
+            .expect("response did not return within 1s")
+            .unwrap();
+        line.push(byte);
+    }
+    String::from_utf8(line[..line.len() - 2].to_vec()).unwrap()
+}
+
+fn config(basepath: &str, shards: usize, read_log: bool) -> Config {
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        storage_basepath: basepath.to_string(),
+        storage_shards: shards,
+        read_log,
+        ..Default::default()
+    }
+}
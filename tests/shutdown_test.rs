@@ -0,0 +1,87 @@
+mod common;
+
+use anode_kv::config::Config;
+use anode_kv::server::Server;
+use common::{assert_closed, connect, expect};
+use tokio::io::AsyncWriteExt;
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn it_shuts_down_and_keeps_what_was_written() {
+    let dir = std::env::temp_dir().join(format!("anode-shutdown-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let basepath = dir.join("log").to_string_lossy().to_string();
+
+    let mut server = Server::create(config(&basepath, false)).await.unwrap();
+    let addr = server.addr();
+    let running = tokio::spawn(async move { server.run().await });
+
+    let mut client = connect(&addr).await;
+    let mut idle = connect(&addr).await;
+    expect(&mut client, b"*3\r\n+SET\r\n+a\r\n+1\r\n", b"+OK\r\n").await;
+    expect(&mut client, b"*3\r\n+SET\r\n+b\r\n+2\r\n", b"+OK\r\n").await;
+
+    client.write_all(b"*1\r\n+SHUTDOWN\r\n").await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), running)
+        .await
+        .expect("server did not shut down within 5s")
+        .unwrap()
+        .unwrap();
+    assert_closed(&mut client).await;
+    assert_closed(&mut idle).await;
+
+    let mut server = Server::create(config(&basepath, true)).await.unwrap();
+    let addr = server.addr();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    let mut client = connect(&addr).await;
+    expect(&mut client, b"*2\r\n+GET\r\n+a\r\n", b"$1\r\n1\r\n").await;
+    expect(&mut client, b"*2\r\n+GET\r\n+b\r\n", b"$1\r\n2\r\n").await;
+}
+
+#[tokio::test]
+async fn it_saves_every_shard_on_shutdown_save() {
+    let dir = std::env::temp_dir().join(format!("anode-shutdown-save-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let basepath = dir.join("log").to_string_lossy().to_string();
+
+    let config = config(&basepath, false);
+    let shards = config.storage_shards;
+    let mut server = Server::create(config.clone()).await.unwrap();
+    let addr = server.addr();
+    let running = tokio::spawn(async move { server.run().await });
+
+    let mut client = connect(&addr).await;
+    expect(&mut client, b"*3\r\n+SET\r\n+a\r\n+1\r\n", b"+OK\r\n").await;
+    client
+        .write_all(b"*2\r\n+SHUTDOWN\r\n+SAVE\r\n")
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), running)
+        .await
+        .expect("server did not shut down within 5s")
+        .unwrap()
+        .unwrap();
+
+    for shard in 0..shards {
+        let snapshot = format!("{}.snapshot", config.shard_basepath(shard));
+        assert!(
+            std::path::Path::new(&snapshot).exists(),
+            "{} wasn't saved",
+            snapshot
+        );
+    }
+}
+
+fn config(basepath: &str, read_log: bool) -> Config {
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        storage_basepath: basepath.to_string(),
+        read_log,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use anode_kv::config::Config;
+use anode_kv::transaction::{
+    previous_snapshot_filename, segment_filename, snapshot_filename, RecoveryMode,
+};
+use common::{connect, expect, launch_server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{Duration, Instant};
+
+#[tokio::test]
+async fn it_recovers_from_a_snapshot_and_the_log_after_it() {
+    let config = config("tail");
+    let mut stream = connect(&launch_server(config.clone()).await).await;
+
+    expect(&mut stream, b"*3\r\n+SET\r\n+a\r\n+1\r\n", b"+OK\r\n").await;
+    expect(&mut stream, b"*3\r\n+SADD\r\n+s\r\n+x\r\n", b"$1\r\n1\r\n").await;
+    expect(&mut stream, b"*1\r\n+SAVE\r\n", b"+OK\r\n").await;
+    expect(&mut stream, b"*2\r\n+INCR\r\n+a\r\n", b"$1\r\n2\r\n").await;
+    expect(&mut stream, b"*3\r\n+SADD\r\n+s\r\n+y\r\n", b"$1\r\n1\r\n").await;
+
+    // spoil the start of the log: strict recovery would refuse it, so the
+    // restart only works if it replays just what came after the snapshot
+    let log = segment_filename(&config.storage_basepath, 0);
+    let mut bytes = std::fs::read(&log).unwrap();
+    bytes[20] ^= 0xff;
+    std::fs::write(&log, bytes).unwrap();
+
+    let mut restarted = connect(
+        &launch_server(Config {
+            read_log: true,
+            log_recovery: RecoveryMode::Strict,
+            ..config
+        })
+        .await,
+    )
+    .await;
+    expect(&mut restarted, b"*2\r\n+GET\r\n+a\r\n", b"$1\r\n2\r\n").await;
+    expect(&mut restarted, b"*2\r\n+SMEMBERS\r\n+s\r\n", b"*2\r\n").await;
+}
+
+#[tokio::test]
+async fn it_falls_back_on_the_previous_snapshot() {
+    let config = config("previous");
+    let mut stream = connect(&launch_server(config.clone()).await).await;
+
+    expect(&mut stream, b"*3\r\n+SET\r\n+a\r\n+1\r\n", b"+OK\r\n").await;
+    expect(&mut stream, b"*1\r\n+SAVE\r\n", b"+OK\r\n").await;
+    expect(&mut stream, b"*3\r\n+SET\r\n+b\r\n+2\r\n", b"+OK\r\n").await;
+    expect(&mut stream, b"*1\r\n+SAVE\r\n", b"+OK\r\n").await;
+    assert!(std::path::Path::new(&previous_snapshot_filename(&config.storage_basepath)).exists());
+
+    let newest = snapshot_filename(&config.storage_basepath);
+    let mut bytes = std::fs::read(&newest).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&newest, bytes).unwrap();
+
+    let mut restarted = connect(
+        &launch_server(Config {
+            read_log: true,
+            ..config
+        })
+        .await,
+    )
+    .await;
+    expect(&mut restarted, b"*2\r\n+GET\r\n+a\r\n", b"$1\r\n1\r\n").await;
+    expect(&mut restarted, b"*2\r\n+GET\r\n+b\r\n", b"$1\r\n2\r\n").await;
+}
+
+#[tokio::test]
+async fn it_saves_in_the_background_and_by_rule() {
+    let config = Config {
+        save: vec!["1 1".parse().unwrap()],
+        ..config("rules")
+    };
+    let snapshot = snapshot_filename(&config.storage_basepath);
+    let mut stream = connect(&launch_server(config).await).await;
+
+    expect(
+        &mut stream,
+        b"*1\r\n+BGSAVE\r\n",
+        b"+Background saving started\r\n",
+    )
+    .await;
+    wait_for(|| std::path::Path::new(&snapshot).exists()).await;
+    std::fs::remove_file(&snapshot).unwrap();
+
+    // nothing's changed, so the rule doesn't save again until something does
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(!std::path::Path::new(&snapshot).exists());
+    expect(&mut stream, b"*3\r\n+SET\r\n+a\r\n+1\r\n", b"+OK\r\n").await;
+    wait_for(|| std::path::Path::new(&snapshot).exists()).await;
+
+    stream.write_all(b"*1\r\n+LASTSAVE\r\n").await.unwrap();
+    let mut reply = vec![];
+    while !reply.ends_with(b"\r\n") {
+        reply.push(stream.read_u8().await.unwrap());
+    }
+    assert_eq!(b':', reply[0]);
+}
+
+/// A single shard logging to its own directory.
+fn config(name: &str) -> Config {
+    let dir = format!(".tmp/snapshot-test-{}", name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        storage_basepath: format!("{}/log", dir),
+        storage_shards: 1,
+        durability: anode_kv::transaction::Durability::Logged,
+        ..Default::default()
+    }
+}
+
+async fn wait_for(done: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for save");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
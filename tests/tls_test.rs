@@ -0,0 +1,196 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anode_kv::config::Config;
+use anode_kv::server::Server;
+use common::expect;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+struct Authority {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Authority {
+    fn new() -> Authority {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "anode test ca");
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        Authority { cert, key }
+    }
+
+    fn issue(&self, common_name: &str) -> (Certificate, KeyPair) {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        (cert, key)
+    }
+}
+
+#[tokio::test]
+async fn it_serves_resp_over_tls() {
+    let dir = scratch_dir("serves");
+    let ca = Authority::new();
+    let (cert, key) = ca.issue("server");
+    write_pem(&dir, &ca, &cert, &key);
+
+    let (addr, tls_addr) = launch_server(tls_config(&dir, false)).await;
+
+    let mut stream = connect_tls(&tls_addr, &ca, None).await.unwrap();
+    expect(&mut stream, b"*3\r\n+SET\r\n+k\r\n+v\r\n", b"+OK\r\n").await;
+
+    let mut plain = TcpStream::connect(&addr).await.unwrap();
+    plain.write_all(b"*2\r\n+GET\r\n+k\r\n").await.unwrap();
+    let mut buffer = vec![0; b"$1\r\nv\r\n".len()];
+    plain.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(b"$1\r\nv\r\n".to_vec(), buffer);
+}
+
+#[tokio::test]
+async fn it_requires_client_certificates_when_configured() {
+    let dir = scratch_dir("mtls");
+    let ca = Authority::new();
+    let (cert, key) = ca.issue("server");
+    write_pem(&dir, &ca, &cert, &key);
+
+    let (_, tls_addr) = launch_server(tls_config(&dir, true)).await;
+
+    let client = ca.issue("reporting");
+    let mut stream = connect_tls(&tls_addr, &ca, Some(&client)).await.unwrap();
+    expect(&mut stream, b"*2\r\n+ECHO\r\n+hi\r\n", b"$2\r\nhi\r\n").await;
+
+    // an untrusted certificate is as good as none
+    let stranger = Authority::new().issue("reporting");
+    for identity in [None, Some(&stranger)] {
+        let rejected = match connect_tls(&tls_addr, &ca, identity).await {
+            Err(_) => true,
+            Ok(mut stream) => {
+                // with TLS 1.3 the client finds out on its first read
+                let _ = stream.write_all(b"*2\r\n+ECHO\r\n+hi\r\n").await;
+                let mut buffer = [0u8; 16];
+                !matches!(stream.read(&mut buffer).await, Ok(n) if n > 0)
+            }
+        };
+        assert!(rejected);
+    }
+}
+
+#[tokio::test]
+async fn it_reloads_certificates_without_dropping_connections() {
+    let dir = scratch_dir("reload");
+    let ca = Authority::new();
+    let (cert, key) = ca.issue("before");
+    write_pem(&dir, &ca, &cert, &key);
+
+    let (_, tls_addr) = launch_server(tls_config(&dir, false)).await;
+
+    let mut established = connect_tls(&tls_addr, &ca, None).await.unwrap();
+    assert_eq!(cert.der(), &server_cert(&established));
+
+    let (cert, key) = ca.issue("after");
+    write_pem(&dir, &ca, &cert, &key);
+
+    let mut reloaded = false;
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let stream = connect_tls(&tls_addr, &ca, None).await.unwrap();
+        if cert.der() == &server_cert(&stream) {
+            reloaded = true;
+            break;
+        }
+    }
+    assert!(reloaded, "new certificate was never served");
+
+    expect(
+        &mut established,
+        b"*2\r\n+ECHO\r\n+still here\r\n",
+        b"$10\r\nstill here\r\n",
+    )
+    .await;
+}
+
+fn server_cert(stream: &TlsStream<TcpStream>) -> CertificateDer<'static> {
+    let (_, session) = stream.get_ref();
+    session.peer_certificates().unwrap()[0].clone().into_owned()
+}
+
+async fn connect_tls(
+    addr: &str,
+    ca: &Authority,
+    identity: Option<&(Certificate, KeyPair)>,
+) -> std::io::Result<TlsStream<TcpStream>> {
+    let mut roots = RootCertStore::empty();
+    roots.add(ca.cert.der().clone()).unwrap();
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => {
+            let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+            builder
+                .with_client_auth_cert(vec![cert.der().clone()], key)
+                .unwrap()
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let connector = TlsConnector::from(Arc::new(config));
+    let socket = TcpStream::connect(addr).await?;
+    let name = ServerName::try_from("localhost").unwrap();
+    connector.connect(name, socket).await
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("anode-tls-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write_pem(dir: &Path, ca: &Authority, cert: &Certificate, key: &KeyPair) {
+    std::fs::write(dir.join("ca.pem"), ca.cert.pem()).unwrap();
+    std::fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), key.serialize_pem()).unwrap();
+}
+
+fn tls_config(dir: &Path, require_client_cert: bool) -> Config {
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        tls_address: Some("127.0.0.1:0".to_string()),
+        tls_cert: Some(dir.join("cert.pem")),
+        tls_key: Some(dir.join("key.pem")),
+        tls_ca_cert: Some(dir.join("ca.pem")),
+        tls_require_client_cert: require_client_cert,
+        tls_reload_interval_ms: 10,
+        ..Default::default()
+    }
+}
+
+async fn launch_server(config: Config) -> (String, String) {
+    let mut server = Server::create(config).await.unwrap();
+    let addr = server.addr();
+    let tls_addr = server.tls_addr().unwrap();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    (addr, tls_addr)
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use anode_kv::config::Config;
+use anode_kv::server::Server;
+use common::{expect, launch_server};
+use tokio::net::{TcpStream, UnixStream};
+
+#[tokio::test]
+async fn it_serves_resp_over_unix_sockets() {
+    let paths = vec![socket_path("first"), socket_path("second")];
+    let config = Config {
+        address: "127.0.0.1:0".to_string(),
+        unix_socket: paths.clone(),
+        unix_socket_mode: Some(0o600),
+        ..Default::default()
+    };
+    let addr = launch_server(config).await;
+
+    for path in &paths {
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o7777);
+    }
+
+    let mut first = UnixStream::connect(&paths[0]).await.unwrap();
+    let mut second = UnixStream::connect(&paths[1]).await.unwrap();
+    let mut tcp = TcpStream::connect(&addr).await.unwrap();
+
+    expect(&mut first, b"*3\r\n+SET\r\n+k\r\n+v\r\n", b"+OK\r\n").await;
+    expect(&mut second, b"*2\r\n+GET\r\n+k\r\n", b"$1\r\nv\r\n").await;
+    expect(&mut tcp, b"*2\r\n+GET\r\n+k\r\n", b"$1\r\nv\r\n").await;
+}
+
+#[tokio::test]
+async fn it_replaces_stale_sockets_but_not_live_ones_or_other_files() {
+    let stale = socket_path("stale");
+    drop(std::os::unix::net::UnixListener::bind(&stale).unwrap());
+    let config = Config {
+        address: "127.0.0.1:0".to_string(),
+        unix_socket: vec![stale.clone()],
+        ..Default::default()
+    };
+    launch_server(config).await;
+    let mut stream = UnixStream::connect(&stale).await.unwrap();
+    expect(&mut stream, b"*2\r\n+ECHO\r\n+hi\r\n", b"$2\r\nhi\r\n").await;
+
+    // a socket something is still listening on is left alone
+    let config = Config {
+        address: "127.0.0.1:0".to_string(),
+        unix_socket: vec![stale.clone()],
+        ..Default::default()
+    };
+    assert!(Server::create(config).await.is_err());
+    expect(&mut stream, b"*2\r\n+ECHO\r\n+hi\r\n", b"$2\r\nhi\r\n").await;
+    let mut again = UnixStream::connect(&stale).await.unwrap();
+    expect(&mut again, b"*2\r\n+ECHO\r\n+hi\r\n", b"$2\r\nhi\r\n").await;
+
+    let regular = socket_path("regular");
+    std::fs::write(&regular, b"precious").unwrap();
+    let config = Config {
+        address: "127.0.0.1:0".to_string(),
+        unix_socket: vec![regular.clone()],
+        ..Default::default()
+    };
+    assert!(Server::create(config).await.is_err());
+    assert_eq!(b"precious".to_vec(), std::fs::read(&regular).unwrap());
+    std::fs::remove_file(&regular).unwrap();
+}
+
+fn socket_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("anode-{}-{}.sock", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}